@@ -4,7 +4,7 @@ extern crate amber;
 extern crate rand;
 extern crate test;
 
-use amber::matcher::{BruteForceMatcher, FjsMatcher, Matcher, QuickSearchMatcher, TbmMatcher};
+use amber::matcher::{BruteForceMatcher, FjsMatcher, Matcher, MemmemMatcher, QuickSearchMatcher, TbmMatcher};
 use rand::{Rng, SeedableRng, StdRng};
 use test::Bencher;
 
@@ -79,6 +79,13 @@ fn normal_fjs(b: &mut Bencher) {
     bench(b, &m);
 }
 
+#[bench]
+fn normal_memmem(b: &mut Bencher) {
+    let mut m = MemmemMatcher::new();
+    m.max_threads = 1;
+    bench(b, &m);
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // Multithread
 // ---------------------------------------------------------------------------------------------------------------------
@@ -1,19 +1,40 @@
-use amber::console::{Console, ConsoleTextKind};
-use amber::matcher::{QuickSearchMatcher, RegexMatcher, TbmMatcher};
-use amber::pipeline::{Pipeline, PipelineFork, PipelineInfo, PipelineJoin};
-use amber::pipeline_finder::PipelineFinder;
-use amber::pipeline_matcher::PipelineMatcher;
-use amber::pipeline_replacer::PipelineReplacer;
-use amber::pipeline_sorter::PipelineSorter;
-use amber::util::{as_secsf64, decode_error, exit, read_from_file};
+use amber::checkpoint::{Checkpoint, CheckpointHeader};
+use amber::console::{ColorChoice, Console, ConsoleTextKind, ErrorFormat};
+use amber::encoding::{EncodingMap, EncodingRule};
+use amber::history::{History, HistoryEntry};
+use amber::keymap::Keymap;
+use amber::lexer::TokenKind;
+use amber::matcher::{
+    CaseFoldMatcher, Matcher, MatcherKind, MemmemMatcher, QuickSearchMatcher, RegexMatcher, TbmMatcher,
+};
+use amber::pipeline::{Pipeline, PipelineFork, PipelineInfo};
+use amber::pipeline_builder::{PipelineBuilder, ID_FINDER, ID_MATCHER, ID_OUTPUT, ID_SORTER};
+use amber::pipeline_finder::{OwnerFilter, PathInfo, PermFilter, PipelineFinder, VcsFilter};
+use amber::pipeline_matcher::{BinaryFiles, Overlap, PipelineMatcher, Within};
+use amber::pipeline_replacer::{Occurrence, PipelineReplacer, SymlinkPolicy};
+use amber::profile::Profile;
+use amber::renamer::Renamer;
+use amber::syntax::{SyntaxFilter, SyntaxLang};
+use amber::tmpfile;
+use amber::trace::{TraceStage, TraceStages, Tracer};
+use amber::util::{
+    args_with_env, as_secsf64, decode_error, exit, exit_status, find_arg, lower_priority, parse_map, read_from_file,
+    split_args, unescape, unescape_regex, ColumnUnit, IndexRange, IoLimiter, IoRate, IoStrategy, MemoryBudget,
+    EXIT_ERROR,
+};
 use crossbeam::channel::unbounded;
 use dirs;
+use getch::Getch;
 use lazy_static::lazy_static;
 use serde_derive::Deserialize;
 use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::io::Read;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use structopt::{clap, StructOpt};
@@ -27,14 +48,15 @@ use structopt::{clap, StructOpt};
 #[structopt(long_version(option_env!("LONG_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))))]
 #[structopt(setting(clap::AppSettings::ColoredHelp))]
 #[structopt(setting(clap::AppSettings::DeriveDisplayOrder))]
+#[structopt(setting(clap::AppSettings::AllArgsOverrideSelf))]
 pub struct Opt {
     /// Keyword for search
-    #[structopt(name = "KEYWORD")]
-    pub keyword: String,
+    #[structopt(name = "KEYWORD", required_unless = "map")]
+    pub keyword: Option<String>,
 
     /// Keyword for replace
-    #[structopt(name = "REPLACEMENT")]
-    pub replacement: String,
+    #[structopt(name = "REPLACEMENT", required_unless = "map")]
+    pub replacement: Option<String>,
 
     /// Use file contents of KEYWORD as keyword for search
     #[structopt(long = "key-from-file")]
@@ -44,6 +66,42 @@ pub struct Opt {
     #[structopt(long = "rep-from-file")]
     pub rep_from_file: bool,
 
+    /// Replace all pairs of `keyword<TAB>replacement` lines in FILE at once ( KEYWORD and REPLACEMENT are paths )
+    #[structopt(long = "map", value_name = "FILE", conflicts_with_all = &["key-from-file", "rep-from-file", "rename"])]
+    pub map: Option<String>,
+
+    /// Matches replaced when keywords of --map overlap ( first: the earliest one, longest: the longest one,
+    /// report-all: files with overlapping matches are not replaced and reported as errors )
+    #[structopt(
+        long = "overlap",
+        default_value = "first",
+        value_name = "POLICY",
+        possible_values = &["report-all", "longest", "first"]
+    )]
+    pub overlap: Overlap,
+
+    /// Run REPLACEMENT as a shell command per match, which reads the matched text from stdin and prints the replacement
+    #[structopt(long = "exec-replace")]
+    pub exec_replace: bool,
+
+    /// Run CMD by the shell for each file with the replaced contents as stdin ( and `$AMBR_FILE` ),
+    /// and don't replace the file if it fails ( e.g. `jq .`, `python -m py_compile "$AMBR_FILE"` )
+    #[structopt(long = "validate", value_name = "CMD")]
+    pub validate: Option<String>,
+
+    /// Read each file again after replaced, and report the files whose replacements are not found or whose keywords
+    /// are left unless the replacements contain them ( e.g. encoding or offset bugs )
+    #[structopt(long = "verify")]
+    pub verify: bool,
+
+    /// Run CMD for each file replaced, where `{}` is replaced by the path or the path is appended ( e.g. `git add` )
+    #[structopt(long = "post-cmd", value_name = "CMD")]
+    pub post_cmd: Option<String>,
+
+    /// Decode `\xNN` in KEYWORD and REPLACEMENT as arbitrary bytes ( `\\` is a backslash )
+    #[structopt(long = "escapes")]
+    pub escapes: bool,
+
     /// Search paths
     #[structopt(name = "PATHS")]
     pub paths: Vec<String>,
@@ -52,6 +110,58 @@ pub struct Opt {
     #[structopt(long = "max-threads", default_value = &MAX_THREADS, value_name = "NUM")]
     pub max_threads: usize,
 
+    /// Number of threads reading directories
+    #[structopt(long = "finder-threads", default_value = "1", value_name = "NUM")]
+    pub finder_threads: usize,
+
+    /// Search NTFS alternate data streams of files like `file.txt:stream` ( Windows only )
+    #[structopt(long = "streams")]
+    pub streams: bool,
+
+    /// Don't descend into directories on other file systems ( e.g. NFS mounts, /proc ) than each of PATHS
+    #[structopt(long = "one-file-system")]
+    pub one_file_system: bool,
+
+    /// Search hard links and symbolic links of a file as many times as found ( Unix only )
+    #[structopt(long = "no-dedupe")]
+    pub no_dedupe: bool,
+
+    /// Skip symbolic links resolved through more than N links ( e.g. a -> b -> c is 2 )
+    #[structopt(long = "max-symlink-depth", value_name = "N")]
+    pub max_symlink_depth: Option<usize>,
+
+    /// Print broken symbolic links with the targets, which are counted in the statistics anyway
+    #[structopt(long = "broken-symlinks")]
+    pub broken_symlinks: bool,
+
+    /// Search only files owned by USER given by the name or the user ID ( Unix only )
+    #[structopt(long = "owner", value_name = "USER")]
+    pub owner: Option<OwnerFilter>,
+
+    /// Search only files with permission bits in octal ( 644: exactly, -022: all of bits, /002: any of bits, Unix only )
+    #[structopt(long = "perm", value_name = "MODE", allow_hyphen_values = true)]
+    pub perm: Option<PermFilter>,
+
+    /// Number of threads matching keyword ( default: max threads up to 8 )
+    #[structopt(long = "matcher-threads", value_name = "NUM")]
+    pub matcher_threads: Option<usize>,
+
+    /// Skip files having more than N matches ( e.g. minified or generated files ), and report them at the end
+    #[structopt(long = "skip-noisy", value_name = "N", default_value = "0")]
+    pub skip_noisy: usize,
+
+    /// Retry reading files N times with backoff on transient errors of network file systems ( e.g. ESTALE, timeouts )
+    #[structopt(long = "io-retries", value_name = "N", default_value = "2")]
+    pub io_retries: usize,
+
+    /// Number of results queued between pipeline stages before blocking the former stage ( 0: unlimited )
+    #[structopt(long = "queue-size", default_value = "256", value_name = "NUM")]
+    pub queue_size: usize,
+
+    /// Number of files passed at once between pipeline stages ( 1: one by one )
+    #[structopt(long = "batch-size", default_value = "64", value_name = "NUM")]
+    pub batch_size: usize,
+
     /// File size per one thread
     #[structopt(long = "size-per-thread", default_value = "1048576", value_name = "BYTES")]
     pub size_per_thread: usize,
@@ -60,14 +170,194 @@ pub struct Opt {
     #[structopt(long = "bin-check-bytes", default_value = "256", value_name = "BYTES")]
     pub bin_check_bytes: usize,
 
+    /// How to load files ( auto: mmap files larger than --mmap-bytes and read the others, mmap: mmap all, read: read all )
+    #[structopt(long = "io", default_value = "auto", value_name = "STRATEGY", possible_values = &["auto", "mmap", "read"])]
+    pub io: IoStrategy,
+
     /// [Experimental] Minimum size for using mmap
     #[structopt(long = "mmap-bytes", default_value = "1048576", value_name = "BYTES")]
     pub mmap_bytes: u64,
 
+    /// [Experimental] Maximum size for using mmap, larger files are searched by streaming ( 0: unlimited )
+    #[structopt(long = "mmap-threshold", default_value = "0", value_name = "BYTES")]
+    pub mmap_threshold: u64,
+
+    /// Maximum total size of files searched concurrently ( 0: unlimited )
+    #[structopt(long = "max-memory", default_value = "0", value_name = "BYTES")]
+    pub max_memory: u64,
+
+    /// Maximum total bandwidth of reading files like `50MB/s` ( KB, MB and GB are powers of 1000, and KiB, MiB and
+    /// GiB are of 1024 )
+    #[structopt(long = "io-limit", value_name = "RATE")]
+    pub io_limit: Option<IoRate>,
+
+    /// Lower the CPU and I/O priority not to slow down other workloads
+    #[structopt(long = "nice")]
+    pub nice: bool,
+
+    /// Encoding of source files for display ( e.g. SHIFT_JIS, EUC-JP )
+    #[structopt(long = "display-encoding", value_name = "ENCODING")]
+    pub display_encoding: Option<String>,
+
+    /// Encoding of the files matched by the glob like `legacy/**=shift_jis`, where the keyword is searched as encoded
+    /// ( repeatable, and the last rule matched wins )
+    #[structopt(long = "encoding-map", value_name = "GLOB=ENCODING", number_of_values = 1)]
+    pub encoding_map: Vec<EncodingRule>,
+
+    /// Number of context lines shown before/after the match at interactive replace
+    #[structopt(long = "context", default_value = "0", value_name = "NUM")]
+    pub context: usize,
+
+    /// Replace policy for symbolic link ( follow: write through to the target, skip: leave it, replace-link: replace the link itself by a regular file )
+    #[structopt(
+        long = "symlink-policy",
+        default_value = "follow",
+        value_name = "POLICY",
+        possible_values = &["follow", "skip", "replace-link"]
+    )]
+    pub symlink_policy: SymlinkPolicy,
+
+    /// Search only the files selected by git ( tracked: tracked files, modified: unstaged changes, staged: staged changes )
+    #[structopt(long = "vcs", value_name = "FILTER", possible_values = &["tracked", "modified", "staged"])]
+    pub vcs: Option<VcsFilter>,
+
+    /// Skip matches in lines which also contain PATTERN ( regular expression with --regex )
+    #[structopt(long = "not", value_name = "PATTERN")]
+    pub not: Option<String>,
+
+    /// Replace only in the files which also contain PATTERN like `import foo` ( regular expression with --regex )
+    #[structopt(long = "filter-files", value_name = "PATTERN")]
+    pub filter_files: Option<String>,
+
+    /// Write the proposed changes to FILE as a HTML report without replacing
+    #[structopt(long = "report", value_name = "FILE", parse(from_os_str))]
+    pub report: Option<PathBuf>,
+
+    /// Append each decision ( time, path, line, column, old / new text and whether accepted ) to FILE as JSON lines
+    #[structopt(long = "log", value_name = "FILE", parse(from_os_str))]
+    pub log: Option<PathBuf>,
+
+    /// Record each file replaced to FILE as soon as persisted to continue an interrupted replacement by --resume
+    #[structopt(
+        long = "checkpoint",
+        value_name = "FILE",
+        parse(from_os_str),
+        conflicts_with = "resume"
+    )]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Skip the files recorded in FILE by --checkpoint, and continue recording the following files to FILE
+    #[structopt(long = "resume", value_name = "FILE", parse(from_os_str))]
+    pub resume: Option<PathBuf>,
+
+    /// Replace all files or nothing: files are persisted at the end only if no error occurs and none is changed
+    /// ( a failure while persisting stops the rest, and the files already replaced are reported )
+    #[structopt(long = "atomic")]
+    pub atomic: bool,
+
+    /// Replace files even if changed by another process since matched
+    #[structopt(long = "force")]
+    pub force: bool,
+
+    /// Clear the read-only bit of files temporarily to replace them, and restore it afterwards
+    #[structopt(long = "force-writable")]
+    pub force_writable: bool,
+
+    /// Remove temporary files ( .amber-tmp* ) left by a killed replacement without asking
+    #[structopt(long = "clean-tmpfiles")]
+    pub clean_tmpfiles: bool,
+
+    /// Review the changes of all files grouped by file after matching, and accept or reject each file or change
+    #[structopt(long = "review")]
+    pub review: bool,
+
+    /// Show the number of the matches and the first changes of each file, and ask once per file instead of each match
+    #[structopt(long = "confirm-files", conflicts_with = "review")]
+    pub confirm_files: bool,
+
+    /// Convert line endings and indentation of replacements by end_of_line, indent_style and insert_final_newline of .editorconfig
+    #[structopt(long = "editorconfig")]
+    pub editorconfig: bool,
+
+    /// Replace only the matches within the lines of RANGE like `100..250` ( 1-based, inclusive ) in each file
+    #[structopt(long = "lines", value_name = "RANGE")]
+    pub lines: Option<IndexRange>,
+
+    /// Replace only the matches beginning within the columns of RANGE like `1..4` ( 1-based bytes, inclusive )
+    #[structopt(long = "columns", value_name = "RANGE")]
+    pub columns: Option<IndexRange>,
+
+    /// Replace only the matches between the matches of BEGIN_REGEX and END_REGEX like `--within BEGIN END`
+    #[structopt(long = "within", value_names = &["BEGIN_REGEX", "END_REGEX"], number_of_values = 2)]
+    pub within: Vec<String>,
+
+    /// Parse files as LANG ( c, go, javascript, python, rust ) for --node-type with the `syntax` feature
+    #[structopt(long = "syntax", value_name = "LANG")]
+    pub syntax: Option<SyntaxLang>,
+
+    /// Replace only the matches inside the syntax nodes of KIND like `string_literal`
+    #[structopt(long = "node-type", value_name = "KIND", number_of_values = 1, requires = "syntax")]
+    pub node_type: Vec<String>,
+
+    /// Replace only the matches outside the syntax nodes of KIND like `line_comment`
+    #[structopt(
+        long = "exclude-node-type",
+        value_name = "KIND",
+        number_of_values = 1,
+        requires = "syntax",
+        conflicts_with = "node-type"
+    )]
+    pub exclude_node_type: Vec<String>,
+
+    /// Replace only the matches outside comments and string literals of the languages known by the extension
+    #[structopt(long = "code-only", conflicts_with_all = &["comments-only", "strings-only", "in-strings"])]
+    pub code_only: bool,
+
+    /// Replace only the matches inside comments
+    #[structopt(long = "comments-only", conflicts_with_all = &["strings-only", "in-strings"])]
+    pub comments_only: bool,
+
+    /// Replace only the matches inside string literals
+    #[structopt(long = "strings-only")]
+    pub strings_only: bool,
+
+    /// Report only the matches inside the quotes of string literals, which are found by `"`, `'` and `` ` `` in a line
+    /// if the language is unknown by the extension
+    #[structopt(long = "in-strings", conflicts_with = "strings-only")]
+    pub in_strings: bool,
+
+    /// Replace only the matches being complete identifiers ( not adjoining Unicode alphanumerics or `_` )
+    #[structopt(long = "identifier")]
+    pub identifier: bool,
+
+    /// Replace only the matches within the byte offsets of RANGE like `0..1023` ( 0-based, inclusive ) in each file
+    #[structopt(long = "bytes", value_name = "RANGE")]
+    pub bytes: Option<IndexRange>,
+
+    /// Replace only the N-th match or the matches of a range like `3..7` ( inclusive ) in each file
+    #[structopt(long = "occurrence", value_name = "N")]
+    pub occurrence: Option<Occurrence>,
+
+    /// Count the matches of --occurrence through all files in the order of paths instead of each file
+    #[structopt(long = "global-occurrence", requires = "occurrence")]
+    pub global_occurrence: bool,
+
+    /// Rename files and directories whose names contain KEYWORD instead of replacing the contents
+    #[structopt(long = "rename")]
+    pub rename: bool,
+
+    /// Print renames without renaming ( with --rename )
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
     /// Enable regular expression search
     #[structopt(short = "r", long = "regex", hidden = DEFAULT_FLAGS.regex)]
     pub regex: bool,
 
+    /// Match case-insensitively by Unicode case folding ( e.g. `é` matches `É`, and `ss` matches `ß` except by --regex )
+    #[structopt(short = "i", long = "ignore-case", conflicts_with = "rename")]
+    pub ignore_case: bool,
+
     /// Enable column output
     #[structopt(long = "column", hidden = DEFAULT_FLAGS.column)]
     pub column: bool,
@@ -76,10 +366,57 @@ pub struct Opt {
     #[structopt(long = "row", hidden = DEFAULT_FLAGS.row)]
     pub row: bool,
 
+    /// Unit of the row output ( bytes, chars: grapheme clusters, display: cells on terminals like CJK and emoji as 2 )
+    #[structopt(
+        long = "column-unit",
+        default_value = "bytes",
+        value_name = "UNIT",
+        possible_values = &["bytes", "chars", "display"]
+    )]
+    pub column_unit: ColumnUnit,
+
+    /// Expand tabs to the multiples of N in the row output and the replacement preview like editors ( 0: a tab is a character )
+    #[structopt(long = "tab-width", default_value = "0", value_name = "N")]
+    pub tab_width: usize,
+
+    /// Truncate lines of the replacement preview longer than N characters around the match ( 0: disabled )
+    #[structopt(long = "max-columns", default_value = "200", value_name = "N")]
+    pub max_columns: usize,
+
     /// Enable binary file search
     #[structopt(long = "binary", hidden = DEFAULT_FLAGS.binary)]
     pub binary: bool,
 
+    /// Use the options of [profile.NAME] in ~/.ambr.toml
+    #[structopt(long = "config-profile", value_name = "NAME")]
+    pub config_profile: Option<String>,
+
+    /// Print the consumed time and the processed items / bytes of each pipeline stage to stderr in FORMAT
+    #[structopt(long = "profile", value_name = "FORMAT", possible_values = &["json"])]
+    pub profile: Option<String>,
+
+    /// Format of skipped files and errors on stderr ( json: an object of `path`, `kind` and `message` per line )
+    #[structopt(
+        long = "errors",
+        default_value = "text",
+        value_name = "FORMAT",
+        possible_values = &["text", "json"]
+    )]
+    pub errors: ErrorFormat,
+
+    /// Suppress error messages of files ( e.g. permission denied, broken symlinks ), which still decide the exit status
+    #[structopt(short = "s", long = "no-messages")]
+    pub no_messages: bool,
+
+    /// Print the decisions of the pipeline stages to stderr ( -v: skipped files and the matcher, -vv: each file with
+    /// the time )
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    pub verbose: usize,
+
+    /// Print --verbose only of STAGES separated by comma ( finder, matcher, sorter, replacer or all ), which implies -v
+    #[structopt(long = "trace", value_name = "STAGES")]
+    pub trace: Option<TraceStages>,
+
     /// Enable statistics output
     #[structopt(long = "statistics", hidden = DEFAULT_FLAGS.statistics)]
     pub statistics: bool,
@@ -100,8 +437,18 @@ pub struct Opt {
     #[structopt(long = "symlink", hidden = DEFAULT_FLAGS.symlink)]
     pub symlink: bool,
 
-    /// Enable colored output
-    #[structopt(long = "color", hidden = DEFAULT_FLAGS.color)]
+    /// Color the output WHEN ( auto: only if stdout is a terminal, NO_COLOR disables and CLICOLOR_FORCE enables it ),
+    /// where `--color` alone is always
+    #[structopt(
+        long = "color",
+        value_name = "WHEN",
+        possible_values = &["auto", "always", "never"],
+        require_equals = true
+    )]
+    pub color_choice: Option<Option<ColorChoice>>,
+
+    /// Whether to color the output, which is decided by --color, --no-color and `color` of the config file
+    #[structopt(skip)]
     pub color: bool,
 
     /// Enable filename output
@@ -116,6 +463,10 @@ pub struct Opt {
     #[structopt(long = "skip-gitignore", hidden = DEFAULT_FLAGS.skip_gitignore)]
     pub skip_gitignore: bool,
 
+    /// Enable .amberignore skip
+    #[structopt(long = "skip-amberignore", hidden = DEFAULT_FLAGS.skip_amberignore)]
+    pub skip_amberignore: bool,
+
     /// Enable output order guarantee
     #[structopt(long = "fixed-order", hidden = DEFAULT_FLAGS.fixed_order)]
     pub fixed_order: bool,
@@ -128,6 +479,22 @@ pub struct Opt {
     #[structopt(long = "preserve-time", hidden = DEFAULT_FLAGS.preserve_time)]
     pub preserve_time: bool,
 
+    /// Enable timestamp, ownership and extended attributes ( including ACLs ) preserve
+    #[structopt(long = "preserve-all", hidden = DEFAULT_FLAGS.preserve_all)]
+    pub preserve_all: bool,
+
+    /// Enable in-place write keeping the original inode ( less atomic than replacing via temporary file )
+    #[structopt(long = "in-place", hidden = DEFAULT_FLAGS.in_place)]
+    pub in_place: bool,
+
+    /// Enable line ending conversion of replacement to the dominant style of each file
+    #[structopt(long = "respect-line-endings", hidden = DEFAULT_FLAGS.respect_line_endings)]
+    pub respect_line_endings: bool,
+
+    /// Enable BOM removal from replaced files
+    #[structopt(long = "strip-bom", hidden = DEFAULT_FLAGS.strip_bom)]
+    pub strip_bom: bool,
+
     /// Disable regular expression search
     #[structopt(long = "no-regex", hidden = !DEFAULT_FLAGS.regex)]
     pub no_regex: bool,
@@ -164,7 +531,7 @@ pub struct Opt {
     #[structopt(long = "no-symlink", hidden = !DEFAULT_FLAGS.symlink)]
     pub no_symlink: bool,
 
-    /// Disable colored output
+    /// Disable colored output ( same as --color=never )
     #[structopt(long = "no-color", hidden = !DEFAULT_FLAGS.color)]
     pub no_color: bool,
 
@@ -180,8 +547,12 @@ pub struct Opt {
     #[structopt(long = "no-skip-gitignore", hidden = !DEFAULT_FLAGS.skip_gitignore)]
     pub no_skip_gitignore: bool,
 
-    /// Disable output order guarantee
-    #[structopt(long = "no-fixed-order", hidden = !DEFAULT_FLAGS.fixed_order)]
+    /// Disable .amberignore skip
+    #[structopt(long = "no-skip-amberignore", hidden = !DEFAULT_FLAGS.skip_amberignore)]
+    pub no_skip_amberignore: bool,
+
+    /// Disable output order guarantee to pass results as soon as matched ( also --no-reorder )
+    #[structopt(long = "no-fixed-order", alias = "no-reorder", hidden = !DEFAULT_FLAGS.fixed_order)]
     pub no_fixed_order: bool,
 
     /// Disable .*ignore file search at parent directories
@@ -192,10 +563,36 @@ pub struct Opt {
     #[structopt(long = "no-preserve-time", hidden = !DEFAULT_FLAGS.preserve_time)]
     pub no_preserve_time: bool,
 
+    /// Disable timestamp, ownership and extended attributes ( including ACLs ) preserve
+    #[structopt(long = "no-preserve-all", hidden = !DEFAULT_FLAGS.preserve_all)]
+    pub no_preserve_all: bool,
+
+    /// Disable in-place write keeping the original inode
+    #[structopt(long = "no-in-place", hidden = !DEFAULT_FLAGS.in_place)]
+    pub no_in_place: bool,
+
+    /// Disable line ending conversion of replacement
+    #[structopt(long = "no-respect-line-endings", hidden = !DEFAULT_FLAGS.respect_line_endings)]
+    pub no_respect_line_endings: bool,
+
+    /// Disable BOM removal from replaced files
+    #[structopt(long = "no-strip-bom", hidden = !DEFAULT_FLAGS.strip_bom)]
+    pub no_strip_bom: bool,
+
     /// [Experimental] Enable TBM matcher
     #[structopt(long = "tbm")]
     pub tbm: bool,
 
+    /// Matcher used to search KEYWORD ( auto: selected by the other options, quick/tbm/memmem: literal keywords only,
+    /// regex: same as --regex )
+    #[structopt(
+        long = "matcher",
+        default_value = "auto",
+        value_name = "KIND",
+        possible_values = &["auto", "quick", "tbm", "memmem", "regex"]
+    )]
+    pub matcher: MatcherKind,
+
     /// [Experimental] Enable SSE 4.2
     #[structopt(long = "sse")]
     pub sse: bool,
@@ -230,11 +627,33 @@ struct DefaultFlags {
     #[serde(default = "flag_true")]
     skip_gitignore: bool,
     #[serde(default = "flag_true")]
+    skip_amberignore: bool,
+    #[serde(default = "flag_true")]
     fixed_order: bool,
     #[serde(default = "flag_true")]
     parent_ignore: bool,
     #[serde(default = "flag_false")]
     preserve_time: bool,
+    #[serde(default = "flag_false")]
+    preserve_all: bool,
+    #[serde(default = "flag_false")]
+    in_place: bool,
+    #[serde(default = "flag_false")]
+    respect_line_endings: bool,
+    #[serde(default = "flag_false")]
+    strip_bom: bool,
+    #[serde(default)]
+    profile: HashMap<String, ProfileFlags>,
+    /// Keys of the interactive replacement like `all_in_file = "a"`, and the action of Enter like `enter = "no"`
+    #[serde(default)]
+    keys: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileFlags {
+    /// Options inserted before the command-line arguments
+    #[serde(default)]
+    options: String,
 }
 
 impl DefaultFlags {
@@ -268,6 +687,7 @@ impl DefaultFlags {
 
     fn merge(&self, mut opt: Opt) -> Opt {
         opt.regex = if self.regex { !opt.no_regex } else { opt.regex };
+        opt.regex = opt.regex || opt.matcher == MatcherKind::Regex;
         opt.column = if self.column { !opt.no_column } else { opt.column };
         opt.row = if self.row { !opt.no_row } else { opt.row };
         opt.binary = if self.binary { !opt.no_binary } else { opt.binary };
@@ -288,7 +708,15 @@ impl DefaultFlags {
             opt.recursive
         };
         opt.symlink = if self.symlink { !opt.no_symlink } else { opt.symlink };
-        opt.color = if self.color { !opt.no_color } else { opt.color };
+        // `color = true` of the config file is auto not to color the output piped to other commands
+        let color_choice = match opt.color_choice {
+            _ if opt.no_color => ColorChoice::Never,
+            Some(Some(x)) => x,
+            Some(None) => ColorChoice::Always,
+            None if self.color => ColorChoice::Auto,
+            None => ColorChoice::Never,
+        };
+        opt.color = color_choice.is_color();
         opt.file = if self.file { !opt.no_file } else { opt.file };
         opt.skip_vcs = if self.skip_vcs { !opt.no_skip_vcs } else { opt.skip_vcs };
         opt.skip_gitignore = if self.skip_gitignore {
@@ -296,6 +724,11 @@ impl DefaultFlags {
         } else {
             opt.skip_gitignore
         };
+        opt.skip_amberignore = if self.skip_amberignore {
+            !opt.no_skip_amberignore
+        } else {
+            opt.skip_amberignore
+        };
         opt.fixed_order = if self.fixed_order {
             !opt.no_fixed_order
         } else {
@@ -311,8 +744,137 @@ impl DefaultFlags {
         } else {
             opt.preserve_time
         };
+        opt.preserve_all = if self.preserve_all {
+            !opt.no_preserve_all
+        } else {
+            opt.preserve_all
+        };
+        opt.in_place = if self.in_place { !opt.no_in_place } else { opt.in_place };
+        opt.respect_line_endings = if self.respect_line_endings {
+            !opt.no_respect_line_endings
+        } else {
+            opt.respect_line_endings
+        };
+        opt.strip_bom = if self.strip_bom {
+            !opt.no_strip_bom
+        } else {
+            opt.strip_bom
+        };
         opt
     }
+
+    fn keymap(&self) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        let mut names: Vec<_> = self.keys.keys().collect();
+        names.sort();
+        for name in names {
+            let value = &self.keys[name];
+            if name == "enter" {
+                keymap.enter = value.parse()?;
+            } else {
+                keymap.bind(name.parse()?, value);
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+// Temporary files left by a killed replacement are removed if allowed, and listed otherwise
+fn clean_leftovers(opt: &Opt, leftovers: Vec<PathBuf>, console: &mut Console) {
+    for path in &leftovers {
+        console.write(
+            ConsoleTextKind::Info,
+            &format!("Found temporary file left by a killed replacement: {:?}\n", path),
+        );
+    }
+    let remove = if opt.clean_tmpfiles {
+        true
+    } else if opt.interactive && Console::is_stdin_tty() {
+        console.write(ConsoleTextKind::Other, "Remove them? [y]es/[n]o: ");
+        console.flush();
+        let key = Getch::new().getch().map(char::from).unwrap_or('n');
+        console.write(ConsoleTextKind::Other, &format!("{}\n", key));
+        key == 'Y' || key == 'y'
+    } else {
+        console.write(ConsoleTextKind::Info, "Remove them by --clean-tmpfiles\n");
+        false
+    };
+    if remove {
+        for path in &leftovers {
+            if let Err(e) = fs::remove_file(path) {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), path),
+                );
+            }
+        }
+    }
+}
+
+// Returns the exit status by whether any file is renamed ( or to be renamed by --dry-run )
+fn rename_files(opt: &Opt, base_paths: Vec<PathBuf>, keyword: &[u8], replacement: &[u8], console: &mut Console) -> i32 {
+    let keyword = String::from_utf8_lossy(keyword);
+    let replacement = String::from_utf8_lossy(replacement);
+    let mut renamer = match Renamer::new(&keyword, &replacement, opt.regex) {
+        Ok(x) => x,
+        Err(e) => {
+            console.write(ConsoleTextKind::Error, &format!("Error: {}\n", e));
+            exit(EXIT_ERROR, console);
+        }
+    };
+    renamer.is_color = opt.color;
+    renamer.is_interactive = opt.interactive;
+    renamer.dry_run = opt.dry_run;
+
+    let mut finder = PipelineFinder::new();
+    finder.is_recursive = opt.recursive;
+    finder.follow_symlink = opt.symlink;
+    finder.one_file_system = opt.one_file_system;
+    finder.dedupe = !opt.no_dedupe;
+    finder.max_symlink_depth = opt.max_symlink_depth;
+    finder.print_broken_symlinks = opt.broken_symlinks;
+    finder.owner = opt.owner.clone();
+    finder.perm = opt.perm;
+    finder.threads = opt.finder_threads;
+    finder.alternate_streams = opt.streams;
+    finder.skip_vcs = opt.skip_vcs;
+    finder.skip_gitignore = opt.skip_gitignore;
+    finder.skip_ambignore = opt.skip_amberignore;
+    finder.print_skipped = opt.skipped;
+    finder.find_parent_ignore = opt.parent_ignore;
+    finder.vcs_filter = opt.vcs;
+    finder.trace = Tracer::new(TraceStage::Finder, opt.verbose, opt.trace.as_ref());
+
+    let (tx_finder, rx_finder) = unbounded();
+    let (tx_main, rx_main) = unbounded::<PipelineInfo<PathInfo>>();
+
+    let _ = thread::Builder::new().name("finder".to_string()).spawn(move || {
+        finder.setup(0, rx_finder, vec![tx_main]);
+    });
+
+    let mut seq_no = 0;
+    let _ = tx_finder.send(PipelineInfo::SeqBeg(seq_no));
+    for p in &base_paths {
+        let _ = tx_finder.send(PipelineInfo::SeqDat(seq_no, p.clone()));
+        seq_no += 1;
+    }
+    let _ = tx_finder.send(PipelineInfo::SeqEnd(seq_no));
+
+    let mut files = Vec::new();
+    loop {
+        match rx_main.recv() {
+            Ok(PipelineInfo::SeqDat(_, x)) => files.push(x.path),
+            Ok(PipelineInfo::MsgInfo(_id, s)) => console.write_diagnostic(ConsoleTextKind::Info, &format!("{}\n", s)),
+            Ok(PipelineInfo::MsgErr(_id, s)) => console.write_diagnostic(ConsoleTextKind::Error, &format!("{}\n", s)),
+            Ok(PipelineInfo::SeqEnd(_)) => break,
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+
+    let plan = renamer.plan(&base_paths, &files);
+    renamer.rename(&plan);
+    exit_status(!plan.is_empty(), console.has_error() || renamer.has_error())
 }
 
 fn flag_true() -> bool {
@@ -338,154 +900,483 @@ fn main() {
 
     // - Create opt ------------------------------------------------------------
 
-    let opt = Opt::from_args();
+    let profile = match find_arg("--config-profile") {
+        Some(name) => match DEFAULT_FLAGS.profile.get(&name) {
+            Some(x) => split_args(&x.options),
+            None => {
+                let mut console = Console::new();
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: profile {:?} is not found in ~/.ambr.toml\n", name),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        None => Vec::new(),
+    };
+    let mut args = args_with_env("AMBR_OPTIONS", profile);
+
+    // - Prompt keyword and replacement ----------------------------------------
+    let history = History::new();
+    let mut history_args: Vec<String> = env::args_os()
+        .skip(1)
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect();
+    if env::args_os().len() == 1 && Console::is_stdin_tty() {
+        let mut console = Console::new();
+        let mut read = |prompt, replacement| {
+            let recall = history.as_ref().map_or(Vec::new(), |x| x.recall("ambr", replacement));
+            match console.read_line(prompt, &recall) {
+                Ok(x) => x,
+                Err(e) => {
+                    console.write(
+                        ConsoleTextKind::Error,
+                        &format!("Error: {} @ stdin\n", decode_error(e.kind())),
+                    );
+                    exit(EXIT_ERROR, &mut console);
+                }
+            }
+        };
+        let keyword = read("Keyword: ", false);
+        if keyword.is_empty() {
+            std::process::exit(EXIT_ERROR);
+        }
+        let replacement = read("Replacement: ", true);
+        history_args = vec![keyword.clone(), replacement.clone()];
+        args.push(OsString::from(keyword));
+        args.push(OsString::from(replacement));
+    }
+
+    let opt = match Opt::from_iter_safe(args) {
+        Ok(x) => x,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            std::process::exit(EXIT_ERROR);
+        }
+        Err(e) => e.exit(),
+    };
     let opt = DEFAULT_FLAGS.merge(opt);
 
+    // Threads spawned after inherit the priority
+    if opt.nice {
+        lower_priority();
+    }
+
+    // Failures to write the history don't stop the replacement
+    if let Some(ref history) = history {
+        let (keyword, replacement) = if opt.map.is_none() {
+            (opt.keyword.clone(), opt.replacement.clone())
+        } else {
+            (None, None)
+        };
+        let _ = history.push(&HistoryEntry {
+            command: String::from("ambr"),
+            args: history_args,
+            keyword,
+            replacement,
+        });
+    }
+
     let mut console = Console::new();
     console.is_color = opt.color;
+    console.error_format = opt.errors;
+    console.no_messages = opt.no_messages;
 
     // - Set base path, keyword and replacement --------------------------------
+    // KEYWORD and REPLACEMENT are treated as paths with --map
+    let (opt_keyword, opt_replacement, opt_paths) = if opt.map.is_some() {
+        let paths = opt
+            .keyword
+            .iter()
+            .chain(opt.replacement.iter())
+            .chain(opt.paths.iter())
+            .cloned()
+            .collect();
+        (String::new(), String::new(), paths)
+    } else {
+        (
+            opt.keyword.clone().unwrap(),
+            opt.replacement.clone().unwrap(),
+            opt.paths.clone(),
+        )
+    };
+
     let mut base_paths: Vec<PathBuf> = Vec::new();
-    if opt.paths.is_empty() {
+    if opt_paths.is_empty() {
         base_paths.push(PathBuf::from("./"));
     } else {
-        for p in &opt.paths {
+        for p in &opt_paths {
             base_paths.push(PathBuf::from(p));
         }
     }
 
     let keyword = if opt.key_from_file {
-        match read_from_file(&opt.keyword) {
+        match read_from_file(&opt_keyword) {
             Ok(x) => {
                 if x.len() != 0 {
                     x
                 } else {
                     console.write(
                         ConsoleTextKind::Error,
-                        &format!("Error: file is empty @ {:?}\n", opt.keyword),
+                        &format!("Error: file is empty @ {:?}\n", opt_keyword),
                     );
-                    exit(1, &mut console);
+                    exit(EXIT_ERROR, &mut console);
                 }
             }
             Err(e) => {
                 console.write(
                     ConsoleTextKind::Error,
-                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), opt.keyword),
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), opt_keyword),
                 );
-                exit(1, &mut console);
+                exit(EXIT_ERROR, &mut console);
             }
         }
     } else {
-        opt.keyword.into_bytes()
+        opt_keyword.into_bytes()
     };
 
     let replacement = if opt.rep_from_file {
-        match read_from_file(&opt.replacement) {
+        match read_from_file(&opt_replacement) {
             Ok(x) => x,
             Err(e) => {
                 console.write(
                     ConsoleTextKind::Error,
-                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), opt.replacement),
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), opt_replacement),
                 );
-                exit(1, &mut console);
+                exit(EXIT_ERROR, &mut console);
             }
         }
     } else {
-        opt.replacement.into_bytes()
+        opt_replacement.into_bytes()
     };
 
+    let mut pairs = match opt.map {
+        Some(ref path) => match read_from_file(path).map(|x| parse_map(&x)) {
+            Ok(Ok(x)) if !x.is_empty() => x,
+            Ok(Ok(_)) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: file is empty @ {:?}\n", path));
+                exit(EXIT_ERROR, &mut console);
+            }
+            Ok(Err(line)) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: no keyword or tab at line {} @ {:?}\n", line, path),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+            Err(e) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), path),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        None => vec![(keyword, replacement)],
+    };
+
+    if opt.escapes {
+        for (keyword, replacement) in &mut pairs {
+            *keyword = if opt.regex {
+                unescape_regex(keyword)
+            } else {
+                unescape(keyword)
+            };
+            *replacement = unescape(replacement);
+        }
+    }
+    let (keyword, replacement) = pairs.remove(0);
+
+    // - Rename files ----------------------------------------------------------
+    if opt.rename {
+        let code = rename_files(&opt, base_paths, &keyword, &replacement, &mut console);
+        exit(code, &mut console);
+    }
+
     // ---------------------------------------------------------------------------------------------
     // Pipeline Construct
     // ---------------------------------------------------------------------------------------------
 
-    let id_finder = 0;
-    let id_sorter = 1;
-    let id_replacer = 2;
-    let id_matcher = 3;
+    let id_finder = ID_FINDER;
+    let id_sorter = ID_SORTER;
+    let id_replacer = ID_OUTPUT;
+    let id_matcher = ID_MATCHER;
 
-    let matcher_num = cmp::min(8, opt.max_threads);
+    let matcher_num = cmp::max(1, opt.matcher_threads.unwrap_or_else(|| cmp::min(8, opt.max_threads)));
 
-    let (tx_finder, rx_finder) = unbounded();
-    let (tx_replacer, rx_replacer) = unbounded();
     let (tx_main, rx_main) = unbounded();
 
-    let mut tx_matcher = Vec::new();
-    let mut rx_sorter = Vec::new();
-
     let mut finder = PipelineFinder::new();
-    let mut sorter = PipelineSorter::new(matcher_num);
+    let encoding_map = if opt.encoding_map.is_empty() {
+        None
+    } else {
+        Some(EncodingMap::new(opt.encoding_map.clone()))
+    };
+
     let mut replacer = PipelineReplacer::new(&keyword, &replacement, opt.regex);
 
     finder.is_recursive = opt.recursive;
+    finder.batch_size = opt.batch_size;
     finder.follow_symlink = opt.symlink;
+    finder.one_file_system = opt.one_file_system;
+    finder.dedupe = !opt.no_dedupe;
+    finder.max_symlink_depth = opt.max_symlink_depth;
+    finder.print_broken_symlinks = opt.broken_symlinks;
+    finder.owner = opt.owner.clone();
+    finder.perm = opt.perm;
+    let broken_symlinks = finder.broken_symlinks.clone();
+    let leftovers = finder.leftovers.clone();
     finder.skip_vcs = opt.skip_vcs;
     finder.skip_gitignore = opt.skip_gitignore;
+    finder.skip_ambignore = opt.skip_amberignore;
     finder.print_skipped = opt.skipped;
     finder.find_parent_ignore = opt.parent_ignore;
-    sorter.through = !opt.fixed_order;
+    finder.vcs_filter = opt.vcs;
+    finder.trace = Tracer::new(TraceStage::Finder, opt.verbose, opt.trace.as_ref());
     replacer.is_color = opt.color;
+    replacer.display_encoding = opt.display_encoding.clone();
+    replacer.encoding_map = encoding_map.clone();
     replacer.is_interactive = opt.interactive;
     replacer.preserve_time = opt.preserve_time;
+    replacer.preserve_all = opt.preserve_all;
+    replacer.in_place = opt.in_place;
     replacer.print_file = opt.file;
     replacer.print_column = opt.column;
     replacer.print_row = opt.row;
+    replacer.io = opt.io;
+    replacer.mmap_bytes = opt.mmap_bytes;
+    replacer.column_unit = opt.column_unit;
+    replacer.tab_width = opt.tab_width;
+    replacer.max_columns = Some(opt.max_columns).filter(|x| *x != 0);
+    replacer.context = opt.context;
+    replacer.print_skipped = opt.skipped;
+    replacer.symlink_policy = opt.symlink_policy;
+    replacer.report_path = opt.report.clone();
+    replacer.log_path = opt.log.clone();
+    replacer.atomic = opt.atomic;
+    replacer.force = opt.force;
+    replacer.force_writable = opt.force_writable;
+    replacer.editorconfig = opt.editorconfig;
+    replacer.lines = opt.lines;
+    replacer.byte_range = opt.bytes;
+    replacer.exec_replace = opt.exec_replace;
+    replacer.validate = opt.validate.clone();
+    replacer.verify = opt.verify;
+    replacer.post_cmd = opt.post_cmd.clone();
+    replacer.map = pairs.clone();
+    replacer.overlap = opt.overlap;
+    replacer.ignore_case = opt.ignore_case;
+    replacer.occurrence = opt.occurrence;
+    replacer.global_occurrence = opt.global_occurrence;
+    replacer.respect_line_endings = opt.respect_line_endings;
+    replacer.strip_bom = opt.strip_bom;
+    replacer.trace = Tracer::new(TraceStage::Replacer, opt.verbose, opt.trace.as_ref());
+    replacer.review = opt.review;
+    replacer.confirm_files = opt.confirm_files;
+
+    let header = CheckpointHeader {
+        keyword: String::from_utf8_lossy(&keyword).into_owned(),
+        replacement: String::from_utf8_lossy(&replacement).into_owned(),
+    };
+    let checkpoint = match (&opt.checkpoint, &opt.resume) {
+        (Some(path), _) => Some((Checkpoint::create(path, &header), path)),
+        (None, Some(path)) => Some((Checkpoint::resume(path, &header), path)),
+        _ => None,
+    };
+    if let Some((checkpoint, path)) = checkpoint {
+        match checkpoint {
+            Ok(x) => {
+                if opt.resume.is_some() {
+                    finder.completed = Some(Arc::new(x.completed().clone()));
+                }
+                replacer.checkpoint = Some(x);
+            }
+            Err(e) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ {:?}\n", e, path));
+                exit(EXIT_ERROR, &mut console);
+            }
+        }
+    }
+    replacer.keymap = match DEFAULT_FLAGS.keymap() {
+        Ok(x) => x,
+        Err(e) => {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!("Error: {} @ [keys] of ~/.ambr.toml\n", e),
+            );
+            exit(EXIT_ERROR, &mut console);
+        }
+    };
+
+    let syntax = match opt.syntax {
+        Some(lang) => {
+            let exclude = !opt.exclude_node_type.is_empty();
+            let kinds = if exclude {
+                &opt.exclude_node_type
+            } else {
+                &opt.node_type
+            };
+            if !SyntaxFilter::is_enabled() || kinds.is_empty() {
+                console.write(
+                    ConsoleTextKind::Error,
+                    "Error: --syntax requires the `syntax` feature and --node-type or --exclude-node-type\n",
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+            Some(SyntaxFilter {
+                lang,
+                kinds: kinds.clone(),
+                exclude,
+            })
+        }
+        None => None,
+    };
+
+    let within = match opt.within.as_slice() {
+        [begin, end] => match Within::new(begin, end) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ --within\n", e));
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        _ => None,
+    };
 
-    let use_regex = opt.regex;
-    let use_tbm = opt.tbm;
-    let skip_binary = !opt.binary;
+    if opt.regex && opt.matcher != MatcherKind::Auto && opt.matcher != MatcherKind::Regex {
+        console.write(
+            ConsoleTextKind::Error,
+            &format!(
+                "Error: --matcher {} can't search a regex @ --regex\n",
+                opt.matcher.name()
+            ),
+        );
+        exit(EXIT_ERROR, &mut console);
+    }
+    let matcher_kind = match opt.matcher {
+        MatcherKind::Auto if opt.regex => MatcherKind::Regex,
+        MatcherKind::Auto if opt.tbm && !opt.escapes => MatcherKind::Tbm,
+        MatcherKind::Auto => MatcherKind::Memmem,
+        x => x,
+    };
+    let matcher_trace = Tracer::new(TraceStage::Matcher, opt.verbose, opt.trace.as_ref());
+    matcher_trace.log(1, || {
+        let how = if opt.matcher == MatcherKind::Auto {
+            "auto"
+        } else {
+            "--matcher"
+        };
+        let fold = if opt.ignore_case && matcher_kind != MatcherKind::Regex {
+            ", case folded"
+        } else {
+            ""
+        };
+        format!(
+            "Matcher: {} ( {}{}, {} threads )",
+            matcher_kind.name(),
+            how,
+            fold,
+            matcher_num
+        )
+    });
+    let escapes = opt.escapes;
+    let binary_files = if opt.binary {
+        BinaryFiles::Text
+    } else {
+        BinaryFiles::WithoutMatch
+    };
     let print_skipped = opt.skipped;
     let binary_check_bytes = opt.bin_check_bytes;
+    let io = opt.io;
     let mmap_bytes = opt.mmap_bytes;
+    let mmap_threshold = opt.mmap_threshold;
+    let memory_budget = if opt.max_memory != 0 {
+        Some(Arc::new(MemoryBudget::new(opt.max_memory)))
+    } else {
+        None
+    };
+    let io_limit = opt.io_limit.map(|x| Arc::new(IoLimiter::new(x)));
     let max_threads = opt.max_threads;
     let size_per_thread = opt.size_per_thread;
-
-    for i in 0..matcher_num {
-        let keyword = keyword.clone();
-        let (tx_in, rx_in) = unbounded();
-        let (tx_out, rx_out) = unbounded();
-        tx_matcher.push(tx_in);
-        rx_sorter.push(rx_out);
-
-        let _ = thread::Builder::new().name("matcher".to_string()).spawn(move || {
-            if use_regex {
-                let m = RegexMatcher::new();
-                let mut matcher = PipelineMatcher::new(m, &keyword);
-                matcher.skip_binary = skip_binary;
-                matcher.print_skipped = print_skipped;
-                matcher.binary_check_bytes = binary_check_bytes;
-                matcher.mmap_bytes = mmap_bytes;
-                matcher.setup(id_matcher + i, rx_in, tx_out);
-            } else if use_tbm {
+    let ignore_case = opt.ignore_case;
+
+    let mut builder = PipelineBuilder::new(finder).through(!opt.fixed_order);
+    builder.queue_size = opt.queue_size;
+    builder.batch_size = opt.batch_size;
+    builder.trace = Tracer::new(TraceStage::Sorter, opt.verbose, opt.trace.as_ref());
+    // The replacer searches the file edited by `$EDITOR` again by the same matcher
+    let new_matcher = || {
+        let m: Box<dyn Matcher + Send> = match matcher_kind {
+            MatcherKind::Regex => {
+                let mut m = RegexMatcher::new();
+                m.case_insensitive = ignore_case;
+                Box::new(m)
+            }
+            MatcherKind::Quick => {
+                let mut m = QuickSearchMatcher::new();
+                m.max_threads = max_threads;
+                m.size_per_thread = size_per_thread;
+                Box::new(m)
+            }
+            MatcherKind::Tbm => {
                 let mut m = TbmMatcher::new();
                 m.max_threads = max_threads;
                 m.size_per_thread = size_per_thread;
-                let mut matcher = PipelineMatcher::new(m, &keyword);
-                matcher.skip_binary = skip_binary;
-                matcher.print_skipped = print_skipped;
-                matcher.binary_check_bytes = binary_check_bytes;
-                matcher.mmap_bytes = mmap_bytes;
-                matcher.setup(id_matcher + i, rx_in, tx_out);
-            } else {
-                let mut m = QuickSearchMatcher::new();
+                Box::new(m)
+            }
+            _ => {
+                let mut m = MemmemMatcher::new();
+                m.char_boundary = !escapes;
                 m.max_threads = max_threads;
                 m.size_per_thread = size_per_thread;
-                let mut matcher = PipelineMatcher::new(m, &keyword);
-                matcher.skip_binary = skip_binary;
-                matcher.print_skipped = print_skipped;
-                matcher.binary_check_bytes = binary_check_bytes;
-                matcher.mmap_bytes = mmap_bytes;
-                matcher.setup(id_matcher + i, rx_in, tx_out);
-            };
-        });
+                Box::new(m)
+            }
+        };
+        let m: Box<dyn Matcher + Send> = if ignore_case && matcher_kind != MatcherKind::Regex {
+            Box::new(CaseFoldMatcher::new(m))
+        } else {
+            m
+        };
+        let mut matcher = PipelineMatcher::new(m, &keyword);
+        matcher.binary_files = binary_files;
+        matcher.ignore_case = ignore_case;
+        matcher.print_skipped = print_skipped;
+        matcher.binary_check_bytes = binary_check_bytes;
+        matcher.io = io;
+        matcher.mmap_bytes = mmap_bytes;
+        matcher.mmap_threshold = mmap_threshold;
+        matcher.memory_budget = memory_budget.clone();
+        matcher.io_limit = io_limit.clone();
+        matcher.not = opt.not.clone().map(String::into_bytes);
+        matcher.filter_files = opt.filter_files.clone().map(String::into_bytes);
+        matcher.columns = opt.columns;
+        matcher.within = within.clone();
+        matcher.encoding_map = encoding_map.clone();
+        matcher.syntax = syntax.clone();
+        matcher.identifier = opt.identifier;
+        matcher.any_of = pairs.iter().map(|x| x.0.clone()).collect();
+        matcher.overlap = opt.overlap;
+        matcher.token_kind = if opt.code_only {
+            Some(TokenKind::Code)
+        } else if opt.comments_only {
+            Some(TokenKind::Comment)
+        } else if opt.strings_only {
+            Some(TokenKind::String)
+        } else {
+            None
+        };
+        matcher.in_strings = opt.in_strings;
+        matcher.noisy_threshold = opt.skip_noisy;
+        matcher.io_retries = opt.io_retries;
+        matcher.batch_size = opt.batch_size;
+        matcher.trace = matcher_trace.clone();
+        matcher
+    };
+    for _ in 0..matcher_num {
+        builder = builder.matcher(new_matcher());
     }
-
-    let _ = thread::Builder::new().name("finder".to_string()).spawn(move || {
-        finder.setup(id_finder, rx_finder, tx_matcher);
-    });
-
-    let _ = thread::Builder::new().name("sorter".to_string()).spawn(move || {
-        sorter.setup(id_sorter, rx_sorter, tx_replacer);
-    });
+    let (tx_finder, rx_replacer) = builder.build();
+    replacer.matcher = Some(new_matcher());
 
     let _ = thread::Builder::new().name("replacer".to_string()).spawn(move || {
         replacer.setup(id_replacer, rx_replacer, tx_main);
@@ -517,9 +1408,19 @@ fn main() {
         time_matcher_all.push(Duration::new(0, 0));
     }
 
+    let mut profile = Profile::new();
+    profile.add_stage(id_finder, "find", 0);
+    for i in 0..matcher_num {
+        profile.add_stage(id_matcher + i, "match", i);
+    }
+    profile.add_stage(id_sorter, "sort", 0);
+    profile.add_stage(id_replacer, "replace", 0);
+
+    let mut match_num = 0;
     loop {
         match rx_main.try_recv() {
             Ok(PipelineInfo::SeqEnd(_)) => break,
+            Ok(PipelineInfo::SeqDat(_, num)) => match_num += num,
             Ok(PipelineInfo::MsgTime(id, t0, t1)) if id == id_finder => {
                 time_finder_bsy = t0;
                 time_finder_all = t1;
@@ -536,17 +1437,35 @@ fn main() {
                 time_matcher_bsy[id - id_matcher] = t0;
                 time_matcher_all[id - id_matcher] = t1;
             }
-            Ok(PipelineInfo::MsgInfo(_id, s)) => console.write(ConsoleTextKind::Info, &format!("{}\n", s)),
-            Ok(PipelineInfo::MsgErr(_id, s)) => console.write(ConsoleTextKind::Error, &format!("{}\n", s)),
+            Ok(PipelineInfo::MsgCount(id, n, b)) => profile.set_count(id, n, b),
+            Ok(PipelineInfo::MsgInfo(_id, s)) => console.write_diagnostic(ConsoleTextKind::Info, &format!("{}\n", s)),
+            Ok(PipelineInfo::MsgErr(_id, s)) => console.write_diagnostic(ConsoleTextKind::Error, &format!("{}\n", s)),
             Ok(_) => (),
             Err(_) => (),
         }
     }
 
+    // - Clean temporary files -------------------------------------------------
+    // Temporary files are found by the finder not to walk the directories again
+    let leftovers = tmpfile::filter_leftovers(leftovers.lock().unwrap().clone());
+    if !leftovers.is_empty() {
+        clean_leftovers(&opt, leftovers, &mut console);
+    }
+
     // ---------------------------------------------------------------------------------------------
     // Pipeline Flow
     // ---------------------------------------------------------------------------------------------
 
+    if opt.profile.is_some() {
+        profile.set_time(id_finder, time_finder_bsy, time_finder_all);
+        for i in 0..matcher_num {
+            profile.set_time(id_matcher + i, time_matcher_bsy[i], time_matcher_all[i]);
+        }
+        profile.set_time(id_sorter, time_sorter_bsy, time_sorter_all);
+        profile.set_time(id_replacer, time_replacer_bsy, time_replacer_all);
+        eprintln!("{}", profile.to_json());
+    }
+
     let sec_finder_bsy = as_secsf64(time_finder_bsy);
     let sec_finder_all = as_secsf64(time_finder_all);
     let sec_sorter_bsy = as_secsf64(time_sorter_bsy);
@@ -563,9 +1482,14 @@ fn main() {
 
     if opt.statistics {
         console.write(ConsoleTextKind::Info, &format!("\nStatistics\n"));
+        console.write(ConsoleTextKind::Info, &format!("  Max threads: {}\n", opt.max_threads));
+        console.write(
+            ConsoleTextKind::Info,
+            &format!("  Broken link: {}\n", broken_symlinks.get()),
+        );
         console.write(
             ConsoleTextKind::Info,
-            &format!("  Max threads: {}\n\n", opt.max_threads),
+            &format!("  Matcher    : {}\n\n", matcher_kind.name()),
         );
         console.write(ConsoleTextKind::Info, &format!("  Consumed time ( busy / total )\n"));
         console.write(
@@ -591,5 +1515,6 @@ fn main() {
         );
     }
 
-    exit(0, &mut console);
+    let code = exit_status(match_num != 0, console.has_error());
+    exit(code, &mut console);
 }
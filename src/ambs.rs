@@ -1,21 +1,46 @@
-use amber::console::{Console, ConsoleTextKind};
-use amber::matcher::{QuickSearchMatcher, RegexMatcher, TbmMatcher};
-use amber::pipeline::{Pipeline, PipelineFork, PipelineInfo, PipelineJoin};
-use amber::pipeline_finder::PipelineFinder;
-use amber::pipeline_matcher::PipelineMatcher;
-use amber::pipeline_printer::PipelinePrinter;
-use amber::pipeline_sorter::PipelineSorter;
-use amber::util::{as_secsf64, decode_error, exit, read_from_file};
+use amber::cache::{ResultCache, CACHE_DIR};
+use amber::console::{ColorChoice, Console, ConsoleTextKind, Diagnostic, ErrorFormat};
+use amber::daemon;
+use amber::encoding::{EncodingMap, EncodingRule};
+use amber::follow::FileFollower;
+use amber::history::{History, HistoryEntry};
+use amber::index::{Index, INDEX_DIR};
+use amber::lexer::TokenKind;
+use amber::matcher::{
+    CaseFoldMatcher, Match, Matcher, MatcherKind, MemmemMatcher, QuickSearchMatcher, RegexMatcher, TbmMatcher,
+};
+use amber::picker::{self, Candidate, PickAction, PICK_TEMPLATE};
+use amber::pipeline::{CancelToken, Pipeline, PipelineFork, PipelineInfo};
+use amber::pipeline_builder::{PipelineBuilder, ID_FINDER, ID_MATCHER, ID_OUTPUT, ID_SORTER};
+use amber::pipeline_finder::{DirCache, OwnerFilter, PathInfo, PermFilter, PipelineFinder, VcsFilter};
+use amber::pipeline_matcher::{AllOfScope, BinaryFiles, Overlap, PipelineMatcher, Within};
+use amber::pipeline_printer::{root_label, CountBy, FormatTemplate, OutputFormat, PipelinePrinter, SortMatches};
+use amber::preset::Preset;
+use amber::profile::Profile;
+use amber::query::Query;
+use amber::rpc::RpcServer;
+use amber::syntax::{SyntaxFilter, SyntaxLang};
+use amber::trace::{TraceStage, TraceStages, Tracer};
+use amber::util::{
+    args_with_env, as_secsf64, decode_error, exit, exit_status, find_arg, lower_priority, parse_hex, read_from_file,
+    split_args, split_root_label, ColumnUnit, IndexRange, IoLimiter, IoRate, IoStrategy, MemoryBudget, PathPrefix,
+    EXIT_ERROR,
+};
 use crossbeam::channel::unbounded;
 use dirs;
 use lazy_static::lazy_static;
+use regex::bytes::{Regex, RegexBuilder};
 use serde_derive::Deserialize;
 use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::fs;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use structopt::{clap, StructOpt};
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -27,15 +52,20 @@ use structopt::{clap, StructOpt};
 #[structopt(long_version(option_env!("LONG_VERSION").unwrap_or(env!("CARGO_PKG_VERSION"))))]
 #[structopt(setting(clap::AppSettings::ColoredHelp))]
 #[structopt(setting(clap::AppSettings::DeriveDisplayOrder))]
+#[structopt(setting(clap::AppSettings::AllArgsOverrideSelf))]
 pub struct Opt {
     /// Keyword for search
-    #[structopt(name = "KEYWORD")]
-    pub keyword: String,
+    #[structopt(name = "KEYWORD", required_unless_one = &["index", "daemon", "rpc", "files", "all-of", "query", "preset", "preset-file", "patterns", "history"])]
+    pub keyword: Option<String>,
 
     /// Use file contents of KEYWORD as keyword for search
     #[structopt(long = "key-from-file")]
     pub key_from_file: bool,
 
+    /// Parse KEYWORD as hex bytes like `DE AD BE EF`, and print matches as the offset and hex dump
+    #[structopt(long = "hex", conflicts_with_all = &["all-of", "query"])]
+    pub hex: bool,
+
     /// Search paths
     #[structopt(name = "PATHS")]
     pub paths: Vec<String>,
@@ -44,6 +74,66 @@ pub struct Opt {
     #[structopt(long = "max-threads", default_value = &MAX_THREADS, value_name = "NUM")]
     pub max_threads: usize,
 
+    /// Number of threads reading directories
+    #[structopt(long = "finder-threads", default_value = "1", value_name = "NUM")]
+    pub finder_threads: usize,
+
+    /// Number of threads matching keyword ( default: max threads up to 8 )
+    #[structopt(long = "matcher-threads", value_name = "NUM")]
+    pub matcher_threads: Option<usize>,
+
+    /// Search NTFS alternate data streams of files like `file.txt:stream` ( Windows only )
+    #[structopt(long = "streams")]
+    pub streams: bool,
+
+    /// Don't descend into directories on other file systems ( e.g. NFS mounts, /proc ) than each of PATHS
+    #[structopt(long = "one-file-system")]
+    pub one_file_system: bool,
+
+    /// Search hard links and symbolic links of a file as many times as found ( Unix only )
+    #[structopt(long = "no-dedupe")]
+    pub no_dedupe: bool,
+
+    /// Skip symbolic links resolved through more than N links ( e.g. a -> b -> c is 2 )
+    #[structopt(long = "max-symlink-depth", value_name = "N")]
+    pub max_symlink_depth: Option<usize>,
+
+    /// Print broken symbolic links with the targets, which are counted in the statistics anyway
+    #[structopt(long = "broken-symlinks")]
+    pub broken_symlinks: bool,
+
+    /// Search FIFOs and device files ( e.g. block devices ), which are skipped by default not to block ( Unix only )
+    #[structopt(long = "special-files")]
+    pub special_files: bool,
+
+    /// Search only files owned by USER given by the name or the user ID ( Unix only )
+    #[structopt(long = "owner", value_name = "USER")]
+    pub owner: Option<OwnerFilter>,
+
+    /// Search only files with permission bits in octal ( 644: exactly, -022: all of bits, /002: any of bits, Unix only )
+    #[structopt(long = "perm", value_name = "MODE", allow_hyphen_values = true)]
+    pub perm: Option<PermFilter>,
+
+    /// Match and print smaller files at first after finding all files
+    #[structopt(long = "small-first")]
+    pub small_first: bool,
+
+    /// Skip files having more than N matches ( e.g. minified or generated files ), and report them at the end
+    #[structopt(long = "skip-noisy", value_name = "N", default_value = "0")]
+    pub skip_noisy: usize,
+
+    /// Retry reading files N times with backoff on transient errors of network file systems ( e.g. ESTALE, timeouts )
+    #[structopt(long = "io-retries", value_name = "N", default_value = "2")]
+    pub io_retries: usize,
+
+    /// Number of results queued between pipeline stages before blocking the former stage ( 0: unlimited )
+    #[structopt(long = "queue-size", default_value = "256", value_name = "NUM")]
+    pub queue_size: usize,
+
+    /// Number of files passed at once between pipeline stages ( 1: one by one )
+    #[structopt(long = "batch-size", default_value = "64", value_name = "NUM")]
+    pub batch_size: usize,
+
     /// File size per one thread
     #[structopt(long = "size-per-thread", default_value = "1048576", value_name = "BYTES")]
     pub size_per_thread: usize,
@@ -52,26 +142,431 @@ pub struct Opt {
     #[structopt(long = "bin-check-bytes", default_value = "256", value_name = "BYTES")]
     pub bin_check_bytes: usize,
 
+    /// How to load files ( auto: mmap files larger than --mmap-bytes and read the others, mmap: mmap all, read: read all )
+    #[structopt(long = "io", default_value = "auto", value_name = "STRATEGY", possible_values = &["auto", "mmap", "read"])]
+    pub io: IoStrategy,
+
     /// [Experimental] Minimum size for using mmap
     #[structopt(long = "mmap-bytes", default_value = "1048576", value_name = "BYTES")]
     pub mmap_bytes: u64,
 
+    /// [Experimental] Maximum size for using mmap, larger files are searched by streaming ( 0: unlimited )
+    #[structopt(long = "mmap-threshold", default_value = "0", value_name = "BYTES")]
+    pub mmap_threshold: u64,
+
+    /// Maximum total size of files searched concurrently ( 0: unlimited )
+    #[structopt(long = "max-memory", default_value = "0", value_name = "BYTES")]
+    pub max_memory: u64,
+
+    /// Maximum total bandwidth of reading files like `50MB/s` ( KB, MB and GB are powers of 1000, and KiB, MiB and
+    /// GiB are of 1024 )
+    #[structopt(long = "io-limit", value_name = "RATE")]
+    pub io_limit: Option<IoRate>,
+
+    /// Lower the CPU and I/O priority not to slow down other workloads
+    #[structopt(long = "nice")]
+    pub nice: bool,
+
+    /// Encoding of source files for display ( e.g. SHIFT_JIS, EUC-JP )
+    #[structopt(long = "display-encoding", value_name = "ENCODING")]
+    pub display_encoding: Option<String>,
+
+    /// Encoding of the files matched by the glob like `legacy/**=shift_jis`, where the keyword is searched as encoded
+    /// ( repeatable, and the last rule matched wins )
+    #[structopt(long = "encoding-map", value_name = "GLOB=ENCODING", number_of_values = 1)]
+    pub encoding_map: Vec<EncodingRule>,
+
+    /// Build or update the content index at ./.amber-index for PATHS
+    #[structopt(long = "index", value_name = "MODE", possible_values = &["build"])]
+    pub index: Option<String>,
+
+    /// Skip files which don't contain the keyword according to the content index at ./.amber-index
+    #[structopt(long = "use-index")]
+    pub use_index: bool,
+
+    /// Reuse the matches of files not modified since the last search with the same keyword and options, which are
+    /// cached at ./.amber-cache
+    #[structopt(long = "cache")]
+    pub cache: bool,
+
+    /// Run as a daemon serving searches at SOCKET, with the content index and the directory listings of the current
+    /// directory kept in memory
+    #[structopt(long = "daemon", value_name = "SOCKET")]
+    pub daemon: Option<String>,
+
+    /// Send the search to the daemon at SOCKET instead of searching by itself
+    #[structopt(long = "connect", value_name = "SOCKET")]
+    pub connect: Option<String>,
+
+    /// Serve newline-delimited JSON-RPC requests ( search / replace-preview / cancel ) on stdin
+    #[structopt(long = "rpc")]
+    pub rpc: bool,
+
+    /// List the recent searches and replacements, or run the N-th of them again ( recorded only if AMBER_HISTORY is set )
+    #[structopt(long = "history", value_name = "N")]
+    pub history: Option<Option<usize>>,
+
+    /// Search only the files selected by git ( tracked: tracked files, modified: unstaged changes, staged: staged changes )
+    #[structopt(long = "vcs", value_name = "FILTER", possible_values = &["tracked", "modified", "staged"])]
+    pub vcs: Option<VcsFilter>,
+
+    /// Search the files of git revision REV ( e.g. HEAD~5 ) instead of the working tree
+    #[structopt(long = "rev", value_name = "REV")]
+    pub rev: Option<String>,
+
+    /// Output format ( csv/tsv: `path,line,column,match` records, xml: a document of files and matches,
+    /// emacs: `path:line:text` lines of grep-mode with `column:` after the line by --row,
+    /// json: an object per match, sarif: a SARIF log for code scanning tools )
+    #[structopt(
+        long = "format",
+        default_value = "default",
+        value_name = "FORMAT",
+        possible_values = &["default", "csv", "tsv", "xml", "emacs", "json", "sarif"]
+    )]
+    pub format: OutputFormat,
+
+    /// Search only the files containing all of the keywords ( KEYWORD is treated as a path, put `--` before PATHS )
+    #[structopt(long = "all-of", value_name = "KEYWORD", min_values = 2)]
+    pub all_of: Vec<String>,
+
+    /// Search only the files satisfying QUERY like `(foo OR bar) AND NOT baz` ( KEYWORD is treated as a path )
+    #[structopt(long = "query", value_name = "QUERY", conflicts_with = "all-of")]
+    pub query: Option<String>,
+
+    /// Search the regexes of the built-in rules of NAME at once, and print the rule of each match
+    /// ( secrets: API keys, tokens and private keys, KEYWORD is treated as a path )
+    #[structopt(
+        long = "preset",
+        value_name = "NAME",
+        possible_values = &["secrets"],
+        conflicts_with_all = &["all-of", "query", "hex", "key-from-file"]
+    )]
+    pub preset: Option<Preset>,
+
+    /// Search the rules of FILE like --preset, where each `[[rule]]` has `name`, `pattern` ( regex ),
+    /// `severity` ( error / warning / note ) and `description` printed by `--format json` and `--format sarif`
+    #[structopt(
+        long = "preset-file",
+        value_name = "FILE",
+        parse(from_os_str),
+        conflicts_with_all = &["preset", "all-of", "query", "hex", "key-from-file"]
+    )]
+    pub preset_file: Option<PathBuf>,
+
+    /// Search PATTERN as well as the other patterns given by this option, where the matches of each pattern are
+    /// highlighted in a different color ( repeatable, KEYWORD is treated as a path )
+    #[structopt(
+        short = "e",
+        long = "pattern",
+        value_name = "PATTERN",
+        number_of_values = 1,
+        conflicts_with_all = &["all-of", "query", "preset", "preset-file", "hex", "key-from-file"]
+    )]
+    pub patterns: Vec<String>,
+
+    /// Skip matches in lines which also contain PATTERN ( regular expression with --regex )
+    #[structopt(long = "not", value_name = "PATTERN")]
+    pub not: Option<String>,
+
+    /// Search only the lines of RANGE like `1..50` ( 1-based, inclusive ) in each file
+    #[structopt(long = "lines", value_name = "RANGE")]
+    pub lines: Option<IndexRange>,
+
+    /// Report only the matches beginning within the columns of RANGE like `1..4` ( 1-based bytes, inclusive )
+    #[structopt(long = "columns", value_name = "RANGE")]
+    pub columns: Option<IndexRange>,
+
+    /// Report only the matches between the matches of BEGIN_REGEX and END_REGEX like `--within BEGIN END`
+    #[structopt(long = "within", value_names = &["BEGIN_REGEX", "END_REGEX"], number_of_values = 2)]
+    pub within: Vec<String>,
+
+    /// Parse files as LANG ( c, go, javascript, python, rust ) for --node-type with the `syntax` feature
+    #[structopt(long = "syntax", value_name = "LANG")]
+    pub syntax: Option<SyntaxLang>,
+
+    /// Report only the matches inside the syntax nodes of KIND like `string_literal`
+    #[structopt(long = "node-type", value_name = "KIND", number_of_values = 1, requires = "syntax")]
+    pub node_type: Vec<String>,
+
+    /// Report only the matches outside the syntax nodes of KIND like `line_comment`
+    #[structopt(
+        long = "exclude-node-type",
+        value_name = "KIND",
+        number_of_values = 1,
+        requires = "syntax",
+        conflicts_with = "node-type"
+    )]
+    pub exclude_node_type: Vec<String>,
+
+    /// Report only the matches outside comments and string literals of the languages known by the extension
+    #[structopt(long = "code-only", conflicts_with_all = &["comments-only", "strings-only", "in-strings"])]
+    pub code_only: bool,
+
+    /// Report only the matches inside comments
+    #[structopt(long = "comments-only", conflicts_with_all = &["strings-only", "in-strings"])]
+    pub comments_only: bool,
+
+    /// Report only the matches inside string literals
+    #[structopt(long = "strings-only")]
+    pub strings_only: bool,
+
+    /// Report only the matches inside the quotes of string literals, which are found by `"`, `'` and `` ` `` in a line
+    /// if the language is unknown by the extension
+    #[structopt(long = "in-strings", conflicts_with = "strings-only")]
+    pub in_strings: bool,
+
+    /// Range containing all of the keywords of --all-of or satisfying --query ( file: the whole file, line: a line )
+    #[structopt(
+        long = "all-of-scope",
+        default_value = "file",
+        value_name = "SCOPE",
+        possible_values = &["file", "line"]
+    )]
+    pub all_of_scope: AllOfScope,
+
+    /// Matches kept when matches of the rules of --preset overlap
+    /// ( first: the earliest one, longest: the longest one, report-all: all matches )
+    #[structopt(
+        long = "overlap",
+        default_value = "first",
+        value_name = "POLICY",
+        possible_values = &["report-all", "longest", "first"]
+    )]
+    pub overlap: Overlap,
+
+    /// Treatment of binary files ( binary: print `Binary file <path> matches`, hex: print hex dumps around matches,
+    /// text: print matched lines as text, without-match: skip ) [default: text with --hex, binary with --binary, otherwise without-match]
+    #[structopt(
+        long = "binary-files",
+        value_name = "TYPE",
+        possible_values = &["binary", "hex", "text", "without-match"]
+    )]
+    pub binary_files: Option<BinaryFiles>,
+
+    /// Print each match by TEMPLATE like `{path}\t{line}\t{text}` instead of --format
+    /// ( placeholders: {path} {line} {column} {offset} {offset_end} {match} {text} )
+    #[structopt(long = "format-template", value_name = "TEMPLATE")]
+    pub format_template: Option<FormatTemplate>,
+
+    /// Search KEYWORD from the paths of files instead of the contents
+    #[structopt(long = "path-search")]
+    pub path_search: bool,
+
+    /// Keep FILE open like `tail -f`, and print the matches in the lines appended until interrupted ( PATHS are ignored )
+    #[structopt(
+        long = "follow-file",
+        value_name = "FILE",
+        conflicts_with_all = &["all-of", "query", "preset", "preset-file", "hex", "path-search"]
+    )]
+    pub follow_file: Option<PathBuf>,
+
+    /// Print the lines matched in only either of the trees compared by the relative path and the line content, where
+    /// `-` is only in TREE_A and `+` is only in TREE_B ( e.g. occurrences left after a refactor, PATHS are ignored )
+    #[structopt(
+        long = "compare",
+        value_names = &["TREE_A", "TREE_B"],
+        number_of_values = 2,
+        conflicts_with_all = &["all-of", "query", "preset", "preset-file", "hex", "path-search", "follow-file"]
+    )]
+    pub compare: Vec<PathBuf>,
+
+    /// Print the files to be searched without searching ( KEYWORD is treated as a path )
+    #[structopt(long = "files")]
+    pub files: bool,
+
+    /// Print the throughput of each matcher searching KEYWORD in the files instead of the matches
+    #[structopt(long = "bench", conflicts_with_all = &["files", "index", "all-of", "query"])]
+    pub bench: bool,
+
+    /// Separator between the path, column, row and text of each match
+    #[structopt(
+        long = "field-separator",
+        default_value = ":",
+        value_name = "SEP",
+        allow_hyphen_values = true
+    )]
+    pub field_separator: String,
+
+    /// Print SEP ( may be empty ) as a line between the matches of each file
+    #[structopt(long = "group-separator", value_name = "SEP", allow_hyphen_values = true)]
+    pub group_separator: Option<String>,
+
+    /// Print the path once as `path (N matches)` before the matches of each file
+    #[structopt(long = "heading")]
+    pub heading: bool,
+
+    /// Print N lines per file at most, and fold the rest into a `… and N more` line
+    #[structopt(long = "max-lines-per-file", value_name = "N")]
+    pub max_lines_per_file: Option<usize>,
+
+    /// Print the commit, the author and the date of the line of each match by `git blame` ( also in JSON records )
+    #[structopt(long = "blame")]
+    pub blame: bool,
+
+    /// URL of the hyperlinks of file names on terminals supporting OSC 8
+    /// ( placeholders: {host} {path} {line} {column}, e.g. `vscode://file{path}:{line}`, none: disabled )
+    #[structopt(
+        long = "hyperlink-format",
+        default_value = "file://{host}{path}",
+        value_name = "FORMAT"
+    )]
+    pub hyperlink_format: String,
+
+    /// Rewrite the prefix of printed paths and hyperlinks like `/container/src=/home/me/src` to open them on the host
+    /// ( repeatable, and the longest prefix matched wins )
+    #[structopt(long = "path-prefix-map", value_name = "FROM=TO", number_of_values = 1)]
+    pub path_prefix_map: Vec<PathPrefix>,
+
+    /// Print matched lines in the same format as `grep -rn`
+    #[structopt(long = "grep-compat")]
+    pub grep_compat: bool,
+
+    /// Terminate paths by NUL instead of `:` like `grep --null` ( only for --grep-compat and --format emacs )
+    #[structopt(short = "Z", long = "null")]
+    pub null: bool,
+
+    /// Print all lines of the files containing matches with the matches highlighted ( only for the default format )
+    #[structopt(long = "passthru")]
+    pub passthru: bool,
+
+    /// Print the number of matches per directory down to DEPTH after searching
+    #[structopt(long = "stats-by-dir", value_name = "DEPTH")]
+    pub stats_by_dir: Option<usize>,
+
+    /// Exit with status 1 if any match is found, and 0 if not ( for CI )
+    #[structopt(long = "check")]
+    pub check: bool,
+
     /// Enable regular expression search
     #[structopt(short = "r", long = "regex", hidden = DEFAULT_FLAGS.regex)]
     pub regex: bool,
 
+    /// Match case-insensitively by Unicode case folding ( e.g. `é` matches `É`, and `ss` matches `ß` except by --regex )
+    #[structopt(short = "i", long = "ignore-case", conflicts_with = "hex")]
+    pub ignore_case: bool,
+
     /// Enable column output
     #[structopt(long = "column", hidden = DEFAULT_FLAGS.column)]
     pub column: bool,
 
+    /// Write results to FILE without colors instead of stdout, and print only the summary ( FILE should be out of PATHS )
+    #[structopt(long = "output", value_name = "FILE")]
+    pub output: Option<String>,
+
+    /// Stop finding and matching files after printing NUM matches
+    #[structopt(long = "max-results", value_name = "NUM")]
+    pub max_results: Option<usize>,
+
+    /// Write each matched line immediately even if stdout is not a terminal ( e.g. piped to fzf ), where files are
+    /// passed one by one and printed in the order matched instead of the order found
+    #[structopt(long = "line-buffered")]
+    pub line_buffered: bool,
+
+    /// Truncate lines longer than N characters around the matches with `...` ( e.g. minified files )
+    #[structopt(long = "max-columns", value_name = "N")]
+    pub max_columns: Option<usize>,
+
+    /// Show the number of characters omitted by --max-columns like `[...120 chars]`
+    #[structopt(long = "max-columns-preview", requires = "max-columns")]
+    pub max_columns_preview: bool,
+
+    /// Print lines longer than N bytes only around the matches not to print pathological lines ( e.g. huge JSON in one
+    /// line ), and report them as infos ( 0: unlimited )
+    #[structopt(long = "max-line-bytes", value_name = "N", default_value = "1048576")]
+    pub max_line_bytes: usize,
+
     /// Enable row output
     #[structopt(long = "row", hidden = DEFAULT_FLAGS.row)]
     pub row: bool,
 
+    /// Unit of the row output ( bytes, chars: grapheme clusters, display: cells on terminals like CJK and emoji as 2 )
+    #[structopt(
+        long = "column-unit",
+        default_value = "bytes",
+        value_name = "UNIT",
+        possible_values = &["bytes", "chars", "display"]
+    )]
+    pub column_unit: ColumnUnit,
+
+    /// Expand tabs to the multiples of N in the row output like editors ( 0: a tab is a character )
+    #[structopt(long = "tab-width", default_value = "0", value_name = "N")]
+    pub tab_width: usize,
+
     /// Enable binary file search
     #[structopt(long = "binary", hidden = DEFAULT_FLAGS.binary)]
     pub binary: bool,
 
+    /// Use the options of [profile.NAME] in ~/.ambs.toml
+    #[structopt(long = "config-profile", value_name = "NAME")]
+    pub config_profile: Option<String>,
+
+    /// Print the consumed time and the processed items / bytes of each pipeline stage to stderr in FORMAT
+    #[structopt(long = "profile", value_name = "FORMAT", possible_values = &["json"])]
+    pub profile: Option<String>,
+
+    /// Print the numbers of files discovered, searched and matched periodically to stderr in FORMAT while searching
+    #[structopt(long = "progress", value_name = "FORMAT", possible_values = &["json"])]
+    pub progress: Option<String>,
+
+    /// Format of skipped files and errors on stderr ( json: an object of `path`, `kind` and `message` per line )
+    #[structopt(
+        long = "errors",
+        default_value = "text",
+        value_name = "FORMAT",
+        possible_values = &["text", "json"]
+    )]
+    pub errors: ErrorFormat,
+
+    /// Print nothing to stdout, and exit with status 0 as soon as the first match is found
+    #[structopt(short = "q", long = "quiet")]
+    pub quiet: bool,
+
+    /// Print the frequency table of the matched texts instead of the matches ( match: the whole match,
+    /// capture:N or capture:NAME: the capture group of --regex )
+    #[structopt(long = "count-matches-by", value_name = "KEY")]
+    pub count_matches_by: Option<CountBy>,
+
+    /// Sort the matches of each file by KEY instead of the position ( column: the column in bytes, text: the matched
+    /// text ), where the matches in the same line are printed separately
+    #[structopt(
+        long = "sort-matches",
+        value_name = "KEY",
+        possible_values = &["column", "text"],
+        conflicts_with = "passthru"
+    )]
+    pub sort_matches: Option<SortMatches>,
+
+    /// Pick a matched line by $AMBER_PICKER ( e.g. fzf ) or the embedded picker, and print `path:line` of it
+    #[structopt(long = "pick", conflicts_with_all = &["output", "quiet", "count-matches-by", "format-template"])]
+    pub pick: bool,
+
+    /// Action for the line picked by --pick ( print: print `path:line`, edit: open it by $EDITOR )
+    #[structopt(
+        long = "pick-action",
+        value_name = "ACTION",
+        default_value = "print",
+        possible_values = &["print", "edit"]
+    )]
+    pub pick_action: PickAction,
+
+    /// Suppress error messages of files ( e.g. permission denied, broken symlinks ), which still decide the exit status
+    #[structopt(short = "s", long = "no-messages")]
+    pub no_messages: bool,
+
+    /// Print files skipped with the reasons ( e.g. binary file, .gitignore, permission denied ) after the results,
+    /// and include them in `--profile json`
+    #[structopt(long = "report-skipped")]
+    pub report_skipped: bool,
+
+    /// Print the decisions of the pipeline stages to stderr ( -v: skipped files and the matcher, -vv: each file with
+    /// the time )
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    pub verbose: usize,
+
+    /// Print --verbose only of STAGES separated by comma ( finder, matcher, sorter, printer or all ), which implies -v
+    #[structopt(long = "trace", value_name = "STAGES")]
+    pub trace: Option<TraceStages>,
+
     /// Enable statistics output
     #[structopt(long = "statistics", hidden = DEFAULT_FLAGS.statistics)]
     pub statistics: bool,
@@ -88,8 +583,18 @@ pub struct Opt {
     #[structopt(long = "symlink", hidden = DEFAULT_FLAGS.symlink)]
     pub symlink: bool,
 
-    /// Enable colored output
-    #[structopt(long = "color", hidden = DEFAULT_FLAGS.color)]
+    /// Color the output WHEN ( auto: only if stdout is a terminal, NO_COLOR disables and CLICOLOR_FORCE enables it ),
+    /// where `--color` alone is always
+    #[structopt(
+        long = "color",
+        value_name = "WHEN",
+        possible_values = &["auto", "always", "never"],
+        require_equals = true
+    )]
+    pub color_choice: Option<Option<ColorChoice>>,
+
+    /// Whether to color the output, which is decided by --color, --no-color and `color` of the config file
+    #[structopt(skip)]
     pub color: bool,
 
     /// Enable filename output
@@ -104,6 +609,10 @@ pub struct Opt {
     #[structopt(long = "skip-gitignore", hidden = DEFAULT_FLAGS.skip_gitignore)]
     pub skip_gitignore: bool,
 
+    /// Enable .amberignore skip
+    #[structopt(long = "skip-amberignore", hidden = DEFAULT_FLAGS.skip_amberignore)]
+    pub skip_amberignore: bool,
+
     /// Enable output order guarantee
     #[structopt(long = "fixed-order", hidden = DEFAULT_FLAGS.fixed_order)]
     pub fixed_order: bool,
@@ -144,7 +653,7 @@ pub struct Opt {
     #[structopt(long = "no-symlink", hidden = !DEFAULT_FLAGS.symlink)]
     pub no_symlink: bool,
 
-    /// Disable colored output
+    /// Disable colored output ( same as --color=never )
     #[structopt(long = "no-color", hidden = !DEFAULT_FLAGS.color)]
     pub no_color: bool,
 
@@ -160,8 +669,12 @@ pub struct Opt {
     #[structopt(long = "no-skip-gitignore", hidden = !DEFAULT_FLAGS.skip_gitignore)]
     pub no_skip_gitignore: bool,
 
-    /// Disable output order guarantee
-    #[structopt(long = "no-fixed-order", hidden = !DEFAULT_FLAGS.fixed_order)]
+    /// Disable .amberignore skip
+    #[structopt(long = "no-skip-amberignore", hidden = !DEFAULT_FLAGS.skip_amberignore)]
+    pub no_skip_amberignore: bool,
+
+    /// Disable output order guarantee to pass results as soon as matched ( also --no-reorder )
+    #[structopt(long = "no-fixed-order", alias = "no-reorder", hidden = !DEFAULT_FLAGS.fixed_order)]
     pub no_fixed_order: bool,
 
     /// Disable .*ignore file search at parent directories
@@ -172,6 +685,16 @@ pub struct Opt {
     #[structopt(long = "tbm")]
     pub tbm: bool,
 
+    /// Matcher used to search KEYWORD ( auto: selected by the other options, quick/tbm/memmem: literal keywords only,
+    /// regex: same as --regex )
+    #[structopt(
+        long = "matcher",
+        default_value = "auto",
+        value_name = "KIND",
+        possible_values = &["auto", "quick", "tbm", "memmem", "regex"]
+    )]
+    pub matcher: MatcherKind,
+
     /// [Experimental] Enable SSE 4.2
     #[structopt(long = "sse")]
     pub sse: bool,
@@ -204,9 +727,20 @@ struct DefaultFlags {
     #[serde(default = "flag_true")]
     skip_gitignore: bool,
     #[serde(default = "flag_true")]
+    skip_amberignore: bool,
+    #[serde(default = "flag_true")]
     fixed_order: bool,
     #[serde(default = "flag_true")]
     parent_ignore: bool,
+    #[serde(default)]
+    profile: HashMap<String, ProfileFlags>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileFlags {
+    /// Options inserted before the command-line arguments
+    #[serde(default)]
+    options: String,
 }
 
 impl DefaultFlags {
@@ -240,6 +774,7 @@ impl DefaultFlags {
 
     fn merge(&self, mut opt: Opt) -> Opt {
         opt.regex = if self.regex { !opt.no_regex } else { opt.regex };
+        opt.regex = opt.regex || opt.matcher == MatcherKind::Regex || opt.preset.is_some() || opt.preset_file.is_some();
         opt.column = if self.column { !opt.no_column } else { opt.column };
         opt.row = if self.row { !opt.no_row } else { opt.row };
         opt.binary = if self.binary { !opt.no_binary } else { opt.binary };
@@ -255,7 +790,15 @@ impl DefaultFlags {
             opt.recursive
         };
         opt.symlink = if self.symlink { !opt.no_symlink } else { opt.symlink };
-        opt.color = if self.color { !opt.no_color } else { opt.color };
+        // `color = true` of the config file is auto not to color the output piped to other commands
+        let color_choice = match opt.color_choice {
+            _ if opt.no_color => ColorChoice::Never,
+            Some(Some(x)) => x,
+            Some(None) => ColorChoice::Always,
+            None if self.color => ColorChoice::Auto,
+            None => ColorChoice::Never,
+        };
+        opt.color = color_choice.is_color();
         opt.file = if self.file { !opt.no_file } else { opt.file };
         opt.skip_vcs = if self.skip_vcs { !opt.no_skip_vcs } else { opt.skip_vcs };
         opt.skip_gitignore = if self.skip_gitignore {
@@ -263,6 +806,11 @@ impl DefaultFlags {
         } else {
             opt.skip_gitignore
         };
+        opt.skip_amberignore = if self.skip_amberignore {
+            !opt.no_skip_amberignore
+        } else {
+            opt.skip_amberignore
+        };
         opt.fixed_order = if self.fixed_order {
             !opt.no_fixed_order
         } else {
@@ -284,6 +832,474 @@ fn flag_false() -> bool {
     false
 }
 
+fn find_files<F: FnMut(&mut Console, PathInfo)>(opt: &Opt, base_paths: Vec<PathBuf>, console: &mut Console, mut f: F) {
+    let mut finder = PipelineFinder::new();
+    finder.is_recursive = opt.recursive;
+    finder.follow_symlink = opt.symlink;
+    finder.one_file_system = opt.one_file_system;
+    finder.dedupe = !opt.no_dedupe;
+    finder.max_symlink_depth = opt.max_symlink_depth;
+    finder.print_broken_symlinks = opt.broken_symlinks;
+    finder.special_files = opt.special_files;
+    finder.owner = opt.owner.clone();
+    finder.perm = opt.perm;
+    finder.threads = opt.finder_threads;
+    finder.small_first = opt.small_first;
+    finder.alternate_streams = opt.streams;
+    finder.skip_vcs = opt.skip_vcs;
+    finder.skip_gitignore = opt.skip_gitignore;
+    finder.skip_ambignore = opt.skip_amberignore;
+    finder.print_skipped = opt.skipped || opt.report_skipped;
+    finder.find_parent_ignore = opt.parent_ignore;
+    finder.vcs_filter = opt.vcs;
+    finder.rev = opt.rev.clone();
+    finder.trace = Tracer::new(TraceStage::Finder, opt.verbose, opt.trace.as_ref());
+
+    let (tx_finder, rx_finder) = unbounded();
+    let (tx_main, rx_main) = unbounded::<PipelineInfo<PathInfo>>();
+
+    let _ = thread::Builder::new().name("finder".to_string()).spawn(move || {
+        finder.setup(0, rx_finder, vec![tx_main]);
+    });
+
+    let mut seq_no = 0;
+    let _ = tx_finder.send(PipelineInfo::SeqBeg(seq_no));
+    for p in base_paths {
+        let _ = tx_finder.send(PipelineInfo::SeqDat(seq_no, p));
+        seq_no += 1;
+    }
+    let _ = tx_finder.send(PipelineInfo::SeqEnd(seq_no));
+
+    loop {
+        match rx_main.recv() {
+            Ok(PipelineInfo::SeqDat(_, x)) => f(console, x),
+            Ok(PipelineInfo::MsgInfo(_id, s)) => console.write_diagnostic(ConsoleTextKind::Info, &format!("{}\n", s)),
+            Ok(PipelineInfo::MsgErr(_id, s)) => console.write_diagnostic(ConsoleTextKind::Error, &format!("{}\n", s)),
+            Ok(PipelineInfo::SeqEnd(_)) => break,
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+}
+
+fn list_files(opt: &Opt, base_paths: Vec<PathBuf>, console: &mut Console) -> usize {
+    let mut found = 0;
+    find_files(opt, base_paths, console, |console, x| {
+        console.write(ConsoleTextKind::Filename, &format!("{}\n", x.path.to_string_lossy()));
+        found += 1;
+    });
+    found
+}
+
+fn search_paths(opt: &Opt, base_paths: Vec<PathBuf>, keyword: &[u8], console: &mut Console) -> usize {
+    let matcher = line_matcher(opt);
+
+    let mut found = 0;
+    find_files(opt, base_paths, console, |console, x| {
+        let path = x.path.to_string_lossy();
+        let segments = split_path(&*matcher, path.as_bytes(), keyword);
+        if segments.is_empty() {
+            return;
+        }
+        found += 1;
+
+        for (i, x) in segments.iter().enumerate() {
+            let kind = if i % 2 == 0 {
+                ConsoleTextKind::Filename
+            } else {
+                ConsoleTextKind::MatchText
+            };
+            console.write(kind, x);
+        }
+        console.write(ConsoleTextKind::Filename, "\n");
+    });
+    found
+}
+
+// `path` split by the matches, where the odd segments are the matches ( empty if not matched ).
+// The matches may begin or end in the middle of a character by --regex, so each segment is decoded separately.
+fn split_path(matcher: &dyn Matcher, path: &[u8], keyword: &[u8]) -> Vec<String> {
+    let matches = matcher.search(path, keyword);
+    if matches.is_empty() {
+        return Vec::new();
+    }
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    for m in &matches {
+        ret.push(String::from_utf8_lossy(&path[pos..m.beg]).into_owned());
+        ret.push(String::from_utf8_lossy(&path[m.beg..m.end]).into_owned());
+        pos = m.end;
+    }
+    ret.push(String::from_utf8_lossy(&path[pos..]).into_owned());
+    ret
+}
+
+// Labels of the roots like `frontend:web/` are removed from the paths, and returned with the roots
+fn split_roots(args: &[String]) -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+    let mut paths = Vec::new();
+    let mut labels = Vec::new();
+    for x in args {
+        match split_root_label(x) {
+            Some((label, path)) => {
+                paths.push(PathBuf::from(path));
+                labels.push((PathBuf::from(path), String::from(label)));
+            }
+            None => paths.push(PathBuf::from(x)),
+        }
+    }
+    (paths, labels)
+}
+
+// Matcher of --follow-file and --compare searching the whole contents at once
+fn line_matcher(opt: &Opt) -> Box<dyn Matcher> {
+    if opt.regex {
+        let mut m = RegexMatcher::new();
+        m.case_insensitive = opt.ignore_case;
+        Box::new(m)
+    } else if opt.ignore_case {
+        Box::new(CaseFoldMatcher::new(Box::new(MemmemMatcher::new())))
+    } else {
+        Box::new(MemmemMatcher::new())
+    }
+}
+
+// Appended lines are searched as soon as completed, and the lines of the matches are printed like the usual output
+fn follow_file(opt: &Opt, path: &Path, keyword: &[u8], console: &mut Console) -> ! {
+    let matcher = line_matcher(opt);
+
+    let mut follower = match FileFollower::open(path) {
+        Ok(x) => x,
+        Err(e) => {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), path),
+            );
+            exit(EXIT_ERROR, console);
+        }
+    };
+    console.path_prefix_map = opt.path_prefix_map.clone();
+    let sep = opt.field_separator.clone();
+    loop {
+        let (first, src) = match follower.poll() {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                thread::sleep(FOLLOW_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), path),
+                );
+                exit(EXIT_ERROR, console);
+            }
+        };
+
+        let matches = matcher.search(&src, keyword);
+        let mut line = first;
+        let mut pos = 0;
+        let mut i = 0;
+        while i < matches.len() {
+            let m = &matches[i];
+            line += memchr::memchr_iter(0x0a, &src[pos..m.beg]).count();
+            pos = m.beg;
+            let end = src[m.beg..]
+                .iter()
+                .position(|&x| x == 0x0a)
+                .map_or(src.len(), |x| m.beg + x);
+            let num = matches[i..].iter().take_while(|x| x.beg < end).count();
+
+            if opt.file {
+                console.write_filename(path, line, 1);
+                console.write(ConsoleTextKind::Filename, &sep);
+            }
+            if opt.column {
+                console.write(ConsoleTextKind::Other, &format!("{}{}", line, sep));
+            }
+            console.write_matches_line(&src, &matches[i..i + num]);
+            i += num;
+        }
+        console.flush();
+    }
+}
+
+// Line matched in a tree of --compare, where `matches` are the offsets in `text`
+struct ComparedLine {
+    path: PathBuf,
+    line: usize,
+    text: Vec<u8>,
+    matches: Vec<Match>,
+}
+
+// Matched lines of the text files under `root` keyed by the relative path and the line content
+fn collect_lines(
+    opt: &Opt,
+    root: &Path,
+    matcher: &dyn Matcher,
+    keyword: &[u8],
+    console: &mut Console,
+) -> BTreeMap<(PathBuf, Vec<u8>), Vec<ComparedLine>> {
+    let mut ret: BTreeMap<_, Vec<ComparedLine>> = BTreeMap::new();
+    find_files(opt, vec![root.to_path_buf()], console, |console, x| {
+        let src = match fs::read(&x.path) {
+            Ok(x) => x,
+            Err(e) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), x.path),
+                );
+                return;
+            }
+        };
+        // Binary files are skipped like the usual search
+        if memchr::memchr(0, &src[..src.len().min(COMPARE_BINARY_CHECK_BYTES)]).is_some() {
+            return;
+        }
+        let path = x.path.strip_prefix(root).unwrap_or(&x.path).to_path_buf();
+        let matches = matcher.search(&src, keyword);
+
+        let mut line = 1;
+        let mut pos = 0;
+        let mut i = 0;
+        while i < matches.len() {
+            let beg = matches[i].beg;
+            line += memchr::memchr_iter(0x0a, &src[pos..beg]).count();
+            pos = beg;
+            let line_beg = memchr::memrchr(0x0a, &src[..beg]).map_or(0, |x| x + 1);
+            let mut line_end = memchr::memchr(0x0a, &src[beg..]).map_or(src.len(), |x| beg + x);
+            let num = matches[i..].iter().take_while(|x| x.beg < line_end).count();
+            if line_end > line_beg && src[line_end - 1] == 0x0d {
+                line_end -= 1;
+            }
+            let text = src[line_beg..line_end].to_vec();
+            let matches = matches[i..i + num]
+                .iter()
+                .map(|m| Match::new(m.beg - line_beg, m.end.min(line_end).max(m.beg) - line_beg))
+                .collect();
+            ret.entry((path.clone(), text.clone())).or_default().push(ComparedLine {
+                path: path.clone(),
+                line,
+                text,
+                matches,
+            });
+            i += num;
+        }
+    });
+    ret
+}
+
+// Lines of the same path and content in both trees are paired in order, and the rest are printed with `-` if only in
+// the first tree, and `+` if only in the second tree
+fn compare_trees(opt: &Opt, trees: &[PathBuf], keyword: &[u8], console: &mut Console) -> ! {
+    let matcher = line_matcher(opt);
+    let a = collect_lines(opt, &trees[0], &*matcher, keyword, console);
+    let b = collect_lines(opt, &trees[1], &*matcher, keyword, console);
+
+    let mut diffs: Vec<(usize, &ComparedLine)> = Vec::new();
+    for (key, xs) in &a {
+        let paired = b.get(key).map_or(0, |x| x.len());
+        diffs.extend(xs.iter().skip(paired).map(|x| (0, x)));
+    }
+    for (key, ys) in &b {
+        let paired = a.get(key).map_or(0, |x| x.len());
+        diffs.extend(ys.iter().skip(paired).map(|x| (1, x)));
+    }
+    diffs.sort_by(|x, y| (&x.1.path, x.1.line, x.0).cmp(&(&y.1.path, y.1.line, y.0)));
+
+    let sep = opt.field_separator.clone();
+    let mut only = [0, 0];
+    for (side, x) in &diffs {
+        only[*side] += 1;
+        let mark = if *side == 0 { "- " } else { "+ " };
+        console.write(ConsoleTextKind::Other, mark);
+        if opt.file {
+            console.write_filename(&trees[*side].join(&x.path), x.line, 1);
+            console.write(ConsoleTextKind::Filename, &sep);
+        }
+        if opt.column {
+            console.write(ConsoleTextKind::Other, &format!("{}{}", x.line, sep));
+        }
+        if x.matches.is_empty() {
+            console.write(
+                ConsoleTextKind::Text,
+                &format!("{}\n", String::from_utf8_lossy(&x.text)),
+            );
+        } else {
+            console.write_matches_line(&x.text, &x.matches);
+        }
+    }
+
+    console.flush();
+    if !opt.quiet {
+        console.write(
+            ConsoleTextKind::Info,
+            &format!(
+                "\nCompared: {} lines only in {:?}, {} lines only in {:?}\n",
+                only[0], trees[0], only[1], trees[1]
+            ),
+        );
+    }
+    let code = check_status(opt, diffs.len(), console);
+    exit(code, console);
+}
+
+// Files are read at first not to measure the I/O, and literal matchers are skipped with --regex
+fn bench_matchers(opt: &Opt, base_paths: Vec<PathBuf>, keyword: &[u8], console: &mut Console) -> i32 {
+    let mut srcs = Vec::new();
+    find_files(opt, base_paths, console, |console, x| match fs::read(&x.path) {
+        Ok(src) => srcs.push(src),
+        Err(e) => console.write(
+            ConsoleTextKind::Error,
+            &format!("Error: {} @ {:?}\n", decode_error(e.kind()), x.path),
+        ),
+    });
+    let bytes: usize = srcs.iter().map(|x| x.len()).sum();
+
+    let mut matchers: Vec<(&str, Box<dyn Matcher>, Vec<u8>)> = Vec::new();
+    if !opt.regex {
+        matchers.push(("quick", Box::new(QuickSearchMatcher::new()), keyword.to_vec()));
+        matchers.push(("tbm", Box::new(TbmMatcher::new()), keyword.to_vec()));
+        matchers.push(("memmem", Box::new(MemmemMatcher::new()), keyword.to_vec()));
+    }
+    let regex_keyword = if opt.regex {
+        keyword.to_vec()
+    } else {
+        regex::escape(&String::from_utf8_lossy(keyword)).into_bytes()
+    };
+    matchers.push(("regex", Box::new(RegexMatcher::new()), regex_keyword));
+
+    console.write(
+        ConsoleTextKind::Info,
+        &format!(
+            "{} files, {} bytes\n{:<8} {:>10} {:>10} {:>10}\n",
+            srcs.len(),
+            bytes,
+            "matcher",
+            "matches",
+            "seconds",
+            "MB/s"
+        ),
+    );
+    for (name, matcher, keyword) in &matchers {
+        let beg = Instant::now();
+        let found: usize = srcs.iter().map(|x| matcher.search(x, keyword).len()).sum();
+        let secs = as_secsf64(beg.elapsed());
+        console.write(
+            ConsoleTextKind::Other,
+            &format!(
+                "{:<8} {:>10} {:>10.3} {:>10.1}\n",
+                name,
+                found,
+                secs,
+                bytes as f64 / 1e6 / secs
+            ),
+        );
+    }
+    exit_status(!srcs.is_empty(), console.has_error())
+}
+
+fn build_index(opt: &Opt, base_paths: Vec<PathBuf>, console: &mut Console) {
+    let old = Index::load(Path::new(INDEX_DIR)).unwrap_or_default();
+    let mut index = Index::new();
+
+    find_files(opt, base_paths, console, |console, x| {
+        if let Err(e) = index.add(&x.path, &old) {
+            console.write_diagnostic(
+                ConsoleTextKind::Error,
+                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), x.path),
+            );
+        }
+    });
+
+    match index.save(Path::new(INDEX_DIR)) {
+        Ok(_) => console.write(
+            ConsoleTextKind::Info,
+            &format!("Indexed {} files @ {:?}\n", index.entries.len(), INDEX_DIR),
+        ),
+        Err(e) => {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), INDEX_DIR),
+            );
+            exit(EXIT_ERROR, console);
+        }
+    }
+}
+
+// Entries are numbered from the oldest like shells, and run by the binary next to this one if it exists
+fn run_history(index: Option<usize>, console: &mut Console) -> i32 {
+    let entries = match History::new() {
+        Some(x) => x.load(),
+        None => {
+            console.write(
+                ConsoleTextKind::Error,
+                "Error: history is not recorded ( set AMBER_HISTORY to record it )\n",
+            );
+            return EXIT_ERROR;
+        }
+    };
+    let quote = |x: &String| {
+        if x.is_empty() || x.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+            format!("{:?}", x)
+        } else {
+            x.clone()
+        }
+    };
+
+    let index = match index {
+        Some(x) => x,
+        None => {
+            for (i, x) in entries.iter().enumerate() {
+                let args: Vec<_> = x.args.iter().map(quote).collect();
+                console.write(
+                    ConsoleTextKind::Other,
+                    &format!("{:>5}  {} {}\n", i + 1, x.command, args.join(" ")),
+                );
+            }
+            return exit_status(!entries.is_empty(), false);
+        }
+    };
+
+    let entry = match index.checked_sub(1).and_then(|x| entries.get(x)) {
+        Some(x) => x,
+        None => {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!("Error: history {} is not found\n", index),
+            );
+            return EXIT_ERROR;
+        }
+    };
+    let program = env::current_exe()
+        .ok()
+        .map(|x| {
+            x.with_file_name(&entry.command)
+                .with_extension(env::consts::EXE_EXTENSION)
+        })
+        .filter(|x| x.exists())
+        .unwrap_or_else(|| PathBuf::from(&entry.command));
+    match Command::new(&program).args(&entry.args).status() {
+        Ok(x) => x.code().unwrap_or(EXIT_ERROR),
+        Err(e) => {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), program),
+            );
+            EXIT_ERROR
+        }
+    }
+}
+
+// Exit code when interrupted by Ctrl-C ( 128 + SIGINT like shells )
+const EXIT_INTERRUPTED: i32 = 130;
+
+// Interval between records of --progress
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+// Files with NUL in the head are binary files skipped by --compare
+const COMPARE_BINARY_CHECK_BYTES: usize = 8 * 1024;
+
 lazy_static! {
     static ref MAX_THREADS: String = format!("{}", num_cpus::get());
     static ref DEFAULT_FLAGS: DefaultFlags = DefaultFlags::load();
@@ -293,6 +1309,57 @@ lazy_static! {
 // Main
 // ---------------------------------------------------------------------------------------------------------------------
 
+// Captures of each match are extracted by the regexes of the keywords again, which are indexed like `Match::keyword`
+fn build_capture_regexes(opt: &Opt, keywords: &[&[u8]], console: &mut Console) -> Vec<Regex> {
+    let count_by = match opt.count_matches_by {
+        Some(CountBy::Capture(ref x)) => Some(x),
+        _ => None,
+    };
+    if count_by.is_some() && !opt.regex {
+        console.write(
+            ConsoleTextKind::Error,
+            "Error: a capture group can't be counted without --regex @ --count-matches-by\n",
+        );
+        exit(EXIT_ERROR, console);
+    }
+    let json = matches!(opt.format, OutputFormat::Json | OutputFormat::Sarif) && opt.format_template.is_none();
+    if !opt.regex || (count_by.is_none() && !json) {
+        return Vec::new();
+    }
+    let mut regexes = Vec::new();
+    for keyword in keywords {
+        let keyword = String::from_utf8_lossy(keyword);
+        match RegexBuilder::new(&keyword)
+            .multi_line(true)
+            .case_insensitive(opt.ignore_case)
+            .build()
+        {
+            Ok(x) => regexes.push(x),
+            Err(e) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ {:?}\n", e, keyword));
+                exit(EXIT_ERROR, console);
+            }
+        }
+    }
+    if let Some(capture) = count_by {
+        let found = regexes.iter().any(|regex| match capture.parse::<usize>() {
+            Ok(i) => i < regex.captures_len(),
+            Err(_) => regex.capture_names().any(|x| x == Some(capture.as_str())),
+        });
+        if !found {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!(
+                    "Error: no capture group {} in the keyword @ --count-matches-by\n",
+                    capture
+                ),
+            );
+            exit(EXIT_ERROR, console);
+        }
+    }
+    regexes
+}
+
 #[allow(dead_code)]
 fn main() {
     // ---------------------------------------------------------------------------------------------
@@ -301,137 +1368,640 @@ fn main() {
 
     // - Create opt ------------------------------------------------------------
 
-    let opt = Opt::from_args();
+    let profile = match find_arg("--config-profile") {
+        Some(name) => match DEFAULT_FLAGS.profile.get(&name) {
+            Some(x) => split_args(&x.options),
+            None => {
+                let mut console = Console::new();
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: profile {:?} is not found in ~/.ambs.toml\n", name),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        None => Vec::new(),
+    };
+    let args = args_with_env("AMBS_OPTIONS", profile);
+    let opt = match Opt::from_iter_safe(args.clone()) {
+        Ok(x) => x,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            std::process::exit(EXIT_ERROR);
+        }
+        Err(e) => e.exit(),
+    };
     let opt = DEFAULT_FLAGS.merge(opt);
 
+    // Threads spawned after inherit the priority
+    if opt.nice {
+        lower_priority();
+    }
+
+    // - History ---------------------------------------------------------------
+    if let Some(index) = opt.history {
+        let mut console = Console::new();
+        let code = run_history(index, &mut console);
+        exit(code, &mut console);
+    }
+
+    // - Daemon / client -------------------------------------------------------
+    if let Some(ref socket) = opt.daemon {
+        let index = Index::load(Path::new(INDEX_DIR)).ok().map(Arc::new);
+        let dir_cache = Arc::new(DirCache::new());
+        warm_dir_cache(&dir_cache);
+        let ret = daemon::serve(
+            Path::new(socket),
+            |args| match Opt::from_iter_safe(args) {
+                Ok(opt) => run(DEFAULT_FLAGS.merge(opt), index.clone(), Some(dir_cache.clone())),
+                Err(e) => e.exit(),
+            },
+            || warm_dir_cache(&dir_cache),
+        );
+        if let Err(e) = ret {
+            let mut console = Console::new();
+            console.write(ConsoleTextKind::Error, &format!("Error: {} @ {:?}\n", e, socket));
+            exit(EXIT_ERROR, &mut console);
+        }
+        return;
+    }
+
+    if opt.rpc {
+        let mut server = RpcServer::new(io::stdout());
+        server.max_threads = opt.max_threads;
+        server.size_per_thread = opt.size_per_thread;
+        let stdin = io::stdin();
+        server.serve(stdin.lock());
+        return;
+    }
+
+    if let Some(ref socket) = opt.connect {
+        let args = client_args(args.iter().map(|x| x.to_string_lossy().into_owned()).collect());
+        // The exit status of the search in the daemon is forwarded as is, and the output is already reset by it
+        match daemon::request(Path::new(socket), &args) {
+            Ok(code) => process::exit(code),
+            Err(e) => {
+                let mut console = Console::new();
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ {:?}\n", e, socket));
+                exit(EXIT_ERROR, &mut console);
+            }
+        }
+    }
+
+    // Failures to write the history don't stop the search
+    if let Some(history) = History::new() {
+        if opt.index.is_none() && !opt.files {
+            let _ = history.push(&HistoryEntry {
+                command: String::from("ambs"),
+                args: env::args_os()
+                    .skip(1)
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .collect(),
+                keyword: opt.keyword.clone(),
+                replacement: None,
+            });
+        }
+    }
+
+    run(opt, None, None);
+}
+
+fn client_args(args: Vec<String>) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut skip = false;
+    for a in args {
+        if skip {
+            skip = false;
+        } else if a == "--connect" {
+            skip = true;
+        } else if !a.starts_with("--connect=") {
+            ret.push(a);
+        }
+    }
+    ret
+}
+
+// The index can skip only the files not containing the trigrams of KEYWORD in UTF-8, so it's not used if the files
+// found may contain another keyword ( -e, --preset, --query ) or KEYWORD transcoded by --encoding-map
+fn use_index(opt: &Opt, any_of: &[Vec<u8>]) -> bool {
+    opt.use_index
+        && !opt.regex
+        && !opt.ignore_case
+        && opt.query.is_none()
+        && any_of.is_empty()
+        && opt.encoding_map.is_empty()
+}
+
+// Directories under the current directory are read into the cache by the default rules to ignore files, and the
+// listings not modified are reused as is
+fn warm_dir_cache(dir_cache: &Arc<DirCache>) {
+    let (tx_in, rx_in) = unbounded();
+    let (tx_out, rx_out) = unbounded::<PipelineInfo<PathInfo>>();
+    drop(rx_out);
+    let _ = tx_in.send(PipelineInfo::SeqBeg(0));
+    let _ = tx_in.send(PipelineInfo::SeqDat(0, PathBuf::from("./")));
+    let _ = tx_in.send(PipelineInfo::SeqEnd(1));
+
+    let mut finder = PipelineFinder::new();
+    finder.dir_cache = Some(dir_cache.clone());
+    finder.setup(ID_FINDER, rx_in, vec![tx_out]);
+    dir_cache.prune();
+}
+
+fn run(opt: Opt, cached_index: Option<Arc<Index>>, cached_dirs: Option<Arc<DirCache>>) {
     let mut console = Console::new();
-    console.is_color = opt.color;
+    console.is_color =
+        opt.color && opt.format == OutputFormat::Default && opt.format_template.is_none() && !opt.grep_compat;
+    console.error_format = opt.errors;
+    console.no_messages = opt.no_messages;
+
+    // - Build index / list files ---------------------------------------------
+    if opt.index.is_some() || opt.files {
+        let args: Vec<String> = opt.keyword.iter().chain(opt.paths.iter()).cloned().collect();
+        let (mut base_paths, _) = split_roots(&args);
+        if base_paths.is_empty() {
+            base_paths.push(PathBuf::from("./"));
+        }
+        let found = if opt.files {
+            list_files(&opt, base_paths, &mut console) != 0
+        } else {
+            build_index(&opt, base_paths, &mut console);
+            true
+        };
+        let code = exit_status(found, console.has_error());
+        exit(code, &mut console);
+    }
+
+    // - Benchmark matchers ----------------------------------------------------
+    if opt.bench {
+        let mut base_paths: Vec<PathBuf> = opt.paths.iter().map(PathBuf::from).collect();
+        if base_paths.is_empty() {
+            base_paths.push(PathBuf::from("./"));
+        }
+        let keyword = opt.keyword.clone().unwrap().into_bytes();
+        let code = bench_matchers(&opt, base_paths, &keyword, &mut console);
+        exit(code, &mut console);
+    }
 
     // - Set base path, keyword and replacement --------------------------------
-    let mut base_paths: Vec<PathBuf> = Vec::new();
-    if opt.paths.is_empty() {
-        base_paths.push(PathBuf::from("./"));
+    let (query, opt_all_of) = match opt.query {
+        Some(ref x) => match Query::parse(x) {
+            Ok((query, keywords)) => (Some(query), keywords),
+            Err(e) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ query {:?}\n", e, x));
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        None => (None, opt.all_of.clone()),
+    };
+
+    let preset = match opt.preset_file {
+        Some(ref path) => match Preset::from_file(path) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ {:?}\n", e, path));
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        None => opt.preset.clone(),
+    };
+
+    // KEYWORD is a path if keywords are given by --all-of, --query, --preset or --pattern
+    let (opt_keyword, opt_paths) = if let Some(ref preset) = preset {
+        let paths = opt.keyword.iter().chain(opt.paths.iter()).cloned().collect();
+        (preset.rules[0].pattern.clone(), paths)
+    } else if !opt.patterns.is_empty() {
+        let paths = opt.keyword.iter().chain(opt.paths.iter()).cloned().collect();
+        (opt.patterns[0].clone(), paths)
+    } else if opt.all_of.is_empty() && query.is_none() {
+        (opt.keyword.clone().unwrap(), opt.paths.clone())
     } else {
-        for p in &opt.paths {
-            base_paths.push(PathBuf::from(p));
-        }
+        let paths = opt.keyword.iter().chain(opt.paths.iter()).cloned().collect();
+        (opt_all_of[0].clone(), paths)
+    };
+    let all_of: Vec<Vec<u8>> = opt_all_of.iter().skip(1).map(|x| x.clone().into_bytes()).collect();
+    let any_of: Vec<Vec<u8>> = match preset {
+        Some(ref x) => x.rules.iter().skip(1).map(|x| x.pattern.clone().into_bytes()).collect(),
+        None => opt.patterns.iter().skip(1).map(|x| x.clone().into_bytes()).collect(),
+    };
+
+    let (mut base_paths, root_labels) = split_roots(&opt_paths);
+    if base_paths.is_empty() {
+        base_paths.push(PathBuf::from("./"));
     }
 
-    let keyword = if opt.key_from_file {
-        match read_from_file(&opt.keyword) {
+    let keyword = if opt.key_from_file && opt_all_of.is_empty() {
+        match read_from_file(&opt_keyword) {
             Ok(x) => {
                 if x.len() != 0 {
                     x
                 } else {
                     console.write(
                         ConsoleTextKind::Error,
-                        &format!("Error: file is empty @ {:?}\n", opt.keyword),
+                        &format!("Error: file is empty @ {:?}\n", opt_keyword),
                     );
-                    exit(1, &mut console);
+                    exit(EXIT_ERROR, &mut console);
                 }
             }
             Err(e) => {
                 console.write(
                     ConsoleTextKind::Error,
-                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), opt.keyword),
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), opt_keyword),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+        }
+    } else {
+        opt_keyword.into_bytes()
+    };
+
+    let keyword = if opt.hex {
+        match parse_hex(&String::from_utf8_lossy(&keyword)) {
+            Some(x) => x,
+            None => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: invalid hex bytes @ {:?}\n", String::from_utf8_lossy(&keyword)),
                 );
-                exit(1, &mut console);
+                exit(EXIT_ERROR, &mut console);
             }
         }
     } else {
-        opt.keyword.into_bytes()
+        keyword
+    };
+
+    // --all-of and --preset are exclusive, so either is indexed from 1
+    let keywords: Vec<&[u8]> = Some(&keyword[..])
+        .into_iter()
+        .chain(all_of.iter().chain(any_of.iter()).map(|x| &x[..]))
+        .collect();
+    let capture_regexes = build_capture_regexes(&opt, &keywords, &mut console);
+
+    // Legend of the colors of the keywords printed with the statistics, where rules are shown by the names
+    let legend: Vec<String> = match preset {
+        Some(ref x) => x.rules.iter().map(|x| x.name.clone()).collect(),
+        None => keywords
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect(),
     };
 
+    // - Follow file -----------------------------------------------------------
+    if let Some(ref path) = opt.follow_file {
+        follow_file(&opt, path, &keyword, &mut console);
+    }
+
+    // - Compare trees ---------------------------------------------------------
+    if !opt.compare.is_empty() {
+        compare_trees(&opt, &opt.compare, &keyword, &mut console);
+    }
+
+    // - Search paths ----------------------------------------------------------
+    if opt.path_search {
+        let found = search_paths(&opt, base_paths, &keyword, &mut console);
+        let code = check_status(&opt, found, &mut console);
+        exit(code, &mut console);
+    }
+
     // ---------------------------------------------------------------------------------------------
     // Pipeline Construct
     // ---------------------------------------------------------------------------------------------
 
-    let id_finder = 0;
-    let id_sorter = 1;
-    let id_printer = 2;
-    let id_matcher = 3;
+    let id_finder = ID_FINDER;
+    let id_sorter = ID_SORTER;
+    let id_printer = ID_OUTPUT;
+    let id_matcher = ID_MATCHER;
 
-    let matcher_num = cmp::min(8, opt.max_threads);
+    let matcher_num = cmp::max(1, opt.matcher_threads.unwrap_or_else(|| cmp::min(8, opt.max_threads)));
 
-    let (tx_finder, rx_finder) = unbounded();
-    let (tx_printer, rx_printer) = unbounded();
     let (tx_main, rx_main) = unbounded();
-
-    let mut tx_matcher = Vec::new();
-    let mut rx_sorter = Vec::new();
+    let cancel = CancelToken::new();
 
     let mut finder = PipelineFinder::new();
-    let mut sorter = PipelineSorter::new(matcher_num);
+    let encoding_map = if opt.encoding_map.is_empty() {
+        None
+    } else {
+        Some(EncodingMap::new(opt.encoding_map.clone()))
+    };
+
     let mut printer = PipelinePrinter::new();
 
+    // Batches and the sorter hold the matches already found until the files before them are matched
+    let batch_size = if opt.line_buffered { 1 } else { opt.batch_size };
+
     finder.is_recursive = opt.recursive;
+    finder.batch_size = batch_size;
     finder.follow_symlink = opt.symlink;
+    finder.one_file_system = opt.one_file_system;
+    finder.dedupe = !opt.no_dedupe;
+    finder.max_symlink_depth = opt.max_symlink_depth;
+    finder.print_broken_symlinks = opt.broken_symlinks;
+    finder.special_files = opt.special_files;
+    finder.owner = opt.owner.clone();
+    finder.perm = opt.perm;
+    finder.cancel = cancel.clone();
+    let discovered = finder.discovered.clone();
+    let broken_symlinks = finder.broken_symlinks.clone();
     finder.skip_vcs = opt.skip_vcs;
     finder.skip_gitignore = opt.skip_gitignore;
-    finder.print_skipped = opt.skipped;
+    finder.skip_ambignore = opt.skip_amberignore;
+    finder.print_skipped = opt.skipped || opt.report_skipped;
     finder.find_parent_ignore = opt.parent_ignore;
-    sorter.through = !opt.fixed_order;
-    printer.is_color = opt.color;
+    finder.vcs_filter = opt.vcs;
+    finder.rev = opt.rev.clone();
+    finder.trace = Tracer::new(TraceStage::Finder, opt.verbose, opt.trace.as_ref());
+    finder.dir_cache = cached_dirs;
+    if use_index(&opt, &any_of) {
+        let index = match cached_index {
+            Some(x) => Ok(x),
+            None => Index::load(Path::new(INDEX_DIR)).map(Arc::new),
+        };
+        match index {
+            Ok(x) => {
+                finder.index = Some(x);
+                finder.index_trigrams = Index::trigrams(&keyword);
+            }
+            Err(e) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), INDEX_DIR),
+                );
+            }
+        }
+    }
+    printer.is_color = opt.color && opt.output.is_none();
+    printer.display_encoding = opt.display_encoding.clone();
+    printer.encoding_map = encoding_map.clone();
+    if let Some(ref path) = opt.output {
+        match fs::File::create(path) {
+            Ok(x) => printer.output = Some(x),
+            Err(e) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), path),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+        }
+    }
+    // Matches are written to a temporary file to be passed to the picker at the end
+    let mut pick_file = None;
+    if opt.pick {
+        match tempfile::tempfile().and_then(|x| Ok((x.try_clone()?, x))) {
+            Ok((x, y)) => {
+                printer.output = Some(x);
+                pick_file = Some(y);
+            }
+            Err(e) => {
+                console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ --pick\n", decode_error(e.kind())),
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+        }
+        printer.is_color = false;
+    }
     printer.print_file = opt.file;
     printer.print_column = opt.column;
     printer.print_row = opt.row;
+    printer.column_unit = opt.column_unit;
+    printer.line_buffered = opt.line_buffered;
+    printer.max_columns = opt.max_columns;
+    printer.max_columns_preview = opt.max_columns_preview;
+    printer.max_line_bytes = opt.max_line_bytes;
+    printer.max_results = opt.max_results;
+    printer.quiet = opt.quiet;
+    printer.count_by = opt.count_matches_by.clone();
+    printer.sort_matches = opt.sort_matches;
+    printer.capture_regexes = capture_regexes;
+    printer.cancel = cancel.clone();
+    printer.trace = Tracer::new(TraceStage::Printer, opt.verbose, opt.trace.as_ref());
+    printer.tab_width = opt.tab_width;
+    printer.format = opt.format;
+    if opt.format_template.is_some() {
+        printer.format = OutputFormat::Default;
+        printer.template = opt.format_template.clone();
+    }
+    if opt.pick {
+        printer.format = OutputFormat::Default;
+        printer.template = PICK_TEMPLATE.parse().ok();
+    }
+    printer.field_separator = opt.field_separator.clone();
+    if opt.hyperlink_format != "none" && Console::supports_hyperlink() {
+        printer.hyperlink_format = Some(opt.hyperlink_format.clone());
+    }
+    printer.path_prefix_map = opt.path_prefix_map.clone();
+    printer.root_labels = root_labels.clone();
+    printer.group_separator = opt.group_separator.clone();
+    printer.heading = opt.heading;
+    printer.max_lines_per_file = opt.max_lines_per_file;
+    printer.hex = opt.hex;
+    printer.passthru = opt.passthru;
+    printer.binary_hex = opt.binary_files == Some(BinaryFiles::Hex);
+    printer.group_line = !all_of.is_empty() || query.is_some() || opt.patterns.len() > 1;
+    printer.rules = preset.map(|x| x.rules).unwrap_or_default();
+    printer.blame = opt.blame;
+    if opt.grep_compat {
+        printer.format = OutputFormat::Grep;
+        // grep prints paths without "./" when no path is given, and without path when the only path is a file
+        if opt_paths.is_empty() {
+            printer.strip_prefix = Some(PathBuf::from("./"));
+        }
+        printer.print_file = !(opt_paths.len() == 1 && Path::new(&opt_paths[0]).is_file());
+    }
+    if opt.format == OutputFormat::Emacs && opt_paths.is_empty() {
+        printer.strip_prefix = Some(PathBuf::from("./"));
+    }
+    printer.null = opt.null;
+
+    let syntax = match opt.syntax {
+        Some(lang) => {
+            let exclude = !opt.exclude_node_type.is_empty();
+            let kinds = if exclude {
+                &opt.exclude_node_type
+            } else {
+                &opt.node_type
+            };
+            if !SyntaxFilter::is_enabled() || kinds.is_empty() {
+                console.write(
+                    ConsoleTextKind::Error,
+                    "Error: --syntax requires the `syntax` feature and --node-type or --exclude-node-type\n",
+                );
+                exit(EXIT_ERROR, &mut console);
+            }
+            Some(SyntaxFilter {
+                lang,
+                kinds: kinds.clone(),
+                exclude,
+            })
+        }
+        None => None,
+    };
+
+    let within = match opt.within.as_slice() {
+        [begin, end] => match Within::new(begin, end) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ --within\n", e));
+                exit(EXIT_ERROR, &mut console);
+            }
+        },
+        _ => None,
+    };
 
-    let use_regex = opt.regex;
-    let use_tbm = opt.tbm;
-    let skip_binary = !opt.binary;
-    let print_skipped = opt.skipped;
+    if opt.regex && opt.matcher != MatcherKind::Auto && opt.matcher != MatcherKind::Regex {
+        console.write(
+            ConsoleTextKind::Error,
+            &format!(
+                "Error: --matcher {} can't search a regex @ --regex\n",
+                opt.matcher.name()
+            ),
+        );
+        exit(EXIT_ERROR, &mut console);
+    }
+    if opt.hex && opt.matcher == MatcherKind::Regex {
+        console.write(
+            ConsoleTextKind::Error,
+            &format!("Error: --matcher regex can't search hex bytes @ --hex\n"),
+        );
+        exit(EXIT_ERROR, &mut console);
+    }
+    let matcher_kind = match opt.matcher {
+        MatcherKind::Auto if opt.regex && !opt.hex => MatcherKind::Regex,
+        MatcherKind::Auto if opt.tbm && !opt.hex => MatcherKind::Tbm,
+        MatcherKind::Auto => MatcherKind::Memmem,
+        x => x,
+    };
+    let matcher_trace = Tracer::new(TraceStage::Matcher, opt.verbose, opt.trace.as_ref());
+    matcher_trace.log(1, || {
+        let how = if opt.matcher == MatcherKind::Auto {
+            "auto"
+        } else {
+            "--matcher"
+        };
+        let fold = if opt.ignore_case && matcher_kind != MatcherKind::Regex {
+            ", case folded"
+        } else {
+            ""
+        };
+        format!(
+            "Matcher: {} ( {}{}, {} threads )",
+            matcher_kind.name(),
+            how,
+            fold,
+            matcher_num
+        )
+    });
+    let hex = opt.hex;
+    let binary_files = match opt.binary_files {
+        Some(x) => x,
+        None if opt.hex => BinaryFiles::Text,
+        None if opt.binary => BinaryFiles::Binary,
+        None => BinaryFiles::WithoutMatch,
+    };
+    let print_skipped = opt.skipped || opt.report_skipped;
     let binary_check_bytes = opt.bin_check_bytes;
+    let io = opt.io;
     let mmap_bytes = opt.mmap_bytes;
+    let mmap_threshold = opt.mmap_threshold;
+    let memory_budget = if opt.max_memory != 0 {
+        Some(Arc::new(MemoryBudget::new(opt.max_memory)))
+    } else {
+        None
+    };
+    let io_limit = opt.io_limit.map(|x| Arc::new(IoLimiter::new(x)));
     let max_threads = opt.max_threads;
     let size_per_thread = opt.size_per_thread;
+    let ignore_case = opt.ignore_case;
+    let all_of_scope = opt.all_of_scope;
 
-    for i in 0..matcher_num {
-        let keyword = keyword.clone();
-        let (tx_in, rx_in) = unbounded();
-        let (tx_out, rx_out) = unbounded();
-        tx_matcher.push(tx_in);
-        rx_sorter.push(rx_out);
-
-        let _ = thread::Builder::new().name("matcher".to_string()).spawn(move || {
-            if use_regex {
-                let m = RegexMatcher::new();
-                let mut matcher = PipelineMatcher::new(m, &keyword);
-                matcher.skip_binary = skip_binary;
-                matcher.print_skipped = print_skipped;
-                matcher.binary_check_bytes = binary_check_bytes;
-                matcher.mmap_bytes = mmap_bytes;
-                matcher.setup(id_matcher + i, rx_in, tx_out);
-            } else if use_tbm {
+    // The version is a part of the key because the matches may be changed by updates
+    let cache = if opt.cache {
+        let key = format!("{}\n{:?}\n{:?}", env!("CARGO_PKG_VERSION"), keyword, opt);
+        Some(Arc::new(ResultCache::load(Path::new(CACHE_DIR), &key)))
+    } else {
+        None
+    };
+
+    let mut builder = PipelineBuilder::new(finder).through(!opt.fixed_order || opt.quiet || opt.line_buffered);
+    builder.queue_size = opt.queue_size;
+    builder.batch_size = batch_size;
+    builder.trace = Tracer::new(TraceStage::Sorter, opt.verbose, opt.trace.as_ref());
+    for _ in 0..matcher_num {
+        let m: Box<dyn Matcher + Send> = match matcher_kind {
+            MatcherKind::Regex => {
+                let mut m = RegexMatcher::new();
+                m.case_insensitive = ignore_case;
+                Box::new(m)
+            }
+            MatcherKind::Quick => {
+                let mut m = QuickSearchMatcher::new();
+                m.max_threads = max_threads;
+                m.size_per_thread = size_per_thread;
+                Box::new(m)
+            }
+            MatcherKind::Tbm => {
                 let mut m = TbmMatcher::new();
                 m.max_threads = max_threads;
                 m.size_per_thread = size_per_thread;
-                let mut matcher = PipelineMatcher::new(m, &keyword);
-                matcher.skip_binary = skip_binary;
-                matcher.print_skipped = print_skipped;
-                matcher.binary_check_bytes = binary_check_bytes;
-                matcher.mmap_bytes = mmap_bytes;
-                matcher.setup(id_matcher + i, rx_in, tx_out);
-            } else {
-                let mut m = QuickSearchMatcher::new();
+                Box::new(m)
+            }
+            _ => {
+                let mut m = MemmemMatcher::new();
+                m.char_boundary = !hex;
                 m.max_threads = max_threads;
                 m.size_per_thread = size_per_thread;
-                let mut matcher = PipelineMatcher::new(m, &keyword);
-                matcher.skip_binary = skip_binary;
-                matcher.print_skipped = print_skipped;
-                matcher.binary_check_bytes = binary_check_bytes;
-                matcher.mmap_bytes = mmap_bytes;
-                matcher.setup(id_matcher + i, rx_in, tx_out);
-            };
-        });
+                Box::new(m)
+            }
+        };
+        let m: Box<dyn Matcher + Send> = if ignore_case && matcher_kind != MatcherKind::Regex {
+            Box::new(CaseFoldMatcher::new(m))
+        } else {
+            m
+        };
+        let mut matcher = PipelineMatcher::new(m, &keyword);
+        matcher.binary_files = binary_files;
+        matcher.ignore_case = ignore_case;
+        matcher.print_skipped = print_skipped;
+        matcher.binary_check_bytes = binary_check_bytes;
+        matcher.io = io;
+        matcher.mmap_bytes = mmap_bytes;
+        matcher.mmap_threshold = mmap_threshold;
+        matcher.memory_budget = memory_budget.clone();
+        matcher.io_limit = io_limit.clone();
+        matcher.not = opt.not.clone().map(String::into_bytes);
+        matcher.lines = opt.lines;
+        matcher.columns = opt.columns;
+        matcher.within = within.clone();
+        matcher.encoding_map = encoding_map.clone();
+        matcher.syntax = syntax.clone();
+        matcher.token_kind = if opt.code_only {
+            Some(TokenKind::Code)
+        } else if opt.comments_only {
+            Some(TokenKind::Comment)
+        } else if opt.strings_only {
+            Some(TokenKind::String)
+        } else {
+            None
+        };
+        matcher.in_strings = opt.in_strings;
+        matcher.noisy_threshold = opt.skip_noisy;
+        matcher.io_retries = opt.io_retries;
+        matcher.batch_size = batch_size;
+        matcher.extract_documents = true;
+        matcher.decompress = true;
+        matcher.all_of = all_of.clone();
+        matcher.any_of = any_of.clone();
+        matcher.overlap = opt.overlap;
+        matcher.all_of_scope = all_of_scope;
+        matcher.query = query.clone();
+        matcher.cache = cache.clone();
+        matcher.cancel = cancel.clone();
+        matcher.trace = matcher_trace.clone();
+        builder = builder.matcher(matcher);
     }
-
-    let _ = thread::Builder::new().name("finder".to_string()).spawn(move || {
-        finder.setup(id_finder, rx_finder, tx_matcher);
-    });
-
-    let _ = thread::Builder::new().name("sorter".to_string()).spawn(move || {
-        sorter.setup(id_sorter, rx_sorter, tx_printer);
-    });
+    let (tx_finder, rx_printer) = builder.build();
 
     let _ = thread::Builder::new().name("printer".to_string()).spawn(move || {
         printer.setup(id_printer, rx_printer, tx_main);
@@ -441,6 +2011,17 @@ fn main() {
     // Pipeline Flow
     // ---------------------------------------------------------------------------------------------
 
+    // The first Ctrl-C stops finding and matching, and the matches already queued are printed with the statistics
+    {
+        let cancel = cancel.clone();
+        let _ = ctrlc::set_handler(move || {
+            if cancel.is_cancelled() {
+                exit(EXIT_INTERRUPTED, &mut Console::new());
+            }
+            cancel.cancel();
+        });
+    }
+
     let mut seq_no = 0;
     let _ = tx_finder.send(PipelineInfo::SeqBeg(seq_no));
     for p in base_paths {
@@ -463,9 +2044,56 @@ fn main() {
         time_matcher_all.push(Duration::new(0, 0));
     }
 
+    let mut profile = Profile::new();
+    profile.add_stage(id_finder, "find", 0);
+    for i in 0..matcher_num {
+        profile.add_stage(id_matcher + i, "match", i);
+    }
+    profile.add_stage(id_sorter, "sort", 0);
+    profile.add_stage(id_printer, "display", 0);
+
+    let mut match_num = 0;
+    let mut file_num = 0;
+    let mut searched_num = 0;
+    let mut dir_match_num = BTreeMap::new();
+    // Numbers of the matches, the files with matches and the files searched of each label in the order given
+    let mut root_nums: Vec<(String, usize, usize, usize)> = Vec::new();
+    for (_, label) in &root_labels {
+        if !root_nums.iter().any(|x| &x.0 == label) {
+            root_nums.push((label.clone(), 0, 0, 0));
+        }
+    }
+    let mut skipped = Vec::new();
+    let time_beg = Instant::now();
+    let mut time_progress = time_beg;
     loop {
+        if opt.progress.is_some() && time_progress.elapsed() >= PROGRESS_INTERVAL {
+            write_progress(discovered.get(), searched_num, file_num, time_beg.elapsed(), false);
+            time_progress = Instant::now();
+        }
         match rx_main.try_recv() {
             Ok(PipelineInfo::SeqEnd(_)) => break,
+            // The other stages are aborted by exit because the status is decided
+            Ok(PipelineInfo::SeqDat(_, (_, num))) if opt.quiet && num != 0 && !opt.check => exit(0, &mut console),
+            Ok(PipelineInfo::SeqDat(_, (path, num))) => {
+                searched_num += 1;
+                match_num += num;
+                if num != 0 {
+                    file_num += 1;
+                }
+                if let Some(label) = root_label(&root_labels, &path) {
+                    if let Some(x) = root_nums.iter_mut().find(|x| x.0 == label) {
+                        x.1 += num;
+                        x.2 += (num != 0) as usize;
+                        x.3 += 1;
+                    }
+                }
+                if let Some(depth) = opt.stats_by_dir {
+                    if num != 0 {
+                        *dir_match_num.entry(dir_of_depth(&path, depth)).or_insert(0) += num;
+                    }
+                }
+            }
             Ok(PipelineInfo::MsgTime(id, t0, t1)) if id == id_finder => {
                 time_finder_bsy = t0;
                 time_finder_all = t1;
@@ -482,17 +2110,77 @@ fn main() {
                 time_matcher_bsy[id - id_matcher] = t0;
                 time_matcher_all[id - id_matcher] = t1;
             }
-            Ok(PipelineInfo::MsgInfo(_id, s)) => console.write(ConsoleTextKind::Info, &format!("{}\n", s)),
-            Ok(PipelineInfo::MsgErr(_id, s)) => console.write(ConsoleTextKind::Error, &format!("{}\n", s)),
+            Ok(PipelineInfo::MsgCount(id, n, b)) => profile.set_count(id, n, b),
+            Ok(PipelineInfo::MsgInfo(_id, s)) => {
+                if opt.report_skipped {
+                    collect_skipped(&mut skipped, &s);
+                }
+                // Files skipped are found only for the report without --skipped
+                let hidden = opt.quiet || (!opt.skipped && s.starts_with("Skipped: "));
+                if !hidden {
+                    console.write_diagnostic(ConsoleTextKind::Info, &format!("{}\n", s));
+                }
+            }
+            Ok(PipelineInfo::MsgErr(_id, s)) => {
+                if opt.report_skipped {
+                    collect_skipped(&mut skipped, &s);
+                }
+                console.write_diagnostic(ConsoleTextKind::Error, &format!("{}\n", s));
+            }
             Ok(_) => (),
             Err(_) => (),
         }
     }
 
+    if opt.progress.is_some() {
+        write_progress(discovered.get(), searched_num, file_num, time_beg.elapsed(), true);
+    }
+
+    // The cache is not saved when cancelled because the files not searched would be dropped
+    if let Some(ref cache) = cache.filter(|_| !cancel.is_cancelled()) {
+        if let Err(e) = cache.save(Path::new(CACHE_DIR)) {
+            console.write(
+                ConsoleTextKind::Error,
+                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), CACHE_DIR),
+            );
+        }
+    }
+
+    if opt.quiet {
+        let code = check_status(&opt, match_num, &mut console);
+        exit(code, &mut console);
+    }
+
+    if let Some(file) = pick_file {
+        pick_match(&opt, file, &mut console);
+    }
+
     // ---------------------------------------------------------------------------------------------
     // Pipeline Flow
     // ---------------------------------------------------------------------------------------------
 
+    // Cancelled by --max-results is not interrupted
+    let interrupted = cancel.is_cancelled() && opt.max_results.map_or(true, |x| match_num < x);
+    if interrupted {
+        console.write(
+            ConsoleTextKind::Info,
+            &format!("\nInterrupted: {} matches found\n", match_num),
+        );
+    }
+
+    if opt.profile.is_some() {
+        profile.set_time(id_finder, time_finder_bsy, time_finder_all);
+        for i in 0..matcher_num {
+            profile.set_time(id_matcher + i, time_matcher_bsy[i], time_matcher_all[i]);
+        }
+        profile.set_time(id_sorter, time_sorter_bsy, time_sorter_all);
+        profile.set_time(id_printer, time_printer_bsy, time_printer_all);
+        if opt.report_skipped {
+            profile.skipped = Some(skipped.clone());
+        }
+        eprintln!("{}", profile.to_json());
+    }
+
     let sec_finder_bsy = as_secsf64(time_finder_bsy);
     let sec_finder_all = as_secsf64(time_finder_all);
     let sec_sorter_bsy = as_secsf64(time_sorter_bsy);
@@ -505,10 +2193,22 @@ fn main() {
 
     if opt.statistics {
         console.write(ConsoleTextKind::Info, &format!("\nStatistics\n"));
+        console.write(ConsoleTextKind::Info, &format!("  Max threads: {}\n", opt.max_threads));
+        console.write(
+            ConsoleTextKind::Info,
+            &format!("  Broken link: {}\n", broken_symlinks.get()),
+        );
         console.write(
             ConsoleTextKind::Info,
-            &format!("  Max threads: {}\n\n", opt.max_threads),
+            &format!("  Matcher    : {}\n\n", matcher_kind.name()),
         );
+        if legend.len() > 1 {
+            console.write(ConsoleTextKind::Info, "  Colors of the keywords\n");
+            for (i, x) in legend.iter().enumerate() {
+                console.write_keyword_info(i, &format!("    {}\n", x));
+            }
+            console.write(ConsoleTextKind::Info, "\n");
+        }
         console.write(ConsoleTextKind::Info, &format!("  Consumed time ( busy / total )\n"));
         console.write(
             ConsoleTextKind::Info,
@@ -533,5 +2233,231 @@ fn main() {
         );
     }
 
-    exit(0, &mut console);
+    if let Some(depth) = opt.stats_by_dir {
+        let mut dirs: Vec<_> = dir_match_num.into_iter().collect();
+        dirs.sort_by_key(|x| cmp::Reverse(x.1));
+        let width = dirs.iter().map(|(x, _)| x.to_string_lossy().len()).max().unwrap_or(0);
+        console.write(
+            ConsoleTextKind::Info,
+            &format!("\nMatches by directory ( depth {} )\n", depth),
+        );
+        for (dir, num) in dirs {
+            let dir = dir.to_string_lossy();
+            console.write(
+                ConsoleTextKind::Info,
+                &format!("  {:width$} : {}\n", dir, num, width = width),
+            );
+        }
+    }
+
+    if !root_nums.is_empty() {
+        let width = root_nums.iter().map(|x| x.0.len()).max().unwrap_or(0);
+        console.write(ConsoleTextKind::Info, "\nMatches by root\n");
+        for (label, matches, files, searched) in &root_nums {
+            console.write(
+                ConsoleTextKind::Info,
+                &format!(
+                    "  {:width$} : {} matches in {} files ( {} files searched )\n",
+                    label,
+                    matches,
+                    files,
+                    searched,
+                    width = width
+                ),
+            );
+        }
+    }
+
+    if opt.report_skipped {
+        let width = skipped
+            .iter()
+            .map(|x| x.path.as_deref().unwrap_or("").len())
+            .max()
+            .unwrap_or(0);
+        console.write(
+            ConsoleTextKind::Info,
+            &format!("\nSkipped files ( {} )\n", skipped.len()),
+        );
+        for d in &skipped {
+            let path = d.path.as_deref().unwrap_or("");
+            console.write(
+                ConsoleTextKind::Info,
+                &format!("  {:width$} : {}\n", path, d.kind, width = width),
+            );
+        }
+    }
+
+    if let Some(ref path) = opt.output {
+        console.write(
+            ConsoleTextKind::Info,
+            &format!(
+                "{} matches in {} files are written to {:?}\n",
+                match_num, file_num, path
+            ),
+        );
+    }
+
+    if interrupted {
+        exit(EXIT_INTERRUPTED, &mut console);
+    }
+
+    let code = check_status(&opt, match_num, &mut console);
+    exit(code, &mut console);
+}
+
+// The matches written by the printer are read back, and the status is decided by the line picked
+fn pick_match(opt: &Opt, mut file: fs::File, console: &mut Console) -> ! {
+    let mut src = String::new();
+    let read = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut src));
+    if let Err(e) = read {
+        console.write(
+            ConsoleTextKind::Error,
+            &format!("Error: {} @ --pick\n", decode_error(e.kind())),
+        );
+        exit(EXIT_ERROR, console);
+    }
+    let candidates = Candidate::parse_all(&src);
+    if candidates.is_empty() {
+        let code = check_status(opt, 0, console);
+        exit(code, console);
+    }
+    console.flush();
+    let picked = match picker::pick(&candidates) {
+        Ok(Some(x)) => &candidates[x],
+        Ok(None) => exit(EXIT_INTERRUPTED, console),
+        Err(e) => {
+            console.write(ConsoleTextKind::Error, &format!("Error: {} @ --pick\n", e));
+            exit(EXIT_ERROR, console);
+        }
+    };
+    match opt.pick_action {
+        PickAction::Print => {
+            console.write(
+                ConsoleTextKind::Other,
+                &format!("{}:{}\n", picked.path.to_string_lossy(), picked.line),
+            );
+        }
+        PickAction::Edit => {
+            if let Err(e) = picker::open_editor(picked) {
+                console.write(ConsoleTextKind::Error, &format!("Error: {} @ {:?}\n", e, picked.path));
+                exit(EXIT_ERROR, console);
+            }
+        }
+    }
+    exit(0, console);
+}
+
+// Files skipped or failed are collected from the messages of the stages, where `Noisy` files are searched but dropped
+fn collect_skipped(skipped: &mut Vec<Diagnostic>, msg: &str) {
+    for line in msg.lines() {
+        let d = Diagnostic::parse(line);
+        let label = ["Skipped: ", "Noisy: ", "Error: "]
+            .iter()
+            .any(|x| d.message.starts_with(x));
+        if label && d.path.is_some() {
+            skipped.push(d);
+        }
+    }
+}
+
+// Progress records are written periodically as JSON lines, where the last one is `done`
+fn write_progress(discovered: usize, searched: usize, matched: usize, elapsed: Duration, done: bool) {
+    let record = serde_json::json!({
+        "discovered": discovered,
+        "searched": searched,
+        "matched": matched,
+        "elapsed": as_secsf64(elapsed),
+        "done": done,
+    });
+    eprintln!("{}", record);
+}
+
+// Returns the exit status by the number of matches, which is inverted by --check to fail if any match is found
+fn check_status(opt: &Opt, found: usize, console: &mut Console) -> i32 {
+    let errored = console.has_error();
+    if !opt.check {
+        return exit_status(found != 0, errored);
+    }
+    if found != 0 {
+        console.write(
+            ConsoleTextKind::Error,
+            &format!("Check failed: {} matches found\n", found),
+        );
+    }
+    exit_status(found == 0, errored)
+}
+
+// Directory of `path` truncated to `depth` components ( "." and ".." are not counted )
+fn dir_of_depth(path: &Path, depth: usize) -> PathBuf {
+    let mut ret = PathBuf::new();
+    let mut num = 0;
+    if let Some(parent) = path.parent() {
+        for c in parent.components() {
+            if num >= depth {
+                break;
+            }
+            if let Component::Normal(_) = c {
+                num += 1;
+            }
+            ret.push(c);
+        }
+    }
+    if ret.as_os_str().is_empty() {
+        ret.push(".");
+    }
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ambs_all_of() {
+        let opt = Opt::from_iter_safe(&["ambs", "--all-of", "a", "b"]).unwrap();
+        assert_eq!(opt.keyword, None);
+        assert_eq!(opt.all_of, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn ambs_use_index() {
+        let opt = Opt::from_iter_safe(&["ambs", "--use-index", "alpha"]).unwrap();
+        assert!(use_index(&opt, &[]));
+
+        let opt = Opt::from_iter_safe(&["ambs", "--use-index", "-e", "alpha", "-e", "beta"]).unwrap();
+        assert!(!use_index(&opt, &[b"beta".to_vec()]));
+
+        let opt = Opt::from_iter_safe(&["ambs", "--use-index", "--encoding-map", "*.txt=shift_jis", "alpha"]).unwrap();
+        assert!(!use_index(&opt, &[]));
+    }
+
+    #[test]
+    fn ambs_path_search() {
+        let opt = Opt::from_iter_safe(&["ambs", "--path-search", "-i", "foo"]).unwrap();
+        let segments = split_path(&*line_matcher(&opt), b"d/Foo.txt", b"foo");
+        assert_eq!(segments, vec!["d/", "Foo", ".txt"]);
+
+        let opt = Opt::from_iter_safe(&["ambs", "--path-search", "--regex", r"(?-u)\xc3", "d"]).unwrap();
+        let segments = split_path(&*line_matcher(&opt), "d/café.txt".as_bytes(), br"(?-u)\xc3");
+        assert_eq!(segments, vec!["d/caf", "\u{fffd}", "\u{fffd}.txt"]);
+
+        assert!(split_path(&*line_matcher(&opt), b"d/cafe.txt", br"(?-u)\xc3").is_empty());
+    }
+
+    #[test]
+    fn ambs_patterns() {
+        let opt = Opt::from_iter_safe(&["ambs", "-e", "foo", "-e", "bar", "src"]).unwrap();
+        assert_eq!(opt.keyword, Some(String::from("src")));
+        assert_eq!(opt.patterns, vec!["foo", "bar"]);
+
+        let opt = Opt::from_iter_safe(&["ambs", "-e", "foo", "-e", "bar"]).unwrap();
+        assert_eq!(opt.keyword, None);
+        assert_eq!(opt.patterns, vec!["foo", "bar"]);
+    }
 }
@@ -0,0 +1,162 @@
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::Path;
+use std::process::Command;
+use time::{OffsetDateTime, UtcOffset};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Blame
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Commit introducing a line by `git blame`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Blame {
+    pub commit: String,
+    pub author: String,
+    /// Date of the author like `2024-01-31` in the time zone of the author
+    pub date: String,
+}
+
+impl Blame {
+    /// `abcdef12 (author 2024-01-31)` like the output of `git blame`
+    pub fn label(&self) -> String {
+        let commit: String = self.commit.chars().take(8).collect();
+        format!("{} ({} {})", commit, self.author, self.date)
+    }
+}
+
+// The whole file is blamed instead of passing too many ranges as arguments
+const MAX_BLAME_RANGES: usize = 1000;
+
+/// Returns the blames of `lines` ( 1-based ) of the file by `git blame`, which is run in the directory of the file
+pub fn blame_lines(path: &Path, lines: &[usize]) -> Result<HashMap<usize, Blame>, Error> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut lines = lines.to_vec();
+    lines.sort_unstable();
+    for x in lines {
+        match ranges.last_mut() {
+            Some(last) if x <= last.1 + 1 => last.1 = x,
+            _ => ranges.push((x, x)),
+        }
+    }
+
+    let dir = path.parent().filter(|x| !x.as_os_str().is_empty());
+    let name = path.file_name().unwrap_or(path.as_os_str());
+    let mut cmd = Command::new("git");
+    cmd.arg("blame").arg("--porcelain");
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    if ranges.len() <= MAX_BLAME_RANGES {
+        for (beg, end) in ranges {
+            cmd.arg("-L").arg(format!("{},{}", beg, end));
+        }
+    }
+    let output = cmd.arg("--").arg(name).output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        let message = message.lines().next().unwrap_or("git blame failed");
+        return Err(Error::other(message.trim_start_matches("fatal: ").to_string()));
+    }
+    Ok(parse_porcelain(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// The headers of each commit are given at the first line of the commit only
+fn parse_porcelain(src: &str) -> HashMap<usize, Blame> {
+    let mut commits: HashMap<&str, (&str, i64, &str)> = HashMap::new();
+    let mut lines: Vec<(usize, &str)> = Vec::new();
+    let mut current: Option<&str> = None;
+    for x in src.lines() {
+        if x.starts_with('\t') {
+            current = None;
+            continue;
+        }
+        let (key, value) = x.split_once(' ').unwrap_or((x, ""));
+        match current {
+            None => {
+                let line = value.split(' ').nth(1).and_then(|x| x.parse().ok());
+                if let Some(line) = line {
+                    lines.push((line, key));
+                    current = Some(key);
+                }
+            }
+            Some(commit) => {
+                let entry = commits.entry(commit).or_insert(("", 0, "+0000"));
+                match key {
+                    "author" => entry.0 = value,
+                    "author-time" => entry.1 = value.parse().unwrap_or(0),
+                    "author-tz" => entry.2 = value,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    lines
+        .into_iter()
+        .filter_map(|(line, commit)| {
+            let &(author, time, tz) = commits.get(commit)?;
+            let date = OffsetDateTime::from_unix_timestamp(time)
+                .to_offset(parse_tz(tz))
+                .format("%F");
+            let blame = Blame {
+                commit: String::from(commit),
+                author: String::from(author),
+                date,
+            };
+            Some((line, blame))
+        })
+        .collect()
+}
+
+// Time zone like `+0900` or `-0130`
+fn parse_tz(src: &str) -> UtcOffset {
+    let minutes = match (src.get(1..3), src.get(3..5)) {
+        (Some(h), Some(m)) => h.parse::<i16>().unwrap_or(0) * 60 + m.parse::<i16>().unwrap_or(0),
+        _ => 0,
+    };
+    UtcOffset::minutes(if src.starts_with('-') { -minutes } else { minutes })
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blame() {
+        let src = "\
+0123456789abcdef0123456789abcdef01234567 1 2 1
+author Alice
+author-mail <alice@example.com>
+author-time 1700000000
+author-tz +0900
+summary Add foo
+filename a.rs
+\tfoo();
+fedcba9876543210fedcba9876543210fedcba98 4 5 2
+author Bob
+author-time 1700000000
+author-tz -1000
+filename a.rs
+\tfoo(1);
+0123456789abcdef0123456789abcdef01234567 3 6
+\tfoo(2);
+";
+        let blames = parse_porcelain(src);
+        assert_eq!(blames.len(), 3);
+        assert_eq!(blames[&2].label(), "01234567 (Alice 2023-11-15)");
+        assert_eq!(blames[&5].author, "Bob");
+        assert_eq!(blames[&5].date, "2023-11-14");
+        assert_eq!(blames[&6], blames[&2]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "foo\n").unwrap();
+        assert!(blame_lines(&path, &[1]).is_err());
+    }
+}
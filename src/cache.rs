@@ -0,0 +1,165 @@
+use crate::matcher::Match;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// ResultCache
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub const CACHE_DIR: &str = ".amber-cache";
+
+/// Matches of a file, which are reused while the length and the modified time are the same
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub mtime: u64,
+    pub size: u64,
+    /// `beg`, `end` and `keyword` of each match
+    pub matches: Vec<(usize, usize, usize)>,
+}
+
+/// Matches of the files searched by a query, stored as JSON lines in a file per query.
+///
+/// The query is identified by `key`, which must contain the keywords and all options changing the matches.
+/// Only the files searched this time are saved, so files removed or not searched any more are dropped.
+pub struct ResultCache {
+    key: String,
+    old: HashMap<PathBuf, CacheEntry>,
+    new: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl ResultCache {
+    pub fn new(key: &str) -> Self {
+        ResultCache {
+            key: String::from(key),
+            old: HashMap::new(),
+            new: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Loads the cache of `key` from `dir`, which is empty if not saved or broken
+    pub fn load(dir: &Path, key: &str) -> Self {
+        let mut cache = ResultCache::new(key);
+        let file = match File::open(dir.join(cache.file_name())) {
+            Ok(x) => x,
+            Err(_) => return cache,
+        };
+        let mut lines = BufReader::new(file).lines();
+        match lines.next() {
+            Some(Ok(ref x)) if serde_json::from_str::<String>(x).ok().as_deref() == Some(key) => (),
+            _ => return cache,
+        }
+        for line in lines {
+            match line.ok().and_then(|x| serde_json::from_str::<CacheEntry>(&x).ok()) {
+                Some(x) => {
+                    cache.old.insert(x.path.clone(), x);
+                }
+                None => return ResultCache::new(key),
+            }
+        }
+        cache
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), Error> {
+        fs::create_dir_all(dir)?;
+        let mut writer = BufWriter::new(File::create(dir.join(self.file_name()))?);
+        writeln!(writer, "{}", serde_json::to_string(&self.key)?)?;
+        for entry in self.new.lock().unwrap().values() {
+            writeln!(writer, "{}", serde_json::to_string(entry)?)?;
+        }
+        writer.flush()
+    }
+
+    /// Returns the matches of `path` if the file is not modified after caching
+    pub fn lookup(&self, path: &Path, size: u64, modified: Option<SystemTime>) -> Option<Vec<Match>> {
+        let (real_path, mtime) = ResultCache::stat(path, modified)?;
+        let entry = self
+            .old
+            .get(&real_path)
+            .filter(|x| x.mtime == mtime && x.size == size)?;
+        let ret = entry
+            .matches
+            .iter()
+            .map(|&(beg, end, keyword)| Match {
+                keyword,
+                ..Match::new(beg, end)
+            })
+            .collect();
+        self.new.lock().unwrap().insert(real_path, entry.clone());
+        Some(ret)
+    }
+
+    pub fn insert(&self, path: &Path, size: u64, modified: Option<SystemTime>, matches: &[Match]) {
+        let (real_path, mtime) = match ResultCache::stat(path, modified) {
+            Some(x) => x,
+            None => return,
+        };
+        let entry = CacheEntry {
+            path: real_path.clone(),
+            mtime,
+            size,
+            matches: matches.iter().map(|x| (x.beg, x.end, x.keyword)).collect(),
+        };
+        self.new.lock().unwrap().insert(real_path, entry);
+    }
+
+    // Files without the modified time are not cached
+    fn stat(path: &Path, modified: Option<SystemTime>) -> Option<(PathBuf, u64)> {
+        let mtime = modified?.duration_since(UNIX_EPOCH).ok()?.as_nanos() as u64;
+        let real_path = fs::canonicalize(path).ok()?;
+        Some((real_path, mtime))
+    }
+
+    fn file_name(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.key.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "foo bar foo").unwrap();
+        let stamp = |x: &Path| {
+            let attr = fs::metadata(x).unwrap();
+            (attr.len(), attr.modified().ok())
+        };
+
+        let cache = ResultCache::load(dir.path(), "foo");
+        let (size, modified) = stamp(&path);
+        assert!(cache.lookup(&path, size, modified).is_none());
+        cache.insert(&path, size, modified, &[Match::new(0, 3), Match::new(8, 11)]);
+        cache.save(dir.path()).unwrap();
+
+        let cache = ResultCache::load(dir.path(), "foo");
+        let matches = cache.lookup(&path, size, modified).unwrap();
+        assert_eq!(
+            matches.iter().map(|x| (x.beg, x.end)).collect::<Vec<_>>(),
+            vec![(0, 3), (8, 11)]
+        );
+        assert!(ResultCache::load(dir.path(), "bar")
+            .lookup(&path, size, modified)
+            .is_none());
+
+        fs::write(&path, "foo").unwrap();
+        let (size, modified) = stamp(&path);
+        assert!(cache.lookup(&path, size, modified).is_none());
+    }
+}
@@ -0,0 +1,134 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Checkpoint
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Replacement recorded at the first line of the state file, which must be the same to resume
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CheckpointHeader {
+    pub keyword: String,
+    pub replacement: String,
+}
+
+/// File completed with the numbers of the matches replaced and kept
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CheckpointEntry {
+    pub path: PathBuf,
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+/// Progress of a replacement stored as JSON lines, where each file is appended as soon as it is replaced
+pub struct Checkpoint {
+    file: File,
+    completed: HashSet<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Starts a new state file, which is truncated if exists
+    pub fn create(path: &Path, header: &CheckpointHeader) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(header)?)?;
+        file.sync_data()?;
+        Ok(Checkpoint {
+            file,
+            completed: HashSet::new(),
+        })
+    }
+
+    /// Loads the files completed, and appends the following files to the same state file.
+    ///
+    /// The last line may be broken by an interruption, so broken lines are ignored and terminated.
+    pub fn resume(path: &Path, header: &CheckpointHeader) -> Result<Self, Error> {
+        let src = fs::read_to_string(path)?;
+        let mut lines = src.lines();
+        let first = lines.next().unwrap_or_default();
+        match serde_json::from_str::<CheckpointHeader>(first) {
+            Ok(ref x) if x == header => (),
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the keyword or the replacement is different from the checkpoint",
+                ))
+            }
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "invalid checkpoint")),
+        }
+        let mut completed = HashSet::new();
+        for line in lines {
+            if let Ok(x) = serde_json::from_str::<CheckpointEntry>(line) {
+                completed.insert(x.path);
+            }
+        }
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        if !src.ends_with('\n') {
+            writeln!(file)?;
+        }
+        Ok(Checkpoint { file, completed })
+    }
+
+    /// Files completed before resuming
+    pub fn completed(&self) -> &HashSet<PathBuf> {
+        &self.completed
+    }
+
+    /// Appended and synced per file not to replace the file again after a crash
+    pub fn record(&mut self, entry: &CheckpointEntry) -> Result<(), Error> {
+        writeln!(self.file, "{}", serde_json::to_string(entry)?)?;
+        self.file.sync_data()
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let header = CheckpointHeader {
+            keyword: String::from("foo"),
+            replacement: String::from("bar"),
+        };
+        let entry = |x: &str| CheckpointEntry {
+            path: PathBuf::from(x),
+            accepted: 1,
+            rejected: 0,
+        };
+
+        let mut checkpoint = Checkpoint::create(&path, &header).unwrap();
+        checkpoint.record(&entry("a.txt")).unwrap();
+        drop(checkpoint);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"path\":\"b.t")
+            .unwrap();
+
+        let mut checkpoint = Checkpoint::resume(&path, &header).unwrap();
+        assert!(checkpoint.completed().contains(Path::new("a.txt")));
+        assert_eq!(checkpoint.completed().len(), 1);
+        checkpoint.record(&entry("c.txt")).unwrap();
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::resume(&path, &header).unwrap();
+        assert!(checkpoint.completed().contains(Path::new("c.txt")));
+        assert_eq!(checkpoint.completed().len(), 2);
+
+        let other = CheckpointHeader {
+            keyword: String::from("foo"),
+            replacement: String::from("baz"),
+        };
+        assert!(Checkpoint::resume(&path, &other).is_err());
+    }
+}
@@ -1,55 +1,345 @@
 extern crate term;
 
 use crate::matcher::Match;
+use crate::util::{transcode_to_utf8, PathPrefix};
+use getch::Getch;
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::borrow::Cow;
+use std::cmp;
+use std::env;
+use std::fs::File;
 use std::io;
 use std::io::Write;
+use std::path::Path;
 use std::process;
+use std::str::FromStr;
 use term::color::Color;
-use term::{StderrTerminal, StdoutTerminal};
+use term::terminfo::TermInfo;
+use term::StderrTerminal;
+use unicode_width::UnicodeWidthChar;
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Console
 // ---------------------------------------------------------------------------------------------------------------------
 
+#[derive(Clone, Copy)]
 pub enum ConsoleTextKind {
     Filename,
     Text,
     MatchText,
+    KeywordText(usize),
+    DeleteText,
+    InsertText,
     Other,
     Info,
     Error,
 }
 
+/// Format of diagnostics such as skipped files and errors on stderr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    Text,
+    /// A JSON object of `path`, `kind` and `message` per line
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("invalid error format: {}", s)),
+        }
+    }
+}
+
+/// When to color the output, which decides `Console::is_color`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// Colored only if stdout is a terminal which is not dumb, unless NO_COLOR or CLICOLOR_FORCE is set
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("invalid color choice: {}", s)),
+        }
+    }
+}
+
+impl ColorChoice {
+    /// Returns whether to color the output on the current stdout and environment variables
+    pub fn is_color(self) -> bool {
+        let set = |x: &str| env::var_os(x).is_some_and(|x| !x.is_empty() && x != "0");
+        let dumb = env::var("TERM").is_ok_and(|x| x == "dumb");
+        self.decide(
+            Console::is_stdout_tty() && !dumb,
+            set("NO_COLOR"),
+            set("CLICOLOR_FORCE"),
+        )
+    }
+
+    // Flags are prior to the environment variables, and NO_COLOR is prior to CLICOLOR_FORCE
+    fn decide(self, is_tty: bool, no_color: bool, force: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if no_color => false,
+            ColorChoice::Auto => force || is_tty,
+        }
+    }
+}
+
+/// Way to color the text, which is chosen by the capability of the terminal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorBackend {
+    /// Escape sequences written with the text ( terminals other than legacy Windows consoles )
+    Ansi,
+    /// Text attributes of the Windows console set by the Win32 API, which are applied immediately
+    WinApi,
+}
+
+lazy_static! {
+    static ref COLOR_BACKEND: ColorBackend = ColorBackend::detect();
+    static ref CONSOLE_CODEPAGE: Option<u32> = Console::switch_codepage();
+}
+
+impl ColorBackend {
+    /// Returns the backend of the current terminal, which is detected once for the process
+    pub fn current() -> Self {
+        *COLOR_BACKEND
+    }
+
+    #[cfg(not(windows))]
+    fn detect() -> Self {
+        ColorBackend::Ansi
+    }
+
+    // Escape sequences are enabled on Windows 10 or later, and legacy consoles fail to enable them.
+    // Stdout redirected to a file or a pipe is not a console, so the escape sequences are written as is.
+    #[cfg(windows)]
+    fn detect() -> Self {
+        use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+        use winapi::um::processenv::GetStdHandle;
+        use winapi::um::winbase::STD_OUTPUT_HANDLE;
+        use winapi::um::wincon::ENABLE_VIRTUAL_TERMINAL_PROCESSING;
+
+        // Terminals like mintty, ConEmu or Windows Terminal interpret escape sequences by themselves
+        let ansi_term = env::var_os("TERM").is_some()
+            || env::var_os("WT_SESSION").is_some()
+            || env::var_os("ANSICON").is_some()
+            || env::var("ConEmuANSI").map(|x| x == "ON").unwrap_or(false);
+        if ansi_term {
+            return ColorBackend::Ansi;
+        }
+
+        unsafe {
+            let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+            let mut mode = 0;
+            if GetConsoleMode(handle, &mut mode) == 0 {
+                return ColorBackend::Ansi;
+            }
+            if SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0 {
+                ColorBackend::Ansi
+            } else {
+                ColorBackend::WinApi
+            }
+        }
+    }
+}
+
 pub struct Console {
     pub is_color: bool,
-    term_stdout: Box<StdoutTerminal>,
+    pub display_encoding: Option<String>,
+    /// URL of OSC 8 hyperlinks of file names like `vscode://file/{path}:{line}` ( None: disabled )
+    pub hyperlink_format: Option<String>,
+    /// Prefixes of file names and hyperlinks rewritten ( e.g. paths in containers to the host )
+    pub path_prefix_map: Vec<PathPrefix>,
+    pub error_format: ErrorFormat,
+    /// Errors of `write_diagnostic` are not written, but still decide the exit status
+    pub no_messages: bool,
+    /// Tabs of the diff lines are expanded to spaces by this width from the beginning of the line ( 0: disabled )
+    pub tab_width: usize,
+    /// Write stdout at each line instead of when the buffer is filled ( default: whether stdout is a terminal )
+    pub line_buffered: bool,
+    /// Lines of matches longer than this number of characters are truncated around the matches ( None: disabled )
+    pub max_columns: Option<usize>,
+    /// Show the number of characters omitted by `max_columns` in the markers instead of `...`
+    pub max_columns_preview: bool,
+    /// Lines of matches longer than this number of bytes are printed only in the windows around the matches
+    /// not to decode the whole line ( 0: unlimited )
+    pub max_line_bytes: usize,
+    // Number of lines printed in the windows by `max_line_bytes`, which is taken by `take_long_lines`
+    long_lines: usize,
+    backend: ColorBackend,
+    term_stdout: Box<StdoutBuffer>,
+    // Stdout is written to this file instead if set
+    output: Option<File>,
     term_stderr: Box<StderrTerminal>,
     color_out: Color,
     color_err: Color,
     colored_out: bool,
     colored_err: bool,
+    errored: bool,
 }
 
+// Colors of the second and later keywords of `--all-of`, `--pattern` and rules, which are cycled
+const KEYWORD_COLORS: [Color; 4] = [
+    term::color::BRIGHT_MAGENTA,
+    term::color::BRIGHT_BLUE,
+    term::color::BRIGHT_RED,
+    term::color::BRIGHT_CYAN,
+];
+
 const CR: u8 = 0x0d;
 const LF: u8 = 0x0a;
 
+// Bytes printed before and after the matches in lines longer than `max_line_bytes`
+const LONG_LINE_WINDOW: usize = 256;
+
+// Stdout is written at once per this size at least ( only at the end of lines not to interleave with other threads )
+const STDOUT_BUF_BYTES: usize = 64 * 1024;
+
+// Escape sequences of colors are rendered into the buffer with the text
+type StdoutBuffer = dyn term::Terminal<Output = Vec<u8>> + Send;
+
 impl Console {
     pub fn new() -> Self {
+        Console::with_backend(ColorBackend::current())
+    }
+
+    pub fn with_backend(backend: ColorBackend) -> Self {
+        lazy_static::initialize(&CONSOLE_CODEPAGE);
         Console {
-            term_stdout: term::stdout().unwrap_or_else(|| {
+            backend,
+            term_stdout: Console::stdout_buffer(backend).unwrap_or_else(|| {
                 process::exit(1);
             }),
-            term_stderr: term::stderr().unwrap_or_else(|| {
+            term_stderr: Console::stderr_terminal(backend).unwrap_or_else(|| {
                 process::exit(1);
             }),
             is_color: true,
+            display_encoding: None,
+            hyperlink_format: None,
+            path_prefix_map: Vec::new(),
+            error_format: ErrorFormat::Text,
+            no_messages: false,
+            tab_width: 0,
+            line_buffered: Console::is_stdout_tty(),
+            max_columns: None,
+            max_columns_preview: false,
+            max_line_bytes: 0,
+            long_lines: 0,
+            output: None,
             color_out: term::color::BLACK,
             color_err: term::color::BLACK,
             colored_out: false,
             colored_err: false,
+            errored: false,
+        }
+    }
+
+    // Without terminfo ( e.g. TERM is unset on CI or Windows ), the basic escape sequences of xterm are used
+    fn ansi_terminal<T: Write>(out: T) -> Option<term::TerminfoTerminal<T>> {
+        match TermInfo::from_env() {
+            Ok(x) => Some(term::TerminfoTerminal::new_with_terminfo(out, x)),
+            Err(_) => TermInfo::from_name("xterm")
+                .ok()
+                .map(|x| term::TerminfoTerminal::new_with_terminfo(out, x)),
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn stdout_buffer(_backend: ColorBackend) -> Option<Box<StdoutBuffer>> {
+        Console::ansi_terminal(Vec::new()).map(|x| Box::new(x) as Box<StdoutBuffer>)
+    }
+
+    #[cfg(windows)]
+    fn stdout_buffer(backend: ColorBackend) -> Option<Box<StdoutBuffer>> {
+        match backend {
+            ColorBackend::Ansi => Console::ansi_terminal(Vec::new()).map(|x| Box::new(x) as Box<StdoutBuffer>),
+            ColorBackend::WinApi => term::WinConsole::new(Vec::new())
+                .ok()
+                .map(|x| Box::new(x) as Box<StdoutBuffer>),
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn stderr_terminal(_backend: ColorBackend) -> Option<Box<StderrTerminal>> {
+        Console::ansi_terminal(io::stderr()).map(|x| Box::new(x) as Box<StderrTerminal>)
+    }
+
+    #[cfg(windows)]
+    fn stderr_terminal(backend: ColorBackend) -> Option<Box<StderrTerminal>> {
+        match backend {
+            ColorBackend::Ansi => Console::ansi_terminal(io::stderr()).map(|x| Box::new(x) as Box<StderrTerminal>),
+            ColorBackend::WinApi => term::WinConsole::new(io::stderr())
+                .ok()
+                .map(|x| Box::new(x) as Box<StderrTerminal>),
+        }
+    }
+
+    pub fn backend(&self) -> ColorBackend {
+        self.backend
+    }
+
+    #[cfg(not(windows))]
+    fn switch_codepage() -> Option<u32> {
+        None
+    }
+
+    // Windows consoles of legacy code pages ( e.g. 932 or 437 ) render UTF-8 bytes passed through pipes or child
+    // processes as garbled text or question marks, so the console is switched to UTF-8 once for the process.
+    // The text is not transcoded to the legacy code page because the standard library writes consoles by UTF-16.
+    // Returns the original code page to be restored by `restore_codepage`.
+    #[cfg(windows)]
+    fn switch_codepage() -> Option<u32> {
+        use winapi::um::consoleapi::GetConsoleOutputCP;
+        use winapi::um::wincon::SetConsoleOutputCP;
+
+        const CP_UTF8: u32 = 65001;
+        unsafe {
+            // 0 means that the process has no console ( e.g. stdout and stderr are redirected by a service )
+            let codepage = GetConsoleOutputCP();
+            if codepage == 0 || codepage == CP_UTF8 {
+                return None;
+            }
+            if SetConsoleOutputCP(CP_UTF8) != 0 {
+                Some(codepage)
+            } else {
+                None
+            }
         }
     }
 
+    /// Restores the code page of the Windows console switched to UTF-8 by `Console::new`, which must be called at exit
+    /// not to leave the shell in UTF-8
+    pub fn restore_codepage() {
+        #[cfg(windows)]
+        if let Some(codepage) = *CONSOLE_CODEPAGE {
+            unsafe {
+                winapi::um::wincon::SetConsoleOutputCP(codepage);
+            }
+        }
+    }
+
+    /// Writes stdout to `file` instead, where colors should be disabled by `is_color`
+    pub fn set_output(&mut self, file: File) {
+        self.output = Some(file);
+        self.line_buffered = false;
+    }
+
     pub fn carriage_return(&mut self) {
         let _ = self.term_stdout.carriage_return();
     }
@@ -69,34 +359,232 @@ impl Console {
     }
 
     pub fn write(&mut self, kind: ConsoleTextKind, val: &str) {
-        let color = match kind {
+        let color = Console::color(kind);
+        match kind {
+            ConsoleTextKind::Error => {
+                self.errored = true;
+                self.write_stderr(val, color)
+            }
+            ConsoleTextKind::Info => self.write_stderr(val, color),
+            _ => self.write_stdout(val, color),
+        }
+    }
+
+    fn color(kind: ConsoleTextKind) -> Color {
+        match kind {
             ConsoleTextKind::Filename => term::color::BRIGHT_GREEN,
             ConsoleTextKind::Text => term::color::WHITE,
             ConsoleTextKind::MatchText => term::color::BRIGHT_YELLOW,
+            ConsoleTextKind::DeleteText => term::color::BRIGHT_RED,
+            ConsoleTextKind::InsertText => term::color::BRIGHT_GREEN,
+            ConsoleTextKind::KeywordText(i) => KEYWORD_COLORS[(i + KEYWORD_COLORS.len() - 1) % KEYWORD_COLORS.len()],
             ConsoleTextKind::Other => term::color::BRIGHT_CYAN,
             ConsoleTextKind::Info => term::color::BRIGHT_CYAN,
             ConsoleTextKind::Error => term::color::BRIGHT_RED,
+        }
+    }
+
+    /// Writes diagnostics like `Skipped: "foo" ( binary file )` or `Error: permission denied @ "foo"`,
+    /// which are written as JSON objects of `Diagnostic` if `error_format` is `Json`
+    pub fn write_diagnostic(&mut self, kind: ConsoleTextKind, val: &str) {
+        if let ConsoleTextKind::Error = kind {
+            if self.no_messages {
+                self.errored = true;
+                return;
+            }
+        }
+        if self.error_format == ErrorFormat::Text {
+            self.write(kind, val);
+            return;
+        }
+
+        if let ConsoleTextKind::Error = kind {
+            self.errored = true;
+        }
+        if self.colored_err {
+            let _ = self.term_stderr.reset();
+            self.color_err = term::color::BLACK;
+            self.colored_err = false;
+        }
+        for line in val.lines().filter(|x| !x.trim().is_empty()) {
+            let d = Diagnostic::parse(line);
+            let obj = json!({ "path": d.path, "kind": d.kind, "message": d.message });
+            writeln!(self.term_stderr, "{}", obj).unwrap_or_else(|_| {
+                process::exit(1);
+            });
+        }
+    }
+
+    /// Returns whether any error has been written, which decides the exit status
+    pub fn has_error(&self) -> bool {
+        self.errored
+    }
+
+    /// Writes the file name as a hyperlink to `line` and `column` if `hyperlink_format` is set
+    pub fn write_filename(&mut self, path: &Path, line: usize, column: usize) {
+        let name = PathPrefix::map(&self.path_prefix_map, path)
+            .to_string_lossy()
+            .into_owned();
+        // Legacy Windows consoles print the escape sequences of hyperlinks as is
+        match self.hyperlink_format {
+            Some(ref format) if self.is_color && self.backend == ColorBackend::Ansi => {
+                let url = Console::hyperlink_url(format, path, &self.path_prefix_map, line, column);
+                self.write(ConsoleTextKind::Filename, &format!("\x1b]8;;{}\x1b\\", url));
+                self.write(ConsoleTextKind::Filename, &name);
+                self.write(ConsoleTextKind::Filename, "\x1b]8;;\x1b\\");
+            }
+            _ => self.write(ConsoleTextKind::Filename, &name),
+        }
+    }
+
+    // The real path is rewritten by `prefixes` to be opened on the host of the terminal
+    fn hyperlink_url(format: &str, path: &Path, prefixes: &[PathPrefix], line: usize, column: usize) -> String {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let path = PathPrefix::map(prefixes, &path);
+        let path = path.to_string_lossy().replace('\\', "/");
+        // Windows paths like C:/foo are linked as /C:/foo
+        let path = if path.starts_with('/') {
+            path
+        } else {
+            format!("/{}", path)
         };
+        let mut encoded = String::new();
+        for b in path.bytes() {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                    encoded.push(b as char)
+                }
+                _ => encoded.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        format
+            .replace("{host}", &Console::hostname())
+            .replace("{path}", &encoded)
+            .replace("{line}", &format!("{}", line))
+            .replace("{column}", &format!("{}", column))
+    }
 
-        match kind {
-            ConsoleTextKind::Error => self.write_stderr(val, color),
-            ConsoleTextKind::Info => self.write_stderr(val, color),
-            _ => self.write_stdout(val, color),
+    #[cfg(not(windows))]
+    fn hostname() -> String {
+        let mut buf = [0u8; 256];
+        let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if ret != 0 {
+            return String::new();
         }
+        let len = buf.iter().position(|&x| x == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[cfg(windows)]
+    fn hostname() -> String {
+        String::new()
+    }
+
+    /// Returns whether stdout is a terminal known to support OSC 8 hyperlinks
+    pub fn supports_hyperlink() -> bool {
+        #[cfg(not(windows))]
+        let is_tty = unsafe { libc::isatty(1) != 0 };
+        #[cfg(windows)]
+        let is_tty = env::var_os("WT_SESSION").is_some();
+
+        let term_program = env::var("TERM_PROGRAM").unwrap_or_default();
+        let vte_version = env::var("VTE_VERSION").ok().and_then(|x| x.parse::<u32>().ok());
+        is_tty
+            && env::var("TERM").map(|x| x != "dumb").unwrap_or(true)
+            && (["iTerm.app", "WezTerm", "vscode"].contains(&term_program.as_str())
+                || env::var_os("WT_SESSION").is_some()
+                || env::var_os("KITTY_WINDOW_ID").is_some()
+                || vte_version.map(|x| x >= 5000).unwrap_or(false))
+    }
+
+    /// Returns whether stdout is a terminal, which is assumed on Windows
+    pub fn is_stdout_tty() -> bool {
+        #[cfg(not(windows))]
+        let is_tty = unsafe { libc::isatty(1) != 0 };
+        #[cfg(windows)]
+        let is_tty = true;
+
+        is_tty
+    }
+
+    /// Returns whether stdin is a terminal to prompt for input
+    pub fn is_stdin_tty() -> bool {
+        #[cfg(not(windows))]
+        let is_tty = unsafe { libc::isatty(0) != 0 };
+        #[cfg(windows)]
+        let is_tty = false;
+
+        is_tty
+    }
+
+    /// Reads a line from the terminal echoing the input, where Up/Down keys recall `history` from the latest
+    pub fn read_line(&mut self, prompt: &str, history: &[String]) -> io::Result<String> {
+        let getch = Getch::new();
+        let mut buf = Vec::new();
+        // None is the new line being edited
+        let mut recalled: Option<usize> = None;
+        loop {
+            self.write_with_clear(ConsoleTextKind::Other, prompt);
+            self.write(ConsoleTextKind::MatchText, &String::from_utf8_lossy(&buf));
+            self.flush();
+
+            match getch.getch()? {
+                // EOF or Enter
+                0 | b'\r' | b'\n' => break,
+                // Backspace / Delete
+                0x08 | 0x7f => {
+                    // pop a whole UTF-8 character
+                    while let Some(x) = buf.pop() {
+                        if x & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                }
+                // Ctrl-U
+                0x15 => buf.clear(),
+                // Up / Down ( ESC [ A / ESC [ B )
+                0x1b => {
+                    if getch.getch()? != b'[' {
+                        continue;
+                    }
+                    let next = match (getch.getch()?, recalled) {
+                        (b'A', None) if !history.is_empty() => Some(0),
+                        (b'A', Some(x)) if x + 1 < history.len() => Some(x + 1),
+                        (b'B', Some(x)) => x.checked_sub(1),
+                        (_, x) => x,
+                    };
+                    if next != recalled {
+                        recalled = next;
+                        buf = recalled.map_or(Vec::new(), |x| history[x].clone().into_bytes());
+                    }
+                }
+                x if x < 0x20 => (),
+                x => buf.push(x),
+            }
+        }
+        self.write(ConsoleTextKind::Other, "\n");
+        self.flush();
+        Ok(String::from_utf8_lossy(&buf).into_owned())
     }
 
     pub fn flush(&mut self) {
+        self.flush_stdout();
         let _ = io::stdout().flush();
         let _ = io::stderr().flush();
     }
 
     pub fn reset(&mut self) {
-        self.term_stdout.reset().unwrap_or_else(|_| {
-            process::exit(1);
-        });
-        self.term_stderr.reset().unwrap_or_else(|_| {
-            process::exit(1);
-        });
+        // Reset only colored output not to pollute plain output ( e.g. --format csv )
+        if self.is_color | self.colored_out {
+            self.term_stdout.reset().unwrap_or_else(|_| {
+                process::exit(1);
+            });
+        }
+        if self.is_color | self.colored_err {
+            self.term_stderr.reset().unwrap_or_else(|_| {
+                process::exit(1);
+            });
+        }
     }
 
     fn get_line_beg(src: &[u8], beg: usize) -> usize {
@@ -128,17 +616,201 @@ impl Console {
         ret
     }
 
+    fn decode<'a>(&self, src: &'a [u8]) -> Cow<'a, str> {
+        if let Some(ref encoding) = self.display_encoding {
+            if let Some(x) = transcode_to_utf8(src, encoding) {
+                return Cow::Owned(x);
+            }
+        }
+        String::from_utf8_lossy(src)
+    }
+
+    // Shortens the text before and after the matches of `matched` characters by `max_columns`,
+    // where the omitted parts are replaced by markers showing the number of the characters if `preview`
+    fn clip_around<'a>(
+        &self,
+        before: Cow<'a, str>,
+        matched: usize,
+        after: Cow<'a, str>,
+        preview: bool,
+    ) -> (Cow<'a, str>, Cow<'a, str>) {
+        let max = match self.max_columns {
+            Some(x) => x,
+            None => return (before, after),
+        };
+        let (len_before, len_after) = (before.chars().count(), after.chars().count());
+        let (keep_before, keep_after) = truncate_around(len_before, matched, len_after, max);
+        let before = if keep_before < len_before {
+            let omitted = len_before - keep_before;
+            let i = before.char_indices().nth(omitted).map_or(before.len(), |x| x.0);
+            Cow::Owned(format!("{}{}", omitted_marker(omitted, preview), &before[i..]))
+        } else {
+            before
+        };
+        let after = if keep_after < len_after {
+            let i = after.char_indices().nth(keep_after).map_or(after.len(), |x| x.0);
+            Cow::Owned(format!(
+                "{}{}",
+                &after[..i],
+                omitted_marker(len_after - keep_after, preview)
+            ))
+        } else {
+            after
+        };
+        (before, after)
+    }
+
+    /// Returns the number of the lines printed in the windows by `max_line_bytes` since the last call
+    pub fn take_long_lines(&mut self) -> usize {
+        std::mem::take(&mut self.long_lines)
+    }
+
+    // Range of the line of `beg..end` to be printed and the bytes omitted before and after it,
+    // where lines longer than `max_line_bytes` are shortened to the windows at the boundaries of UTF-8 characters
+    fn line_window(&mut self, src: &[u8], beg: usize, end: usize) -> (usize, usize, usize, usize) {
+        let line_beg = Console::get_line_beg(src, beg);
+        let line_end = Console::get_line_end(src, end);
+        if self.max_line_bytes == 0 || line_end - line_beg <= self.max_line_bytes {
+            return (line_beg, line_end, 0, 0);
+        }
+        self.long_lines += 1;
+        let is_head = |i: usize| i >= src.len() || src[i] & 0xc0 != 0x80;
+        let mut window_beg = cmp::max(line_beg, beg.saturating_sub(LONG_LINE_WINDOW));
+        while window_beg < beg && !is_head(window_beg) {
+            window_beg += 1;
+        }
+        let mut window_end = cmp::max(end, cmp::min(line_end, end + LONG_LINE_WINDOW));
+        while window_end > end && !is_head(window_end) {
+            window_end -= 1;
+        }
+        (window_beg, window_end, window_beg - line_beg, line_end - window_end)
+    }
+
+    fn write_omitted_bytes(&mut self, num: usize) {
+        if num != 0 {
+            self.write(ConsoleTextKind::Other, &format!("[...{} bytes]", num));
+        }
+    }
+
     pub fn write_match_line(&mut self, src: &[u8], m: &Match) {
-        let beg = Console::get_line_beg(src, m.beg);
-        let end = Console::get_line_end(src, m.end);
+        let (beg, end, omitted_before, omitted_after) = self.line_window(src, m.beg, m.end);
 
-        if beg < m.beg {
-            self.write(ConsoleTextKind::Text, &String::from_utf8_lossy(&src[beg..m.beg]));
+        let matched = self.decode(&src[m.beg..m.end]);
+        let (before, after) = self.clip_around(
+            self.decode(&src[beg..cmp::max(beg, m.beg)]),
+            matched.chars().count(),
+            self.decode(&src[cmp::min(m.end, end)..end]),
+            self.max_columns_preview,
+        );
+        self.write_omitted_bytes(omitted_before);
+        if !before.is_empty() {
+            self.write(ConsoleTextKind::Text, &before);
         }
-        self.write(ConsoleTextKind::MatchText, &String::from_utf8_lossy(&src[m.beg..m.end]));
-        if m.end < end {
-            self.write(ConsoleTextKind::Text, &String::from_utf8_lossy(&src[m.end..end]));
+        self.write(Console::keyword_kind(m.keyword), &matched);
+        if !after.is_empty() {
+            self.write(ConsoleTextKind::Text, &after);
+        }
+        self.write_omitted_bytes(omitted_after);
+        self.write(ConsoleTextKind::Text, "\n");
+    }
+
+    /// Kind of the text matched by the keyword indexed by `Match::keyword`, which is colored by the keyword
+    pub fn keyword_kind(keyword: usize) -> ConsoleTextKind {
+        if keyword == 0 {
+            ConsoleTextKind::MatchText
+        } else {
+            ConsoleTextKind::KeywordText(keyword)
+        }
+    }
+
+    /// Writes `val` to stderr in the color of the keyword like `Info` ( e.g. the legend of the colors )
+    pub fn write_keyword_info(&mut self, keyword: usize, val: &str) {
+        self.write_stderr(val, Console::color(Console::keyword_kind(keyword)));
+    }
+
+    /// Writes the bytes of the match as hex and ASCII like `de ad be ef  |....|`
+    pub fn write_hex_match(&mut self, src: &[u8], m: &Match) {
+        let bytes = &src[m.beg..m.end];
+        self.write(ConsoleTextKind::MatchText, &hex_bytes(bytes));
+        self.write(ConsoleTextKind::Text, &format!("  |{}|\n", ascii_dump(bytes)));
+    }
+
+    /// Writes the rows of 16 bytes containing `ms` and `context` rows around them like xxd,
+    /// with the bytes of matches highlighted
+    pub fn write_hex_dump(&mut self, src: &[u8], ms: &[Match], context: usize) {
+        let last = ms[ms.len() - 1].end.max(ms[ms.len() - 1].beg + 1) - 1;
+        let beg_row = (ms[0].beg / HEX_ROW).saturating_sub(context);
+        let end_row = cmp::min(last / HEX_ROW + 1 + context, src.len().div_ceil(HEX_ROW));
+
+        for row in beg_row..end_row {
+            let beg = row * HEX_ROW;
+            let end = cmp::min(beg + HEX_ROW, src.len());
+            let kind = |i: usize| {
+                if ms.iter().any(|m| m.beg <= i && i < m.end) {
+                    ConsoleTextKind::MatchText
+                } else {
+                    ConsoleTextKind::Text
+                }
+            };
+
+            self.write(ConsoleTextKind::Other, &format!("{:08x}: ", beg));
+            for i in 0..HEX_ROW {
+                match src[beg..end].get(i) {
+                    Some(x) => self.write(kind(beg + i), &format!("{:02x}", x)),
+                    None => self.write(ConsoleTextKind::Text, "  "),
+                }
+                if i % 2 == 1 {
+                    self.write(ConsoleTextKind::Text, " ");
+                }
+            }
+            self.write(ConsoleTextKind::Text, " ");
+            for (i, x) in src[beg..end].iter().enumerate() {
+                self.write(kind(beg + i), &ascii_dump(&[*x]));
+            }
+            self.write(ConsoleTextKind::Text, "\n");
+        }
+    }
+
+    /// Writes the line of `ms` once with all matches highlighted by the color of each keyword
+    pub fn write_matches_line(&mut self, src: &[u8], ms: &[Match]) {
+        let last = ms.iter().map(|m| m.end).max().unwrap_or(ms[0].beg);
+        let (beg, end, omitted_before, omitted_after) = self.line_window(src, ms[0].beg, last);
+
+        // The text between the matches is kept, and only the text around all of them is truncated
+        let matched = self.decode(&src[ms[0].beg..last]).chars().count();
+        let (before, after) = self.clip_around(
+            self.decode(&src[beg..cmp::max(beg, ms[0].beg)]),
+            matched,
+            self.decode(&src[cmp::min(last, end)..end]),
+            self.max_columns_preview,
+        );
+        self.write_omitted_bytes(omitted_before);
+        if !before.is_empty() {
+            self.write(ConsoleTextKind::Text, &before);
+        }
+        let mut pos = ms[0].beg;
+        for m in ms {
+            // Overlapped matches of other keywords are not highlighted
+            if m.beg < pos {
+                continue;
+            }
+            if pos < m.beg {
+                self.write(ConsoleTextKind::Text, &self.decode(&src[pos..m.beg]));
+            }
+            self.write(Console::keyword_kind(m.keyword), &self.decode(&src[m.beg..m.end]));
+            pos = m.end;
         }
+        if !after.is_empty() {
+            self.write(ConsoleTextKind::Text, &after);
+        }
+        self.write_omitted_bytes(omitted_after);
+        self.write(ConsoleTextKind::Text, "\n");
+    }
+
+    /// Writes the line without highlight, which doesn't contain the line ending
+    pub fn write_plain_line(&mut self, src: &[u8]) {
+        let src = src.strip_suffix(&[CR]).unwrap_or(src);
+        self.write(ConsoleTextKind::Text, &self.decode(src));
         self.write(ConsoleTextKind::Text, "\n");
     }
 
@@ -147,15 +819,160 @@ impl Console {
         let end = Console::get_line_end(src, m.end);
 
         if beg < m.beg {
-            self.write(ConsoleTextKind::Text, &String::from_utf8_lossy(&src[beg..m.beg]));
+            self.write(ConsoleTextKind::Text, &self.decode(&src[beg..m.beg]));
         }
         self.write(ConsoleTextKind::MatchText, &String::from_utf8_lossy(&rep));
         if m.end < end {
-            self.write(ConsoleTextKind::Text, &String::from_utf8_lossy(&src[m.end..end]));
+            self.write(ConsoleTextKind::Text, &self.decode(&src[m.end..end]));
+        }
+        self.write(ConsoleTextKind::Text, "\n");
+    }
+
+    /// Writes the line of the match before ( `after` is false ) or after replacing by `rep`,
+    /// with the deleted or inserted words highlighted.
+    ///
+    /// All lines of a match across lines are written, where the lines after the first are indented by `indent`.
+    pub fn write_diff_line(&mut self, src: &[u8], m: &Match, rep: &[u8], after: bool, indent: usize) {
+        // A match may begin at the line feed of the line
+        let beg = src[..m.beg]
+            .iter()
+            .rposition(|&x| x == CR || x == LF)
+            .map_or(0, |x| x + 1);
+        let end = Console::get_line_end(src, m.end);
+        let mut width = 0;
+
+        let before = self.expand_tabs(&self.decode(&src[beg..cmp::max(beg, m.beg)]), &mut width);
+        let old = self.decode(&src[m.beg..m.end]).into_owned();
+        let new = String::from_utf8_lossy(rep).into_owned();
+        let mut words = Vec::new();
+        for (kind, text) in diff_words(&old, &new) {
+            let kind = match kind {
+                DiffKind::Equal => ConsoleTextKind::MatchText,
+                DiffKind::Delete if !after => ConsoleTextKind::DeleteText,
+                DiffKind::Insert if after => ConsoleTextKind::InsertText,
+                _ => continue,
+            };
+            words.push((kind, self.expand_tabs(&text, &mut width)));
         }
+        let rest = self.expand_tabs(&self.decode(&src[cmp::min(m.end, end)..end]), &mut width);
+
+        // Both lines are truncated by the longer one of the match and the replacement to be aligned,
+        // and the markers show the omitted characters as the column of the window
+        let matched = cmp::max(old.chars().count(), new.chars().count());
+        let (before, rest) = self.clip_around(Cow::Owned(before), matched, Cow::Owned(rest), true);
+        self.write_lines(ConsoleTextKind::Text, &before, indent);
+        for (kind, text) in words {
+            self.write_lines(kind, &text, indent);
+        }
+        self.write_lines(ConsoleTextKind::Text, &rest, indent);
         self.write(ConsoleTextKind::Text, "\n");
     }
 
+    // Line breaks in `text` are written without CR and followed by `indent` spaces
+    fn write_lines(&mut self, kind: ConsoleTextKind, text: &str, indent: usize) {
+        let mut lines = text.split('\n').peekable();
+        while let Some(line) = lines.next() {
+            if lines.peek().is_some() {
+                self.write(kind, line.strip_suffix('\r').unwrap_or(line));
+                self.write(ConsoleTextKind::Text, "\n");
+                self.write(ConsoleTextKind::Other, &" ".repeat(indent));
+            } else {
+                self.write(kind, line);
+            }
+        }
+    }
+
+    // `width` is the display width from the beginning of the line, which is advanced by `text`
+    fn expand_tabs(&self, text: &str, width: &mut usize) -> String {
+        if self.tab_width == 0 {
+            return String::from(text);
+        }
+        let mut ret = String::new();
+        for c in text.chars() {
+            if c == '\n' {
+                ret.push(c);
+                *width = 0;
+            } else if c == '\t' {
+                let n = self.tab_width - *width % self.tab_width;
+                ret.push_str(&" ".repeat(n));
+                *width += n;
+            } else {
+                ret.push(c);
+                *width += UnicodeWidthChar::width(c).unwrap_or(0);
+            }
+        }
+        ret
+    }
+
+    fn get_context_beg(src: &[u8], m: &Match, num: usize) -> usize {
+        let mut beg = Console::get_line_beg(src, m.beg);
+        for _ in 0..num {
+            let mut pos = beg;
+            if pos > 0 && src[pos - 1] == LF {
+                pos -= 1;
+            }
+            if pos > 0 && src[pos - 1] == CR {
+                pos -= 1;
+            }
+            if pos == beg {
+                break;
+            }
+            beg = if pos == 0 {
+                0
+            } else {
+                Console::get_line_beg(src, pos - 1)
+            };
+        }
+        beg
+    }
+
+    fn get_context_end(src: &[u8], m: &Match, num: usize) -> usize {
+        let mut end = Console::get_line_end(src, m.end);
+        for _ in 0..num {
+            let mut pos = end;
+            if pos < src.len() && src[pos] == CR {
+                pos += 1;
+            }
+            if pos < src.len() && src[pos] == LF {
+                pos += 1;
+            }
+            if pos == end || pos >= src.len() {
+                break;
+            }
+            end = Console::get_line_end(src, pos);
+        }
+        end
+    }
+
+    pub fn write_context_before(&mut self, src: &[u8], m: &Match, num: usize) {
+        let beg = Console::get_context_beg(src, m, num);
+        let end = Console::get_line_beg(src, m.beg);
+        if beg < end {
+            self.write_context(&src[beg..end]);
+        }
+    }
+
+    pub fn write_context_after(&mut self, src: &[u8], m: &Match, num: usize) {
+        let mut beg = Console::get_line_end(src, m.end);
+        if beg < src.len() && src[beg] == CR {
+            beg += 1;
+        }
+        if beg < src.len() && src[beg] == LF {
+            beg += 1;
+        }
+        let end = Console::get_context_end(src, m, num);
+        if beg < end {
+            self.write_context(&src[beg..end]);
+        }
+    }
+
+    fn write_context(&mut self, src: &[u8]) {
+        let src = self.decode(src).into_owned();
+        for line in src.lines() {
+            self.write(ConsoleTextKind::Text, &format!("{}\n", line));
+        }
+    }
+
     fn write_stdout(&mut self, val: &str, color: Color) {
         if self.is_color {
             if self.color_out != color {
@@ -175,7 +992,28 @@ impl Console {
         //    self.term_stdout.reset().unwrap_or_else( |_| { process::exit( 1 ); } );
         //}
 
-        //let _ = io::stdout().flush();
+        // The colors of legacy Windows consoles are applied immediately, so the text must not be kept in the buffer
+        let full = self.term_stdout.get_ref().len() >= STDOUT_BUF_BYTES;
+        let immediate = self.backend == ColorBackend::WinApi;
+        if immediate || val.ends_with('\n') && (full || self.line_buffered) {
+            self.flush_stdout();
+        }
+    }
+
+    // The buffer is written by one call with the lock not to be interleaved with other threads
+    fn flush_stdout(&mut self) {
+        let buf = self.term_stdout.get_mut();
+        if buf.is_empty() {
+            return;
+        }
+        let result = match self.output {
+            Some(ref mut x) => x.write_all(buf),
+            None => io::stdout().lock().write_all(buf),
+        };
+        result.unwrap_or_else(|_| {
+            process::exit(1);
+        });
+        buf.clear();
     }
 
     fn write_stderr(&mut self, val: &str, color: Color) {
@@ -200,3 +1038,430 @@ impl Console {
         //let _ = io::stderr().flush();
     }
 }
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        self.flush_stdout();
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Diagnostic
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Diagnostic message of the pipeline split into the path and the kind like `binary_file` or `permission_denied`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub path: Option<String>,
+    pub kind: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Parses messages of the forms `Label: <path> ( reason )`, `Label: reason @ <path>` and `Label: text`
+    pub fn parse(msg: &str) -> Self {
+        let message = msg.trim();
+        let (label, rest) = match message.find(": ") {
+            Some(x) => (&message[..x], &message[x + 2..]),
+            None => (message, ""),
+        };
+
+        let (path, kind) = if let (Some(x), true) = (rest.rfind(" ( "), rest.ends_with(')')) {
+            // `Skipped` is distinguished by the reason, others like `Noisy` and `Retried` by the label
+            let reason = rest[x + 3..rest.len() - 1].trim();
+            let kind = if label == "Skipped" { reason } else { label };
+            (Some(unquote(&rest[..x])), kind)
+        } else if let Some(x) = rest.rfind(" @ ") {
+            let reason = &rest[..x];
+            let reason = reason.split(" ( ").next().unwrap_or(reason);
+            let reason = reason.split(": ").next().unwrap_or(reason);
+            (Some(unquote(&rest[x + 3..])), reason)
+        } else if rest.starts_with('"') && rest.ends_with('"') {
+            (Some(unquote(rest)), label)
+        } else {
+            (None, label)
+        };
+
+        Diagnostic {
+            path,
+            kind: snake_case(kind),
+            message: String::from(message),
+        }
+    }
+}
+
+// Words are joined by `_` like `read_only_file`
+fn omitted_marker(num: usize, preview: bool) -> String {
+    if preview {
+        format!("[...{} chars]", num)
+    } else {
+        String::from("...")
+    }
+}
+
+/// Returns the characters of `before` and `after` kept to fit a line in `max` characters with the match centered,
+/// where the room not used by one side is given to the other side
+fn truncate_around(before: usize, matched: usize, after: usize, max: usize) -> (usize, usize) {
+    if before + matched + after <= max {
+        return (before, after);
+    }
+    let room = max.saturating_sub(matched);
+    let keep_after = cmp::min(after, room - cmp::min(before, room / 2));
+    let keep_before = cmp::min(before, room - keep_after);
+    (keep_before, keep_after)
+}
+
+fn snake_case(src: &str) -> String {
+    src.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+// Paths printed by `{:?}` are unescaped, and others are returned as is
+fn unquote(src: &str) -> String {
+    if src.len() < 2 || !src.starts_with('"') || !src.ends_with('"') {
+        return String::from(src);
+    }
+    let mut ret = String::new();
+    let mut chars = src[1..src.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('r') => ret.push('\r'),
+            Some('t') => ret.push('\t'),
+            Some('0') => ret.push('\0'),
+            Some('u') => {
+                let hex: String = chars.by_ref().skip(1).take_while(|&x| x != '}').collect();
+                if let Some(x) = u32::from_str_radix(&hex, 16).ok().and_then(std::char::from_u32) {
+                    ret.push(x);
+                }
+            }
+            Some(x) => ret.push(x),
+            None => (),
+        }
+    }
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Hex dump
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Bytes of each row of hex dumps
+pub const HEX_ROW: usize = 16;
+
+/// Returns bytes as space-separated hex like `de ad be ef`
+pub fn hex_bytes(src: &[u8]) -> String {
+    src.iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(" ")
+}
+
+// Non-printable bytes are shown as `.` like xxd
+fn ascii_dump(src: &[u8]) -> String {
+    src.iter()
+        .map(|&x| if (0x20..0x7f).contains(&x) { x as char } else { '.' })
+        .collect()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Diff
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffKind {
+    Equal,
+    Delete,
+    Insert,
+}
+
+// Words, whitespaces and other characters are compared as tokens
+fn tokenize(src: &str) -> Vec<&str> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let mut ret = Vec::new();
+    let mut beg = 0;
+    let mut prev = None;
+    for (i, c) in src.char_indices() {
+        let cls = class(c);
+        if i > beg && (prev != Some(cls) || cls == 2) {
+            ret.push(&src[beg..i]);
+            beg = i;
+        }
+        prev = Some(cls);
+    }
+    if beg < src.len() {
+        ret.push(&src[beg..]);
+    }
+    ret
+}
+
+fn push_diff(ret: &mut Vec<(DiffKind, String)>, kind: DiffKind, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match ret.last_mut() {
+        Some((k, t)) if *k == kind => t.push_str(text),
+        _ => ret.push((kind, String::from(text))),
+    }
+}
+
+// A pair of changed words is compared by characters to show a subtle change like a typo
+fn push_changed(ret: &mut Vec<(DiffKind, String)>, old: &str, new: &str) {
+    let prefix = old
+        .char_indices()
+        .zip(new.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(old.len().min(new.len()), |((i, _), _)| i);
+    let suffix = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .sum::<usize>();
+    if prefix + suffix == 0 {
+        push_diff(ret, DiffKind::Delete, old);
+        push_diff(ret, DiffKind::Insert, new);
+        return;
+    }
+    push_diff(ret, DiffKind::Equal, &old[..prefix]);
+    push_diff(ret, DiffKind::Delete, &old[prefix..old.len() - suffix]);
+    push_diff(ret, DiffKind::Insert, &new[prefix..new.len() - suffix]);
+    push_diff(ret, DiffKind::Equal, &old[old.len() - suffix..]);
+}
+
+fn diff_words(old: &str, new: &str) -> Vec<(DiffKind, String)> {
+    let a = tokenize(old);
+    let b = tokenize(new);
+
+    // Too long text is shown as the whole deletion and insertion
+    let mut ret = Vec::new();
+    if a.len() * b.len() > 1_000_000 {
+        push_diff(&mut ret, DiffKind::Delete, old);
+        push_diff(&mut ret, DiffKind::Insert, new);
+        return ret;
+    }
+
+    // Longest common subsequence of tokens
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut deleted = String::new();
+    let mut inserted = String::new();
+    let flush = |ret: &mut Vec<(DiffKind, String)>, deleted: &mut String, inserted: &mut String| {
+        if !deleted.is_empty() && !inserted.is_empty() && tokenize(deleted).len() == 1 && tokenize(inserted).len() == 1
+        {
+            push_changed(ret, deleted, inserted);
+        } else {
+            push_diff(ret, DiffKind::Delete, deleted);
+            push_diff(ret, DiffKind::Insert, inserted);
+        }
+        deleted.clear();
+        inserted.clear();
+    };
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            flush(&mut ret, &mut deleted, &mut inserted);
+            push_diff(&mut ret, DiffKind::Equal, a[i]);
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            inserted.push_str(b[j]);
+            j += 1;
+        } else {
+            deleted.push_str(a[i]);
+            i += 1;
+        }
+    }
+    flush(&mut ret, &mut deleted, &mut inserted);
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn console_diff_words() {
+        let d = |kind, text: &str| (kind, String::from(text));
+
+        assert_eq!(
+            diff_words("let color = 1", "let colour = 1"),
+            vec![
+                d(DiffKind::Equal, "let colo"),
+                d(DiffKind::Insert, "u"),
+                d(DiffKind::Equal, "r = 1"),
+            ]
+        );
+        assert_eq!(
+            diff_words("foo(a, b)", "bar(a)"),
+            vec![
+                d(DiffKind::Delete, "foo"),
+                d(DiffKind::Insert, "bar"),
+                d(DiffKind::Equal, "(a"),
+                d(DiffKind::Delete, ", b"),
+                d(DiffKind::Equal, ")"),
+            ]
+        );
+        assert_eq!(diff_words("abc", "abc"), vec![d(DiffKind::Equal, "abc")]);
+        assert_eq!(diff_words("", "x"), vec![d(DiffKind::Insert, "x")]);
+    }
+
+    #[test]
+    fn console_color_backend() {
+        let mut console = Console::with_backend(ColorBackend::Ansi);
+        assert_eq!(console.backend(), ColorBackend::Ansi);
+        console.line_buffered = false;
+        console.hyperlink_format = Some(String::from("file://{path}"));
+        console.write_filename(Path::new("/a"), 1, 1);
+        let buf = console.term_stdout.get_mut();
+        assert!(buf.starts_with(b"\x1b["));
+        assert!(String::from_utf8_lossy(buf).contains("\x1b]8;;file:///a"));
+        buf.clear();
+
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().canonicalize().unwrap();
+        console.path_prefix_map = vec![PathPrefix {
+            from: real.clone(),
+            to: std::path::PathBuf::from("/host"),
+        }];
+        std::fs::write(dir.path().join("b.rs"), "").unwrap();
+        console.write_filename(&dir.path().join("b.rs"), 1, 1);
+        let buf = String::from_utf8_lossy(console.term_stdout.get_ref()).into_owned();
+        assert!(buf.contains("\x1b]8;;file:///host/b.rs"));
+        assert!(!buf.contains(&*real.to_string_lossy()));
+    }
+
+    #[test]
+    fn console_color_choice() {
+        assert_eq!("never".parse(), Ok(ColorChoice::Never));
+        assert!("yes".parse::<ColorChoice>().is_err());
+
+        assert!(ColorChoice::Auto.decide(true, false, false));
+        assert!(!ColorChoice::Auto.decide(false, false, false));
+        assert!(ColorChoice::Auto.decide(false, false, true));
+        assert!(!ColorChoice::Auto.decide(true, true, true));
+        assert!(ColorChoice::Always.decide(false, true, false));
+        assert!(!ColorChoice::Never.decide(true, false, true));
+    }
+
+    #[test]
+    fn console_diagnostic() {
+        let d = Diagnostic::parse("Skipped: \"a/b c\" ( binary file )\n");
+        assert_eq!(d.path, Some(String::from("a/b c")));
+        assert_eq!(d.kind, "binary_file");
+        assert_eq!(d.message, "Skipped: \"a/b c\" ( binary file )");
+
+        let d = Diagnostic::parse("Error: permission denied @ \"a\\\"b\"");
+        assert_eq!(d.path, Some(String::from("a\"b")));
+        assert_eq!(d.kind, "permission_denied");
+
+        let d = Diagnostic::parse("Error: file is read-only ( use --force-writable to override ) @ \"a\"");
+        assert_eq!(d.kind, "file_is_read_only");
+
+        let d = Diagnostic::parse("Error: git: fatal: bad revision @ ./src");
+        assert_eq!(d.path, Some(String::from("./src")));
+        assert_eq!(d.kind, "git");
+
+        let d = Diagnostic::parse("Noisy: \"a.min.js\" ( more than 100 matches )");
+        assert_eq!(d.kind, "noisy");
+
+        let d = Diagnostic::parse("Aborted: no file is replaced because of the errors above");
+        assert_eq!(d.path, None);
+        assert_eq!(d.kind, "aborted");
+    }
+
+    #[test]
+    fn console_diff_line() {
+        let mut console = Console::with_backend(ColorBackend::Ansi);
+        console.is_color = false;
+        console.line_buffered = false;
+        let src = b"let a = foo(1,\r\n    2);\r\nend";
+        let m = Match {
+            beg: 8,
+            end: 22,
+            sub_match: Vec::new(),
+            keyword: 0,
+        };
+        console.write_diff_line(src, &m, b"bar(1, 2)", false, 4);
+        console.write_diff_line(src, &m, b"bar(1, 2)", true, 4);
+        let buf = console.term_stdout.get_mut();
+        assert_eq!(
+            String::from_utf8_lossy(buf),
+            "let a = foo(1,\n        2);\nlet a = bar(1, 2);\n"
+        );
+        buf.clear();
+    }
+
+    #[test]
+    fn console_max_columns() {
+        assert_eq!(truncate_around(10, 4, 10, 30), (10, 10));
+        assert_eq!(truncate_around(10, 4, 10, 10), (3, 3));
+        assert_eq!(truncate_around(1, 4, 10, 10), (1, 5));
+        assert_eq!(truncate_around(10, 4, 2, 10), (4, 2));
+        assert_eq!(truncate_around(10, 20, 10, 10), (0, 0));
+
+        let mut console = Console::with_backend(ColorBackend::Ansi);
+        console.is_color = false;
+        console.line_buffered = false;
+        console.max_columns = Some(10);
+        let src = "0123456789abcdefghij\nshort foo\n".as_bytes();
+        let m = |beg, end| Match {
+            beg,
+            end,
+            sub_match: Vec::new(),
+            keyword: 0,
+        };
+        console.write_match_line(src, &m(10, 12));
+        console.write_match_line(src, &m(27, 30));
+        console.max_columns_preview = true;
+        console.write_matches_line(src, &[m(1, 2), m(4, 6)]);
+        let buf = console.term_stdout.get_mut();
+        assert_eq!(
+            String::from_utf8_lossy(buf),
+            "...6789abcdef...\nshort foo\n0123456789[...10 chars]\n"
+        );
+        buf.clear();
+
+        let m = m(10, 12);
+        console.write_diff_line(src, &m, b"ABCD", false, 4);
+        console.write_diff_line(src, &m, b"ABCD", true, 4);
+        let buf = console.term_stdout.get_mut();
+        assert_eq!(
+            String::from_utf8_lossy(buf),
+            "[...7 chars]789abcde[...5 chars]\n[...7 chars]789ABCDcde[...5 chars]\n"
+        );
+        buf.clear();
+    }
+
+    #[test]
+    fn console_hex_dump() {
+        assert_eq!(hex_bytes(b"\xde\xad\x00A"), "de ad 00 41");
+        assert_eq!(ascii_dump(b"\xdeA \x7f~"), ".A .~");
+    }
+}
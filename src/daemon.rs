@@ -0,0 +1,215 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Daemon
+// ---------------------------------------------------------------------------------------------------------------------
+
+// A request is the working directory of the client and the command line arguments, separated by NUL.
+// The response is the output of the command followed by NUL and the exit status of the command ( 1 byte ),
+// and the connection is closed when the command is finished.
+
+// Exit status of the command killed by a signal ( same as EXIT_ERROR of util )
+#[cfg(not(windows))]
+const STATUS_KILLED: u8 = 2;
+
+fn encode_request(cwd: &Path, args: &[String]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    ret.extend_from_slice(cwd.to_string_lossy().as_bytes());
+    for a in args {
+        ret.push(0);
+        ret.extend_from_slice(a.as_bytes());
+    }
+    ret
+}
+
+fn decode_request(src: &[u8]) -> Result<(PathBuf, Vec<String>), Error> {
+    let src = String::from_utf8(src.to_vec()).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+    let mut fields = src.split('\0');
+    let cwd = PathBuf::from(fields.next().unwrap_or(""));
+    let args = fields.map(String::from).collect();
+    Ok((cwd, args))
+}
+
+/// Serves requests at `socket`. `handler` runs in a forked process whose stdout/stderr is the connection.
+/// `refresh` runs in the daemon after forking each request to update the state inherited by the next requests.
+/// `socket` left by the previous daemon is replaced, but the other kinds of files are never removed.
+#[cfg(not(windows))]
+pub fn serve<F: FnMut(Vec<String>), G: FnMut()>(socket: &Path, mut handler: F, mut refresh: G) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+
+    match std::fs::symlink_metadata(socket) {
+        Ok(x) if x.file_type().is_socket() => std::fs::remove_file(socket)?,
+        Ok(_) => return Err(Error::new(ErrorKind::AlreadyExists, "file exists and is not a socket")),
+        Err(_) => (),
+    }
+    let listener = UnixListener::bind(socket)?;
+
+    unsafe {
+        libc::signal(libc::SIGCHLD, libc::SIG_IGN);
+    }
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+
+        let mut buf = Vec::new();
+        if stream.read_to_end(&mut buf).is_err() {
+            continue;
+        }
+        let (cwd, args) = match decode_request(&buf) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+
+        // The forked process waits the command in another forked process to send the exit status,
+        // so the daemon itself doesn't wait anything and the finished processes are reaped by SIG_IGN.
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            unsafe {
+                libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+            }
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                unsafe {
+                    libc::dup2(stream.as_raw_fd(), 1);
+                    libc::dup2(stream.as_raw_fd(), 2);
+                }
+                if let Err(e) = std::env::set_current_dir(&cwd) {
+                    let _ = writeln!(stream, "Error: {} @ {:?}", e, cwd);
+                    std::process::exit(i32::from(STATUS_KILLED));
+                }
+                handler(args);
+                std::process::exit(0);
+            } else if pid < 0 {
+                let _ = writeln!(stream, "Error: {}", Error::last_os_error());
+                let _ = stream.write_all(&[0, STATUS_KILLED]);
+                std::process::exit(0);
+            }
+
+            let mut status = 0;
+            let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
+            let code = if ret == pid && libc::WIFEXITED(status) {
+                libc::WEXITSTATUS(status) as u8
+            } else {
+                STATUS_KILLED
+            };
+            let _ = stream.write_all(&[0, code]);
+            std::process::exit(0);
+        } else if pid < 0 {
+            let _ = writeln!(stream, "Error: {}", Error::last_os_error());
+            let _ = stream.write_all(&[0, STATUS_KILLED]);
+        }
+        // The connection is closed in the daemon at first not to keep the client waiting while `refresh`.
+        // The daemon has no thread while forking, so `refresh` must finish before accepting the next request.
+        drop(stream);
+        refresh();
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn serve<F: FnMut(Vec<String>), G: FnMut()>(_socket: &Path, _handler: F, _refresh: G) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Other, "daemon mode is not supported on Windows"))
+}
+
+/// Sends `args` to the daemon at `socket`, copies the output to stdout, and returns the exit status of the command.
+#[cfg(not(windows))]
+pub fn request(socket: &Path, args: &[String]) -> Result<i32, Error> {
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let cwd = std::env::current_dir()?;
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(&encode_request(&cwd, args))?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let status = copy_response(&mut stream, &mut stdout)?;
+    stdout.flush()?;
+    Ok(status)
+}
+
+// The last 2 bytes are held back because they may be the exit status
+fn copy_response<R: Read, W: Write>(src: &mut R, dst: &mut W) -> Result<i32, Error> {
+    let mut buf = [0; 8192];
+    let mut pending = Vec::new();
+    loop {
+        let len = src.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        pending.extend_from_slice(&buf[..len]);
+        if pending.len() > 2 {
+            let end = pending.len() - 2;
+            dst.write_all(&pending[..end])?;
+            pending.drain(..end);
+        }
+    }
+    match pending[..] {
+        [0, status] => Ok(i32::from(status)),
+        _ => {
+            dst.write_all(&pending)?;
+            Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed without the exit status",
+            ))
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn request(_socket: &Path, _args: &[String]) -> Result<i32, Error> {
+    Err(Error::new(ErrorKind::Other, "daemon mode is not supported on Windows"))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_request() {
+        let args = vec![String::from("ambs"), String::from("key word"), String::from("./src")];
+        let req = encode_request(Path::new("/tmp"), &args);
+        let (cwd, ret) = decode_request(&req).unwrap();
+        assert_eq!(cwd, PathBuf::from("/tmp"));
+        assert_eq!(ret, args);
+    }
+
+    #[test]
+    fn daemon_response() {
+        let mut dst = Vec::new();
+        let status = copy_response(&mut &b"a.txt:1:keyword\n\x00\x01"[..], &mut dst).unwrap();
+        assert_eq!(dst, b"a.txt:1:keyword\n");
+        assert_eq!(status, 1);
+
+        let mut dst = Vec::new();
+        assert_eq!(copy_response(&mut &b"\x00\x00"[..], &mut dst).unwrap(), 0);
+        assert!(dst.is_empty());
+
+        let mut dst = Vec::new();
+        assert!(copy_response(&mut &b"a.txt"[..], &mut dst).is_err());
+        assert_eq!(dst, b"a.txt");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn daemon_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.sock");
+        std::fs::write(&path, b"keep").unwrap();
+        let ret = serve(&path, |_| (), || ());
+        assert_eq!(ret.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"keep");
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(any(feature = "zstd", feature = "xz", feature = "bzip2"))]
+use std::io::Read;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Compression
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Detects the compression by the extension, or by the magic bytes at the beginning of `src`
+    pub fn detect(path: &Path, src: &[u8]) -> Option<Self> {
+        let ext = path.extension().map(|x| x.to_string_lossy().to_lowercase());
+        match ext.as_deref() {
+            Some("zst") => return Some(Compression::Zstd),
+            Some("xz") => return Some(Compression::Xz),
+            Some("bz2") => return Some(Compression::Bzip2),
+            _ => (),
+        }
+        if src.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if src.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if src.starts_with(b"BZh") && src.get(3).is_some_and(|x| (b'1'..=b'9').contains(x)) {
+            Some(Compression::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether the decoder is enabled by the feature of the same name
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Xz => cfg!(feature = "xz"),
+            Compression::Bzip2 => cfg!(feature = "bzip2"),
+        }
+    }
+}
+
+/// Returns the decompressed contents, or None if `src` is not compressed by the formats of the enabled features
+pub fn decompress(path: &Path, src: &[u8]) -> Option<Result<Vec<u8>, Error>> {
+    let compression = Compression::detect(path, src).filter(|x| x.is_enabled())?;
+    Some(decode(compression, src))
+}
+
+#[cfg(any(feature = "zstd", feature = "xz", feature = "bzip2"))]
+fn decode(compression: Compression, mut src: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut ret = Vec::new();
+    match compression {
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut decoder = ruzstd::streaming_decoder::StreamingDecoder::new(&mut src)
+                .map_err(|_| Error::from(ErrorKind::InvalidData))?;
+            decoder.read_to_end(&mut ret)?;
+        }
+        #[cfg(feature = "xz")]
+        Compression::Xz => {
+            lzma_rs::xz_decompress(&mut src, &mut ret).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+        }
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => {
+            bzip2_rs::DecoderReader::new(src).read_to_end(&mut ret)?;
+        }
+        #[allow(unreachable_patterns)]
+        _ => return Err(Error::from(ErrorKind::Unsupported)),
+    }
+    Ok(ret)
+}
+
+#[cfg(not(any(feature = "zstd", feature = "xz", feature = "bzip2")))]
+fn decode(_compression: Compression, _src: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::from(ErrorKind::Unsupported))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_detect() {
+        let detect = |path: &str, src: &[u8]| Compression::detect(Path::new(path), src);
+        assert_eq!(detect("syslog.2.zst", b""), Some(Compression::Zstd));
+        assert_eq!(detect("syslog.2.XZ", b""), Some(Compression::Xz));
+        assert_eq!(detect("syslog.2.bz2", b""), Some(Compression::Bzip2));
+        assert_eq!(
+            detect("syslog.2", &[0x28, 0xb5, 0x2f, 0xfd, 0x00]),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(detect("syslog.2", b"\xfd7zXZ\x00\x00"), Some(Compression::Xz));
+        assert_eq!(detect("syslog.2", b"BZh91AY&SY"), Some(Compression::Bzip2));
+        assert_eq!(detect("syslog.2", b"BZh is text"), None);
+        assert_eq!(detect("syslog.2", b"text"), None);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::Error;
+#[cfg(feature = "document")]
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Document
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Returns the text extracted from a document ( .pdf, .docx, .odt ), or None if the path is not a supported document
+#[cfg(feature = "document")]
+pub fn extract_text(path: &Path) -> Option<Result<Vec<u8>, Error>> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    let entry = match ext.as_str() {
+        "pdf" => return Some(extract_pdf(path)),
+        "docx" => "word/document.xml",
+        "odt" => "content.xml",
+        _ => return None,
+    };
+    Some(extract_zip_xml(path, entry))
+}
+
+#[cfg(not(feature = "document"))]
+pub fn extract_text(_path: &Path) -> Option<Result<Vec<u8>, Error>> {
+    None
+}
+
+#[cfg(feature = "document")]
+fn extract_pdf(path: &Path) -> Result<Vec<u8>, Error> {
+    let src = std::fs::read(path)?;
+    // pdf-extract panics on some malformed files, which should not stop the whole search
+    match std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(&src)) {
+        Ok(Ok(x)) => Ok(x.into_bytes()),
+        _ => Err(Error::from(ErrorKind::InvalidData)),
+    }
+}
+
+// Office Open XML and OpenDocument are zip archives containing the body as XML
+#[cfg(feature = "document")]
+fn extract_zip_xml(path: &Path, entry: &str) -> Result<Vec<u8>, Error> {
+    let file = std::fs::File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+    let mut xml = String::new();
+    archive
+        .by_name(entry)
+        .map_err(|_| Error::from(ErrorKind::InvalidData))?
+        .read_to_string(&mut xml)?;
+    Ok(xml_text(&xml).into_bytes())
+}
+
+// Paragraphs and line breaks of WordprocessingML ( w: ) and OpenDocument ( text: ) are converted to line feeds
+#[cfg(any(feature = "document", test))]
+fn xml_text(src: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = src;
+    while let Some(beg) = rest.find('<') {
+        let end = match rest[beg..].find('>') {
+            Some(x) => beg + x,
+            None => break,
+        };
+        ret.push_str(&decode_entities(&rest[..beg]));
+        let tag = rest[beg + 1..end].trim_end_matches('/');
+        let name = tag.split_whitespace().next().unwrap_or("");
+        match name {
+            "/w:p" | "/text:p" | "/text:h" | "w:br" | "w:cr" | "text:line-break" => ret.push('\n'),
+            "w:tab" | "text:tab" => ret.push('\t'),
+            _ => (),
+        }
+        rest = &rest[end + 1..];
+    }
+    ret.push_str(&decode_entities(rest));
+    ret
+}
+
+#[cfg(any(feature = "document", test))]
+fn decode_entities(src: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = src;
+    while let Some(beg) = rest.find('&') {
+        let end = match rest[beg..].find(';') {
+            Some(x) => beg + x,
+            None => break,
+        };
+        ret.push_str(&rest[..beg]);
+        let entity = &rest[beg + 1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => ret.push(c),
+            None => ret.push_str(&rest[beg..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_xml_text() {
+        let xml = r#"<?xml version="1.0"?><w:body><w:p><w:r><w:t>Invoice</w:t><w:tab/><w:t xml:space="preserve">A &amp; B</w:t></w:r></w:p><w:p><w:r><w:t>&#x3042;&#65;&nbsp;</w:t></w:r></w:p></w:body>"#;
+        assert_eq!(xml_text(xml), "Invoice\tA & B\n\u{3042}A&nbsp;\n");
+
+        let xml = r#"<office:text><text:h>Title</text:h><text:p>a<text:line-break/>b</text:p></office:text>"#;
+        assert_eq!(xml_text(xml), "Title\na\nb\n");
+    }
+}
@@ -0,0 +1,314 @@
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// EditorConfig
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EndOfLine {
+    Lf,
+    Crlf,
+    Cr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndentStyle {
+    Space,
+    Tab,
+}
+
+/// Properties of `.editorconfig` applied to replacements
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditorConfig {
+    pub end_of_line: Option<EndOfLine>,
+    pub insert_final_newline: Option<bool>,
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+}
+
+impl EditorConfig {
+    /// Loads the properties of `path` from `.editorconfig` in the directory and the parents up to `root = true`.
+    /// Properties of closer files take precedence.
+    pub fn load(path: &Path) -> Self {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let mut files = Vec::new();
+        for dir in path.ancestors().skip(1) {
+            if let Ok(src) = fs::read_to_string(dir.join(".editorconfig")) {
+                let root = is_root(&src);
+                files.push((dir.to_path_buf(), src));
+                if root {
+                    break;
+                }
+            }
+        }
+
+        let mut ret = EditorConfig::default();
+        for (dir, src) in files.iter().rev() {
+            if let Ok(rel) = path.strip_prefix(dir) {
+                ret.parse(src, &rel.to_string_lossy().replace('\\', "/"));
+            }
+        }
+        ret
+    }
+
+    // Properties of sections matching `rel` ( the path relative to the directory of `.editorconfig` ) are set in order
+    fn parse(&mut self, src: &str, rel: &str) {
+        let mut matched = false;
+        for line in src.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                matched = section_matches(&line[1..line.len() - 1], rel);
+                continue;
+            }
+            if !matched {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(x) => (line[..x].trim().to_lowercase(), line[x + 1..].trim().to_lowercase()),
+                None => continue,
+            };
+            match key.as_str() {
+                "end_of_line" => {
+                    self.end_of_line = match value.as_str() {
+                        "lf" => Some(EndOfLine::Lf),
+                        "crlf" => Some(EndOfLine::Crlf),
+                        "cr" => Some(EndOfLine::Cr),
+                        _ => None,
+                    }
+                }
+                "insert_final_newline" => self.insert_final_newline = value.parse().ok(),
+                "indent_style" => {
+                    self.indent_style = match value.as_str() {
+                        "space" => Some(IndentStyle::Space),
+                        "tab" => Some(IndentStyle::Tab),
+                        _ => None,
+                    }
+                }
+                "indent_size" => self.indent_size = value.parse().ok(),
+                "tab_width" => self.tab_width = value.parse().ok(),
+                _ => (),
+            }
+        }
+    }
+
+    /// Converts line endings and indentation of the lines after the first line of `replacement`.
+    /// A final newline is added if `at_eof` ( the replacement ends the file ) and `insert_final_newline` is set.
+    pub fn apply(&self, replacement: &[u8], at_eof: bool) -> Vec<u8> {
+        let eol: &[u8] = match self.end_of_line {
+            Some(EndOfLine::Lf) => b"\n",
+            Some(EndOfLine::Crlf) => b"\r\n",
+            Some(EndOfLine::Cr) => b"\r",
+            None => b"",
+        };
+
+        let mut ret = Vec::new();
+        for (i, (line, end)) in split_lines(replacement).into_iter().enumerate() {
+            if i == 0 {
+                ret.extend_from_slice(line);
+            } else {
+                ret.extend(self.indent(line));
+            }
+            if !end.is_empty() {
+                ret.extend_from_slice(if eol.is_empty() { end } else { eol });
+            }
+        }
+
+        let ends_with_eol = ret.ends_with(b"\n") || ret.ends_with(b"\r");
+        if at_eof && self.insert_final_newline == Some(true) && !ends_with_eol {
+            ret.extend_from_slice(if eol.is_empty() { b"\n" } else { eol });
+        }
+        ret
+    }
+
+    fn indent(&self, line: &[u8]) -> Vec<u8> {
+        let style = match self.indent_style {
+            Some(x) => x,
+            None => return line.to_vec(),
+        };
+        let tab_width = self.tab_width.or(self.indent_size).unwrap_or(4).max(1);
+        let indent_size = self.indent_size.unwrap_or(tab_width).max(1);
+
+        let len = line
+            .iter()
+            .position(|x| *x != b' ' && *x != b'\t')
+            .unwrap_or(line.len());
+        let width: usize = line[..len]
+            .iter()
+            .map(|x| if *x == b'\t' { tab_width } else { 1 })
+            .sum();
+        let mut ret = match style {
+            IndentStyle::Space => vec![b' '; width],
+            IndentStyle::Tab => {
+                let mut x = vec![b'\t'; width / indent_size];
+                x.extend(vec![b' '; width % indent_size]);
+                x
+            }
+        };
+        ret.extend_from_slice(&line[len..]);
+        ret
+    }
+}
+
+fn is_root(src: &str) -> bool {
+    for line in src.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            break;
+        }
+        if let Some(x) = line.find('=') {
+            if line[..x].trim().eq_ignore_ascii_case("root") && line[x + 1..].trim().eq_ignore_ascii_case("true") {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Sections without `/` match the file name in any directory
+fn section_matches(section: &str, rel: &str) -> bool {
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    let name = rel.rsplit('/').next().unwrap_or(rel);
+    expand_braces(section).iter().any(|x| {
+        let (pattern, target) = if x.contains('/') {
+            (x.trim_start_matches('/'), rel)
+        } else {
+            (x.as_str(), name)
+        };
+        Pattern::new(pattern).is_ok_and(|p| p.matches_with(target, options))
+    })
+}
+
+// `{a,b}` is expanded to the alternatives, which are not supported by glob
+fn expand_braces(src: &str) -> Vec<String> {
+    let beg = match src.find('{') {
+        Some(x) => x,
+        None => return vec![String::from(src)],
+    };
+    let mut depth = 0;
+    let mut items = Vec::new();
+    let mut item_beg = beg + 1;
+    for (i, c) in src[beg..].char_indices().map(|(i, c)| (beg + i, c)) {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => {
+                items.push(&src[item_beg..i]);
+                if items.len() == 1 {
+                    break;
+                }
+                let mut ret = Vec::new();
+                for item in items {
+                    let x = format!("{}{}{}", &src[..beg], item, &src[i + 1..]);
+                    ret.extend(expand_braces(&x));
+                }
+                return ret;
+            }
+            '}' => depth -= 1,
+            ',' if depth == 1 => {
+                items.push(&src[item_beg..i]);
+                item_beg = i + 1;
+            }
+            _ => (),
+        }
+    }
+    vec![String::from(src)]
+}
+
+// Lines are split with the line endings ( LF, CRLF or CR )
+fn split_lines(src: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut ret = Vec::new();
+    let mut beg = 0;
+    let mut i = 0;
+    while i < src.len() {
+        let len = match src[i] {
+            b'\r' if src.get(i + 1) == Some(&b'\n') => 2,
+            b'\r' | b'\n' => 1,
+            _ => 0,
+        };
+        if len == 0 {
+            i += 1;
+            continue;
+        }
+        ret.push((&src[beg..i], &src[i..i + len]));
+        i += len;
+        beg = i;
+    }
+    if beg < src.len() || ret.is_empty() {
+        ret.push((&src[beg..], &src[src.len()..]));
+    }
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editorconfig_section() {
+        assert!(section_matches("*", "src/a.rs"));
+        assert!(section_matches("*.{rs,toml}", "src/a.rs"));
+        assert!(section_matches("*.{rs,toml}", "Cargo.toml"));
+        assert!(!section_matches("*.{rs,toml}", "README.md"));
+        assert!(section_matches("src/*.rs", "src/a.rs"));
+        assert!(!section_matches("src/*.rs", "src/x/a.rs"));
+        assert!(section_matches("/src/**/*.rs", "src/x/a.rs"));
+        assert!(section_matches("Makefile", "sub/Makefile"));
+    }
+
+    #[test]
+    fn editorconfig_load() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(
+            dir.path().join(".editorconfig"),
+            "root = true\n[*]\nend_of_line = crlf\nindent_style = space\n[*.go]\nindent_style = tab\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("sub/.editorconfig"),
+            "[*.go]\ninsert_final_newline = true\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("sub/a.go"), "").unwrap();
+
+        let config = EditorConfig::load(&dir.path().join("sub/a.go"));
+        assert_eq!(config.end_of_line, Some(EndOfLine::Crlf));
+        assert_eq!(config.indent_style, Some(IndentStyle::Tab));
+        assert_eq!(config.insert_final_newline, Some(true));
+    }
+
+    #[test]
+    fn editorconfig_apply() {
+        let config = EditorConfig {
+            end_of_line: Some(EndOfLine::Crlf),
+            insert_final_newline: Some(true),
+            indent_style: Some(IndentStyle::Tab),
+            indent_size: Some(4),
+            tab_width: None,
+        };
+        assert_eq!(config.apply(b"  a\n    b\n      c", false), b"  a\r\n\tb\r\n\t  c");
+        assert_eq!(config.apply(b"a\nb", true), b"a\r\nb\r\n");
+
+        let config = EditorConfig {
+            end_of_line: None,
+            insert_final_newline: None,
+            indent_style: Some(IndentStyle::Space),
+            indent_size: Some(2),
+            tab_width: None,
+        };
+        assert_eq!(config.apply(b"a\r\n\t\tb", true), b"a\r\n    b");
+    }
+}
@@ -0,0 +1,97 @@
+use glob::{MatchOptions, Pattern};
+use std::path::Path;
+use std::str::FromStr;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// EncodingRule
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Rule like `legacy/**=shift_jis`, where the pattern matches the path at any depth unless beginning with `/`
+#[derive(Clone, Debug)]
+pub struct EncodingRule {
+    pattern: Pattern,
+    pub encoding: String,
+}
+
+impl FromStr for EncodingRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, encoding) = match s.rsplit_once('=') {
+            Some((x, y)) if !x.is_empty() && !y.is_empty() => (x, y),
+            _ => return Err(format!("invalid encoding map: {}", s)),
+        };
+        let pattern = if pattern.starts_with('/') || pattern.starts_with("**") {
+            String::from(pattern)
+        } else {
+            format!("**/{}", pattern.trim_start_matches("./"))
+        };
+        let pattern = Pattern::new(&pattern).map_err(|e| format!("invalid encoding map: {} ( {} )", s, e.msg))?;
+        Ok(EncodingRule {
+            pattern,
+            encoding: String::from(encoding),
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// EncodingMap
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Encodings of files decided by the path, where the last rule matched wins and the others are UTF-8
+#[derive(Clone, Debug, Default)]
+pub struct EncodingMap {
+    rules: Vec<EncodingRule>,
+}
+
+impl EncodingMap {
+    pub fn new(rules: Vec<EncodingRule>) -> Self {
+        EncodingMap { rules }
+    }
+
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let path = path.to_string_lossy().replace('\\', "/");
+        let path = path.trim_start_matches("./");
+        self.rules
+            .iter()
+            .rev()
+            .find(|x| x.pattern.matches_with(path, options))
+            .map(|x| x.encoding.as_str())
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_map() {
+        let rules = [
+            "legacy/**=shift_jis",
+            "*.euc=EUC-JP",
+            "legacy/utf8/*=UTF-8",
+            "/srv/**=cp932",
+        ];
+        let map = EncodingMap::new(rules.iter().map(|x| x.parse().unwrap()).collect());
+        assert_eq!(map.lookup(Path::new("legacy/a/b.txt")), Some("shift_jis"));
+        assert_eq!(map.lookup(Path::new("./legacy/a.txt")), Some("shift_jis"));
+        assert_eq!(map.lookup(Path::new("src/legacy/a.txt")), Some("shift_jis"));
+        assert_eq!(map.lookup(Path::new("legacy/utf8/a.txt")), Some("UTF-8"));
+        assert_eq!(map.lookup(Path::new("doc/a.euc")), Some("EUC-JP"));
+        assert_eq!(map.lookup(Path::new("/srv/a.txt")), Some("cp932"));
+        assert_eq!(map.lookup(Path::new("legacy.txt")), None);
+        assert_eq!(map.lookup(Path::new("src/a.txt")), None);
+
+        assert!("legacy/**".parse::<EncodingRule>().is_err());
+        assert!("legacy/[=sjis".parse::<EncodingRule>().is_err());
+    }
+}
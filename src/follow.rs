@@ -0,0 +1,126 @@
+use crate::util::{file_id, long_path};
+use std::fs::{self, File};
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// FileFollower
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Growing file read like `tail -F`, which returns the lines appended after opening.
+///
+/// The file truncated or replaced by another file ( e.g. log rotation ) is followed again from the beginning.
+pub struct FileFollower {
+    path: PathBuf,
+    file: File,
+    id: Option<(u64, u64)>,
+    pos: u64,
+    // Bytes after the last line feed, which are returned when the line is completed
+    pending: Vec<u8>,
+    // Line number of the beginning of `pending`
+    line: usize,
+}
+
+impl FileFollower {
+    /// Opens `path` to follow the lines completed after now
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(long_path(path))?;
+        let id = file_id(&file.metadata()?);
+        let mut ret = FileFollower {
+            path: path.to_path_buf(),
+            file,
+            id,
+            pos: 0,
+            pending: Vec::new(),
+            line: 1,
+        };
+        // The lines already written are counted to number the lines appended
+        ret.read()?;
+        Ok(ret)
+    }
+
+    /// Returns the lines appended since the last call with the line number of the first line, or `None` if no line
+    /// is completed
+    pub fn poll(&mut self) -> Result<Option<(usize, Vec<u8>)>, Error> {
+        if let Some(x) = self.read()? {
+            return Ok(Some(x));
+        }
+
+        // The rest of the old file is read before following the new one
+        let metadata = match fs::metadata(long_path(&self.path)) {
+            Ok(x) => x,
+            Err(_) => return Ok(None),
+        };
+        let replaced = file_id(&metadata) != self.id;
+        if replaced || metadata.len() < self.pos {
+            self.file = File::open(long_path(&self.path))?;
+            self.id = file_id(&self.file.metadata()?);
+            self.pos = 0;
+            self.pending.clear();
+            self.line = 1;
+            return self.read();
+        }
+        Ok(None)
+    }
+
+    fn read(&mut self) -> Result<Option<(usize, Vec<u8>)>, Error> {
+        let mut buf = Vec::new();
+        self.file.read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        self.pos += buf.len() as u64;
+        self.pending.extend_from_slice(&buf);
+        let cut = match self.pending.iter().rposition(|x| *x == 0x0a) {
+            Some(x) => x + 1,
+            None => return Ok(None),
+        };
+        let rest = self.pending.split_off(cut);
+        let lines = std::mem::replace(&mut self.pending, rest);
+        let line = self.line;
+        self.line += memchr::memchr_iter(0x0a, &lines).count();
+        Ok(Some((line, lines)))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn follow() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.log");
+        fs::write(&path, "a\nb\nc").unwrap();
+        let append = |x: &str| {
+            OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(x.as_bytes())
+                .unwrap()
+        };
+
+        let mut follower = FileFollower::open(&path).unwrap();
+        assert_eq!(follower.poll().unwrap(), None);
+        append("d\ne");
+        assert_eq!(follower.poll().unwrap(), Some((3, b"cd\n".to_vec())));
+        assert_eq!(follower.poll().unwrap(), None);
+        append("\nf\n");
+        assert_eq!(follower.poll().unwrap(), Some((4, b"e\nf\n".to_vec())));
+
+        fs::write(&path, "g\n").unwrap();
+        assert_eq!(follower.poll().unwrap(), Some((1, b"g\n".to_vec())));
+
+        let other = dir.path().join("b.log");
+        fs::write(&other, "h\ni\n").unwrap();
+        fs::rename(&other, &path).unwrap();
+        assert_eq!(follower.poll().unwrap(), Some((1, b"h\ni\n".to_vec())));
+    }
+}
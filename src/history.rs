@@ -0,0 +1,205 @@
+use crate::util::try_lock_exclusive;
+use serde_derive::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// History
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Entries older than this are dropped when the file grows to twice of this
+pub const MAX_ENTRIES: usize = 1000;
+
+// The lock is given up after about 1s because the history is not worth blocking the search
+const LOCK_RETRIES: usize = 100;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// `ambs` or `ambr`
+    pub command: String,
+    /// Arguments given in the command-line without the command itself
+    pub args: Vec<String>,
+    pub keyword: Option<String>,
+    pub replacement: Option<String>,
+}
+
+/// Recent searches and replacements stored as JSON lines ( `~/.local/share/amber/history` on Linux )
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// Returns None unless `AMBER_HISTORY` is set, because keywords and replacements may be secrets
+    pub fn new() -> Option<Self> {
+        env::var_os("AMBER_HISTORY")?;
+        dirs::data_dir().map(|x| History::with_path(&x.join("amber").join("history")))
+    }
+
+    pub fn with_path(path: &Path) -> Self {
+        History {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Returns the latest `MAX_ENTRIES` entries from the oldest, where broken lines are skipped
+    pub fn load(&self) -> Vec<HistoryEntry> {
+        match fs::read_to_string(&self.path) {
+            Ok(x) => parse_entries(&x, MAX_ENTRIES),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Appends `entry` with the lock of the file not to lose entries of the other processes,
+    /// where the file is created readable only by the owner
+    pub fn push(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        #[cfg(not(windows))]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path)?;
+        lock(&file)?;
+
+        let mut src = String::new();
+        file.read_to_string(&mut src)?;
+        // The same command as the latest is not stored twice
+        let last = src
+            .lines()
+            .last()
+            .and_then(|x| serde_json::from_str::<HistoryEntry>(x).ok());
+        if last.as_ref() == Some(entry) {
+            return Ok(());
+        }
+
+        // The file is rewritten only sometimes, and the writes are appended to the end even after truncated
+        if src.lines().count() >= MAX_ENTRIES * 2 {
+            let entries = parse_entries(&src, MAX_ENTRIES - 1);
+            file.set_len(0)?;
+            let mut dst = String::new();
+            for x in &entries {
+                dst.push_str(&serde_json::to_string(x)?);
+                dst.push('\n');
+            }
+            file.write_all(dst.as_bytes())?;
+        }
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    /// Returns the keywords or replacements of `command` from the latest without duplicates for the recall
+    pub fn recall(&self, command: &str, replacement: bool) -> Vec<String> {
+        let mut ret: Vec<String> = Vec::new();
+        for x in self.load().into_iter().rev().filter(|x| x.command == command) {
+            let val = if replacement { x.replacement } else { x.keyword };
+            if let Some(val) = val {
+                if !ret.contains(&val) {
+                    ret.push(val);
+                }
+            }
+        }
+        ret
+    }
+}
+
+fn parse_entries(src: &str, max: usize) -> Vec<HistoryEntry> {
+    let entries: Vec<HistoryEntry> = src.lines().filter_map(|x| serde_json::from_str(x).ok()).collect();
+    let skip = entries.len().saturating_sub(max);
+    entries.into_iter().skip(skip).collect()
+}
+
+// The lock is released when the file is closed
+fn lock(file: &File) -> Result<(), Error> {
+    for _ in 0..LOCK_RETRIES {
+        if try_lock_exclusive(file)? {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err(Error::new(
+        ErrorKind::WouldBlock,
+        "history is locked by another process",
+    ))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_push() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::with_path(&dir.path().join("amber").join("history"));
+        let entry = |command: &str, keyword: &str, replacement: Option<&str>| HistoryEntry {
+            command: String::from(command),
+            args: vec![String::from(keyword)],
+            keyword: Some(String::from(keyword)),
+            replacement: replacement.map(String::from),
+        };
+
+        history.push(&entry("ambs", "foo", None)).unwrap();
+        history.push(&entry("ambr", "bar", Some("baz"))).unwrap();
+        history.push(&entry("ambr", "bar", Some("baz"))).unwrap();
+        history.push(&entry("ambr", "qux", Some("baz"))).unwrap();
+        assert_eq!(history.load().len(), 3);
+        assert_eq!(history.recall("ambr", false), vec!["qux", "bar"]);
+        assert_eq!(history.recall("ambr", true), vec!["baz"]);
+        assert_eq!(history.recall("ambs", false), vec!["foo"]);
+
+        #[cfg(not(windows))]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join("amber").join("history"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        for i in 0..MAX_ENTRIES * 2 {
+            history.push(&entry("ambs", &i.to_string(), None)).unwrap();
+        }
+        let entries = history.load();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries.last().unwrap().keyword.as_deref(), Some("1999"));
+    }
+
+    #[test]
+    fn history_push_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let history = History::with_path(&path);
+                thread::spawn(move || {
+                    for j in 0..10 {
+                        let keyword = format!("{}-{}", i, j);
+                        let entry = HistoryEntry {
+                            command: String::from("ambs"),
+                            args: vec![keyword.clone()],
+                            keyword: Some(keyword),
+                            replacement: None,
+                        };
+                        history.push(&entry).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for x in threads {
+            x.join().unwrap();
+        }
+        assert_eq!(History::with_path(&path).load().len(), 80);
+    }
+}
@@ -66,7 +66,13 @@ pub struct IgnoreGit {
 
 impl IgnoreGit {
     pub fn new(path: &PathBuf) -> Self {
-        let (f_name, f_path, d_name, d_path) = IgnoreGit::parse(&path);
+        let base = path.parent().unwrap().to_path_buf();
+        IgnoreGit::with_base(path, &base)
+    }
+
+    /// Patterns containing `/` are relative to `base` instead of the directory of the file ( e.g. `info/exclude` )
+    pub fn with_base(path: &PathBuf, base: &PathBuf) -> Self {
+        let (f_name, f_path, d_name, d_path) = IgnoreGit::parse(&path, &base);
         IgnoreGit {
             file_name: f_name,
             file_path: f_path,
@@ -82,6 +88,7 @@ impl IgnoreGit {
 
     fn parse(
         path: &PathBuf,
+        base: &PathBuf,
     ) -> (
         Vec<IgnoreGitPat>,
         Vec<IgnoreGitPat>,
@@ -100,7 +107,7 @@ impl IgnoreGit {
         };
         let f = BufReader::new(f);
 
-        let base = path.parent().unwrap().to_string_lossy();
+        let base = base.to_string_lossy();
 
         for line in f.lines() {
             let s = line.unwrap();
@@ -0,0 +1,188 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Index
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub const INDEX_DIR: &str = ".amber-index";
+const INDEX_FILE: &str = "index";
+// mtime is stored in nanoseconds since version 2
+const INDEX_MAGIC: &[u8] = b"AMBERIDX2\n";
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    /// Nanoseconds since the unix epoch not to miss files rewritten in the same second
+    pub mtime: u64,
+    pub size: u64,
+    pub trigrams: Vec<u32>,
+}
+
+/// Trigrams of each file, which are checked for each file found by the finder
+/// ( files are still walked and canonicalized, and only reading the files skipped is saved )
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    pub entries: HashMap<PathBuf, IndexEntry>,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Index {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn trigrams(src: &[u8]) -> Vec<u32> {
+        let mut set = HashSet::new();
+        for w in src.windows(3) {
+            set.insert((w[0] as u32) << 16 | (w[1] as u32) << 8 | w[2] as u32);
+        }
+        let mut ret: Vec<u32> = set.into_iter().collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    fn stat(path: &Path) -> Result<(PathBuf, u64, u64), Error> {
+        let real_path = fs::canonicalize(path)?;
+        let metadata = fs::metadata(&real_path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok((real_path, mtime, metadata.len()))
+    }
+
+    /// Adds `path` to the index. The entry of `old` is reused if the file is not modified.
+    pub fn add(&mut self, path: &Path, old: &Index) -> Result<(), Error> {
+        let (real_path, mtime, size) = Index::stat(path)?;
+        if let Some(x) = old.entries.get(&real_path) {
+            if x.mtime == mtime && x.size == size {
+                self.entries.insert(real_path, x.clone());
+                return Ok(());
+            }
+        }
+        let src = fs::read(&real_path)?;
+        let trigrams = Index::trigrams(&src);
+        self.entries.insert(real_path, IndexEntry { mtime, size, trigrams });
+        Ok(())
+    }
+
+    /// Returns false only if `path` is indexed, not modified after indexing and doesn't contain all `trigrams`.
+    pub fn may_contain(&self, path: &Path, trigrams: &[u32]) -> bool {
+        if trigrams.is_empty() {
+            return true;
+        }
+        let (real_path, mtime, size) = match Index::stat(path) {
+            Ok(x) => x,
+            Err(_) => return true,
+        };
+        match self.entries.get(&real_path) {
+            Some(x) if x.mtime == mtime && x.size == size => {
+                trigrams.iter().all(|t| x.trigrams.binary_search(t).is_ok())
+            }
+            _ => true,
+        }
+    }
+
+    pub fn load(dir: &Path) -> Result<Index, Error> {
+        let mut reader = BufReader::new(File::open(dir.join(INDEX_FILE))?);
+
+        let mut magic = vec![0u8; INDEX_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != INDEX_MAGIC {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+
+        let mut index = Index::new();
+        let num = read_u64(&mut reader)?;
+        for _ in 0..num {
+            let len = read_u32(&mut reader)? as usize;
+            let mut path = vec![0u8; len];
+            reader.read_exact(&mut path)?;
+            let path = String::from_utf8(path).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+            let mtime = read_u64(&mut reader)?;
+            let size = read_u64(&mut reader)?;
+            let len = read_u32(&mut reader)? as usize;
+            let mut trigrams = Vec::with_capacity(len);
+            for _ in 0..len {
+                trigrams.push(read_u32(&mut reader)?);
+            }
+            index
+                .entries
+                .insert(PathBuf::from(path), IndexEntry { mtime, size, trigrams });
+        }
+
+        Ok(index)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), Error> {
+        fs::create_dir_all(dir)?;
+        let mut writer = BufWriter::new(File::create(dir.join(INDEX_FILE))?);
+
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (path, entry) in &self.entries {
+            let path = path.to_string_lossy();
+            writer.write_all(&(path.len() as u32).to_le_bytes())?;
+            writer.write_all(path.as_bytes())?;
+            writer.write_all(&entry.mtime.to_le_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            writer.write_all(&(entry.trigrams.len() as u32).to_le_bytes())?;
+            for t in &entry.trigrams {
+                writer.write_all(&t.to_le_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index() {
+        let mut index = Index::new();
+        index.add(&PathBuf::from("./src/ambs.rs"), &Index::new()).unwrap();
+        index.add(&PathBuf::from("./src/util.rs"), &Index::new()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        index.save(dir.path()).unwrap();
+        let index = Index::load(dir.path()).unwrap();
+
+        let trigrams = Index::trigrams(b"amber");
+        assert!(index.may_contain(&PathBuf::from("./src/ambs.rs"), &trigrams));
+        assert!(!index.may_contain(&PathBuf::from("./src/util.rs"), &trigrams));
+        assert!(index.may_contain(&PathBuf::from("./src/lib.rs"), &trigrams));
+
+        // A file rewritten in the same second with the same size is searched
+        let path = dir.path().join("a.txt");
+        fs::write(&path, b"alpha").unwrap();
+        filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(1_700_000_000, 100_000_000)).unwrap();
+        let mut index = Index::new();
+        index.add(&path, &Index::new()).unwrap();
+        fs::write(&path, b"gamma").unwrap();
+        filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(1_700_000_000, 900_000_000)).unwrap();
+        assert!(index.may_contain(&path, &Index::trigrams(b"gamma")));
+    }
+}
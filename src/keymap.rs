@@ -0,0 +1,175 @@
+use std::str::FromStr;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// KeyAction
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyAction {
+    Yes,
+    No,
+    All,
+    AllInFile,
+    Skip,
+    Quit,
+    Edit,
+    Open,
+    Undo,
+}
+
+// Order of the prompt
+const ACTIONS: [KeyAction; 9] = [
+    KeyAction::Yes,
+    KeyAction::No,
+    KeyAction::All,
+    KeyAction::AllInFile,
+    KeyAction::Skip,
+    KeyAction::Quit,
+    KeyAction::Edit,
+    KeyAction::Open,
+    KeyAction::Undo,
+];
+
+impl KeyAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyAction::Yes => "yes",
+            KeyAction::No => "no",
+            KeyAction::All => "all",
+            KeyAction::AllInFile => "all_in_file",
+            KeyAction::Skip => "skip",
+            KeyAction::Quit => "quit",
+            KeyAction::Edit => "edit",
+            KeyAction::Open => "open",
+            KeyAction::Undo => "undo",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            KeyAction::AllInFile => "all in file",
+            KeyAction::Skip => "skip file",
+            x => x.name(),
+        }
+    }
+}
+
+impl FromStr for KeyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ACTIONS
+            .iter()
+            .find(|x| x.name() == s)
+            .copied()
+            .ok_or_else(|| format!("invalid key action: {}", s))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Keymap
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Keys of the interactive replacement, where Enter runs `enter`
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    keys: Vec<(char, KeyAction)>,
+    pub enter: KeyAction,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let keys = [
+            ("yY ", KeyAction::Yes),
+            ("nN", KeyAction::No),
+            ("a", KeyAction::All),
+            ("A", KeyAction::AllInFile),
+            ("sS", KeyAction::Skip),
+            ("qQ", KeyAction::Quit),
+            ("eE", KeyAction::Edit),
+            ("oO", KeyAction::Open),
+            ("uU", KeyAction::Undo),
+        ];
+        Keymap {
+            keys: keys.iter().flat_map(|(k, a)| k.chars().map(move |c| (c, *a))).collect(),
+            enter: KeyAction::Yes,
+        }
+    }
+}
+
+impl Keymap {
+    /// Replaces the keys of `action` by the characters of `keys`, which are unbound from the other actions
+    pub fn bind(&mut self, action: KeyAction, keys: &str) {
+        self.keys.retain(|(k, a)| *a != action && !keys.contains(*k));
+        self.keys.extend(keys.chars().map(|c| (c, action)));
+    }
+
+    pub fn action(&self, key: char) -> Option<KeyAction> {
+        if key == '\r' || key == '\n' {
+            return Some(self.enter);
+        }
+        self.keys.iter().find(|(k, _)| *k == key).map(|(_, a)| *a)
+    }
+
+    /// Prompt like `[Y]es/[n]o/...` showing the first key of each action, where the action of Enter is capitalized.
+    ///
+    /// The actions without keys and the ones not `available` are omitted.
+    pub fn prompt<F: Fn(KeyAction) -> bool>(&self, available: F) -> String {
+        let mut ret = Vec::new();
+        for action in ACTIONS.iter().copied().filter(|x| available(*x)) {
+            let key = match self.keys.iter().find(|(_, a)| *a == action) {
+                Some((k, _)) => *k,
+                None => continue,
+            };
+            let label = action.label();
+            let shown = if action == self.enter {
+                key.to_uppercase().to_string()
+            } else {
+                key.to_string()
+            };
+            match label.chars().next() {
+                Some(x) if x.eq_ignore_ascii_case(&key) => ret.push(format!("[{}]{}", shown, &label[1..])),
+                _ => ret.push(format!("[{}] {}", shown, label)),
+            }
+        }
+        ret.join("/")
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keymap() {
+        let mut keymap = Keymap::default();
+        assert_eq!(
+            keymap.prompt(|_| true),
+            "[Y]es/[n]o/[a]ll/[A]ll in file/[s]kip file/[q]uit/[e]dit/[o]pen/[u]ndo"
+        );
+        assert_eq!(keymap.action('\n'), Some(KeyAction::Yes));
+        assert_eq!(keymap.action('A'), Some(KeyAction::AllInFile));
+        assert_eq!(keymap.action('x'), None);
+
+        keymap.bind("all".parse().unwrap(), "!");
+        keymap.bind(KeyAction::AllInFile, "a");
+        keymap.enter = "no".parse().unwrap();
+        assert_eq!(keymap.action('\r'), Some(KeyAction::No));
+        assert_eq!(keymap.action('!'), Some(KeyAction::All));
+        assert_eq!(keymap.action('a'), Some(KeyAction::AllInFile));
+        assert_eq!(keymap.action('A'), None);
+        assert_eq!(
+            keymap.prompt(|x| x != KeyAction::Open),
+            "[y]es/[N]o/[!] all/[a]ll in file/[s]kip file/[q]uit/[e]dit/[u]ndo"
+        );
+
+        assert_eq!(
+            "retry".parse::<KeyAction>(),
+            Err(String::from("invalid key action: retry"))
+        );
+    }
+}
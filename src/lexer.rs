@@ -0,0 +1,352 @@
+use crate::matcher::Match;
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// TokenKind
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    Code,
+    Comment,
+    String,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Lexer
+// ---------------------------------------------------------------------------------------------------------------------
+
+// `(beg, end, kind, (beg, end) of the contents)`
+type Token = (usize, usize, TokenKind, (usize, usize));
+
+/// Lightweight lexer finding comments and string literals without parsing ( raw strings and heredocs are not supported )
+#[derive(Clone, Copy, Debug)]
+pub struct Lexer {
+    line_comments: &'static [&'static str],
+    block_comments: &'static [(&'static str, &'static str)],
+    // Longer delimiters must precede the shorter ones like `"""` and `"`
+    strings: &'static [(&'static str, &'static str)],
+    nested: bool,
+    escape: bool,
+    // `'` is a character literal only if it is closed right after a character, otherwise a lifetime
+    rust_chars: bool,
+    // Literals not closed in the line are not literals like the apostrophe of `don't`
+    single_line: bool,
+}
+
+const C_LIKE: Lexer = Lexer {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    strings: &[("\"", "\""), ("'", "'")],
+    nested: false,
+    escape: true,
+    rust_chars: false,
+    single_line: false,
+};
+
+const JS_LIKE: Lexer = Lexer {
+    strings: &[("\"", "\""), ("'", "'"), ("`", "`")],
+    ..C_LIKE
+};
+
+const RUST: Lexer = Lexer {
+    strings: &[("\"", "\"")],
+    nested: true,
+    rust_chars: true,
+    ..C_LIKE
+};
+
+const PYTHON: Lexer = Lexer {
+    line_comments: &["#"],
+    block_comments: &[],
+    strings: &[("\"\"\"", "\"\"\""), ("'''", "'''"), ("\"", "\""), ("'", "'")],
+    nested: false,
+    escape: true,
+    rust_chars: false,
+    single_line: false,
+};
+
+const SCRIPT: Lexer = Lexer {
+    strings: &[("\"", "\""), ("'", "'")],
+    ..PYTHON
+};
+
+const SQL: Lexer = Lexer {
+    line_comments: &["--"],
+    block_comments: &[("/*", "*/")],
+    strings: &[("'", "'")],
+    nested: false,
+    escape: false,
+    rust_chars: false,
+    single_line: false,
+};
+
+const LUA: Lexer = Lexer {
+    block_comments: &[("--[[", "]]")],
+    strings: &[("\"", "\""), ("'", "'")],
+    escape: true,
+    ..SQL
+};
+
+// Files of unknown languages have no comments, and quoted literals in a line only
+const QUOTED: Lexer = Lexer {
+    line_comments: &[],
+    block_comments: &[],
+    strings: &[("\"", "\""), ("'", "'"), ("`", "`")],
+    nested: false,
+    escape: true,
+    rust_chars: false,
+    single_line: true,
+};
+
+impl Lexer {
+    /// Returns the lexer for the extension of `path`, or None if the language is unknown
+    pub fn from_path(path: &Path) -> Option<Lexer> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        match ext.as_str() {
+            "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "hh" | "cs" | "java" | "kt" | "kts" | "scala" | "swift"
+            | "dart" | "php" => Some(C_LIKE),
+            "js" | "jsx" | "mjs" | "ts" | "tsx" | "go" => Some(JS_LIKE),
+            "rs" => Some(RUST),
+            "py" | "pyi" | "toml" => Some(PYTHON),
+            "sh" | "bash" | "zsh" | "rb" | "pl" | "pm" | "r" | "yml" | "yaml" | "mk" | "cmake" => Some(SCRIPT),
+            "sql" => Some(SQL),
+            "lua" => Some(LUA),
+            _ => None,
+        }
+    }
+
+    /// Returns the lexer for the extension of `path`, or the one finding quoted literals if the language is unknown
+    pub fn from_path_or_quoted(path: &Path) -> Lexer {
+        Lexer::from_path(path).unwrap_or(QUOTED)
+    }
+
+    /// Returns the spans of comments and string literals in `src` in order
+    pub fn tokens(&self, src: &[u8]) -> Vec<(usize, usize, TokenKind)> {
+        self.scan(src).into_iter().map(|x| (x.0, x.1, x.2)).collect()
+    }
+
+    /// Returns the spans of the contents of string literals in `src` in order, which exclude the quotes
+    pub fn literals(&self, src: &[u8]) -> Vec<(usize, usize, TokenKind)> {
+        self.scan(src)
+            .into_iter()
+            .filter(|x| x.2 == TokenKind::String)
+            .map(|x| (x.3 .0, x.3 .1, x.2))
+            .collect()
+    }
+
+    fn scan(&self, src: &[u8]) -> Vec<Token> {
+        let mut ret = Vec::new();
+        let mut i = 0;
+        while i < src.len() {
+            let rest = &src[i..];
+            let end = if let Some(x) = self.block_comments.iter().find(|x| rest.starts_with(x.0.as_bytes())) {
+                let end = self.block_end(src, i, x);
+                Some((end, TokenKind::Comment, (i, end)))
+            } else if self.line_comments.iter().any(|x| rest.starts_with(x.as_bytes())) {
+                let end = memchr::memchr(0x0a, rest).map_or(src.len(), |x| i + x);
+                Some((end, TokenKind::Comment, (i, end)))
+            } else if let Some(x) = self.strings.iter().find(|x| rest.starts_with(x.0.as_bytes())) {
+                let beg = i + x.0.len();
+                self.string_end(src, beg, x.1)
+                    .map(|(inner, end)| (end, TokenKind::String, (beg, inner)))
+            } else if self.rust_chars && rest[0] == b'\'' {
+                char_end(src, i).map(|x| (x, TokenKind::String, (i + 1, x - 1)))
+            } else {
+                None
+            };
+            match end {
+                Some((end, kind, inner)) => {
+                    ret.push((i, end, kind, inner));
+                    i = end;
+                }
+                None => i += 1,
+            }
+        }
+        ret
+    }
+
+    fn block_end(&self, src: &[u8], beg: usize, delim: &(&str, &str)) -> usize {
+        let (open, close) = (delim.0.as_bytes(), delim.1.as_bytes());
+        let mut depth = 0;
+        let mut i = beg;
+        while i < src.len() {
+            if src[i..].starts_with(open) && (self.nested || depth == 0) {
+                depth += 1;
+                i += open.len();
+            } else if src[i..].starts_with(close) {
+                depth -= 1;
+                i += close.len();
+                if depth == 0 {
+                    return i;
+                }
+            } else {
+                i += 1;
+            }
+        }
+        src.len()
+    }
+
+    // Returns the end of the contents and the literal, where a literal not closed ends at the end of file
+    fn string_end(&self, src: &[u8], mut i: usize, close: &str) -> Option<(usize, usize)> {
+        while i < src.len() {
+            if self.escape && src[i] == b'\\' {
+                i += 2;
+            } else if src[i..].starts_with(close.as_bytes()) {
+                return Some((i, i + close.len()));
+            } else if self.single_line && src[i] == 0x0a {
+                return None;
+            } else {
+                i += 1;
+            }
+        }
+        if self.single_line {
+            None
+        } else {
+            Some((src.len(), src.len()))
+        }
+    }
+}
+
+// `'a'`, `'\n'` and `'\u{3042}'` are character literals, but `'a` is a lifetime
+fn char_end(src: &[u8], beg: usize) -> Option<usize> {
+    let rest = src.get(beg + 1..)?;
+    let len = if rest.first() == Some(&b'\\') {
+        rest.iter().skip(2).position(|x| *x == b'\'')? + 2
+    } else {
+        std::str::from_utf8(&rest[..std::cmp::min(4, rest.len())])
+            .or_else(|e| std::str::from_utf8(&rest[..e.valid_up_to()]))
+            .ok()?
+            .chars()
+            .next()?
+            .len_utf8()
+    };
+    if rest.get(len) == Some(&b'\'') && len <= 10 {
+        Some(beg + len + 2)
+    } else {
+        None
+    }
+}
+
+/// Matches are kept only if they are wholly inside the tokens of `kind` ( code is outside of comments and strings )
+pub fn filter_tokens(tokens: &[(usize, usize, TokenKind)], kind: TokenKind, mut matches: Vec<Match>) -> Vec<Match> {
+    matches.retain(|m| {
+        let i = tokens.partition_point(|x| x.1 <= m.beg);
+        match (tokens.get(i), kind) {
+            (Some(x), TokenKind::Code) => x.0 >= m.end,
+            (None, TokenKind::Code) => true,
+            (Some(x), _) => x.2 == kind && x.0 <= m.beg && m.end <= x.1,
+            (None, _) => false,
+        }
+    });
+    matches
+}
+
+/// Matches adjoining identifier characters ( Unicode alphanumerics and `_` ) are dropped as parts of other identifiers
+pub fn filter_identifiers(src: &[u8], mut matches: Vec<Match>) -> Vec<Match> {
+    matches.retain(|m| {
+        let beg = (m.beg.saturating_sub(4)..m.beg)
+            .find(|x| src[*x] & 0xc0 != 0x80)
+            .unwrap_or(m.beg);
+        let prev = String::from_utf8_lossy(&src[beg..m.beg]).chars().last();
+        let next = String::from_utf8_lossy(&src[m.end..std::cmp::min(m.end + 4, src.len())])
+            .chars()
+            .next();
+        !prev.map_or(false, is_identifier_char) && !next.map_or(false, is_identifier_char)
+    });
+    matches
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lexer_tokens() {
+        let lexer = Lexer::from_path(Path::new("a.rs")).unwrap();
+        let src = br#"let foo = "foo \" foo"; /* foo /* foo */ */ fn f<'a>(x: &'a str) -> char { '\'' } // foo"#;
+        let ret: Vec<_> = lexer
+            .tokens(src)
+            .iter()
+            .map(|x| (String::from_utf8_lossy(&src[x.0..x.1]).into_owned(), x.2))
+            .collect();
+        assert_eq!(
+            ret,
+            vec![
+                (String::from(r#""foo \" foo""#), TokenKind::String),
+                (String::from("/* foo /* foo */ */"), TokenKind::Comment),
+                (String::from(r#"'\''"#), TokenKind::String),
+                (String::from("// foo"), TokenKind::Comment),
+            ]
+        );
+
+        let lexer = Lexer::from_path(Path::new("a.py")).unwrap();
+        let src = b"x = '''a # b''' # c\n";
+        assert_eq!(
+            lexer.tokens(src),
+            vec![(4, 15, TokenKind::String), (16, 19, TokenKind::Comment)]
+        );
+        assert!(Lexer::from_path(Path::new("a.txt")).is_none());
+    }
+
+    #[test]
+    fn lexer_literals() {
+        let lexer = Lexer::from_path_or_quoted(Path::new("a.txt"));
+        let src = b"url = \"http://a\" // don't\nb = `http://b` 'c\\'d' http://c\n";
+        let ret: Vec<_> = lexer
+            .literals(src)
+            .iter()
+            .map(|x| String::from_utf8_lossy(&src[x.0..x.1]).into_owned())
+            .collect();
+        assert_eq!(ret, vec!["http://a", "http://b", "c\\'d"]);
+
+        let lexer = Lexer::from_path_or_quoted(Path::new("a.rs"));
+        let src = b"let a = 'x'; // \"b\"\n";
+        assert_eq!(lexer.literals(src), vec![(9, 10, TokenKind::String)]);
+    }
+
+    #[test]
+    fn lexer_filter_identifiers() {
+        let src = "old oldSuffix _old old_ (old) \u{3042}old old\u{3042} 1old old.x".as_bytes();
+        let matches = (0..src.len())
+            .filter(|x| src[*x..].starts_with(b"old"))
+            .map(|x| Match {
+                beg: x,
+                end: x + 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            })
+            .collect();
+        let ret: Vec<_> = filter_identifiers(src, matches).iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![0, 25, 49]);
+    }
+
+    #[test]
+    fn lexer_filter_tokens() {
+        let tokens = [(4, 9, TokenKind::String), (12, 20, TokenKind::Comment)];
+        let matches = || {
+            [(0, 3), (5, 8), (8, 11), (13, 16)]
+                .iter()
+                .map(|x| Match {
+                    beg: x.0,
+                    end: x.1,
+                    sub_match: Vec::new(),
+                    keyword: 0,
+                })
+                .collect::<Vec<_>>()
+        };
+        let begs = |x: Vec<Match>| x.iter().map(|m| m.beg).collect::<Vec<_>>();
+
+        assert_eq!(begs(filter_tokens(&tokens, TokenKind::Code, matches())), vec![0]);
+        assert_eq!(begs(filter_tokens(&tokens, TokenKind::String, matches())), vec![5]);
+        assert_eq!(begs(filter_tokens(&tokens, TokenKind::Comment, matches())), vec![13]);
+    }
+}
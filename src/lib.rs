@@ -2,12 +2,41 @@
 
 #[macro_use]
 pub mod util;
+pub mod blame;
+pub mod cache;
+pub mod checkpoint;
 pub mod console;
+pub mod daemon;
+pub mod decompress;
+pub mod document;
+pub mod editorconfig;
+pub mod encoding;
+pub mod follow;
+pub mod history;
 pub mod ignore;
+pub mod index;
+pub mod keymap;
+pub mod lexer;
 pub mod matcher;
+pub mod picker;
 pub mod pipeline;
+#[cfg(feature = "async")]
+pub mod pipeline_async;
+pub mod pipeline_builder;
 pub mod pipeline_finder;
 pub mod pipeline_matcher;
 pub mod pipeline_printer;
 pub mod pipeline_replacer;
 pub mod pipeline_sorter;
+pub mod preset;
+pub mod profile;
+pub mod query;
+pub mod renamer;
+pub mod report;
+pub mod rpc;
+pub mod search;
+pub mod syntax;
+#[cfg(feature = "test-support")]
+pub mod testing;
+pub mod tmpfile;
+pub mod trace;
@@ -1,10 +1,14 @@
 use crossbeam::channel::unbounded;
-use regex::RegexBuilder;
+use memchr::memmem;
+use regex::bytes::{Regex, RegexBuilder};
+use regex_syntax::hir::{Class, Hir, HirKind, Literal};
+use regex_syntax::ParserBuilder;
 use rlibc::memcmp;
 use scoped_threadpool::Pool;
 use std::cmp;
 use std::collections::HashMap;
-use std::str;
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Matcher
@@ -15,12 +19,85 @@ pub struct Match {
     pub beg: usize,
     pub end: usize,
     pub sub_match: Vec<Match>,
+    /// Index of the keyword matched, which is not 0 only for `--all-of`
+    pub keyword: usize,
 }
 
+impl Match {
+    pub fn new(beg: usize, end: usize) -> Self {
+        Match {
+            beg,
+            end,
+            sub_match: Vec::new(),
+            keyword: 0,
+        }
+    }
+}
+
+/// Search algorithm used by `PipelineMatcher` and `PipelineReplacer`.
+///
+/// Custom matchers implementing this trait can be passed to `PipelineMatcher::new` to run through the pipeline.
+/// `search` must return the byte ranges of `pat` in `src` in ascending order without overlap.
+/// `pat` is the keyword given to the pipeline, which may be interpreted freely ( e.g. as a regex or a token ).
+/// Matchers are moved to the matcher threads, so they should be `Send` to be used by multiple threads.
 pub trait Matcher {
     fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match>;
 }
 
+impl<T: Matcher + ?Sized> Matcher for Box<T> {
+    fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match> {
+        (**self).search(src, pat)
+    }
+}
+
+// A matcher can be shared by the matcher threads
+impl<T: Matcher + ?Sized> Matcher for Arc<T> {
+    fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match> {
+        (**self).search(src, pat)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// MatcherKind
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Matcher selected by `--matcher`, where `Auto` is resolved by the other options like `--regex`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatcherKind {
+    Auto,
+    Quick,
+    Tbm,
+    Memmem,
+    Regex,
+}
+
+impl MatcherKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MatcherKind::Auto => "auto",
+            MatcherKind::Quick => "quick",
+            MatcherKind::Tbm => "tbm",
+            MatcherKind::Memmem => "memmem",
+            MatcherKind::Regex => "regex",
+        }
+    }
+}
+
+impl FromStr for MatcherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(MatcherKind::Auto),
+            "quick" => Ok(MatcherKind::Quick),
+            "tbm" => Ok(MatcherKind::Tbm),
+            "memmem" => Ok(MatcherKind::Memmem),
+            "regex" => Ok(MatcherKind::Regex),
+            _ => Err(format!("invalid matcher type: {}", s)),
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // macro
 // ---------------------------------------------------------------------------------------------------------------------
@@ -90,6 +167,7 @@ impl Matcher for BruteForceMatcher {
                         beg: i,
                         end: i + pat_len,
                         sub_match: Vec::new(),
+                        keyword: 0,
                     });
                     i = i + pat_len - 1;
                 }
@@ -146,6 +224,7 @@ impl QuickSearchMatcher {
                         beg: i,
                         end: i + pat_len,
                         sub_match: Vec::new(),
+                        keyword: 0,
                     });
                     i += pat_len;
                     continue;
@@ -276,6 +355,7 @@ impl TbmMatcher {
                     beg: i + 1 - pat_len,
                     end: i + 1,
                     sub_match: Vec::new(),
+                    keyword: 0,
                 });
                 i += pat_len;
                 continue;
@@ -409,6 +489,7 @@ impl FjsMatcher {
                                 beg: i - mp,
                                 end: i - mp + pat_len,
                                 sub_match: Vec::new(),
+                                keyword: 0,
                             });
                             prev = (i - mp) as isize;
                         }
@@ -433,6 +514,7 @@ impl FjsMatcher {
                                 beg: i - pat_len,
                                 end: i,
                                 sub_match: Vec::new(),
+                                keyword: 0,
                             });
                             prev = (i - pat_len) as isize;
                         }
@@ -512,36 +594,267 @@ impl Matcher for FjsMatcher {
     }
 }
 
+// ---------------------------------------------------------------------------------------------------------------------
+// MemmemMatcher
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct MemmemMatcher {
+    pub max_threads: usize,
+    pub size_per_thread: usize,
+    /// Skip matches beginning in the middle of a character ( disabled to match arbitrary bytes )
+    pub char_boundary: bool,
+}
+
+impl Default for MemmemMatcher {
+    fn default() -> Self {
+        MemmemMatcher::new()
+    }
+}
+
+impl MemmemMatcher {
+    pub fn new() -> Self {
+        MemmemMatcher {
+            max_threads: 4,
+            size_per_thread: 1024 * 1024,
+            char_boundary: true,
+        }
+    }
+
+    fn search_sub(&self, src: &[u8], pat: &[u8], finder: &memmem::Finder, beg: usize, end: usize) -> Vec<Match> {
+        let src_len = src.len();
+        let pat_len = pat.len();
+        let mut ret = Vec::new();
+
+        // Matches beginning in [beg, end) are searched
+        let limit = cmp::min((end + pat_len).saturating_sub(1), src_len);
+
+        let mut i = beg;
+        while i < end {
+            let pos = match finder.find(&src[i..limit]) {
+                Some(x) => i + x,
+                None => break,
+            };
+
+            if !self.char_boundary || MatcherUtil::check_char_boundary(src, pos) {
+                ret.push(Match {
+                    beg: pos,
+                    end: pos + pat_len,
+                    sub_match: Vec::new(),
+                    keyword: 0,
+                });
+                i = pos + pat_len;
+            } else {
+                i = pos + 1;
+            }
+        }
+
+        ret
+    }
+}
+
+impl Matcher for MemmemMatcher {
+    fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match> {
+        if pat.is_empty() {
+            return Vec::new();
+        }
+
+        let src_len = src.len();
+        let finder = memmem::Finder::new(pat);
+
+        let thread_num = cmp::min(src_len / self.size_per_thread + 1, self.max_threads);
+
+        if thread_num == 1 {
+            self.search_sub(src, pat, &finder, 0, src_len)
+        } else {
+            let (tx, rx) = unbounded();
+            let mut pool = Pool::new(thread_num as u32);
+
+            pool.scoped(|scoped| {
+                for i in 0..thread_num {
+                    let tx = tx.clone();
+                    let beg = src_len * i / thread_num;
+                    let end = src_len * (i + 1) / thread_num;
+                    let finder = &finder;
+                    scoped.execute(move || {
+                        let tmp = self.search_sub(src, pat, finder, beg, end);
+                        let _ = tx.send((i, tmp));
+                    });
+                }
+            });
+
+            let mut rets = HashMap::new();
+            for _ in 0..thread_num {
+                let (i, tmp) = rx.recv().unwrap();
+                rets.insert(i, tmp);
+            }
+
+            let mut ret = Vec::new();
+            for i in 0..thread_num {
+                let tmp = rets.get(&i).unwrap();
+                for t in tmp {
+                    ret.push(t.clone());
+                }
+            }
+            ret
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // RegexMatcher
 // ---------------------------------------------------------------------------------------------------------------------
 
-pub struct RegexMatcher;
+pub struct RegexMatcher {
+    /// Match letters case-insensitively by Unicode simple case folding
+    pub case_insensitive: bool,
+    // The pattern compiled at the first search, which is reused while the pattern is the same
+    compiled: Mutex<Option<Arc<CompiledRegex>>>,
+}
+
+struct CompiledRegex {
+    pat: Vec<u8>,
+    case_insensitive: bool,
+    re: Option<Regex>,
+    /// Literal contained in any match, and whether only the lines containing it can be searched
+    prefilter: Option<(String, bool)>,
+}
 
 impl RegexMatcher {
     pub fn new() -> Self {
-        RegexMatcher
+        RegexMatcher {
+            case_insensitive: false,
+            compiled: Mutex::new(None),
+        }
+    }
+
+    fn compile(&self, pat: &[u8]) -> Arc<CompiledRegex> {
+        let mut compiled = self.compiled.lock().unwrap();
+        if let Some(ref x) = *compiled {
+            if x.pat == pat && x.case_insensitive == self.case_insensitive {
+                return x.clone();
+            }
+        }
+
+        let pat_str = str::from_utf8(pat).ok();
+        let re = pat_str.and_then(|x| {
+            RegexBuilder::new(x)
+                .multi_line(true)
+                .case_insensitive(self.case_insensitive)
+                .build()
+                .ok()
+        });
+
+        // Letters are parsed as classes if case-insensitive, so only the caseless literals are used for the prefilter.
+        // Lines are searched separately only if the pattern can't match line feeds nor is anchored to the whole text.
+        let hir = pat_str.and_then(|x| {
+            ParserBuilder::new()
+                .multi_line(true)
+                .case_insensitive(self.case_insensitive)
+                .allow_invalid_utf8(true)
+                .build()
+                .parse(x)
+                .ok()
+        });
+        let prefilter = hir.and_then(|x| {
+            let single_line = !RegexMatcher::can_match_lf(&x) && !x.is_any_anchored_start() && !x.is_any_anchored_end();
+            RegexMatcher::required_literal(&x).map(|y| (y, single_line))
+        });
+
+        let ret = Arc::new(CompiledRegex {
+            pat: pat.to_vec(),
+            case_insensitive: self.case_insensitive,
+            re,
+            prefilter,
+        });
+        *compiled = Some(ret.clone());
+        ret
+    }
+}
+
+impl RegexMatcher {
+    // Returns the longest literal which must be contained in any match
+    fn required_literal(hir: &Hir) -> Option<String> {
+        match hir.kind() {
+            HirKind::Literal(Literal::Unicode(c)) => Some(c.to_string()),
+            HirKind::Group(g) => RegexMatcher::required_literal(&g.hir),
+            HirKind::Concat(hirs) => {
+                let mut ret = String::new();
+                let mut lit = String::new();
+                for h in hirs {
+                    if let HirKind::Literal(Literal::Unicode(c)) = h.kind() {
+                        lit.push(*c);
+                    } else {
+                        if lit.len() > ret.len() {
+                            ret = lit;
+                        }
+                        lit = String::new();
+                    }
+                }
+                if lit.len() > ret.len() {
+                    ret = lit;
+                }
+                if ret.is_empty() {
+                    None
+                } else {
+                    Some(ret)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn can_match_lf(hir: &Hir) -> bool {
+        match hir.kind() {
+            HirKind::Literal(Literal::Unicode(c)) => *c == '\n',
+            HirKind::Literal(Literal::Byte(b)) => *b == 0x0a,
+            HirKind::Class(Class::Unicode(c)) => c.iter().any(|r| r.start() <= '\n' && '\n' <= r.end()),
+            HirKind::Class(Class::Bytes(c)) => c.iter().any(|r| r.start() <= 0x0a && 0x0a <= r.end()),
+            HirKind::Repetition(r) => RegexMatcher::can_match_lf(&r.hir),
+            HirKind::Group(g) => RegexMatcher::can_match_lf(&g.hir),
+            HirKind::Concat(hirs) | HirKind::Alternation(hirs) => hirs.iter().any(RegexMatcher::can_match_lf),
+            _ => false,
+        }
     }
 }
 
 impl Matcher for RegexMatcher {
     fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match> {
-        let pat_str = match str::from_utf8(pat) {
-            Ok(x) => x,
-            Err(_) => return Vec::new(),
+        let compiled = self.compile(pat);
+        let re = match compiled.re {
+            Some(ref x) => x,
+            None => return Vec::new(),
         };
 
-        let src_str = match str::from_utf8(src) {
-            Ok(x) => x,
-            Err(_) => return Vec::new(),
-        };
-
-        let re = match RegexBuilder::new(pat_str).multi_line(true).build() {
-            Ok(x) => x,
-            Err(_) => return Vec::new(),
-        };
+        // Prefilter by the required literal, and search only the lines containing it if the pattern is single-line
+        if let Some((ref literal, single_line)) = compiled.prefilter {
+            let mut candidates = memmem::find_iter(src, literal.as_bytes()).peekable();
+            if candidates.peek().is_none() {
+                return Vec::new();
+            }
+            if single_line {
+                let mut ret = Vec::new();
+                let mut searched = 0;
+                for pos in candidates {
+                    if pos < searched {
+                        continue;
+                    }
+                    let beg = memchr::memrchr(b'\n', &src[..pos]).map_or(0, |x| x + 1);
+                    let end = memchr::memchr(b'\n', &src[pos..]).map_or(src.len(), |x| pos + x);
+                    for r in re.find_iter(&src[beg..end]) {
+                        ret.push(Match {
+                            beg: beg + r.start(),
+                            end: beg + r.end(),
+                            sub_match: Vec::new(),
+                            keyword: 0,
+                        });
+                    }
+                    searched = end;
+                }
+                return ret;
+            }
+        }
 
-        let result = re.find_iter(src_str);
+        let result = re.find_iter(src);
 
         let mut ret = Vec::new();
         for r in result {
@@ -549,12 +862,123 @@ impl Matcher for RegexMatcher {
                 beg: r.start(),
                 end: r.end(),
                 sub_match: Vec::new(),
+                keyword: 0,
             });
         }
         ret
     }
 }
 
+// ---------------------------------------------------------------------------------------------------------------------
+// CaseFoldMatcher
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Literal matcher wrapper searching by Unicode full case folding ( e.g. `É` matches `é`, and `SS` matches `ß` )
+///
+/// Both of `src` and `pat` are folded before the search, and the matches are mapped back to the offsets of `src`.
+/// Matches starting or ending inside the folding of a character ( e.g. `s` in `ß` ) are dropped.
+pub struct CaseFoldMatcher<T: Matcher> {
+    inner: T,
+}
+
+impl<T: Matcher> CaseFoldMatcher<T> {
+    pub fn new(inner: T) -> Self {
+        CaseFoldMatcher { inner }
+    }
+}
+
+impl<T: Matcher> Matcher for CaseFoldMatcher<T> {
+    fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match> {
+        let (pat, _) = fold_case(pat);
+        let (folded, offsets) = fold_case(src);
+        let is_boundary = |x: usize| x == 0 || x == folded.len() || offsets[x - 1] != offsets[x];
+
+        let mut ret = Vec::new();
+        for m in self.inner.search(&folded, &pat) {
+            if is_boundary(m.beg) && is_boundary(m.end) {
+                ret.push(Match {
+                    beg: offsets[m.beg],
+                    end: offsets[m.end],
+                    sub_match: Vec::new(),
+                    keyword: m.keyword,
+                });
+            }
+        }
+        ret
+    }
+}
+
+/// Returns `src` folded by Unicode full case folding, and the offsets in `src` of each byte of the folded
+///
+/// The offsets have an extra element `src.len()` at the end. Invalid UTF-8 bytes are kept as is.
+pub fn fold_case(src: &[u8]) -> (Vec<u8>, Vec<usize>) {
+    let mut ret = Vec::with_capacity(src.len());
+    let mut offsets = Vec::with_capacity(src.len() + 1);
+    let mut buf = [0; 4];
+    let mut i = 0;
+    while i < src.len() {
+        if src[i] < 0x80 {
+            ret.push(src[i].to_ascii_lowercase());
+            offsets.push(i);
+            i += 1;
+            continue;
+        }
+        let rest = &src[i..cmp::min(i + 4, src.len())];
+        let c = str::from_utf8(rest)
+            .or_else(|e| str::from_utf8(&rest[..e.valid_up_to()]))
+            .ok()
+            .and_then(|x| x.chars().next());
+        let len = match c {
+            Some(c) => {
+                let len = ret.len();
+                match fold_special(c) {
+                    Some(x) => ret.extend_from_slice(x.as_bytes()),
+                    None => {
+                        for x in c.to_lowercase() {
+                            ret.extend_from_slice(x.encode_utf8(&mut buf).as_bytes());
+                        }
+                    }
+                }
+                offsets.resize(offsets.len() + ret.len() - len, i);
+                c.len_utf8()
+            }
+            None => {
+                ret.push(src[i]);
+                offsets.push(i);
+                1
+            }
+        };
+        i += len;
+    }
+    offsets.push(src.len());
+    (ret, offsets)
+}
+
+// Characters folded differently from the lowercase
+fn fold_special(c: char) -> Option<&'static str> {
+    match c {
+        'ß' | 'ẞ' => Some("ss"),
+        'ſ' => Some("s"),
+        'ς' => Some("σ"),
+        'ϐ' => Some("β"),
+        'ϑ' => Some("θ"),
+        'ϕ' => Some("φ"),
+        'ϖ' => Some("π"),
+        'ϰ' => Some("κ"),
+        'ϱ' => Some("ρ"),
+        'ϵ' => Some("ε"),
+        '\u{345}' | '\u{1fbe}' => Some("ι"),
+        'ẛ' => Some("ṡ"),
+        'ﬀ' => Some("ff"),
+        'ﬁ' => Some("fi"),
+        'ﬂ' => Some("fl"),
+        'ﬃ' => Some("ffi"),
+        'ﬄ' => Some("ffl"),
+        'ﬅ' | 'ﬆ' => Some("st"),
+        _ => None,
+    }
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // MatcherUtil
 // ---------------------------------------------------------------------------------------------------------------------
@@ -703,9 +1127,88 @@ mod tests {
     //    test_matcher( &matcher );
     //}
 
+    #[test]
+    fn test_memmem_matcher() {
+        let matcher = MemmemMatcher::new();
+        test_matcher(&matcher);
+
+        let mut matcher = MemmemMatcher::new();
+        matcher.size_per_thread = 4;
+        let src = "abcabcbbbaabc".to_string().into_bytes();
+        let pat = "abc".to_string().into_bytes();
+        let ret = matcher.search(&src, &pat);
+        assert_eq!(ret.len(), 3);
+        assert_eq!((0, 3), (ret[0].beg, ret[0].end));
+        assert_eq!((3, 6), (ret[1].beg, ret[1].end));
+        assert_eq!((10, 13), (ret[2].beg, ret[2].end));
+
+        // The second byte of `Þ­` ( 0xde 0xad ) is matched only without the check of character boundaries
+        let src = b"\xde\xad\xbe\xef";
+        let mut matcher = MemmemMatcher::new();
+        assert!(matcher.search(src, b"\xad\xbe").is_empty());
+        matcher.char_boundary = false;
+        let ret = matcher.search(src, b"\xad\xbe");
+        assert_eq!((1, 3), (ret[0].beg, ret[0].end));
+    }
+
     #[test]
     fn test_regex_matcher() {
         let matcher = RegexMatcher::new();
         test_matcher(&matcher);
+
+        let src = "foo0bar\nfoo1baz\nbar foo2bar\n".to_string().into_bytes();
+        let pat = "foo[0-9]bar".to_string().into_bytes();
+        let ret = matcher.search(&src, &pat);
+        assert_eq!(ret.len(), 2);
+        assert_eq!((0, 7), (ret[0].beg, ret[0].end));
+        assert_eq!((20, 27), (ret[1].beg, ret[1].end));
+
+        let pat = "baz\\sbar".to_string().into_bytes();
+        let ret = matcher.search(&src, &pat);
+        assert_eq!(ret.len(), 1);
+        assert_eq!((12, 19), (ret[0].beg, ret[0].end));
+
+        let pat = "^bar".to_string().into_bytes();
+        let ret = matcher.search(&src, &pat);
+        assert_eq!(ret.len(), 1);
+        assert_eq!((16, 19), (ret[0].beg, ret[0].end));
+
+        // Anchors of the whole text aren't matched at each line searched by the prefilter
+        let src = b"abcd\nabcd\n";
+        let ret: Vec<_> = matcher.search(src, b"\\Aabcd").iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![0]);
+        let ret: Vec<_> = matcher.search(src, b"(?-m)abcd$").iter().map(|m| m.beg).collect();
+        assert!(ret.is_empty());
+        assert_eq!(matcher.search(src, b"abcd").len(), 2);
+    }
+
+    #[test]
+    fn test_case_fold_matcher() {
+        let matcher = CaseFoldMatcher::new(MemmemMatcher::new());
+        let src = "Stra\u{df}e STRASSE caf\u{e9} CAF\u{c9} \u{df}".as_bytes();
+        let ret: Vec<_> = matcher.search(src, b"strasse").iter().map(|m| (m.beg, m.end)).collect();
+        assert_eq!(ret, vec![(0, 7), (8, 15)]);
+        let ret: Vec<_> = matcher
+            .search(src, "CAF\u{c9}".as_bytes())
+            .iter()
+            .map(|m| (m.beg, m.end))
+            .collect();
+        assert_eq!(ret, vec![(16, 21), (22, 27)]);
+        // `s` folded from `\u{df}` is not matched partially
+        let ret: Vec<_> = matcher.search(src, b"se").iter().map(|m| (m.beg, m.end)).collect();
+        assert_eq!(ret, vec![(13, 15)]);
+
+        let mut matcher = RegexMatcher::new();
+        matcher.case_insensitive = true;
+        let ret: Vec<_> = matcher.search(src, b"caf.").iter().map(|m| (m.beg, m.end)).collect();
+        assert_eq!(ret, vec![(16, 21), (22, 27)]);
+    }
+
+    #[test]
+    fn test_matcher_kind() {
+        for x in &["auto", "quick", "tbm", "memmem", "regex"] {
+            assert_eq!(x.parse::<MatcherKind>().unwrap().name(), *x);
+        }
+        assert!("bm".parse::<MatcherKind>().is_err());
     }
 }
@@ -0,0 +1,204 @@
+use crate::util::shell;
+use std::env;
+use std::io::{self, BufRead, Error, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PickAction
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PickAction {
+    /// Print `path:line` of the match picked
+    Print,
+    /// Open the match picked by `$EDITOR`
+    Edit,
+}
+
+impl FromStr for PickAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "print" => Ok(PickAction::Print),
+            "edit" => Ok(PickAction::Edit),
+            _ => Err(format!("invalid pick action: {}", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Candidate
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Template of the printer to write the matches parsed by `Candidate::parse_all`
+pub const PICK_TEMPLATE: &str = "{path}\\t{line}\\t{text}";
+
+/// Line of matches shown by the picker
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub line: usize,
+    pub text: String,
+}
+
+impl Candidate {
+    /// Parses the output of `PICK_TEMPLATE`, where the matches in the same line are merged
+    pub fn parse_all(src: &str) -> Vec<Candidate> {
+        let mut ret: Vec<Candidate> = Vec::new();
+        for x in src.lines() {
+            let mut fields = x.splitn(3, '\t');
+            let (path, line, text) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(path), Some(line), Some(text)) => (path, line, text),
+                _ => continue,
+            };
+            let line = match line.parse() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let path = PathBuf::from(path);
+            if ret.last().is_some_and(|x| x.path == path && x.line == line) {
+                continue;
+            }
+            ret.push(Candidate {
+                path,
+                line,
+                text: String::from(text.trim()),
+            });
+        }
+        ret
+    }
+
+    /// `path:line: text` shown by the picker
+    pub fn label(&self) -> String {
+        format!("{}:{}: {}", self.path.to_string_lossy(), self.line, self.text)
+    }
+
+    /// Returns whether all words of `query` are contained as subsequences ignoring the case
+    pub fn is_match(&self, query: &str) -> bool {
+        let label = self.label().to_lowercase();
+        query.split_whitespace().all(|word| {
+            let mut chars = label.chars();
+            word.to_lowercase().chars().all(|c| chars.any(|x| x == c))
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Picker
+// ---------------------------------------------------------------------------------------------------------------------
+
+// Candidates shown at once by the embedded picker
+const PICK_LINES: usize = 20;
+
+/// Returns the index of the candidate picked by `$AMBER_PICKER` ( e.g. `fzf` ) or the embedded picker, or `None` if
+/// nothing is picked
+pub fn pick(candidates: &[Candidate]) -> Result<Option<usize>, Error> {
+    match env::var("AMBER_PICKER") {
+        Ok(ref x) if !x.is_empty() => pick_external(x, candidates),
+        _ => pick_embedded(candidates, &mut io::stdin().lock(), &mut io::stderr()),
+    }
+}
+
+// The labels are passed as stdin, and the first line of stdout is the label picked
+fn pick_external(cmd: &str, candidates: &[Candidate]) -> Result<Option<usize>, Error> {
+    let mut child = shell(cmd).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        for x in candidates {
+            // The picker may exit before reading all
+            if writeln!(stdin, "{}", x.label()).is_err() {
+                break;
+            }
+        }
+    }
+    let output = child.wait_with_output()?;
+    let picked = String::from_utf8_lossy(&output.stdout);
+    let picked = picked.lines().next().unwrap_or("");
+    Ok(candidates.iter().position(|x| x.label() == picked))
+}
+
+/// Lists the candidates filtered by the query, and picks one by the number, where an empty input quits
+pub fn pick_embedded<R: BufRead, W: Write>(
+    candidates: &[Candidate],
+    input: &mut R,
+    output: &mut W,
+) -> Result<Option<usize>, Error> {
+    let mut query = String::new();
+    loop {
+        let shown: Vec<usize> = (0..candidates.len())
+            .filter(|x| candidates[*x].is_match(&query))
+            .collect();
+        for (i, x) in shown.iter().take(PICK_LINES).enumerate() {
+            writeln!(output, "{:>3}  {}", i + 1, candidates[*x].label())?;
+        }
+        if shown.len() > PICK_LINES {
+            writeln!(output, "     ... and {} more", shown.len() - PICK_LINES)?;
+        }
+        write!(output, "Pick a number or filter by words ( {} matched ): ", shown.len())?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match line.parse::<usize>() {
+            Ok(i) if i >= 1 && i <= shown.len().min(PICK_LINES) => return Ok(Some(shown[i - 1])),
+            Ok(_) => writeln!(output, "Out of range: {}", line)?,
+            Err(_) => query = String::from(line),
+        }
+    }
+}
+
+/// Opens the candidate by `$EDITOR` like `vi +10 path`
+pub fn open_editor(candidate: &Candidate) -> Result<(), Error> {
+    let editor = env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+    let mut args = editor.split_whitespace();
+    let cmd = args.next().unwrap_or("vi");
+    let status = Command::new(cmd)
+        .args(args)
+        .arg(format!("+{}", candidate.line))
+        .arg(&candidate.path)
+        .status()?;
+    if !status.success() {
+        return Err(Error::other(format!("{} exited with {}", cmd, status)));
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picker() {
+        let candidates = Candidate::parse_all(
+            "./src/a.rs\t1\tfn foo() {\n./src/a.rs\t1\tfn foo() {\n./src/b.rs\t20\t    let foo = 1;\n",
+        );
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[1].label(), "./src/b.rs:20: let foo = 1;");
+        assert!(candidates[1].is_match("b.rs LET"));
+        assert!(candidates[1].is_match("brs:2"));
+        assert!(!candidates[0].is_match("b.rs"));
+
+        let mut output = Vec::new();
+        let picked = pick_embedded(&candidates, &mut &b"b.rs\n1\n"[..], &mut output).unwrap();
+        assert_eq!(picked, Some(1));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("  2  ./src/b.rs:20: let foo = 1;\n"));
+        assert!(output.contains("( 1 matched )"));
+
+        let picked = pick_embedded(&candidates, &mut &b"3\n\n"[..], &mut Vec::new()).unwrap();
+        assert_eq!(picked, None);
+        assert_eq!(pick_external("head -n 2 | tail -n 1", &candidates).unwrap(), Some(1));
+    }
+}
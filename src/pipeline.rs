@@ -1,23 +1,269 @@
-use crossbeam::channel::{Receiver, Sender};
-use std::time::Duration;
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvError, SendError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+/// Message passed between pipeline stages.
+///
+/// A sequence starts with `SeqBeg`, and ends with `SeqEnd` after all `SeqDat`.
+/// `SeqDat` are numbered from the number of `SeqBeg`, and every number must be passed to the next stage
+/// because `PipelineSorter` waits each number to keep the order ( drop matches instead of items to filter ).
+/// `SeqBat` carries `SeqDat` gathered by `BatchSender`, and is expanded by `BatchReceiver` in the next stage.
+/// `Msg*` are sent by the stage of the id, and should be forwarded to the end of the pipeline as is.
 pub enum PipelineInfo<T> {
+    /// Beginning of the sequence with the first number
     SeqBeg(usize),
+    /// Item of the sequence with the number
     SeqDat(usize, T),
+    /// Items of the sequence with the numbers
+    SeqBat(Vec<(usize, T)>),
+    /// End of the sequence with the number next to the last item
     SeqEnd(usize),
+    /// Information such as skipped files ( id, message )
     MsgInfo(usize, String),
+    /// Error ( id, message )
     MsgErr(usize, String),
+    /// Busy time and whole time of the stage ( id, busy, whole )
     MsgTime(usize, Duration, Duration),
+    /// Processed items and bytes of the stage ( id, items, bytes )
+    MsgCount(usize, usize, u64),
 }
 
+/// Stage receiving messages from a stage and sending to a stage, which runs until `SeqEnd` on a thread
 pub trait Pipeline<T, U> {
     fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<T>>, tx: Sender<PipelineInfo<U>>);
 }
 
+/// Stage distributing messages to stages
 pub trait PipelineFork<T, U> {
     fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<T>>, tx: Vec<Sender<PipelineInfo<U>>>);
 }
 
+/// Stage gathering messages from stages, which ends after `SeqEnd` of all stages
 pub trait PipelineJoin<T, U> {
     fn setup(&mut self, id: usize, rx: Vec<Receiver<PipelineInfo<T>>>, tx: Sender<PipelineInfo<U>>);
 }
+
+/// Returns a channel which blocks the sender while `capacity` items are queued ( 0: unbounded )
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    if capacity == 0 {
+        unbounded()
+    } else {
+        bounded(capacity)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// BatchSender
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Items waiting in a batch longer than this are sent with the next item
+const BATCH_LATENCY: Duration = Duration::from_millis(10);
+
+/// Sender gathering `SeqDat` into `SeqBat` of up to `size` items to reduce the synchronization per item.
+///
+/// The other messages are sent after the gathered items.
+/// Stages should `flush` before waiting for the input not to hold items while idle.
+pub struct BatchSender<T> {
+    tx: Sender<PipelineInfo<T>>,
+    size: usize,
+    items: Vec<(usize, T)>,
+    time_beg: Instant,
+}
+
+impl<T> BatchSender<T> {
+    pub fn new(tx: Sender<PipelineInfo<T>>, size: usize) -> Self {
+        BatchSender {
+            tx,
+            size,
+            items: Vec::new(),
+            time_beg: Instant::now(),
+        }
+    }
+
+    pub fn send(&mut self, info: PipelineInfo<T>) -> Result<(), SendError<PipelineInfo<T>>> {
+        match info {
+            PipelineInfo::SeqDat(x, p) if self.size > 1 => {
+                if self.items.is_empty() {
+                    self.time_beg = Instant::now();
+                }
+                self.items.push((x, p));
+                if self.items.len() >= self.size || self.time_beg.elapsed() >= BATCH_LATENCY {
+                    self.flush()
+                } else {
+                    Ok(())
+                }
+            }
+            info => {
+                self.flush()?;
+                self.tx.send(info)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), SendError<PipelineInfo<T>>> {
+        match self.items.len() {
+            0 => Ok(()),
+            1 => {
+                let (x, p) = self.items.pop().unwrap();
+                self.tx.send(PipelineInfo::SeqDat(x, p))
+            }
+            _ => self.tx.send(PipelineInfo::SeqBat(std::mem::take(&mut self.items))),
+        }
+    }
+
+    /// Returns true if no item is waiting in the batch
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl<T> Drop for BatchSender<T> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// BatchReceiver
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Receiver expanding `SeqBat` into `SeqDat`, so that stages handle items one by one
+pub struct BatchReceiver<T> {
+    rx: Receiver<PipelineInfo<T>>,
+    items: std::vec::IntoIter<(usize, T)>,
+}
+
+impl<T> BatchReceiver<T> {
+    pub fn new(rx: Receiver<PipelineInfo<T>>) -> Self {
+        BatchReceiver {
+            rx,
+            items: Vec::new().into_iter(),
+        }
+    }
+
+    /// Receives a message other than `SeqBat`
+    pub fn recv(&mut self) -> Result<PipelineInfo<T>, RecvError> {
+        loop {
+            if let Some((x, p)) = self.items.next() {
+                return Ok(PipelineInfo::SeqDat(x, p));
+            }
+            match self.rx.recv()? {
+                PipelineInfo::SeqBat(items) => self.items = items.into_iter(),
+                info => return Ok(info),
+            }
+        }
+    }
+
+    /// Returns true if `recv` blocks
+    pub fn is_empty(&self) -> bool {
+        self.items.as_slice().is_empty() && self.rx.is_empty()
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CancelToken
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Token to stop the pipeline, which is checked by the stages before each directory and file
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// ProgressCounter
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Counter shared between a stage and the main thread to report the progress while the pipeline is running
+#[derive(Clone, Default)]
+pub struct ProgressCounter {
+    count: Arc<AtomicUsize>,
+}
+
+impl ProgressCounter {
+    pub fn new() -> Self {
+        ProgressCounter::default()
+    }
+
+    pub fn add(&self, num: usize) {
+        self.count.fetch_add(num, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PipelineMap
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Stage converting each item by a closure, and forwarding the other messages as is
+pub struct PipelineMap<F> {
+    func: F,
+    time_beg: Instant,
+    time_bsy: Duration,
+    items: usize,
+}
+
+impl<F> PipelineMap<F> {
+    pub fn new(func: F) -> Self {
+        PipelineMap {
+            func,
+            time_beg: Instant::now(),
+            time_bsy: Duration::new(0, 0),
+            items: 0,
+        }
+    }
+}
+
+impl<T, F: FnMut(T) -> T> Pipeline<T, T> for PipelineMap<F> {
+    fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<T>>, tx: Sender<PipelineInfo<T>>) {
+        for info in rx.iter() {
+            match info {
+                PipelineInfo::SeqBeg(x) => {
+                    self.time_beg = Instant::now();
+                    let _ = tx.send(PipelineInfo::SeqBeg(x));
+                }
+                PipelineInfo::SeqDat(x, p) => {
+                    self.items += 1;
+                    watch_time!(self.time_bsy, {
+                        let _ = tx.send(PipelineInfo::SeqDat(x, (self.func)(p)));
+                    });
+                }
+                // Batches are converted as is not to split them
+                PipelineInfo::SeqBat(items) => {
+                    self.items += items.len();
+                    watch_time!(self.time_bsy, {
+                        let items = items.into_iter().map(|(x, p)| (x, (self.func)(p))).collect();
+                        let _ = tx.send(PipelineInfo::SeqBat(items));
+                    });
+                }
+                PipelineInfo::SeqEnd(x) => {
+                    let _ = tx.send(PipelineInfo::MsgCount(id, self.items, 0));
+                    let _ = tx.send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
+                    let _ = tx.send(PipelineInfo::SeqEnd(x));
+                    break;
+                }
+                x => {
+                    let _ = tx.send(x);
+                }
+            }
+        }
+    }
+}
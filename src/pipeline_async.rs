@@ -0,0 +1,288 @@
+use crate::cache::CACHE_DIR;
+use crate::ignore::{Ignore, IgnoreGit, IgnoreVcs};
+use crate::index::INDEX_DIR;
+use crate::matcher::Matcher;
+pub use crate::pipeline::CancelToken;
+use crate::pipeline_matcher::PathMatch;
+use crate::search::SearchMatch;
+use crate::util::decode_error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// AsyncSearch
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Search running the finder and the matchers as tasks on the tokio runtime instead of dedicated threads.
+///
+/// Matches are sent as soon as matched, so they are not sorted in the order found.
+/// Errors are sent as `Err` in the same format as the console output.
+pub struct AsyncSearch<T: Matcher + Send + Sync + 'static> {
+    pub keyword: Vec<u8>,
+    /// Paths to search ( default: `./` )
+    pub paths: Vec<PathBuf>,
+    pub is_recursive: bool,
+    pub follow_symlink: bool,
+    pub skip_vcs: bool,
+    pub skip_gitignore: bool,
+    /// Files containing NUL in the first bytes are skipped as binary
+    pub binary_check_bytes: usize,
+    /// Number of the matcher tasks
+    pub matcher_tasks: usize,
+    /// Capacity of the channels between the tasks
+    pub queue_size: usize,
+    matcher: Arc<T>,
+}
+
+impl<T: Matcher + Send + Sync + 'static> AsyncSearch<T> {
+    pub fn new(matcher: T, keyword: &[u8]) -> Self {
+        AsyncSearch {
+            keyword: keyword.to_vec(),
+            paths: Vec::new(),
+            is_recursive: true,
+            follow_symlink: true,
+            skip_vcs: true,
+            skip_gitignore: true,
+            binary_check_bytes: 128,
+            matcher_tasks: num_cpus::get().clamp(1, 8),
+            queue_size: 256,
+            matcher: Arc::new(matcher),
+        }
+    }
+
+    /// Spawns the tasks on the current runtime, and returns the receiver of the results.
+    /// The receiver is closed when the search is finished or cancelled.
+    pub fn spawn(self, cancel: CancelToken) -> mpsc::Receiver<Result<SearchMatch, String>> {
+        let queue_size = self.queue_size.max(1);
+        let (tx_path, rx_path) = mpsc::channel(queue_size);
+        let (tx_result, rx_result) = mpsc::channel(queue_size);
+
+        let rx_path = Arc::new(Mutex::new(rx_path));
+        for _ in 0..self.matcher_tasks.max(1) {
+            let rx_path = rx_path.clone();
+            let tx_result = tx_result.clone();
+            let matcher = self.matcher.clone();
+            let keyword = self.keyword.clone();
+            let binary_check_bytes = self.binary_check_bytes;
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                loop {
+                    let path = match rx_path.lock().await.recv().await {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    for x in search_file(&*matcher, &keyword, path, binary_check_bytes).await {
+                        if tx_result.send(x).await.is_err() {
+                            return;
+                        }
+                    }
+                    // Searching files is CPU-bound, so other tasks are given the chance to run between files
+                    tokio::task::yield_now().await;
+                }
+            });
+        }
+
+        let finder = AsyncFinder {
+            is_recursive: self.is_recursive,
+            follow_symlink: self.follow_symlink,
+            skip_vcs: self.skip_vcs,
+            skip_gitignore: self.skip_gitignore,
+            ignore_vcs: IgnoreVcs::new(),
+        };
+        let mut paths = self.paths;
+        if paths.is_empty() {
+            paths.push(PathBuf::from("./"));
+        }
+        tokio::spawn(async move {
+            for path in paths {
+                finder.find_path(path, &tx_path, &tx_result, &cancel).await;
+            }
+        });
+
+        rx_result
+    }
+}
+
+async fn search_file<T: Matcher + ?Sized>(
+    matcher: &T,
+    keyword: &[u8],
+    path: PathBuf,
+    binary_check_bytes: usize,
+) -> Vec<Result<SearchMatch, String>> {
+    let src = match tokio::fs::read(&path).await {
+        Ok(x) => x,
+        Err(e) => return vec![Err(format!("Error: {} @ {:?}", decode_error(e.kind()), path))],
+    };
+    let check_bytes = binary_check_bytes.min(src.len());
+    if src[..check_bytes].contains(&0) {
+        return Vec::new();
+    }
+
+    let pm = PathMatch {
+        path,
+        matches: matcher.search(&src, keyword),
+        content: None,
+        binary: false,
+        stamp: None,
+    };
+    SearchMatch::from_path_match(&pm, &src).into_iter().map(Ok).collect()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// AsyncFinder
+// ---------------------------------------------------------------------------------------------------------------------
+
+struct AsyncFinder {
+    is_recursive: bool,
+    follow_symlink: bool,
+    skip_vcs: bool,
+    skip_gitignore: bool,
+    ignore_vcs: IgnoreVcs,
+}
+
+impl AsyncFinder {
+    // Directories are visited by a stack instead of recursion, which needs boxed futures
+    async fn find_path(
+        &self,
+        base: PathBuf,
+        tx_path: &mpsc::Sender<PathBuf>,
+        tx_result: &mpsc::Sender<Result<SearchMatch, String>>,
+        cancel: &CancelToken,
+    ) {
+        let mut stack: Vec<(PathBuf, Option<Arc<IgnoreGit>>)> = vec![(base, None)];
+        while let Some((path, ignore_git)) = stack.pop() {
+            if cancel.is_cancelled() {
+                return;
+            }
+
+            let attr = match tokio::fs::metadata(&path).await {
+                Ok(x) => x,
+                Err(e) => {
+                    let _ = tx_result
+                        .send(Err(format!("Error: {} @ {:?}", decode_error(e.kind()), path)))
+                        .await;
+                    continue;
+                }
+            };
+            if attr.is_file() {
+                if attr.len() != 0 && tx_path.send(path).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            let mut entries = match tokio::fs::read_dir(&path).await {
+                Ok(x) => x,
+                Err(e) => {
+                    let _ = tx_result
+                        .send(Err(format!("Error: {} @ {:?}", decode_error(e.kind()), path)))
+                        .await;
+                    continue;
+                }
+            };
+
+            let gitignore = path.join(".gitignore");
+            let ignore_git = if self.skip_gitignore && tokio::fs::metadata(&gitignore).await.is_ok() {
+                Some(Arc::new(IgnoreGit::new(&gitignore)))
+            } else {
+                ignore_git
+            };
+
+            let mut dirs = Vec::new();
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                let file_type = match entry.file_type().await {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+                let is_dir = !file_type.is_file();
+                if !self.check_path(&path, is_dir, &ignore_git) {
+                    continue;
+                }
+                if file_type.is_file() {
+                    if tx_path.send(path).await.is_err() {
+                        return;
+                    }
+                } else if self.is_recursive && (file_type.is_dir() || (file_type.is_symlink() && self.follow_symlink)) {
+                    dirs.push((path, ignore_git.clone()));
+                }
+            }
+            // Directories are visited in the order of entries
+            stack.extend(dirs.into_iter().rev());
+        }
+    }
+
+    fn check_path(&self, path: &PathBuf, is_dir: bool, ignore_git: &Option<Arc<IgnoreGit>>) -> bool {
+        if is_dir && (path.ends_with(INDEX_DIR) || path.ends_with(CACHE_DIR)) {
+            return false;
+        }
+        if self.skip_vcs && self.ignore_vcs.is_ignore(path, is_dir) {
+            return false;
+        }
+        match ignore_git {
+            Some(x) => !x.is_ignore(path, is_dir),
+            None => true,
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::MemmemMatcher;
+    use std::fs;
+
+    #[test]
+    fn pipeline_async() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sub/.git")).unwrap();
+        fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        fs::write(dir.path().join("a.txt"), "keyword\n").unwrap();
+        fs::write(dir.path().join("a.log"), "keyword\n").unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "x\nkeyword keyword\n").unwrap();
+        fs::write(dir.path().join("sub/.git/c.txt"), "keyword\n").unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut ret = runtime.block_on(async {
+            let mut search = AsyncSearch::new(MemmemMatcher::new(), b"keyword");
+            search.paths.push(dir.path().to_path_buf());
+            let mut rx = search.spawn(CancelToken::new());
+            let mut ret = Vec::new();
+            while let Some(x) = rx.recv().await {
+                let x = x.unwrap();
+                ret.push((x.path.strip_prefix(dir.path()).unwrap().to_path_buf(), x.line, x.column));
+            }
+            ret
+        });
+        ret.sort();
+        assert_eq!(
+            ret,
+            vec![
+                (PathBuf::from("a.txt"), 1, 1),
+                (PathBuf::from("sub/b.txt"), 2, 1),
+                (PathBuf::from("sub/b.txt"), 2, 9),
+            ]
+        );
+
+        let ret = runtime.block_on(async {
+            let mut search = AsyncSearch::new(MemmemMatcher::new(), b"keyword");
+            search.paths.push(dir.path().to_path_buf());
+            let cancel = CancelToken::new();
+            cancel.cancel();
+            let mut rx = search.spawn(cancel);
+            rx.recv().await
+        });
+        assert!(ret.is_none());
+    }
+}
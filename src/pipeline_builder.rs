@@ -0,0 +1,222 @@
+use crate::pipeline::{channel, Pipeline, PipelineFork, PipelineInfo, PipelineJoin};
+use crate::pipeline_finder::{PathInfo, PipelineFinder};
+use crate::pipeline_matcher::PathMatch;
+use crate::pipeline_sorter::PipelineSorter;
+use crate::trace::Tracer;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::path::PathBuf;
+use std::thread;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PipelineBuilder
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub const ID_FINDER: usize = 0;
+pub const ID_SORTER: usize = 1;
+/// Id of the stage receiving the output of the sorter ( e.g. `PipelinePrinter` )
+pub const ID_OUTPUT: usize = 2;
+/// Id of the first matcher, which is followed by the other matchers and custom stages
+pub const ID_MATCHER: usize = 3;
+
+type MatcherStage = Box<dyn Pipeline<PathInfo, PathMatch> + Send>;
+type CustomStage = Box<dyn Fn() -> Box<dyn Pipeline<PathMatch, PathMatch> + Send>>;
+
+/// Wires the finder, the matchers, custom stages and the sorter on threads.
+///
+/// Custom stages are inserted between each matcher and the sorter in the order added.
+/// With N matchers, custom stages are created N times by the factory, and the id of the j-th stage after the i-th
+/// matcher is `ID_MATCHER + N * ( j + 1 ) + i`.
+pub struct PipelineBuilder {
+    /// Capacity of the channels of `PathMatch` ( 0: unbounded )
+    pub queue_size: usize,
+    /// Number of files sent at once from the sorter to the output ( 1: one by one )
+    pub batch_size: usize,
+    /// Tracer of the sorter
+    pub trace: Tracer,
+    finder: PipelineFinder,
+    matchers: Vec<MatcherStage>,
+    stages: Vec<CustomStage>,
+    through: bool,
+}
+
+impl PipelineBuilder {
+    pub fn new(finder: PipelineFinder) -> Self {
+        PipelineBuilder {
+            queue_size: 256,
+            batch_size: 1,
+            trace: Tracer::default(),
+            finder,
+            matchers: Vec::new(),
+            stages: Vec::new(),
+            through: false,
+        }
+    }
+
+    /// Adds a matcher running on its own thread
+    pub fn matcher<T: Pipeline<PathInfo, PathMatch> + Send + 'static>(mut self, matcher: T) -> Self {
+        self.matchers.push(Box::new(matcher));
+        self
+    }
+
+    /// Adds a custom stage created by `factory` for each matcher
+    pub fn stage<T, F>(mut self, factory: F) -> Self
+    where
+        T: Pipeline<PathMatch, PathMatch> + Send + 'static,
+        F: Fn() -> T + 'static,
+    {
+        self.stages.push(Box::new(move || Box::new(factory())));
+        self
+    }
+
+    /// Passes matches to the output as soon as matched instead of in the order found
+    pub fn through(mut self, through: bool) -> Self {
+        self.through = through;
+        self
+    }
+
+    /// Returns the number of matchers
+    pub fn matcher_num(&self) -> usize {
+        self.matchers.len()
+    }
+
+    /// Spawns the stages, and returns the sender of base paths to the finder and the receiver of the sorted matches
+    pub fn build(self) -> (Sender<PipelineInfo<PathBuf>>, Receiver<PipelineInfo<PathMatch>>) {
+        let matcher_num = self.matchers.len();
+        let (tx_finder, rx_finder) = unbounded();
+        // Channels of PathMatch are bounded not to queue too many matches before a slow output
+        let (tx_output, rx_output) = channel(self.queue_size);
+
+        let mut tx_matcher = Vec::new();
+        let mut rx_sorter = Vec::new();
+        for (i, mut matcher) in self.matchers.into_iter().enumerate() {
+            let (tx_in, rx_in) = unbounded();
+            let (tx_out, mut rx_out) = channel(self.queue_size);
+            tx_matcher.push(tx_in);
+
+            let _ = thread::Builder::new().name("matcher".to_string()).spawn(move || {
+                matcher.setup(ID_MATCHER + i, rx_in, tx_out);
+            });
+
+            for (j, factory) in self.stages.iter().enumerate() {
+                let mut stage = factory();
+                let rx_in = rx_out;
+                let (tx_out, rx) = channel(self.queue_size);
+                rx_out = rx;
+
+                let id = ID_MATCHER + matcher_num * (j + 1) + i;
+                let _ = thread::Builder::new().name("stage".to_string()).spawn(move || {
+                    stage.setup(id, rx_in, tx_out);
+                });
+            }
+            rx_sorter.push(rx_out);
+        }
+
+        let mut finder = self.finder;
+        let _ = thread::Builder::new().name("finder".to_string()).spawn(move || {
+            finder.setup(ID_FINDER, rx_finder, tx_matcher);
+        });
+
+        let mut sorter = PipelineSorter::new(matcher_num);
+        sorter.through = self.through;
+        sorter.batch_size = self.batch_size;
+        sorter.trace = self.trace;
+        let _ = thread::Builder::new().name("sorter".to_string()).spawn(move || {
+            sorter.setup(ID_SORTER, rx_sorter, tx_output);
+        });
+
+        (tx_finder, rx_output)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::QuickSearchMatcher;
+    use crate::pipeline::{BatchReceiver, PipelineMap};
+    use crate::pipeline_matcher::PipelineMatcher;
+    use std::fs;
+
+    #[test]
+    fn pipeline_builder() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "keyword\n// keyword\n").unwrap();
+        fs::write(dir.path().join("b.rs"), "// keyword\n").unwrap();
+
+        let mut builder = PipelineBuilder::new(PipelineFinder::new());
+        for _ in 0..2 {
+            builder = builder.matcher(PipelineMatcher::new(QuickSearchMatcher::new(), b"keyword"));
+        }
+        // Drop matches inside comments
+        let builder = builder.stage(|| {
+            PipelineMap::new(|mut pm: PathMatch| {
+                let src = fs::read(&pm.path).unwrap();
+                pm.matches.retain(|m| {
+                    let beg = src[..m.beg].iter().rposition(|x| *x == b'\n').map_or(0, |x| x + 1);
+                    !src[beg..m.beg].starts_with(b"//")
+                });
+                pm
+            })
+        });
+        let (tx, rx) = builder.build();
+
+        let _ = tx.send(PipelineInfo::SeqBeg(0));
+        let _ = tx.send(PipelineInfo::SeqDat(0, dir.path().to_path_buf()));
+        let _ = tx.send(PipelineInfo::SeqEnd(1));
+
+        let mut ret = Vec::new();
+        let mut stage_ids = Vec::new();
+        loop {
+            match rx.recv().unwrap() {
+                PipelineInfo::SeqDat(_, pm) => ret.push((pm.path.file_name().unwrap().to_owned(), pm.matches.len())),
+                PipelineInfo::MsgTime(id, _, _) if id >= ID_MATCHER + 2 => stage_ids.push(id),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+        ret.sort();
+        stage_ids.sort();
+
+        assert_eq!(ret, vec![("a.rs".into(), 1), ("b.rs".into(), 0)]);
+        assert_eq!(stage_ids, vec![ID_MATCHER + 2, ID_MATCHER + 3]);
+    }
+
+    #[test]
+    fn pipeline_builder_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..100 {
+            fs::write(dir.path().join(format!("{}.txt", i)), "keyword\n").unwrap();
+        }
+
+        let mut finder = PipelineFinder::new();
+        finder.batch_size = 8;
+        let mut builder = PipelineBuilder::new(finder);
+        builder.batch_size = 8;
+        for _ in 0..3 {
+            let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"keyword");
+            matcher.batch_size = 8;
+            builder = builder.matcher(matcher);
+        }
+        let builder = builder.stage(|| PipelineMap::new(|pm: PathMatch| pm));
+        let (tx, rx) = builder.build();
+
+        let _ = tx.send(PipelineInfo::SeqBeg(0));
+        let _ = tx.send(PipelineInfo::SeqDat(0, dir.path().to_path_buf()));
+        let _ = tx.send(PipelineInfo::SeqEnd(1));
+
+        let mut rx = BatchReceiver::new(rx);
+        let mut ret = Vec::new();
+        loop {
+            match rx.recv().unwrap() {
+                PipelineInfo::SeqDat(x, pm) => ret.push((x, pm.matches.len())),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+
+        assert_eq!(ret, (0..100).map(|x| (x, 1)).collect::<Vec<_>>());
+    }
+}
@@ -1,9 +1,27 @@
+use crate::cache::CACHE_DIR;
 use crate::ignore::{Ignore, IgnoreGit, IgnoreVcs};
-use crate::pipeline::{PipelineFork, PipelineInfo};
+use crate::index::{Index, INDEX_DIR};
+use crate::pipeline::{BatchReceiver, BatchSender, CancelToken, PipelineFork, PipelineInfo, ProgressCounter};
+use crate::tmpfile::is_leftover;
+use crate::trace::Tracer;
+use crate::util::{
+    alternate_streams, device_id, entry_inode, file_id, file_mode, file_owner, long_path, special_file_kind, user_id,
+};
 use crossbeam::channel::{Receiver, Sender};
-use std::fs;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use crossbeam::deque::{Injector, Stealer, Worker};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, FileType};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::iter;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 // ---------------------------------------------------------------------------------------------------------------------
 // PathInfo
@@ -12,6 +30,411 @@ use std::time::{Duration, Instant};
 #[derive(Debug, Clone)]
 pub struct PathInfo {
     pub path: PathBuf,
+    /// Contents searched instead of the file at `path` ( e.g. a blob of git revision )
+    pub content: Option<Arc<Vec<u8>>>,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// VcsFilter
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VcsFilter {
+    Tracked,
+    Modified,
+    Staged,
+}
+
+impl FromStr for VcsFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tracked" => Ok(VcsFilter::Tracked),
+            "modified" => Ok(VcsFilter::Modified),
+            "staged" => Ok(VcsFilter::Staged),
+            _ => Err(format!("invalid vcs filter: {}", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// OwnerFilter
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Owner of files given by the user name or the user ID ( Unix only )
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnerFilter {
+    pub name: String,
+    pub uid: u32,
+}
+
+impl FromStr for OwnerFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uid = match s.parse() {
+            Ok(x) => x,
+            Err(_) => user_id(s).ok_or_else(|| format!("unknown user: {}", s))?,
+        };
+        Ok(OwnerFilter {
+            name: String::from(s),
+            uid,
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PermFilter
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Permission bits of files in octal like find -perm ( Unix only )
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PermFilter {
+    /// `644`: the permission is exactly the bits
+    Exact(u32),
+    /// `-022`: all the bits are set
+    All(u32),
+    /// `/002`: any of the bits is set
+    Any(u32),
+}
+
+impl PermFilter {
+    pub fn is_match(self, mode: u32) -> bool {
+        match self {
+            PermFilter::Exact(x) => mode == x,
+            PermFilter::All(x) => mode & x == x,
+            PermFilter::Any(x) => mode & x != 0 || x == 0,
+        }
+    }
+}
+
+impl FromStr for PermFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ctor, bits): (fn(u32) -> PermFilter, &str) = if let Some(x) = s.strip_prefix('-') {
+            (PermFilter::All, x)
+        } else if let Some(x) = s.strip_prefix('/') {
+            (PermFilter::Any, x)
+        } else {
+            (PermFilter::Exact, s)
+        };
+        match u32::from_str_radix(bits, 8) {
+            Ok(x) if x <= 0o7777 && !bits.starts_with('+') => Ok(ctor(x)),
+            _ => Err(format!("invalid permission: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PermFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PermFilter::Exact(x) => write!(f, "{:o}", x),
+            PermFilter::All(x) => write!(f, "-{:o}", x),
+            PermFilter::Any(x) => write!(f, "/{:o}", x),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// DirWalker
+// ---------------------------------------------------------------------------------------------------------------------
+
+// Entries of a directory with the inodes
+type DirEntries = Result<Vec<Result<(PathBuf, FileType, Option<u64>), Error>>, Error>;
+
+enum DirState {
+    Queued,
+    Reading,
+    Done(DirEntries),
+}
+
+struct DirStates {
+    map: HashMap<PathBuf, DirState>,
+    /// Number of directories read ahead and not taken by the finder yet
+    done: usize,
+    closed: bool,
+}
+
+type SharedDirStates = Arc<(Mutex<DirStates>, Condvar)>;
+
+// Directories read ahead are limited not to hold a whole tree in memory while the pipeline is busy
+const MAX_READ_AHEAD: usize = 4096;
+
+/// Walks directories ahead by worker threads which push subdirectories to their own deques and steal them from each
+/// other, while the finder visits directories in order to number files deterministically
+struct DirWalker {
+    injector: Arc<Injector<PathBuf>>,
+    states: SharedDirStates,
+}
+
+impl DirWalker {
+    fn new(threads: usize, skip_vcs: bool, dir_cache: Option<Arc<DirCache>>) -> Self {
+        let injector = Arc::new(Injector::new());
+        let states = DirStates {
+            map: HashMap::new(),
+            done: 0,
+            closed: false,
+        };
+        let states: SharedDirStates = Arc::new((Mutex::new(states), Condvar::new()));
+        let workers: Vec<_> = (0..threads).map(|_| Worker::new_lifo()).collect();
+        let stealers: Arc<Vec<_>> = Arc::new(workers.iter().map(|x| x.stealer()).collect());
+        for local in workers {
+            let injector = injector.clone();
+            let stealers = stealers.clone();
+            let states = states.clone();
+            let dir_cache = dir_cache.clone();
+            let _ = thread::Builder::new().name("finder".to_string()).spawn(move || {
+                let ignore_vcs = IgnoreVcs::new();
+                while let Some(path) = DirWalker::next_dir(&local, &injector, &stealers, &states) {
+                    let entries = read_dir_cached(&path, &dir_cache);
+                    let mut guard = states.0.lock().unwrap();
+                    // Discarded or taken by the finder while reading
+                    match guard.map.get(&path) {
+                        Some(DirState::Reading) => (),
+                        _ => continue,
+                    }
+                    // Subdirectories are pushed in reverse to be popped in the order the finder visits them
+                    if let Ok(ref entries) = entries {
+                        for (child, file_type, _) in entries.iter().rev().flatten() {
+                            let skip = child.ends_with(INDEX_DIR)
+                                || child.ends_with(CACHE_DIR)
+                                || (skip_vcs && ignore_vcs.is_ignore(child, true));
+                            if file_type.is_dir() && !skip && !guard.map.contains_key(child) {
+                                guard.map.insert(child.clone(), DirState::Queued);
+                                local.push(child.clone());
+                            }
+                        }
+                    }
+                    guard.map.insert(path, DirState::Done(entries));
+                    guard.done += 1;
+                    states.1.notify_all();
+                }
+            });
+        }
+        DirWalker { injector, states }
+    }
+
+    // Own subdirectories are read at first to walk depth-first like the finder, and others are stolen if empty
+    fn next_dir(
+        local: &Worker<PathBuf>,
+        injector: &Injector<PathBuf>,
+        stealers: &[Stealer<PathBuf>],
+        states: &SharedDirStates,
+    ) -> Option<PathBuf> {
+        loop {
+            let path = local.pop().or_else(|| {
+                iter::repeat_with(|| {
+                    injector
+                        .steal_batch_and_pop(local)
+                        .or_else(|| stealers.iter().map(|x| x.steal()).collect())
+                })
+                .find(|x| !x.is_retry())
+                .and_then(|x| x.success())
+            });
+
+            let mut guard = states.0.lock().unwrap();
+            let path = match path {
+                Some(x) => x,
+                None => {
+                    if guard.closed {
+                        return None;
+                    }
+                    // A timeout is used because directories may be pushed between stealing and waiting
+                    let _ = states.1.wait_timeout(guard, Duration::from_millis(10)).unwrap();
+                    continue;
+                }
+            };
+            while guard.done >= MAX_READ_AHEAD && !guard.closed {
+                guard = states.1.wait(guard).unwrap();
+            }
+            if guard.closed {
+                return None;
+            }
+            match guard.map.get_mut(&path) {
+                Some(x @ DirState::Queued) => {
+                    *x = DirState::Reading;
+                    return Some(path);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn prefetch(&self, path: PathBuf) {
+        {
+            let mut guard = self.states.0.lock().unwrap();
+            // Already pushed by the worker which read the parent directory
+            if guard.map.contains_key(&path) {
+                return;
+            }
+            guard.map.insert(path.clone(), DirState::Queued);
+        }
+        self.injector.push(path);
+        self.states.1.notify_all();
+    }
+
+    // A directory not read yet by workers is read by the caller not to wait for the queue
+    fn read_dir(&self, path: &PathBuf, dir_cache: &Option<Arc<DirCache>>) -> DirEntries {
+        let mut guard = self.states.0.lock().unwrap();
+        loop {
+            match guard.map.remove(path) {
+                Some(DirState::Done(x)) => {
+                    guard.done -= 1;
+                    self.states.1.notify_all();
+                    return x;
+                }
+                Some(DirState::Reading) => {
+                    guard.map.insert(path.clone(), DirState::Reading);
+                    guard = self.states.1.wait(guard).unwrap();
+                }
+                Some(DirState::Queued) | None => break,
+            }
+        }
+        drop(guard);
+        read_dir_cached(path, dir_cache)
+    }
+
+    // A directory skipped by the finder is dropped with the subdirectories read ahead under it
+    fn discard(&self, path: &Path) {
+        let mut guard = self.states.0.lock().unwrap();
+        match guard.map.remove(path) {
+            Some(DirState::Queued) | None => return,
+            Some(DirState::Reading) => (),
+            Some(DirState::Done(_)) => guard.done -= 1,
+        }
+        let states = &mut *guard;
+        let mut done = 0;
+        states.map.retain(|k, v| {
+            let descendant = k.starts_with(path);
+            if descendant {
+                if let DirState::Done(_) = v {
+                    done += 1;
+                }
+            }
+            !descendant
+        });
+        states.done -= done;
+        drop(guard);
+        self.states.1.notify_all();
+    }
+}
+
+impl Drop for DirWalker {
+    fn drop(&mut self) {
+        self.states.0.lock().unwrap().closed = true;
+        self.states.1.notify_all();
+    }
+}
+
+fn read_dir_entries(path: &Path) -> DirEntries {
+    let reader = fs::read_dir(long_path(path))?;
+    // Entries are joined to the original path not to print the extended-length form
+    Ok(reader
+        .map(|entry| {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            Ok((path.join(entry.file_name()), file_type, entry_inode(&entry)))
+        })
+        .collect())
+}
+
+fn read_dir_cached(path: &Path, dir_cache: &Option<Arc<DirCache>>) -> DirEntries {
+    match dir_cache {
+        Some(x) => x.read_dir(path),
+        None => read_dir_entries(path),
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// DirCache
+// ---------------------------------------------------------------------------------------------------------------------
+
+// A directory modified within this duration before reading may be modified again with the same mtime
+const RACY_DURATION: Duration = Duration::from_secs(1);
+
+struct DirListing {
+    mtime: SystemTime,
+    entries: Vec<(OsString, FileType, Option<u64>)>,
+    used: bool,
+}
+
+/// Listings of directories shared between searches ( e.g. kept by the daemon ), which are read again if the mtime of
+/// the directory is changed
+#[derive(Default)]
+pub struct DirCache {
+    map: Mutex<HashMap<PathBuf, DirListing>>,
+}
+
+impl DirCache {
+    pub fn new() -> Self {
+        DirCache::default()
+    }
+
+    /// Number of the directories cached
+    pub fn len(&self) -> usize {
+        self.map.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the directories not read since the last prune ( e.g. removed or ignored now )
+    pub fn prune(&self) {
+        self.map.lock().unwrap().retain(|_, x| {
+            let used = x.used;
+            x.used = false;
+            used
+        });
+    }
+
+    fn read_dir(&self, path: &Path) -> DirEntries {
+        // The key is absolute because the same directory is given by paths relative to each working directory
+        let key = match env::current_dir() {
+            Ok(x) => x
+                .join(path)
+                .components()
+                .filter(|x| *x != Component::CurDir)
+                .collect::<PathBuf>(),
+            Err(_) => return read_dir_entries(path),
+        };
+        let mtime = match fs::metadata(long_path(path)).and_then(|x| x.modified()) {
+            Ok(x) => x,
+            Err(_) => return read_dir_entries(path),
+        };
+
+        if let Some(x) = self.map.lock().unwrap().get_mut(&key) {
+            if x.mtime == mtime {
+                x.used = true;
+                return Ok(x
+                    .entries
+                    .iter()
+                    .map(|(name, file_type, inode)| Ok((path.join(name), *file_type, *inode)))
+                    .collect());
+            }
+        }
+
+        // mtime is taken before reading, so the listing is read again if the directory is modified while reading
+        let entries = read_dir_entries(path)?;
+        let racy = SystemTime::now()
+            .duration_since(mtime)
+            .map_or(true, |x| x < RACY_DURATION);
+        if !racy && entries.iter().all(|x| x.is_ok()) {
+            let listing = DirListing {
+                mtime,
+                entries: entries
+                    .iter()
+                    .flatten()
+                    .filter_map(|(x, file_type, inode)| Some((x.file_name()?.to_os_string(), *file_type, *inode)))
+                    .collect(),
+                used: true,
+            };
+            self.map.lock().unwrap().insert(key, listing);
+        }
+        Ok(entries)
+    }
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -27,14 +450,63 @@ pub struct PipelineFinder {
     pub skip_ambignore: bool,
     pub print_skipped: bool,
     pub find_parent_ignore: bool,
+    pub vcs_filter: Option<VcsFilter>,
+    pub rev: Option<String>,
+    pub index: Option<Arc<Index>>,
+    pub index_trigrams: Vec<u32>,
+    /// Number of threads reading directories ( 1: read by the finder itself )
+    pub threads: usize,
+    /// Send files in ascending order of size after finding all files
+    pub small_first: bool,
+    /// Send NTFS alternate data streams of files too ( Windows only )
+    pub alternate_streams: bool,
+    /// Skip directories on other devices than the base path
+    pub one_file_system: bool,
+    /// Stop finding files when cancelled, and end the sequence with the files already sent
+    pub cancel: CancelToken,
+    /// Read the listings of directories not modified from the cache instead of the file system
+    pub dir_cache: Option<Arc<DirCache>>,
+    /// Skip hard links and symbolic links of files already found ( Unix only )
+    pub dedupe: bool,
+    /// Files replaced before resuming, which are not searched again
+    pub completed: Option<Arc<HashSet<PathBuf>>>,
+    /// Send only files owned by the user ( Unix only )
+    pub owner: Option<OwnerFilter>,
+    /// Send only files with the permission bits ( Unix only )
+    pub perm: Option<PermFilter>,
+    /// Skip symbolic links resolved through more links than this ( e.g. a -> b -> c is 2 )
+    pub max_symlink_depth: Option<usize>,
+    /// Report each broken symbolic link with the target, which is counted by `broken_symlinks` anyway
+    pub print_broken_symlinks: bool,
+    /// Search FIFOs and device files, which are skipped by default because reading them may block or never end
+    pub special_files: bool,
+    /// Incremented for each symbolic link whose target doesn't exist or can't be resolved
+    pub broken_symlinks: ProgressCounter,
+    /// Incremented for each file found including the pending files of `small_first`
+    pub discovered: ProgressCounter,
+    /// Temporary files of amber skipped, which may be left by a killed replacement
+    pub leftovers: Arc<Mutex<Vec<PathBuf>>>,
+    /// Number of files sent at once to each matcher ( 1: one by one )
+    pub batch_size: usize,
+    /// Log the files skipped with the reasons at the level 1, and the directories and files found at the level 2
+    pub trace: Tracer,
     pub infos: Vec<String>,
     pub errors: Vec<String>,
     time_beg: Instant,
     time_bsy: Duration,
+    items: usize,
+    bytes: u64,
     seq_no: usize,
     current_tx: usize,
+    batches: Vec<BatchSender<PathInfo>>,
     ignore_vcs: IgnoreVcs,
     ignore_git: Vec<IgnoreGit>,
+    ignore_repo: Vec<Vec<IgnoreGit>>,
+    ignore_amb: Vec<IgnoreGit>,
+    walker: Option<DirWalker>,
+    base_device: Option<u64>,
+    found_files: HashSet<(u64, u64)>,
+    pending: Vec<(u64, PathInfo)>,
 }
 
 impl PipelineFinder {
@@ -48,22 +520,70 @@ impl PipelineFinder {
             skip_ambignore: true,
             print_skipped: false,
             find_parent_ignore: true,
+            vcs_filter: None,
+            rev: None,
+            index: None,
+            index_trigrams: Vec::new(),
+            threads: 1,
+            small_first: false,
+            alternate_streams: false,
+            one_file_system: false,
+            cancel: CancelToken::new(),
+            dir_cache: None,
+            dedupe: true,
+            completed: None,
+            owner: None,
+            perm: None,
+            max_symlink_depth: None,
+            print_broken_symlinks: false,
+            special_files: false,
+            broken_symlinks: ProgressCounter::new(),
+            discovered: ProgressCounter::new(),
+            leftovers: Arc::new(Mutex::new(Vec::new())),
+            batch_size: 1,
+            trace: Tracer::default(),
             infos: Vec::new(),
             errors: Vec::new(),
             time_beg: Instant::now(),
             time_bsy: Duration::new(0, 0),
+            items: 0,
+            bytes: 0,
             seq_no: 0,
             current_tx: 0,
+            batches: Vec::new(),
             ignore_vcs: IgnoreVcs::new(),
             ignore_git: Vec::new(),
+            ignore_repo: Vec::new(),
+            ignore_amb: Vec::new(),
+            walker: None,
+            base_device: None,
+            found_files: HashSet::new(),
+            pending: Vec::new(),
         }
     }
 
     fn find_path(&mut self, base: PathBuf, tx: &Vec<Sender<PipelineInfo<PathInfo>>>, is_symlink: bool) {
-        let attr = match fs::metadata(&base) {
+        if self.cancel.is_cancelled() {
+            return;
+        }
+        if let (Some(max), true) = (self.max_symlink_depth, is_symlink) {
+            if symlink_depth(&base, max) > max {
+                self.skip(&base, &format!("symlink chain deeper than {}", max));
+                return;
+            }
+        }
+        let attr = match fs::metadata(long_path(&base)) {
             Ok(x) => x,
             Err(e) => {
-                if !is_symlink {
+                self.discard_dir(&base);
+                if is_symlink {
+                    self.broken_symlinks.add(1);
+                    if self.print_broken_symlinks {
+                        let target = fs::read_link(&base).unwrap_or_default();
+                        self.infos
+                            .push(format!("Broken symlink: {:?} -> {:?} ( {} )\n", base, target, e));
+                    }
+                } else {
                     self.errors.push(format!("Error: {} @ {}", e, base.to_str().unwrap()));
                 }
                 return;
@@ -72,10 +592,20 @@ impl PipelineFinder {
 
         if attr.is_file() {
             if attr.len() != 0 {
-                self.send_path(base, &tx);
+                self.send_path(base, file_id(&attr), &tx);
             }
+        } else if let Some(kind) = special_file_kind(&attr.file_type()) {
+            self.send_special(base, kind, tx);
+        } else if self.one_file_system && device_id(&attr) != self.base_device {
+            self.discard_dir(&base);
+            self.skip(&base, "other file system");
         } else {
-            let reader = match fs::read_dir(&base) {
+            self.trace.log(2, || format!("Entered: {:?}", base));
+            let entries = match self.walker {
+                Some(ref x) => x.read_dir(&base, &self.dir_cache),
+                None => read_dir_cached(&base, &self.dir_cache),
+            };
+            let entries = match entries {
                 Ok(x) => x,
                 Err(e) => {
                     self.errors.push(format!("Error: {} @ {}", e, base.to_str().unwrap()));
@@ -83,25 +613,37 @@ impl PipelineFinder {
                 }
             };
 
-            let gitignore_exist = self.push_gitignore(&base);
+            // .gitignore is found from the entries not to read the directory again
+            let gitignore = entries
+                .iter()
+                .flatten()
+                .map(|(x, _, _)| x)
+                .find(|x| x.ends_with(".gitignore"));
+            let gitignore_exist = self.push_gitignore_file(gitignore.cloned());
+            let repo_exist = self.push_repo_ignore(&base);
+            let ambignore_exist = self.push_ambignore(&base);
 
-            for i in reader {
+            // Directories are checked at first to be prefetched, and visited in the order of entries
+            let device = device_id(&attr);
+            let mut paths = Vec::new();
+            let mut specials = Vec::new();
+            for i in entries {
                 match i {
-                    Ok(entry) => {
-                        let file_type = match entry.file_type() {
-                            Ok(x) => x,
-                            Err(e) => {
-                                self.errors.push(format!("Error: {}", e));
-                                continue;
-                            }
-                        };
+                    Ok((path, file_type, inode)) => {
                         if file_type.is_file() {
-                            self.send_path(entry.path(), &tx);
+                            paths.push((path, None, device.zip(inode)));
+                        } else if let Some(kind) = special_file_kind(&file_type) {
+                            specials.push((path, kind));
                         } else {
                             let find_dir = file_type.is_dir() & self.is_recursive;
                             let find_symlink = file_type.is_symlink() & self.is_recursive & self.follow_symlink;
-                            if (find_dir | find_symlink) & self.check_path(&entry.path(), true) {
-                                self.find_path(entry.path(), &tx, find_symlink);
+                            if (find_dir | find_symlink) & self.check_path(&path, true) {
+                                if let (Some(ref x), true) = (&self.walker, find_dir) {
+                                    x.prefetch(path.clone());
+                                }
+                                paths.push((path, Some(find_symlink), None));
+                            } else if file_type.is_dir() {
+                                self.discard_dir(&path);
                             }
                         }
                     }
@@ -109,19 +651,256 @@ impl PipelineFinder {
                 };
             }
 
+            for (path, is_symlink, id) in paths {
+                match is_symlink {
+                    None => self.send_path(path, id, tx),
+                    Some(x) => self.find_path(path, tx, x),
+                }
+            }
+            for (path, kind) in specials {
+                self.send_special(path, kind, tx);
+            }
+
+            self.pop_ambignore(ambignore_exist);
+            self.pop_repo_ignore(repo_exist);
             self.pop_gitignore(gitignore_exist)
         }
     }
 
-    fn send_path(&mut self, path: PathBuf, tx: &Vec<Sender<PipelineInfo<PathInfo>>>) {
-        if self.check_path(&path, false) {
-            let _ = tx[self.current_tx].send(PipelineInfo::SeqDat(self.seq_no, PathInfo { path: path }));
-            self.seq_no += 1;
-            self.current_tx = if self.current_tx == tx.len() - 1 {
-                0
-            } else {
-                self.current_tx + 1
+    fn find_vcs_path(&mut self, base: PathBuf, filter: VcsFilter, tx: &Vec<Sender<PipelineInfo<PathInfo>>>) {
+        let (dir, pathspec) = if base.is_dir() {
+            (base.clone(), None)
+        } else {
+            let dir = match base.parent() {
+                Some(x) if x.as_os_str() != "" => x.to_path_buf(),
+                _ => PathBuf::from("./"),
+            };
+            (dir, base.file_name().map(PathBuf::from))
+        };
+
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(&dir);
+        match filter {
+            VcsFilter::Tracked => cmd.args(["ls-files", "-z"]),
+            VcsFilter::Modified => cmd.args(["diff", "--name-only", "--relative", "--diff-filter=d", "-z"]),
+            VcsFilter::Staged => cmd.args(["diff", "--cached", "--name-only", "--relative", "--diff-filter=d", "-z"]),
+        };
+        if let Some(pathspec) = pathspec {
+            cmd.arg("--").arg(pathspec);
+        }
+
+        let output = match cmd.output() {
+            Ok(x) => x,
+            Err(e) => {
+                self.errors
+                    .push(format!("Error: git: {} @ {}", e, base.to_str().unwrap()));
+                return;
+            }
+        };
+        if !output.status.success() {
+            self.errors.push(format!(
+                "Error: git: {} @ {}",
+                String::from_utf8_lossy(&output.stderr).trim_end(),
+                base.to_str().unwrap()
+            ));
+            return;
+        }
+
+        for name in output.stdout.split(|x| *x == 0) {
+            if name.is_empty() {
+                continue;
+            }
+            let path = dir.join(String::from_utf8_lossy(name).as_ref());
+            match fs::symlink_metadata(&path) {
+                Ok(ref x) if x.is_file() && x.len() != 0 => self.send_path(path, None, tx),
+                _ => (),
+            }
+        }
+    }
+
+    fn find_rev_path(&mut self, base: PathBuf, rev: &str, tx: &[Sender<PipelineInfo<PathInfo>>]) {
+        let (dir, pathspec) = if base.is_dir() {
+            (base.clone(), PathBuf::from("."))
+        } else {
+            let dir = match base.parent() {
+                Some(x) if x.as_os_str() != "" => x.to_path_buf(),
+                _ => PathBuf::from("./"),
+            };
+            (dir, base.file_name().map(PathBuf::from).unwrap_or_default())
+        };
+
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&dir)
+            .args(["ls-tree", "-r", "-z", rev, "--"])
+            .arg(&pathspec)
+            .output();
+        let output = match output {
+            Ok(x) if x.status.success() => x,
+            Ok(x) => {
+                self.errors.push(format!(
+                    "Error: git: {} @ {}",
+                    String::from_utf8_lossy(&x.stderr).trim_end(),
+                    base.to_str().unwrap()
+                ));
+                return;
+            }
+            Err(e) => {
+                self.errors
+                    .push(format!("Error: git: {} @ {}", e, base.to_str().unwrap()));
+                return;
+            }
+        };
+
+        let cat_file = Command::new("git")
+            .arg("-C")
+            .arg(&dir)
+            .args(["cat-file", "--batch"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut cat_file = match cat_file {
+            Ok(x) => x,
+            Err(e) => {
+                self.errors
+                    .push(format!("Error: git: {} @ {}", e, base.to_str().unwrap()));
+                return;
+            }
+        };
+        let mut stdin = cat_file.stdin.take().unwrap();
+        let mut stdout = BufReader::new(cat_file.stdout.take().unwrap());
+
+        for entry in output.stdout.split(|x| *x == 0) {
+            // <mode> SP <type> SP <object> TAB <file>
+            let entry = String::from_utf8_lossy(entry);
+            let (meta, name) = match entry.split_once('\t') {
+                Some(x) => x,
+                None => continue,
+            };
+            let meta: Vec<&str> = meta.split(' ').collect();
+            if meta.len() != 3 || meta[0] == "120000" || meta[1] != "blob" {
+                continue;
+            }
+
+            let path = PathBuf::from(format!("{}:{}", rev, dir.join(name).to_str().unwrap()));
+            match read_blob(&mut stdin, &mut stdout, meta[2]) {
+                Ok(x) => {
+                    if !x.is_empty() {
+                        self.send_info(
+                            PathInfo {
+                                path,
+                                content: Some(Arc::new(x)),
+                            },
+                            tx,
+                        );
+                    }
+                }
+                Err(e) => self.errors.push(format!("Error: git: {} @ {:?}", e, path)),
+            }
+        }
+
+        drop(stdin);
+        let _ = cat_file.wait();
+    }
+
+    fn discard_dir(&self, path: &Path) {
+        if let Some(ref x) = self.walker {
+            x.discard(path);
+        }
+    }
+
+    // Sockets can't be opened by open(2), so they are skipped even with --special-files
+    fn send_special(&mut self, path: PathBuf, kind: &str, tx: &Vec<Sender<PipelineInfo<PathInfo>>>) {
+        if self.special_files && kind != "socket" {
+            self.send_path(path, None, tx);
+        } else {
+            self.skip(&path, &format!("special file: {}", kind));
+        }
+    }
+
+    fn send_path(&mut self, path: PathBuf, id: Option<(u64, u64)>, tx: &[Sender<PipelineInfo<PathInfo>>]) {
+        if !self.check_path(&path, false) {
+            return;
+        }
+        if is_leftover(&path) {
+            self.leftovers.lock().unwrap().push(path.clone());
+            self.skip(&path, "temporary file of amber");
+            return;
+        }
+        if self.completed.as_ref().is_some_and(|x| x.contains(&path)) {
+            self.skip(&path, "completed before --resume");
+            return;
+        }
+        if !self.check_attr(&path) {
+            return;
+        }
+        if let (Some(id), true) = (id, self.dedupe) {
+            if !self.found_files.insert(id) {
+                self.skip(&path, "same file already found");
+                return;
+            }
+        }
+        // Streams are not indexed, so they are sent even if the file itself is filtered by the index
+        let streams = if self.alternate_streams {
+            alternate_streams(&path)
+        } else {
+            Vec::new()
+        };
+        if self.check_index(&path) {
+            self.send_info(PathInfo { path, content: None }, tx);
+        }
+        for path in streams {
+            self.send_info(PathInfo { path, content: None }, tx);
+        }
+    }
+
+    fn send_info(&mut self, info: PathInfo, tx: &[Sender<PipelineInfo<PathInfo>>]) {
+        self.discovered.add(1);
+        self.trace.log(2, || format!("Found: {:?}", info.path));
+        if self.small_first {
+            let size = match info.content {
+                Some(ref x) => x.len() as u64,
+                None => fs::metadata(&info.path).map(|x| x.len()).unwrap_or(0),
             };
+            self.pending.push((size, info));
+            return;
+        }
+        self.send_info_now(info, tx);
+    }
+
+    fn send_info_now(&mut self, info: PathInfo, tx: &[Sender<PipelineInfo<PathInfo>>]) {
+        if self.cancel.is_cancelled() {
+            return;
+        }
+        self.items += 1;
+        if let Some(ref x) = info.content {
+            self.bytes += x.len() as u64;
+        }
+        let _ = self.batches[self.current_tx].send(PipelineInfo::SeqDat(self.seq_no, info));
+        self.seq_no += 1;
+        // Matchers are switched after each batch is sent
+        if !self.batches[self.current_tx].is_empty() {
+            return;
+        }
+        self.current_tx = if self.current_tx == tx.len() - 1 {
+            0
+        } else {
+            self.current_tx + 1
+        };
+    }
+
+    fn flush_batches(&mut self) {
+        for x in &mut self.batches {
+            let _ = x.flush();
+        }
+    }
+
+    // Smaller files are sent at first to be matched and printed soon
+    fn send_pending(&mut self, tx: &[Sender<PipelineInfo<PathInfo>>]) {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.sort_by_key(|(size, _)| *size);
+        for (_, info) in pending {
+            self.send_info_now(info, tx);
         }
     }
 
@@ -146,13 +925,124 @@ impl PipelineFinder {
         false
     }
 
+    fn push_gitignore_file(&mut self, file: Option<PathBuf>) -> bool {
+        match file {
+            Some(ref x) if self.skip_gitignore => {
+                self.ignore_git.push(IgnoreGit::new(x));
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn pop_gitignore(&mut self, exist: bool) {
         if exist {
             let _ = self.ignore_git.pop();
         }
     }
 
+    // info/exclude and core.excludesFile ( ~/.config/git/ignore by default ) are applied in the repository like git
+    fn push_repo_ignore(&mut self, path: &PathBuf) -> bool {
+        if !self.skip_gitignore {
+            return false;
+        }
+
+        let git_dir = path.join(".git");
+        if !git_dir.is_dir() {
+            return false;
+        }
+
+        let mut ignores = Vec::new();
+        let files = [Some(git_dir.join("info").join("exclude")), excludes_file(path)];
+        for file in files.iter().flatten() {
+            if file.is_file() {
+                ignores.push(IgnoreGit::with_base(file, path));
+            }
+        }
+        self.ignore_repo.push(ignores);
+        true
+    }
+
+    fn pop_repo_ignore(&mut self, exist: bool) {
+        if exist {
+            let _ = self.ignore_repo.pop();
+        }
+    }
+
+    // .amberignore has the same syntax as .gitignore, and excludes files only from amber
+    fn push_ambignore(&mut self, path: &Path) -> bool {
+        if !self.skip_ambignore {
+            return false;
+        }
+
+        let file = path.join(".amberignore");
+        if !file.is_file() {
+            return false;
+        }
+        self.ignore_amb.push(IgnoreGit::new(&file));
+        true
+    }
+
+    fn pop_ambignore(&mut self, exist: bool) {
+        if exist {
+            let _ = self.ignore_amb.pop();
+        }
+    }
+
+    fn check_index(&mut self, path: &Path) -> bool {
+        let ok_index = match self.index {
+            Some(ref index) => index.may_contain(path, &self.index_trigrams),
+            None => true,
+        };
+
+        if !ok_index {
+            self.skip(path, "index");
+        }
+
+        ok_index
+    }
+
+    // Owner and permission are read only if filtered, because the entries of directories don't have them
+    fn check_attr(&mut self, path: &Path) -> bool {
+        if self.owner.is_none() && self.perm.is_none() {
+            return true;
+        }
+        let attr = match fs::metadata(long_path(path)) {
+            Ok(x) => x,
+            Err(e) => {
+                self.errors.push(format!("Error: {} @ {}", e, path.to_string_lossy()));
+                return false;
+            }
+        };
+        if let (Some(owner), Some(uid)) = (&self.owner, file_owner(&attr)) {
+            if uid != owner.uid {
+                let reason = format!("not owned by {}", owner.name);
+                self.skip(path, &reason);
+                return false;
+            }
+        }
+        if let (Some(perm), Some(mode)) = (self.perm, file_mode(&attr)) {
+            if !perm.is_match(mode) {
+                self.skip(path, &format!("permission {:o} doesn't match {}", mode, perm));
+                return false;
+            }
+        }
+        true
+    }
+
+    // Skipped files are traced even if they are not printed
+    fn skip(&mut self, path: &Path, reason: &str) {
+        self.trace.log(1, || format!("Skipped: {:?} ( {} )", path, reason));
+        if self.print_skipped {
+            self.infos.push(format!("Skipped: {:?} ( {} )\n", path, reason));
+        }
+    }
+
     fn check_path(&mut self, path: &PathBuf, is_dir: bool) -> bool {
+        if is_dir && (path.ends_with(INDEX_DIR) || path.ends_with(CACHE_DIR)) {
+            return false;
+        }
+
         let ok_vcs = if self.skip_vcs {
             !self.ignore_vcs.is_ignore(&path, is_dir)
         } else {
@@ -165,15 +1055,33 @@ impl PipelineFinder {
             true
         };
 
-        if !ok_vcs & self.print_skipped {
-            self.infos.push(format!("Skipped: {:?} ( vcs file )\n", path));
+        let ok_repo = match self.ignore_repo.last() {
+            Some(x) if self.skip_gitignore => !x.iter().any(|x| x.is_ignore(path, is_dir)),
+            _ => true,
+        };
+
+        let ok_amb = match self.ignore_amb.last() {
+            Some(x) if self.skip_ambignore => !x.is_ignore(path, is_dir),
+            _ => true,
+        };
+
+        if !ok_vcs {
+            self.skip(path, "vcs file");
+        }
+
+        if !ok_repo {
+            self.skip(path, "git excludes");
+        }
+
+        if !ok_git {
+            self.skip(path, ".gitignore");
         }
 
-        if !ok_git & self.print_skipped {
-            self.infos.push(format!("Skipped: {:?} ( .gitignore )\n", path));
+        if !ok_amb {
+            self.skip(path, ".amberignore");
         }
 
-        ok_vcs && ok_git
+        ok_vcs && ok_git && ok_repo && ok_amb
     }
 
     fn set_default_gitignore(&mut self, base: &PathBuf) -> PathBuf {
@@ -211,20 +1119,147 @@ impl PipelineFinder {
 
         return base.clone();
     }
+
+    fn set_default_repo_ignore(&mut self, base: &Path) {
+        if !self.skip_gitignore || !self.find_parent_ignore {
+            return;
+        }
+
+        // The repository containing base is found to apply its excludes, while base itself is checked by find_path
+        for parent in parent_dirs(base) {
+            if self.push_repo_ignore(&parent) {
+                return;
+            }
+        }
+    }
+
+    fn set_default_ambignore(&mut self, base: &Path) {
+        if !self.skip_ambignore || !self.find_parent_ignore {
+            return;
+        }
+
+        for parent in parent_dirs(base) {
+            if self.push_ambignore(&parent) {
+                self.infos
+                    .push(format!("Found .amberignore at the parent directory: {:?}\n", parent));
+                return;
+            }
+        }
+    }
+}
+
+// Parent directories like `base/..` and `base/../..` up to the root
+fn parent_dirs(base: &Path) -> Vec<PathBuf> {
+    let base_abs = match base.canonicalize() {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut ret = Vec::new();
+    let mut parent_abs = base_abs.parent();
+    let mut parent = base.to_path_buf();
+    if parent.is_dir() {
+        parent.push("..");
+    } else {
+        parent = parent.parent().unwrap().to_path_buf();
+    }
+    while let Some(x) = parent_abs {
+        ret.push(parent.clone());
+        parent_abs = x.parent();
+        parent.push("..");
+    }
+    ret
+}
+
+// Number of symbolic links followed to resolve `path`, which is counted up to `max + 1`
+fn symlink_depth(path: &Path, max: usize) -> usize {
+    let mut path = path.to_path_buf();
+    let mut depth = 0;
+    while depth <= max {
+        let target = match fs::read_link(&path) {
+            Ok(x) => x,
+            Err(_) => break,
+        };
+        path = match path.parent() {
+            Some(x) => x.join(target),
+            None => target,
+        };
+        depth += 1;
+    }
+    depth
+}
+
+fn excludes_file(repo: &Path) -> Option<PathBuf> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["config", "--path", "--get", "core.excludesFile"])
+        .output();
+    if let Ok(output) = output {
+        let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !path.is_empty() {
+            return Some(repo.join(path));
+        }
+    }
+
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(ref x) if !x.is_empty() => Some(PathBuf::from(x).join("git").join("ignore")),
+        _ => dirs::home_dir().map(|x| x.join(".config").join("git").join("ignore")),
+    }
+}
+
+fn read_blob<W: Write, R: BufRead>(stdin: &mut W, stdout: &mut R, object: &str) -> Result<Vec<u8>, Error> {
+    writeln!(stdin, "{}", object)?;
+    stdin.flush()?;
+
+    // <object> SP <type> SP <size> LF <contents> LF
+    let mut header = String::new();
+    stdout.read_line(&mut header)?;
+    let size = header
+        .trim_end()
+        .rsplit(' ')
+        .next()
+        .and_then(|x| x.parse::<usize>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, header.trim_end().to_string()))?;
+
+    let mut buf = vec![0; size + 1];
+    stdout.read_exact(&mut buf)?;
+    buf.truncate(size);
+    Ok(buf)
 }
 
 impl PipelineFork<PathBuf, PathInfo> for PipelineFinder {
     fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<PathBuf>>, tx: Vec<Sender<PipelineInfo<PathInfo>>>) {
         self.infos = Vec::new();
         self.errors = Vec::new();
+        self.batches = tx
+            .iter()
+            .map(|x| BatchSender::new(x.clone(), self.batch_size))
+            .collect();
+        self.walker = if self.threads > 1 {
+            Some(DirWalker::new(self.threads - 1, self.skip_vcs, self.dir_cache.clone()))
+        } else {
+            None
+        };
+        let mut rx = BatchReceiver::new(rx);
         let mut seq_beg_arrived = false;
 
         loop {
+            if rx.is_empty() {
+                self.flush_batches();
+            }
             match rx.recv() {
                 Ok(PipelineInfo::SeqDat(_, p)) => {
                     watch_time!(self.time_bsy, {
                         let p = self.set_default_gitignore(&p);
-                        self.find_path(p, &tx, false);
+                        self.set_default_repo_ignore(&p);
+                        self.set_default_ambignore(&p);
+                        self.base_device = fs::metadata(long_path(&p)).ok().and_then(|x| device_id(&x));
+                        match (self.rev.clone(), self.vcs_filter) {
+                            (Some(rev), _) => self.find_rev_path(p, &rev, &tx),
+                            (None, Some(filter)) => self.find_vcs_path(p, filter, &tx),
+                            (None, None) => self.find_path(p, &tx, false),
+                        }
                     });
                 }
 
@@ -242,6 +1277,11 @@ impl PipelineFork<PathBuf, PathInfo> for PipelineFinder {
                 }
 
                 Ok(PipelineInfo::SeqEnd(_)) => {
+                    watch_time!(self.time_bsy, {
+                        self.send_pending(&tx);
+                        self.flush_batches();
+                    });
+
                     for i in &self.infos {
                         let _ = tx[0].send(PipelineInfo::MsgInfo(id, i.clone()));
                     }
@@ -249,12 +1289,15 @@ impl PipelineFork<PathBuf, PathInfo> for PipelineFinder {
                         let _ = tx[0].send(PipelineInfo::MsgErr(id, e.clone()));
                     }
 
+                    let _ = tx[0].send(PipelineInfo::MsgCount(id, self.items, self.bytes));
                     let _ = tx[0].send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
 
                     for tx in &tx {
                         let _ = tx.send(PipelineInfo::SeqEnd(self.seq_no));
                     }
 
+                    self.walker = None;
+                    self.batches.clear();
                     break;
                 }
 
@@ -267,6 +1310,10 @@ impl PipelineFork<PathBuf, PathInfo> for PipelineFinder {
                 Ok(PipelineInfo::MsgTime(i, t0, t1)) => {
                     let _ = tx[0].send(PipelineInfo::MsgTime(i, t0, t1));
                 }
+                Ok(PipelineInfo::MsgCount(i, n, b)) => {
+                    let _ = tx[0].send(PipelineInfo::MsgCount(i, n, b));
+                }
+                Ok(PipelineInfo::SeqBat(_)) => unreachable!(),
                 Err(_) => break,
             }
         }
@@ -327,6 +1374,331 @@ mod tests {
         assert!(!ret.iter().any(|x| x.path == PathBuf::from("./.git/config")));
     }
 
+    #[test]
+    fn pipeline_finder_threads() {
+        let finder = PipelineFinder::new();
+        let expected = test(finder, "./".to_string());
+
+        let mut finder = PipelineFinder::new();
+        finder.threads = 4;
+        let ret = test(finder, "./".to_string());
+
+        let expected: Vec<_> = expected.into_iter().map(|x| x.path).collect();
+        let ret: Vec<_> = ret.into_iter().map(|x| x.path).collect();
+        assert_eq!(expected, ret);
+    }
+
+    #[test]
+    fn pipeline_finder_threads_ignore() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".gitignore"), "skip\n").unwrap();
+        for i in 0..8 {
+            for sub in &["a", "skip", "b"] {
+                let sub = dir.path().join(format!("d{}", i)).join(sub).join("x");
+                fs::create_dir_all(&sub).unwrap();
+                fs::write(sub.join("c.txt"), "c").unwrap();
+            }
+        }
+
+        let finder = PipelineFinder::new();
+        let expected = test(finder, dir.path().to_string_lossy().into_owned());
+
+        let mut finder = PipelineFinder::new();
+        finder.threads = 4;
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+
+        let expected: Vec<_> = expected.into_iter().map(|x| x.path).collect();
+        let ret: Vec<_> = ret.into_iter().map(|x| x.path).collect();
+        assert_eq!(expected, ret);
+        // `.gitignore` itself is found as well as the files not ignored
+        assert_eq!(ret.iter().filter(|x| x.ends_with("c.txt")).count(), 16);
+        assert_eq!(ret.len(), 17);
+        assert!(!ret.iter().any(|x| x.components().any(|x| x.as_os_str() == "skip")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipeline_finder_dedupe() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::hard_link(dir.path().join("a.txt"), dir.path().join("b.txt")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("a.txt"), dir.path().join("c.txt")).unwrap();
+
+        let finder = PipelineFinder::new();
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 1);
+
+        let mut finder = PipelineFinder::new();
+        finder.dedupe = false;
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipeline_finder_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("a.txt", dir.path().join("b.txt")).unwrap();
+        std::os::unix::fs::symlink("b.txt", dir.path().join("c.txt")).unwrap();
+        std::os::unix::fs::symlink("none.txt", dir.path().join("d.txt")).unwrap();
+        let base = dir.path().to_string_lossy().into_owned();
+
+        let mut finder = PipelineFinder::new();
+        finder.dedupe = false;
+        finder.print_broken_symlinks = true;
+        let broken = finder.broken_symlinks.clone();
+        let ret = test(finder, base.clone());
+        assert_eq!(ret.len(), 3);
+        assert_eq!(broken.get(), 1);
+
+        let mut finder = PipelineFinder::new();
+        finder.dedupe = false;
+        finder.max_symlink_depth = Some(1);
+        let ret = test(finder, base);
+        assert_eq!(ret.len(), 2);
+        assert!(!ret.iter().any(|x| x.path.ends_with("c.txt")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipeline_finder_attr() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        fs::set_permissions(dir.path().join("a.txt"), fs::Permissions::from_mode(0o646)).unwrap();
+        fs::set_permissions(dir.path().join("b.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        let base = dir.path().to_string_lossy().into_owned();
+
+        let mut finder = PipelineFinder::new();
+        finder.perm = Some("/002".parse().unwrap());
+        let ret = test(finder, base.clone());
+        assert_eq!(ret.len(), 1);
+        assert!(ret[0].path.ends_with("a.txt"));
+
+        let mut finder = PipelineFinder::new();
+        finder.perm = Some("640".parse().unwrap());
+        finder.owner = Some(unsafe { libc::getuid() }.to_string().parse().unwrap());
+        let ret = test(finder, base.clone());
+        assert_eq!(ret.len(), 1);
+        assert!(ret[0].path.ends_with("b.txt"));
+
+        let mut finder = PipelineFinder::new();
+        finder.owner = Some(OwnerFilter {
+            name: String::from("other"),
+            uid: unsafe { libc::getuid() } + 1,
+        });
+        assert!(test(finder, base).is_empty());
+
+        assert_eq!("-022".parse(), Ok(PermFilter::All(0o022)));
+        assert!("/8".parse::<PermFilter>().is_err());
+        assert!("--2".parse::<PermFilter>().is_err());
+        assert!("root".parse::<OwnerFilter>().is_ok_and(|x| x.uid == 0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipeline_finder_special() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let fifo = dir.path().join("fifo");
+        let name = std::ffi::CString::new(fifo.to_string_lossy().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o644) }, 0);
+        let base = dir.path().to_string_lossy().into_owned();
+
+        let ret = test(PipelineFinder::new(), base.clone());
+        assert_eq!(ret.len(), 1);
+        assert!(ret[0].path.ends_with("a.txt"));
+        assert!(test(PipelineFinder::new(), fifo.to_string_lossy().into_owned()).is_empty());
+
+        let mut finder = PipelineFinder::new();
+        finder.special_files = true;
+        let ret = test(finder, base);
+        assert_eq!(ret.len(), 2);
+        assert!(ret.iter().any(|x| x.path.ends_with("fifo")));
+    }
+
+    #[test]
+    fn pipeline_finder_cancel() {
+        let finder = PipelineFinder::new();
+        finder.cancel.cancel();
+        let ret = test(finder, "./".to_string());
+        assert!(ret.is_empty());
+    }
+
+    #[test]
+    fn pipeline_finder_dir_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        filetime::set_file_mtime(dir.path(), old).unwrap();
+
+        let cache = Arc::new(DirCache::new());
+        let mut finder = PipelineFinder::new();
+        finder.dir_cache = Some(cache.clone());
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 1);
+        assert_eq!(cache.len(), 1);
+
+        // The listing is taken from the cache while the mtime of the directory is the same
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        filetime::set_file_mtime(dir.path(), old).unwrap();
+        let mut finder = PipelineFinder::new();
+        finder.dir_cache = Some(cache.clone());
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 1);
+
+        filetime::set_file_mtime(dir.path(), filetime::FileTime::from_unix_time(1_700_000_000, 1)).unwrap();
+        let mut finder = PipelineFinder::new();
+        finder.dir_cache = Some(cache.clone());
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 2);
+
+        // The directories not read since the last prune are dropped
+        cache.prune();
+        assert_eq!(cache.len(), 1);
+        cache.prune();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn pipeline_finder_small_first() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "aaaaaa").unwrap();
+        fs::write(dir.path().join("b.txt"), "b").unwrap();
+        fs::write(dir.path().join("c.txt"), "ccc").unwrap();
+
+        let mut finder = PipelineFinder::new();
+        finder.small_first = true;
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        let ret: Vec<_> = ret.into_iter().map(|x| x.path).collect();
+        assert_eq!(
+            ret,
+            vec![
+                dir.path().join("b.txt"),
+                dir.path().join("c.txt"),
+                dir.path().join("a.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn pipeline_finder_repo_ignore() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".git").join("info")).unwrap();
+        fs::write(dir.path().join(".git").join("info").join("exclude"), "*.log\n").unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("a.log"), "a").unwrap();
+        fs::write(dir.path().join("sub").join("b.txt"), "b").unwrap();
+
+        let finder = PipelineFinder::new();
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        let ret: Vec<_> = ret.into_iter().map(|x| x.path).collect();
+        assert_eq!(ret, vec![dir.path().join("sub").join("b.txt")]);
+
+        // The repository is found from the parent directory
+        let finder = PipelineFinder::new();
+        let ret = test(finder, dir.path().join("sub").to_string_lossy().into_owned());
+        let ret: Vec<_> = ret.into_iter().map(|x| x.path).collect();
+        assert_eq!(ret, vec![dir.path().join("sub").join("b.txt")]);
+    }
+
+    #[test]
+    fn pipeline_finder_ambignore() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".amberignore"), "*.pb.go\n").unwrap();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("a.pb.go"), "a").unwrap();
+        fs::write(dir.path().join("sub").join("b.go"), "b").unwrap();
+
+        let finder = PipelineFinder::new();
+        let ret = test(finder, dir.path().join("sub").to_string_lossy().into_owned());
+        let ret: Vec<_> = ret.into_iter().map(|x| x.path).collect();
+        assert_eq!(ret, vec![dir.path().join("sub").join("b.go")]);
+
+        let mut finder = PipelineFinder::new();
+        finder.skip_ambignore = false;
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 3);
+    }
+
+    #[test]
+    fn pipeline_finder_vcs_tracked() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        if !git(&["init", "-q"]).status.success() {
+            return;
+        }
+        fs::write(dir.path().join("a.txt"), "aaa").unwrap();
+        fs::write(dir.path().join("b.txt"), "bbb").unwrap();
+        fs::write(dir.path().join("c.txt"), "ccc").unwrap();
+        git(&["add", "a.txt", "b.txt"]);
+
+        let mut finder = PipelineFinder::new();
+        finder.vcs_filter = Some(VcsFilter::Tracked);
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 2);
+        assert!(ret.iter().any(|x| x.path == dir.path().join("a.txt")));
+        assert!(ret.iter().any(|x| x.path == dir.path().join("b.txt")));
+
+        let mut finder = PipelineFinder::new();
+        finder.vcs_filter = Some(VcsFilter::Staged);
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 2);
+
+        let mut finder = PipelineFinder::new();
+        finder.vcs_filter = Some(VcsFilter::Modified);
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 0);
+
+        fs::write(dir.path().join("a.txt"), "aaaa").unwrap();
+        let mut finder = PipelineFinder::new();
+        finder.vcs_filter = Some(VcsFilter::Modified);
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0].path, dir.path().join("a.txt"));
+    }
+
+    #[test]
+    fn pipeline_finder_rev() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(dir.path())
+                .args(["-c", "user.name=amber", "-c", "user.email=amber@example.com"])
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        if !git(&["init", "-q"]).status.success() {
+            return;
+        }
+        fs::write(dir.path().join("a.txt"), "aaa").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["commit", "-q", "-m", "a"]);
+        fs::write(dir.path().join("a.txt"), "bbb").unwrap();
+
+        let mut finder = PipelineFinder::new();
+        finder.rev = Some(String::from("HEAD"));
+        let ret = test(finder, dir.path().to_string_lossy().into_owned());
+        assert_eq!(ret.len(), 1);
+        assert_eq!(
+            ret[0].path,
+            PathBuf::from(format!("HEAD:{}", dir.path().join("a.txt").to_str().unwrap()))
+        );
+        assert_eq!(ret[0].content.as_ref().unwrap().as_slice(), b"aaa");
+    }
+
     #[test]
     fn pipeline_finder_not_skip_vcs() {
         if !Path::new("./.git/config").exists() {
@@ -1,14 +1,94 @@
+use crate::cache::ResultCache;
+use crate::decompress::decompress;
+use crate::document::extract_text;
+use crate::encoding::EncodingMap;
+use crate::lexer::{filter_identifiers, filter_tokens, Lexer, TokenKind};
 use crate::matcher::{Match, Matcher};
-use crate::pipeline::{Pipeline, PipelineInfo};
+use crate::pipeline::{BatchReceiver, BatchSender, CancelToken, Pipeline, PipelineInfo};
 use crate::pipeline_finder::PathInfo;
-use crate::util::{catch, decode_error};
+use crate::query::Query;
+use crate::syntax::SyntaxFilter;
+use crate::trace::Tracer;
+use crate::util::{
+    as_secsf64, bom_len, catch, data_ranges, decode_error, is_transient_error, long_path, retry_delay,
+    transcode_from_utf8, IndexRange, IoLimiter, IoStrategy, MemoryBudget,
+};
 use crossbeam::channel::{Receiver, Sender};
 use memmap::Mmap;
+use regex::bytes::Regex;
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::{Error, Read};
 use std::ops::Deref;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Sections between the matches of `begin` and `end` ( delimiters are not included, and not nested )
+#[derive(Clone, Debug)]
+pub struct Within {
+    begin: Regex,
+    end: Regex,
+}
+
+impl Within {
+    pub fn new(begin: &str, end: &str) -> Result<Self, regex::Error> {
+        Ok(Within {
+            begin: Regex::new(begin)?,
+            end: Regex::new(end)?,
+        })
+    }
+
+    // `inside` is whether `src` begins inside a section, and the state at the end of `src` is returned with the sections
+    pub fn sections(&self, src: &[u8], mut inside: bool) -> (Vec<(usize, usize)>, bool) {
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        while pos <= src.len() {
+            let start = pos;
+            if !inside {
+                match self.begin.find_at(src, pos) {
+                    Some(m) => pos = m.end(),
+                    None => break,
+                }
+                inside = true;
+            }
+            match self.end.find_at(src, pos) {
+                Some(m) => {
+                    ret.push((pos, m.start()));
+                    pos = m.end();
+                    inside = false;
+                }
+                None => {
+                    ret.push((pos, src.len()));
+                    break;
+                }
+            }
+            // Empty delimiters must not match at the same position forever
+            if pos == start {
+                pos += 1;
+            }
+        }
+        (ret, inside)
+    }
+
+    pub fn filter(&self, src: &[u8], inside: bool, matches: Vec<Match>) -> (Vec<Match>, bool) {
+        let (sections, inside) = self.sections(src, inside);
+        let mut sections = sections.into_iter().peekable();
+        let mut ret = Vec::new();
+        for m in matches {
+            while sections.peek().map_or(false, |x| x.1 < m.end) {
+                sections.next();
+            }
+            if sections.peek().map_or(false, |x| x.0 <= m.beg) {
+                ret.push(m);
+            }
+        }
+        (ret, inside)
+    }
+}
 
 // ---------------------------------------------------------------------------------------------------------------------
 // PathMatch
@@ -18,107 +98,915 @@ use std::time::{Duration, Instant};
 pub struct PathMatch {
     pub path: PathBuf,
     pub matches: Vec<Match>,
+    pub content: Option<Arc<Vec<u8>>>,
+    /// Matches are found in a binary file, which is reported without printing lines
+    pub binary: bool,
+    /// Length and modified time of the file at match time, to detect modification before replacement
+    pub stamp: Option<(u64, Option<SystemTime>)>,
+}
+
+/// Returns the matches whose first and last bytes are within `lines`, where `src` begins at the line `first_line`
+pub fn filter_lines(src: &[u8], first_line: usize, lines: IndexRange, matches: Vec<Match>) -> Vec<Match> {
+    let count_lf = |x: &[u8]| memchr::memchr_iter(0x0a, x).count();
+    let mut line = first_line;
+    let mut pos = 0;
+    let mut ret = Vec::new();
+    for m in matches {
+        line += count_lf(&src[pos..m.beg]);
+        pos = m.beg;
+        let last = line + count_lf(&src[m.beg..cmp::max(m.end, m.beg + 1) - 1]);
+        if lines.contains(line) && lines.contains(last) {
+            ret.push(m);
+        }
+    }
+    ret
+}
+
+/// Overlapping matches of several keywords are resolved to the earliest one, and then the longest one
+pub fn resolve_overlaps(mut matches: Vec<Match>) -> Vec<Match> {
+    matches.sort_by_key(|m| (m.beg, cmp::Reverse(m.end), m.keyword));
+    let mut ret: Vec<Match> = Vec::new();
+    for m in matches {
+        if ret.last().map_or(true, |x| x.end <= m.beg) {
+            ret.push(m);
+        }
+    }
+    ret
+}
+
+/// Returns whether any of the matches sorted by the beginning overlaps the previous one
+pub fn has_overlaps(matches: &[Match]) -> bool {
+    matches.windows(2).any(|x| x[1].beg < x[0].end)
+}
+
+// Byte offset after the last line of `lines`, where the rest of files is not searched
+fn lines_end(src: &[u8], lines: IndexRange) -> usize {
+    match lines.end {
+        Some(0) => 0,
+        Some(x) => memchr::memchr_iter(0x0a, src).nth(x - 1).map_or(src.len(), |x| x + 1),
+        None => src.len(),
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// AllOfScope
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AllOfScope {
+    File,
+    Line,
+}
+
+impl FromStr for AllOfScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(AllOfScope::File),
+            "line" => Ok(AllOfScope::Line),
+            _ => Err(format!("invalid all-of scope: {}", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Overlap
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Policy of overlapping matches of several keywords
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overlap {
+    /// All matches are kept even if they overlap
+    ReportAll,
+    /// The longest match wins, and then the earliest one
+    Longest,
+    /// The earliest match wins, and then the longest one
+    First,
+}
+
+impl FromStr for Overlap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report-all" => Ok(Overlap::ReportAll),
+            "longest" => Ok(Overlap::Longest),
+            "first" => Ok(Overlap::First),
+            _ => Err(format!("invalid overlap policy: {}", s)),
+        }
+    }
+}
+
+impl Overlap {
+    /// Returns the matches sorted by the beginning, where overlapping matches are dropped by the policy
+    pub fn resolve(self, mut matches: Vec<Match>) -> Vec<Match> {
+        match self {
+            Overlap::First => resolve_overlaps(matches),
+            Overlap::ReportAll => {
+                matches.sort_by_key(|m| (m.beg, m.keyword));
+                matches
+            }
+            Overlap::Longest => {
+                matches.sort_by_key(|m| (cmp::Reverse(m.end - m.beg), m.beg, m.keyword));
+                // Kept matches don't overlap, so only the last one beginning before the end may overlap
+                let mut kept: BTreeMap<usize, Match> = BTreeMap::new();
+                for m in matches {
+                    let prev = kept.range(..cmp::max(m.end, m.beg + 1)).next_back();
+                    if !matches!(prev, Some((_, x)) if x.end > m.beg || x.beg == m.beg) {
+                        kept.insert(m.beg, m);
+                    }
+                }
+                kept.into_values().collect()
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// BinaryFiles
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryFiles {
+    /// Binary files are searched, and reported as `Binary file <path> matches`
+    Binary,
+    /// Binary files are searched, and reported as hex dumps around matches
+    Hex,
+    /// Binary files are searched as text files
+    Text,
+    /// Binary files are skipped
+    WithoutMatch,
+}
+
+impl FromStr for BinaryFiles {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(BinaryFiles::Binary),
+            "hex" => Ok(BinaryFiles::Hex),
+            "text" => Ok(BinaryFiles::Text),
+            "without-match" => Ok(BinaryFiles::WithoutMatch),
+            _ => Err(format!("invalid binary-files type: {}", s)),
+        }
+    }
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
 // PipelineMatcher
 // ---------------------------------------------------------------------------------------------------------------------
 
+// Maximum capacity of the buffer kept for the next file
+const MAX_READ_BUF: usize = 16 * 1024 * 1024;
+
 pub struct PipelineMatcher<T: Matcher> {
-    pub skip_binary: bool,
+    pub binary_files: BinaryFiles,
     pub print_skipped: bool,
     pub binary_check_bytes: usize,
+    /// Files are mapped by `io`, where `mmap_bytes` is the threshold of `IoStrategy::Auto`
+    pub io: IoStrategy,
     pub mmap_bytes: u64,
+    pub mmap_threshold: u64,
+    pub stream_chunk_bytes: usize,
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Reads of all threads are throttled by the limiter shared ( e.g. on shared storage )
+    pub io_limit: Option<Arc<IoLimiter>>,
+    pub all_of: Vec<Vec<u8>>,
+    /// Keywords matched as well as the keyword, which are indexed from 1
+    pub any_of: Vec<Vec<u8>>,
+    /// Policy of overlapping matches of `any_of`
+    pub overlap: Overlap,
+    /// The matcher folds the case, so a match may be longer than the keyword in bytes ( e.g. `K` ( U+212A ) by `k` )
+    pub ignore_case: bool,
+    pub all_of_scope: AllOfScope,
+    /// Query over the keyword and `all_of`, which are indexed as `Query::Term`
+    pub query: Option<Query>,
+    /// Matches in lines containing this pattern are dropped
+    pub not: Option<Vec<u8>>,
+    /// Matches are kept only in files containing this pattern ( e.g. files importing a module )
+    pub filter_files: Option<Vec<u8>>,
+    /// Matches outside these lines ( 1-based ) are dropped, and the lines after them are not searched
+    pub lines: Option<IndexRange>,
+    /// Matches beginning outside these columns ( 1-based bytes from the beginning of the line ) are dropped
+    pub columns: Option<IndexRange>,
+    /// Matches outside the sections between the delimiters are dropped
+    pub within: Option<Within>,
+    /// Matches inside ( or outside ) the syntax nodes are kept with the `syntax` feature
+    pub syntax: Option<SyntaxFilter>,
+    /// Matches are kept only inside code, comments or string literals of the languages known by the extension
+    pub token_kind: Option<TokenKind>,
+    /// The keyword is searched as encoded to the encoding of each file decided by the path ( others are UTF-8 )
+    pub encoding_map: Option<EncodingMap>,
+    /// Matches are kept only inside the contents of quoted literals, which are found in files of unknown languages too
+    pub in_strings: bool,
+    /// Matches being parts of longer identifiers are dropped
+    pub identifier: bool,
+    /// Files having more matches than this are skipped as noisy ( 0: unlimited )
+    pub noisy_threshold: usize,
+    /// Times to retry reading files on transient errors of network file systems ( e.g. ESTALE, timeouts )
+    pub io_retries: usize,
+    /// Search the text extracted from documents ( .pdf, .docx, .odt ) with the `document` feature
+    pub extract_documents: bool,
+    /// Search the decompressed contents of files compressed by zstd, xz or bzip2 with the feature of the same name
+    pub decompress: bool,
+    /// Pass files without searching when cancelled, so that the matches already queued are printed in order
+    pub cancel: CancelToken,
+    /// Number of matched files sent at once to the next stage ( 1: one by one )
+    pub batch_size: usize,
+    /// Matches of files not modified since the last same search are reused instead of searching
+    pub cache: Option<Arc<ResultCache>>,
+    /// Log the files skipped, dropped or retried at the level 1, and the matches and time of each file at the level 2
+    pub trace: Tracer,
     pub infos: Vec<String>,
     pub errors: Vec<String>,
     time_beg: Instant,
     time_bsy: Duration,
+    items: usize,
+    bytes: u64,
     matcher: T,
     keyword: Vec<u8>,
+    // Keyword encoded for the file being searched by `encoding_map`
+    file_keyword: Option<Vec<u8>>,
+    retried: Vec<(PathBuf, usize)>,
+    // Small files are read into this buffer to avoid allocations per file
+    read_buf: Vec<u8>,
 }
 
 impl<T: Matcher> PipelineMatcher<T> {
     pub fn new(matcher: T, keyword: &[u8]) -> Self {
         PipelineMatcher {
-            skip_binary: true,
+            binary_files: BinaryFiles::WithoutMatch,
             print_skipped: false,
             binary_check_bytes: 128,
+            io: IoStrategy::Auto,
             mmap_bytes: 1024 * 1024,
+            mmap_threshold: 0,
+            stream_chunk_bytes: 16 * 1024 * 1024,
+            memory_budget: None,
+            io_limit: None,
+            all_of: Vec::new(),
+            any_of: Vec::new(),
+            overlap: Overlap::First,
+            ignore_case: false,
+            all_of_scope: AllOfScope::File,
+            query: None,
+            not: None,
+            filter_files: None,
+            lines: None,
+            columns: None,
+            within: None,
+            syntax: None,
+            token_kind: None,
+            encoding_map: None,
+            in_strings: false,
+            identifier: false,
+            noisy_threshold: 0,
+            io_retries: 0,
+            extract_documents: false,
+            decompress: false,
+            cancel: CancelToken::new(),
+            batch_size: 1,
+            cache: None,
+            trace: Tracer::default(),
             infos: Vec::new(),
             errors: Vec::new(),
             time_beg: Instant::now(),
             time_bsy: Duration::new(0, 0),
+            items: 0,
+            bytes: 0,
             matcher: matcher,
             keyword: Vec::from(keyword),
+            file_keyword: None,
+            retried: Vec::new(),
+            read_buf: Vec::new(),
         }
     }
 
     fn search_path(&mut self, info: PathInfo) -> PathMatch {
         let path_org = info.path.clone();
+        self.file_keyword = self
+            .encoding_map
+            .as_ref()
+            .and_then(|x| x.lookup(&info.path))
+            .and_then(|x| transcode_from_utf8(&self.keyword, x));
+
+        if let Some(ref content) = info.content {
+            return self.search_src(&info, content);
+        }
 
-        let result = catch::<_, PathMatch, Error>(|| {
-            let attr = match fs::metadata(&info.path) {
-                Ok(x) => x,
-                Err(e) => {
-                    return Err(e);
+        // The extracted text is passed to the printer as the content instead of the document
+        if self.extract_documents {
+            match extract_text(&long_path(&info.path)) {
+                Some(Ok(text)) => {
+                    let content = Arc::new(text);
+                    let info = PathInfo {
+                        path: info.path,
+                        content: Some(content.clone()),
+                    };
+                    return self.search_src(&info, &content);
                 }
-            };
+                Some(Err(e)) => {
+                    self.errors
+                        .push(format!("Error: {} @ {:?}\n", decode_error(e.kind()), path_org));
+                    return PathMatch {
+                        path: info.path,
+                        matches: Vec::new(),
+                        content: None,
+                        binary: false,
+                        stamp: None,
+                    };
+                }
+                None => (),
+            }
+        }
 
-            let mmap;
-            let mut buf = Vec::new();
-            let src = if attr.len() > self.mmap_bytes {
-                let file = File::open(&info.path)?;
-                mmap = unsafe { Mmap::map(&file) }?;
-                mmap.deref()
-            } else {
-                let mut f = File::open(&info.path)?;
-                f.read_to_end(&mut buf)?;
-                &buf[..]
-            };
+        let mut stamp = None;
+        let mut retried = 0;
+        let result = loop {
+            let result = catch::<_, PathMatch, Error>(|| {
+                let path = long_path(&info.path);
+                let attr = match fs::metadata(&path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        return Err(e);
+                    }
+                };
+                stamp = Some((attr.len(), attr.modified().ok()));
+
+                if let Some(ref cache) = self.cache {
+                    if let Some(matches) = cache.lookup(&info.path, attr.len(), attr.modified().ok()) {
+                        self.trace.log(2, || format!("Cached: {:?}", info.path));
+                        return Ok(PathMatch {
+                            path: info.path.clone(),
+                            matches,
+                            content: None,
+                            binary: false,
+                            stamp: None,
+                        });
+                    }
+                }
+
+                // Syntax trees and tokens are built from the whole file
+                let streaming = self.mmap_threshold != 0
+                    && attr.len() > self.mmap_threshold
+                    && self.syntax.is_none()
+                    && self.token_kind.is_none()
+                    && !self.in_strings;
+
+                let memory_budget = self.memory_budget.clone();
+                let _reserved = memory_budget.as_ref().map(|x| {
+                    if streaming {
+                        x.acquire(cmp::min(attr.len(), self.stream_chunk_bytes as u64))
+                    } else {
+                        x.acquire(attr.len())
+                    }
+                });
 
-            if self.skip_binary {
-                let mut is_binary = false;
-                let check_bytes = if self.binary_check_bytes < src.len() {
-                    self.binary_check_bytes
+                let use_mmap = self.io.use_mmap(attr.len(), self.mmap_bytes);
+                let file = if use_mmap { Some(File::open(&path)?) } else { None };
+
+                // Holes of sparse files are not read through the mmap
+                let ranges = file.as_ref().and_then(data_ranges);
+
+                if streaming && ranges.is_none() {
+                    return self.search_stream(&info);
+                }
+
+                self.throttle(
+                    ranges
+                        .as_ref()
+                        .map_or(attr.len(), |x| x.iter().map(|(beg, end)| end - beg).sum()),
+                );
+                let mmap;
+                let mut buf = std::mem::take(&mut self.read_buf);
+                let src = if let Some(ref file) = file {
+                    mmap = unsafe { Mmap::map(file) }?;
+                    mmap.deref()
                 } else {
-                    src.len()
+                    buf.clear();
+                    let mut f = File::open(&path)?;
+                    f.read_to_end(&mut buf)?;
+                    &buf[..]
                 };
-                for i in 0..check_bytes {
-                    if src[i] <= 0x08 {
-                        is_binary = true;
-                        break;
+
+                // Compressed files are searched by the decompressed contents, which are also printed instead of the file
+                if self.decompress {
+                    if let Some(x) = decompress(&info.path, src) {
+                        let content = Arc::new(x?);
+                        let info = PathInfo {
+                            path: info.path.clone(),
+                            content: Some(content.clone()),
+                        };
+                        return Ok(self.search_src(&info, &content));
                     }
                 }
-                if is_binary {
-                    if self.print_skipped {
-                        self.infos.push(format!("Skipped: {:?} ( binary file )\n", info.path));
-                    }
-                    return Ok(PathMatch {
+
+                // FIFOs and devices given by --special-files can't be read again to print, so the contents are kept
+                if !attr.is_file() {
+                    let content = Arc::new(src.to_vec());
+                    let info = PathInfo {
                         path: info.path.clone(),
-                        matches: Vec::new(),
-                    });
+                        content: Some(content.clone()),
+                    };
+                    return Ok(self.search_src(&info, &content));
                 }
-            }
 
-            let ret = self.matcher.search(src, &self.keyword);
-
-            Ok(PathMatch {
-                path: info.path.clone(),
-                matches: ret,
-            })
-        });
+                let ret = match ranges {
+                    Some(ref x) => self.search_sparse(&info, src, x),
+                    None => self.search_src(&info, src),
+                };
+                // Binary files are searched again to be reported in the same way
+                if let Some(cache) = self.cache.as_ref().filter(|_| !ret.binary) {
+                    cache.insert(&info.path, attr.len(), attr.modified().ok(), &ret.matches);
+                }
+                // Buffers of large files read by `IoStrategy::Read` are not kept
+                if buf.capacity() <= MAX_READ_BUF {
+                    self.read_buf = buf;
+                }
+                Ok(ret)
+            });
+            match result {
+                Err(ref e) if retried < self.io_retries && is_transient_error(e) => {
+                    thread::sleep(retry_delay(retried));
+                    retried += 1;
+                }
+                x => break x,
+            }
+        };
+        if retried != 0 {
+            self.trace
+                .log(1, || format!("Retried: {:?} ( {} times )", path_org, retried));
+            self.retried.push((path_org.clone(), retried));
+        }
 
         match result {
-            Ok(x) => x,
+            Ok(x) => PathMatch { stamp, ..x },
             Err(e) => {
                 self.errors
                     .push(format!("Error: {} @ {:?}\n", decode_error(e.kind()), path_org));
                 PathMatch {
                     path: info.path.clone(),
                     matches: Vec::new(),
+                    content: info.content.clone(),
+                    binary: false,
+                    stamp: None,
+                }
+            }
+        }
+    }
+
+    /// Searches `src` as the contents of `path` by the same keywords and filters as the pipeline
+    /// ( e.g. the file edited while replacing )
+    pub fn search_contents(&mut self, path: &Path, src: &[u8]) -> Vec<Match> {
+        self.file_keyword = self
+            .encoding_map
+            .as_ref()
+            .and_then(|x| x.lookup(path))
+            .and_then(|x| transcode_from_utf8(&self.keyword, x));
+        let info = PathInfo {
+            path: PathBuf::from(path),
+            content: None,
+        };
+        self.search_src(&info, src).matches
+    }
+
+    fn search_src(&mut self, info: &PathInfo, src: &[u8]) -> PathMatch {
+        self.bytes += src.len() as u64;
+        let binary = self.is_binary(src);
+        if binary && self.binary_files == BinaryFiles::WithoutMatch {
+            return self.skip_binary(info);
+        }
+
+        // BOM is excluded from search not to be matched or replaced
+        let bom = bom_len(src);
+        let end = self.lines.map_or(src.len(), |x| cmp::max(bom, lines_end(src, x)));
+        let mut ret = self.search_keywords(&src[bom..end]);
+        for m in &mut ret {
+            m.beg += bom;
+            m.end += bom;
+        }
+        let ret = self.filter_lines(src, 1, ret);
+        let ret = self.filter_identifiers(src, ret);
+        let ret = self.filter_columns(src, ret);
+        let ret = self.filter_within(src, ret);
+        let ret = self.filter_syntax(src, ret);
+        let ret = self.filter_tokens(&info.path, src, ret);
+        let ret = self.filter_not(src, ret);
+        let ret = self.filter_all_of(self.filter_all_of_line(src, ret));
+        let ret = self.filter_files(src, ret);
+
+        PathMatch {
+            path: info.path.clone(),
+            matches: ret,
+            content: info.content.clone(),
+            binary,
+            stamp: None,
+        }
+    }
+
+    fn search_sparse(&mut self, info: &PathInfo, src: &[u8], ranges: &[(u64, u64)]) -> PathMatch {
+        let binary = self.is_binary(src);
+        if binary && self.binary_files == BinaryFiles::WithoutMatch {
+            return self.skip_binary(info);
+        }
+
+        // Data ranges are separated by holes, so matches across ranges contain zeros of holes, which are not searched
+        let bom = match ranges.first() {
+            Some(&(0, _)) => bom_len(src),
+            _ => 0,
+        };
+        let limit = self.lines.map_or(src.len(), |x| cmp::max(bom, lines_end(src, x)));
+        let mut ret = Vec::new();
+        for &(beg, end) in ranges {
+            let beg = cmp::max(beg as usize, bom);
+            let end = cmp::min(end as usize, limit);
+            if beg >= end {
+                continue;
+            }
+            self.bytes += (end - beg) as u64;
+            for mut m in self.search_keywords(&src[beg..end]) {
+                m.beg += beg;
+                m.end += beg;
+                ret.push(m);
+            }
+        }
+        let ret = self.filter_lines(src, 1, ret);
+        let ret = self.filter_identifiers(src, ret);
+        let ret = self.filter_columns(src, ret);
+        let ret = self.filter_within(src, ret);
+        let ret = self.filter_syntax(src, ret);
+        let ret = self.filter_tokens(&info.path, src, ret);
+        let ret = self.filter_not(src, ret);
+        let ret = self.filter_all_of(self.filter_all_of_line(src, ret));
+        let ret = self.filter_files(src, ret);
+
+        PathMatch {
+            path: info.path.clone(),
+            matches: ret,
+            content: info.content.clone(),
+            binary,
+            stamp: None,
+        }
+    }
+
+    fn skip_binary(&mut self, info: &PathInfo) -> PathMatch {
+        self.trace
+            .log(1, || format!("Skipped: {:?} ( binary file )", info.path));
+        if self.print_skipped {
+            self.infos.push(format!("Skipped: {:?} ( binary file )\n", info.path));
+        }
+        PathMatch {
+            path: info.path.clone(),
+            matches: Vec::new(),
+            content: info.content.clone(),
+            binary: true,
+            stamp: None,
+        }
+    }
+
+    fn is_binary(&self, src: &[u8]) -> bool {
+        if self.binary_files == BinaryFiles::Text {
+            return false;
+        }
+        let check_bytes = if self.binary_check_bytes < src.len() {
+            self.binary_check_bytes
+        } else {
+            src.len()
+        };
+        src[0..check_bytes].iter().any(|x| *x <= 0x08)
+    }
+
+    fn throttle(&self, bytes: u64) {
+        if let Some(ref x) = self.io_limit {
+            x.acquire(bytes);
+        }
+    }
+
+    fn search_stream(&mut self, info: &PathInfo) -> Result<PathMatch, Error> {
+        let mut f = File::open(long_path(&info.path))?;
+        let mut buf = Vec::new();
+        let mut offset = 0;
+        let mut line = 1;
+        let mut inside = false;
+        let mut first = true;
+        let mut binary = false;
+        let mut ret = Vec::new();
+        // `filter_files` is searched in each chunk until found
+        let mut filtered_in = self.filter_files.is_none();
+
+        loop {
+            let mut filled = buf.len();
+            buf.resize(filled + self.stream_chunk_bytes, 0);
+            let mut eof = false;
+            while filled < buf.len() {
+                let len = f.read(&mut buf[filled..])?;
+                if len == 0 {
+                    eof = true;
+                    break;
+                }
+                filled += len;
+                self.bytes += len as u64;
+                self.throttle(len as u64);
+            }
+            buf.truncate(filled);
+
+            let skip = if first {
+                binary = self.is_binary(&buf);
+                if binary && self.binary_files == BinaryFiles::WithoutMatch {
+                    return Ok(self.skip_binary(info));
+                }
+                first = false;
+                bom_len(&buf)
+            } else {
+                0
+            };
+
+            // Matches beginning after the last line feed are searched again with the next chunk
+            let cut = if eof {
+                buf.len()
+            } else {
+                match buf.iter().rposition(|x| *x == 0x0a) {
+                    Some(x) => x + 1,
+                    None => buf.len().saturating_sub(self.max_keyword_len().saturating_sub(1)),
+                }
+            };
+
+            let mut next = cut;
+            let mut matches = Vec::new();
+            for m in self.search_keywords(&buf[skip..]) {
+                if m.beg + skip < cut {
+                    next = cmp::max(next, m.end + skip);
+                    matches.push(Match {
+                        beg: m.beg + skip,
+                        end: m.end + skip,
+                        sub_match: Vec::new(),
+                        keyword: m.keyword,
+                    });
+                }
+            }
+            // Lines are not split across chunks except too long lines
+            let matches = self.filter_lines(&buf, line, matches);
+            let matches = self.filter_identifiers(&buf, matches);
+            let matches = self.filter_columns(&buf, matches);
+            let matches = match self.within {
+                Some(ref x) => {
+                    let (matches, next_inside) = x.filter(&buf[..next], inside, matches);
+                    inside = next_inside;
+                    matches
                 }
+                None => matches,
+            };
+            let matches = self.filter_not(&buf, matches);
+            if !filtered_in {
+                filtered_in = self.contains_filter(&buf);
+            }
+            for mut m in self.filter_all_of_line(&buf, matches) {
+                m.beg += offset;
+                m.end += offset;
+                ret.push(m);
+            }
+
+            // The rest of a noisy file and the lines after `lines` are not searched
+            line += memchr::memchr_iter(0x0a, &buf[..next]).count();
+            let after_lines = self.lines.and_then(|x| x.end).map_or(false, |x| line > x);
+            if eof || after_lines || (self.noisy_threshold != 0 && ret.len() > self.noisy_threshold) {
+                break;
             }
+            buf.drain(..next);
+            offset += next;
+        }
+
+        if !filtered_in {
+            ret.clear();
         }
+        Ok(PathMatch {
+            path: info.path.clone(),
+            matches: self.filter_all_of(ret),
+            content: info.content.clone(),
+            binary,
+            stamp: None,
+        })
+    }
+
+    // Matches of a file having too many matches ( e.g. minified or generated ) are dropped
+    fn check_noisy(&mut self, mut pm: PathMatch) -> PathMatch {
+        if self.noisy_threshold != 0 && pm.matches.len() > self.noisy_threshold {
+            self.trace.log(1, || {
+                format!("Noisy: {:?} ( {} matches dropped )", pm.path, pm.matches.len())
+            });
+            self.infos.push(format!(
+                "Noisy: {:?} ( more than {} matches )\n",
+                pm.path, self.noisy_threshold
+            ));
+            pm.matches.clear();
+        }
+        pm
+    }
+
+    fn search_keywords(&self, src: &[u8]) -> Vec<Match> {
+        let keyword = self.file_keyword.as_ref().unwrap_or(&self.keyword);
+        let mut ret = self.matcher.search(src, keyword);
+        if !self.any_of.is_empty() {
+            for (i, keyword) in self.any_of.iter().enumerate() {
+                for mut m in self.matcher.search(src, keyword) {
+                    m.keyword = i + 1;
+                    ret.push(m);
+                }
+            }
+            return self.overlap.resolve(ret);
+        }
+        if !self.is_filtered() {
+            return ret;
+        }
+        for (i, keyword) in self.all_of.iter().enumerate() {
+            for mut m in self.matcher.search(src, keyword) {
+                m.keyword = i + 1;
+                ret.push(m);
+            }
+        }
+        ret.sort_by_key(|m| (m.beg, m.keyword));
+        ret
+    }
+
+    fn max_keyword_len(&self) -> usize {
+        let len = self
+            .all_of
+            .iter()
+            .chain(self.any_of.iter())
+            .map(|x| x.len())
+            .fold(self.file_keyword.as_ref().unwrap_or(&self.keyword).len(), cmp::max);
+        // A folded byte may come from a character of 3 bytes at most
+        if self.ignore_case {
+            len * 3
+        } else {
+            len
+        }
+    }
+
+    fn filter_lines(&self, src: &[u8], first_line: usize, matches: Vec<Match>) -> Vec<Match> {
+        match self.lines {
+            Some(x) => filter_lines(src, first_line, x, matches),
+            None => matches,
+        }
+    }
+
+    fn filter_columns(&self, src: &[u8], mut matches: Vec<Match>) -> Vec<Match> {
+        let columns = match self.columns {
+            Some(x) => x,
+            None => return matches,
+        };
+        let bom = bom_len(src);
+        matches.retain(|m| {
+            let beg = memchr::memrchr(0x0a, &src[..m.beg]).map_or(cmp::min(bom, m.beg), |x| x + 1);
+            columns.contains(m.beg - beg + 1)
+        });
+        matches
+    }
+
+    fn filter_within(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        match self.within {
+            Some(ref x) => x.filter(src, false, matches).0,
+            None => matches,
+        }
+    }
+
+    fn filter_syntax(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        match self.syntax {
+            Some(ref x) => x.filter(src, matches),
+            None => matches,
+        }
+    }
+
+    fn filter_identifiers(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        if self.identifier {
+            filter_identifiers(src, matches)
+        } else {
+            matches
+        }
+    }
+
+    // Files of unknown languages are treated as code only
+    fn filter_tokens(&self, path: &Path, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        if self.in_strings {
+            let tokens = Lexer::from_path_or_quoted(path).literals(src);
+            return filter_tokens(&tokens, TokenKind::String, matches);
+        }
+        match self.token_kind {
+            Some(kind) => {
+                let tokens = Lexer::from_path(path).map_or(Vec::new(), |x| x.tokens(src));
+                filter_tokens(&tokens, kind, matches)
+            }
+            None => matches,
+        }
+    }
+
+    // Lines of matches are searched again by the exclusion pattern
+    fn filter_not(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        let not = match self.not {
+            Some(ref x) => x,
+            None => return matches,
+        };
+
+        let mut ret = Vec::new();
+        let mut line = (0, 0);
+        let mut excluded = false;
+        for m in matches {
+            if m.beg >= line.1 || m.beg < line.0 {
+                let beg = src[..m.beg].iter().rposition(|&x| x == 0x0a).map_or(0, |x| x + 1);
+                let end = src[m.beg..]
+                    .iter()
+                    .position(|&x| x == 0x0a)
+                    .map_or(src.len(), |x| m.beg + x);
+                line = (beg, end);
+                excluded = !self.matcher.search(&src[beg..end], not).is_empty();
+            }
+            if !excluded {
+                ret.push(m);
+            }
+        }
+        ret
+    }
+
+    fn contains_filter(&self, src: &[u8]) -> bool {
+        match self.filter_files {
+            Some(ref x) => !self.matcher.search(src, x).is_empty(),
+            None => true,
+        }
+    }
+
+    // Matches are searched before the filter because most files don't match the keyword in replacements
+    fn filter_files(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        if matches.is_empty() || self.contains_filter(src) {
+            matches
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn is_filtered(&self) -> bool {
+        !self.all_of.is_empty() || self.query.is_some()
+    }
+
+    fn is_satisfied(&self, matches: &[Match]) -> bool {
+        let mut found = vec![false; self.all_of.len() + 1];
+        for m in matches {
+            found[m.keyword] = true;
+        }
+        match self.query {
+            Some(ref x) => x.eval(&found),
+            None => found.iter().all(|x| *x),
+        }
+    }
+
+    // Matches of negated keywords in the query are not printed
+    fn positive_matches(&self, matches: Vec<Match>) -> Vec<Match> {
+        match self.query {
+            Some(ref x) => {
+                let positives = x.positives(self.all_of.len() + 1);
+                matches.into_iter().filter(|m| positives[m.keyword]).collect()
+            }
+            None => matches,
+        }
+    }
+
+    // Matches are kept only if the file contains all keywords or satisfies the query
+    fn filter_all_of(&self, matches: Vec<Match>) -> Vec<Match> {
+        if !self.is_filtered() || self.all_of_scope != AllOfScope::File {
+            matches
+        } else if self.is_satisfied(&matches) {
+            self.positive_matches(matches)
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Matches are kept only if the line contains all keywords or satisfies the query
+    fn filter_all_of_line(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        if !self.is_filtered() || self.all_of_scope != AllOfScope::Line {
+            return matches;
+        }
+
+        let mut ret = Vec::new();
+        let mut line = Vec::new();
+        let mut line_end = 0;
+        for m in matches {
+            if m.beg >= line_end {
+                if self.is_satisfied(&line) {
+                    ret.append(&mut line);
+                }
+                line.clear();
+                line_end = src[m.beg..]
+                    .iter()
+                    .position(|&x| x == 0x0a)
+                    .map_or(src.len(), |x| m.beg + x);
+            }
+            line.push(m);
+        }
+        if self.is_satisfied(&line) {
+            ret.append(&mut line);
+        }
+        self.positive_matches(ret)
     }
 }
 
@@ -126,13 +1014,40 @@ impl<T: Matcher> Pipeline<PathInfo, PathMatch> for PipelineMatcher<T> {
     fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<PathInfo>>, tx: Sender<PipelineInfo<PathMatch>>) {
         self.infos = Vec::new();
         self.errors = Vec::new();
+        let mut rx = BatchReceiver::new(rx);
+        let mut tx = BatchSender::new(tx, self.batch_size);
         let mut seq_beg_arrived = false;
 
         loop {
+            if rx.is_empty() {
+                let _ = tx.flush();
+            }
             match rx.recv() {
                 Ok(PipelineInfo::SeqDat(x, p)) => {
+                    self.items += 1;
+                    if self.cancel.is_cancelled() {
+                        let ret = PathMatch {
+                            path: p.path,
+                            matches: Vec::new(),
+                            content: None,
+                            binary: false,
+                            stamp: None,
+                        };
+                        let _ = tx.send(PipelineInfo::SeqDat(x, ret));
+                        continue;
+                    }
                     watch_time!(self.time_bsy, {
+                        let beg = Instant::now();
                         let ret = self.search_path(p);
+                        self.trace.log(2, || {
+                            format!(
+                                "Searched: {:?} ( {} matches, {:.6}s )",
+                                ret.path,
+                                ret.matches.len(),
+                                as_secsf64(beg.elapsed())
+                            )
+                        });
+                        let ret = self.check_noisy(ret);
                         let _ = tx.send(PipelineInfo::SeqDat(x, ret));
                     });
                 }
@@ -146,6 +1061,10 @@ impl<T: Matcher> Pipeline<PathInfo, PathMatch> for PipelineMatcher<T> {
                 }
 
                 Ok(PipelineInfo::SeqEnd(x)) => {
+                    // Files read after transient errors are reported at the end not to be buried in the results
+                    for (path, retried) in &self.retried {
+                        self.infos.push(format!("Retried: {:?} ( {} times )\n", path, retried));
+                    }
                     for i in &self.infos {
                         let _ = tx.send(PipelineInfo::MsgInfo(id, i.clone()));
                     }
@@ -153,6 +1072,7 @@ impl<T: Matcher> Pipeline<PathInfo, PathMatch> for PipelineMatcher<T> {
                         let _ = tx.send(PipelineInfo::MsgErr(id, e.clone()));
                     }
 
+                    let _ = tx.send(PipelineInfo::MsgCount(id, self.items, self.bytes));
                     let _ = tx.send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
                     let _ = tx.send(PipelineInfo::SeqEnd(x));
                     break;
@@ -167,6 +1087,10 @@ impl<T: Matcher> Pipeline<PathInfo, PathMatch> for PipelineMatcher<T> {
                 Ok(PipelineInfo::MsgTime(i, t0, t1)) => {
                     let _ = tx.send(PipelineInfo::MsgTime(i, t0, t1));
                 }
+                Ok(PipelineInfo::MsgCount(i, n, b)) => {
+                    let _ = tx.send(PipelineInfo::MsgCount(i, n, b));
+                }
+                Ok(PipelineInfo::SeqBat(_)) => unreachable!(),
                 Err(_) => break,
             }
         }
@@ -184,6 +1108,7 @@ mod tests {
     use crate::pipeline::{Pipeline, PipelineInfo};
     use crate::pipeline_finder::PathInfo;
     use crossbeam::channel::unbounded;
+    use std::io::{Seek, SeekFrom, Write};
     use std::path::PathBuf;
     use std::thread;
 
@@ -203,18 +1128,21 @@ mod tests {
             0,
             PathInfo {
                 path: PathBuf::from("./src/ambs.rs"),
+                content: None,
             },
         ));
         let _ = in_tx.send(PipelineInfo::SeqDat(
             1,
             PathInfo {
                 path: PathBuf::from("./src/ambr.rs"),
+                content: None,
             },
         ));
         let _ = in_tx.send(PipelineInfo::SeqDat(
             2,
             PathInfo {
                 path: PathBuf::from("./src/util.rs"),
+                content: None,
             },
         ));
         let _ = in_tx.send(PipelineInfo::SeqEnd(3));
@@ -240,4 +1168,337 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn pipeline_matcher_stream() {
+        let info = PathInfo {
+            path: PathBuf::from("./src/ambs.rs"),
+            content: None,
+        };
+
+        let qs = QuickSearchMatcher::new();
+        let mut matcher = PipelineMatcher::new(qs, &"matcher".to_string().into_bytes());
+        let expected = matcher.search_path(info.clone());
+
+        let qs = QuickSearchMatcher::new();
+        let mut matcher = PipelineMatcher::new(qs, &"matcher".to_string().into_bytes());
+        matcher.mmap_threshold = 1;
+        matcher.stream_chunk_bytes = 64;
+        let ret = matcher.search_path(info);
+
+        assert!(!expected.matches.is_empty());
+        assert_eq!(expected.matches.len(), ret.matches.len());
+        for (x, y) in expected.matches.iter().zip(ret.matches.iter()) {
+            assert_eq!((x.beg, x.end), (y.beg, y.end));
+        }
+    }
+
+    #[test]
+    fn pipeline_matcher_all_of() {
+        let info = PathInfo {
+            path: PathBuf::from("dummy"),
+            content: None,
+        };
+        let src = b"foo bar\nfoo\nbar baz\n";
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.all_of = vec![b"bar".to_vec()];
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.keyword)).collect();
+        assert_eq!(ret, vec![(0, 0), (4, 1), (8, 0), (12, 1)]);
+
+        matcher.all_of_scope = AllOfScope::Line;
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.keyword)).collect();
+        assert_eq!(ret, vec![(0, 0), (4, 1)]);
+
+        matcher.all_of = vec![b"bar".to_vec(), b"qux".to_vec()];
+        matcher.all_of_scope = AllOfScope::File;
+        assert!(matcher.search_src(&info, src).matches.is_empty());
+
+        let (query, keywords) = Query::parse("foo AND NOT baz").unwrap();
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), keywords[0].as_bytes());
+        matcher.all_of = vec![keywords[1].clone().into_bytes()];
+        matcher.query = Some(query);
+        assert!(matcher.search_src(&info, src).matches.is_empty());
+
+        matcher.all_of_scope = AllOfScope::Line;
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.keyword)).collect();
+        assert_eq!(ret, vec![(0, 0), (8, 0)]);
+    }
+
+    #[test]
+    fn pipeline_matcher_not() {
+        let info = PathInfo {
+            path: PathBuf::from("dummy"),
+            content: None,
+        };
+        let src = b"foo bar foo\nfoo\nbar baz\n";
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.not = Some(b"bar".to_vec());
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![12]);
+    }
+
+    #[test]
+    fn pipeline_matcher_filter_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "foo\n".repeat(100) + "use bar;\n").unwrap();
+        let info = PathInfo { path, content: None };
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.filter_files = Some(b"use bar".to_vec());
+        assert_eq!(matcher.search_path(info.clone()).matches.len(), 100);
+        matcher.mmap_threshold = 1;
+        matcher.stream_chunk_bytes = 64;
+        assert_eq!(matcher.search_path(info.clone()).matches.len(), 100);
+
+        matcher.filter_files = Some(b"use baz".to_vec());
+        assert!(matcher.search_path(info.clone()).matches.is_empty());
+        matcher.mmap_threshold = 0;
+        assert!(matcher.search_path(info).matches.is_empty());
+    }
+
+    #[test]
+    fn pipeline_matcher_lines() {
+        let info = PathInfo {
+            path: PathBuf::from("dummy"),
+            content: None,
+        };
+        let src = b"foo\nfoo foo\nfoo\nfoo\n";
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.lines = Some(IndexRange { beg: 2, end: Some(3) });
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![4, 8, 12]);
+
+        // A match across lines is kept only if the last line is within the range too
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo\nfoo");
+        matcher.lines = Some(IndexRange { beg: 1, end: Some(2) });
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![0]);
+
+        let info = PathInfo {
+            path: PathBuf::from("./src/ambs.rs"),
+            content: None,
+        };
+        let lines = "10..900".parse::<IndexRange>().ok();
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"matcher");
+        matcher.lines = lines;
+        let expected = matcher.search_path(info.clone());
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"matcher");
+        matcher.lines = lines;
+        matcher.mmap_threshold = 1;
+        matcher.stream_chunk_bytes = 64;
+        let ret = matcher.search_path(info);
+
+        assert!(!expected.matches.is_empty());
+        assert_eq!(expected.matches.len(), ret.matches.len());
+        for (x, y) in expected.matches.iter().zip(ret.matches.iter()) {
+            assert_eq!((x.beg, x.end), (y.beg, y.end));
+        }
+    }
+
+    #[test]
+    fn pipeline_matcher_columns() {
+        let info = PathInfo {
+            path: PathBuf::from("dummy"),
+            content: None,
+        };
+        let src = b"\xef\xbb\xbffoo foo\n  foo\nfoo\n";
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.columns = Some(IndexRange { beg: 1, end: Some(4) });
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![3, 13, 17]);
+    }
+
+    #[test]
+    fn pipeline_matcher_within() {
+        let info = PathInfo {
+            path: PathBuf::from("dummy"),
+            content: None,
+        };
+        let src = b"foo\nBEGIN\nfoo foo\nEND\nfoo\nBEGIN foo";
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.within = Within::new("BEGIN", "END").ok();
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![10, 14, 32]);
+
+        let within = Within::new("(?m)^BEGIN$", "(?m)^END$").unwrap();
+        assert_eq!(within.sections(src, false), (vec![(9, 18)], false));
+        assert_eq!(within.sections(b"foo\nEND\n", true), (vec![(0, 4)], false));
+    }
+
+    #[test]
+    fn pipeline_matcher_any_of() {
+        let info = PathInfo {
+            path: PathBuf::from("dummy"),
+            content: None,
+        };
+        let src = b"foobar foo barbaz";
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"foo");
+        matcher.any_of = vec![b"foobar".to_vec(), b"barbaz".to_vec(), b"bar".to_vec()];
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.end, m.keyword)).collect();
+        assert_eq!(ret, vec![(0, 6, 1), (7, 10, 0), (11, 17, 2)]);
+
+        let src = b"foobarbaz";
+        matcher.overlap = Overlap::Longest;
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.end, m.keyword)).collect();
+        assert_eq!(ret, vec![(0, 6, 1)]);
+
+        matcher.any_of = vec![b"obarbaz".to_vec(), b"bar".to_vec()];
+        let ret = matcher.search_src(&info, src);
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.end, m.keyword)).collect();
+        assert_eq!(ret, vec![(2, 9, 1)]);
+
+        matcher.overlap = Overlap::ReportAll;
+        let ret = matcher.search_src(&info, src);
+        assert!(has_overlaps(&ret.matches));
+        let ret: Vec<_> = ret.matches.iter().map(|m| (m.beg, m.end, m.keyword)).collect();
+        assert_eq!(ret, vec![(0, 3, 0), (2, 9, 1), (3, 6, 2)]);
+
+        assert_eq!("first".parse(), Ok(Overlap::First));
+        assert!("last".parse::<Overlap>().is_err());
+    }
+
+    #[test]
+    fn pipeline_matcher_noisy() {
+        let info = PathInfo {
+            path: PathBuf::from("./src/ambs.rs"),
+            content: None,
+        };
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"let");
+        matcher.noisy_threshold = 10;
+        let ret = matcher.search_path(info.clone());
+        let ret = matcher.check_noisy(ret);
+        assert!(ret.matches.is_empty());
+        assert_eq!(matcher.infos.len(), 1);
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"fn main");
+        matcher.noisy_threshold = 10;
+        let ret = matcher.search_path(info);
+        let ret = matcher.check_noisy(ret);
+        assert_eq!(ret.matches.len(), 1);
+        assert!(matcher.infos.is_empty());
+    }
+
+    #[test]
+    fn pipeline_matcher_binary_files() {
+        let info = PathInfo {
+            path: PathBuf::from("binary"),
+            content: Some(Arc::new(b"abc\x00\x01keyword\n".to_vec())),
+        };
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"keyword");
+        let ret = matcher.search_path(info.clone());
+        assert!(ret.binary);
+        assert!(ret.matches.is_empty());
+
+        matcher.binary_files = BinaryFiles::Binary;
+        let ret = matcher.search_path(info.clone());
+        assert!(ret.binary);
+        assert_eq!(ret.matches.len(), 1);
+
+        matcher.binary_files = "hex".parse().unwrap();
+        let ret = matcher.search_path(info.clone());
+        assert!(ret.binary);
+        assert_eq!(ret.matches.len(), 1);
+
+        matcher.binary_files = BinaryFiles::Text;
+        let ret = matcher.search_path(info);
+        assert!(!ret.binary);
+        assert_eq!(ret.matches.len(), 1);
+    }
+
+    #[test]
+    fn pipeline_matcher_sparse() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse");
+        {
+            let mut f = File::create(&path).unwrap();
+            f.set_len(64 * 1024 * 1024).unwrap();
+            f.seek(SeekFrom::Start(32 * 1024 * 1024)).unwrap();
+            f.write_all(b"keyword\nkeyword").unwrap();
+        }
+        let info = PathInfo { path, content: None };
+
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"keyword");
+        matcher.binary_files = BinaryFiles::Binary;
+        let ret = matcher.search_path(info.clone());
+        assert!(ret.binary);
+        assert_eq!(ret.matches.len(), 2);
+        assert_eq!(ret.matches[0].beg, 32 * 1024 * 1024);
+        assert_eq!(ret.matches[1].beg, 32 * 1024 * 1024 + 8);
+
+        // --lines and the cache apply to sparse files as the other files
+        let cache = Arc::new(ResultCache::new("keyword"));
+        let mut matcher = PipelineMatcher::new(QuickSearchMatcher::new(), b"keyword");
+        matcher.binary_files = BinaryFiles::Text;
+        matcher.lines = Some(IndexRange { beg: 1, end: Some(1) });
+        matcher.cache = Some(cache.clone());
+        let ret = matcher.search_path(info.clone());
+        assert_eq!(ret.matches.len(), 1);
+        cache.save(dir.path()).unwrap();
+        let cache = ResultCache::load(dir.path(), "keyword");
+        let attr = fs::metadata(&info.path).unwrap();
+        assert_eq!(
+            cache
+                .lookup(&info.path, attr.len(), attr.modified().ok())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    // Matches whole words only, as a downstream crate would implement
+    struct TokenMatcher;
+
+    impl Matcher for TokenMatcher {
+        fn search(&self, src: &[u8], pat: &[u8]) -> Vec<Match> {
+            let is_word = |x: u8| x.is_ascii_alphanumeric() || x == b'_';
+            let mut ret = Vec::new();
+            let mut beg = 0;
+            while beg < src.len() {
+                let len = src[beg..].iter().take_while(|x| is_word(**x)).count();
+                if len == 0 {
+                    beg += 1;
+                    continue;
+                }
+                if &src[beg..beg + len] == pat {
+                    ret.push(Match::new(beg, beg + len));
+                }
+                beg += len;
+            }
+            ret
+        }
+    }
+
+    #[test]
+    fn pipeline_matcher_custom() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        fs::write(&path, "let id = ids[id_max];\nid\n").unwrap();
+        let info = PathInfo { path, content: None };
+
+        let m: Box<dyn Matcher + Send> = Box::new(TokenMatcher);
+        let mut matcher = PipelineMatcher::new(m, b"id");
+        let ret = matcher.search_path(info);
+        let ranges: Vec<_> = ret.matches.iter().map(|x| (x.beg, x.end)).collect();
+        assert_eq!(ranges, vec![(4, 6), (22, 24)]);
+    }
 }
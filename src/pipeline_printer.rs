@@ -1,65 +1,499 @@
-use crate::console::{Console, ConsoleTextKind};
-use crate::pipeline::{Pipeline, PipelineInfo};
+use crate::blame::{blame_lines, Blame};
+use crate::console::{hex_bytes, Console, ConsoleTextKind, HEX_ROW};
+use crate::encoding::EncodingMap;
+use crate::matcher::Match;
+use crate::pipeline::{BatchReceiver, CancelToken, Pipeline, PipelineInfo};
 use crate::pipeline_matcher::PathMatch;
-use crate::util::{catch, decode_error};
+use crate::preset::PresetRule;
+use crate::trace::Tracer;
+use crate::util::{bom_len, catch, decode_error, long_path, ColumnUnit, PathPrefix};
 use crossbeam::channel::{Receiver, Sender};
 use memmap::Mmap;
+use regex::bytes::Regex;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
 use std::fs::File;
-use std::io::Error;
+use std::io::{self, Error, Write};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
+// ---------------------------------------------------------------------------------------------------------------------
+// OutputFormat
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Default,
+    Csv,
+    Tsv,
+    Xml,
+    Grep,
+    Emacs,
+    /// A JSON object per match
+    Json,
+    /// A SARIF log of all matches printed at the end
+    Sarif,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(OutputFormat::Default),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "xml" => Ok(OutputFormat::Xml),
+            "grep" => Ok(OutputFormat::Grep),
+            "emacs" => Ok(OutputFormat::Emacs),
+            "json" => Ok(OutputFormat::Json),
+            "sarif" => Ok(OutputFormat::Sarif),
+            _ => Err(format!("invalid output format: {}", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CountBy
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Key of the frequency table, which is the matched text or a capture group of `--regex` by the index or the name
+#[derive(Debug, Clone, PartialEq)]
+pub enum CountBy {
+    Match,
+    Capture(String),
+}
+
+impl FromStr for CountBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("capture:") {
+            Some(x) if !x.is_empty() => Ok(CountBy::Capture(String::from(x))),
+            Some(_) => Err(format!("invalid count key: {}", s)),
+            None if s == "match" => Ok(CountBy::Match),
+            None => Err(format!("invalid count key: {}", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SortMatches
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Order of the matches within each file instead of the position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortMatches {
+    /// Column of the match in bytes, and the line for the same column
+    Column,
+    /// Matched text, and the position for the same text
+    Text,
+}
+
+impl FromStr for SortMatches {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "column" => Ok(SortMatches::Column),
+            "text" => Ok(SortMatches::Text),
+            _ => Err(format!("invalid sort key: {}", s)),
+        }
+    }
+}
+
+// Capture group of the regex, where `range` is `None` if the group doesn't participate in the match
+struct Capture {
+    index: usize,
+    name: Option<String>,
+    range: Option<(usize, usize)>,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// FormatTemplate
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Path,
+    Line,
+    Column,
+    Offset,
+    OffsetEnd,
+    Match,
+    Text,
+}
+
+/// Output template like `{path}\t{line}\t{text}` printed for each match
+///
+/// Placeholders are `{path}`, `{line}`, `{column}`, `{offset}` / `{offset_end}` ( byte offsets of the match ),
+/// `{match}` ( the matched text ) and `{text}` ( the line of the match ).
+/// `\t`, `\n` and `\\` are unescaped, and `{{` / `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl FromStr for FormatTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('r') => literal.push('\r'),
+                    Some('\\') => literal.push('\\'),
+                    Some(x) => {
+                        literal.push('\\');
+                        literal.push(x);
+                    }
+                    None => literal.push('\\'),
+                },
+                '}' => match chars.next() {
+                    Some('}') => literal.push('}'),
+                    _ => return Err(String::from("unmatched '}' in format template")),
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('{') if name.is_empty() => {
+                                literal.push('{');
+                                break;
+                            }
+                            Some('}') => {
+                                let part = match name.as_str() {
+                                    "path" => TemplatePart::Path,
+                                    "line" => TemplatePart::Line,
+                                    "column" => TemplatePart::Column,
+                                    "offset" => TemplatePart::Offset,
+                                    "offset_end" => TemplatePart::OffsetEnd,
+                                    "match" => TemplatePart::Match,
+                                    "text" => TemplatePart::Text,
+                                    _ => return Err(format!("unknown placeholder in format template: {{{}}}", name)),
+                                };
+                                if !literal.is_empty() {
+                                    parts.push(TemplatePart::Literal(literal.split_off(0)));
+                                }
+                                parts.push(part);
+                                break;
+                            }
+                            Some(x) => name.push(x),
+                            None => return Err(String::from("unmatched '{' in format template")),
+                        }
+                    }
+                }
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(FormatTemplate { parts })
+    }
+}
+
+impl FormatTemplate {
+    fn render(&self, path: &str, line: usize, column: usize, src: &[u8], beg: usize, end: usize) -> String {
+        let mut ret = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(x) => ret.push_str(x),
+                TemplatePart::Path => ret.push_str(path),
+                TemplatePart::Line => ret.push_str(&format!("{}", line)),
+                TemplatePart::Column => ret.push_str(&format!("{}", column)),
+                TemplatePart::Offset => ret.push_str(&format!("{}", beg)),
+                TemplatePart::OffsetEnd => ret.push_str(&format!("{}", end)),
+                TemplatePart::Match => ret.push_str(&String::from_utf8_lossy(&src[beg..end])),
+                TemplatePart::Text => {
+                    let line_beg = src[..beg].iter().rposition(|&x| x == 0x0a).map_or(0, |x| x + 1);
+                    let line_beg = cmp::max(line_beg, bom_len(src));
+                    let line_end = src[end..]
+                        .iter()
+                        .position(|&x| x == 0x0a)
+                        .map_or(src.len(), |x| end + x);
+                    let text = String::from_utf8_lossy(&src[line_beg..line_end]);
+                    ret.push_str(text.trim_end_matches('\r'));
+                }
+            }
+        }
+        ret
+    }
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // PipelinePrinter
 // ---------------------------------------------------------------------------------------------------------------------
 
 pub struct PipelinePrinter {
     pub is_color: bool,
+    pub display_encoding: Option<String>,
+    /// Encodings of files decided by the path, which take precedence over `display_encoding`
+    pub encoding_map: Option<EncodingMap>,
     pub print_file: bool,
     pub print_column: bool,
     pub print_row: bool,
+    /// Unit of the column numbers of `print_row`, records and hyperlinks
+    pub column_unit: ColumnUnit,
+    /// Tabs are expanded to the multiples of this width in the column numbers ( 0: a tab is a character )
+    pub tab_width: usize,
+    pub format: OutputFormat,
+    /// Prefix removed from printed paths ( e.g. "./" of the implicit base path for grep compatible output )
+    pub strip_prefix: Option<PathBuf>,
+    /// Prefixes of printed paths and hyperlinks rewritten like `/container/src=/home/me/src`
+    pub path_prefix_map: Vec<PathPrefix>,
+    /// Roots and the labels like `frontend` of `frontend:web/`, which are printed before the results under the root
+    pub root_labels: Vec<(PathBuf, String)>,
+    /// Terminate paths by NUL instead of `:` like `grep --null` for the grep output and the Emacs output
+    pub null: bool,
+    /// Print the line of multiple matches once with all matches highlighted ( e.g. for `--all-of` )
+    pub group_line: bool,
+    /// Separator between the path, column, row and text of each match
+    pub field_separator: String,
+    /// Line printed between the groups of matches of each file
+    pub group_separator: Option<String>,
+    /// Print `path (N matches)` as the header of the group of each file instead of the path of each line
+    pub heading: bool,
+    /// Lines printed per file, where the rest is folded into a `… and N more` line ( None: unlimited )
+    pub max_lines_per_file: Option<usize>,
+    /// Template of each match, which overrides `format`
+    pub template: Option<FormatTemplate>,
+    /// Rules of the keywords indexed by `Match::keyword`, which are printed with each match ( e.g. `--preset` )
+    pub rules: Vec<PresetRule>,
+    /// Print the commit, the author and the date of the line of each match by `git blame` ( default and JSON )
+    pub blame: bool,
+    /// Hyperlink of file names ( see `Console::hyperlink_format` )
+    pub hyperlink_format: Option<String>,
+    /// Print all lines of the files with matches highlighted instead of the matched lines only
+    pub passthru: bool,
+    /// Print each match as the offset and hex dump instead of the line ( e.g. for `--hex` )
+    pub hex: bool,
+    /// Print hex dumps around matches in binary files instead of `Binary file <path> matches`
+    pub binary_hex: bool,
+    /// Write each line immediately even if stdout is not a terminal ( e.g. for `tail -f` like consumers )
+    pub line_buffered: bool,
+    /// Truncate lines longer than this number of characters around the matches
+    pub max_columns: Option<usize>,
+    /// Show the number of characters omitted by `max_columns`
+    pub max_columns_preview: bool,
+    /// Print lines longer than this number of bytes only around the matches, and report them as infos ( 0: unlimited )
+    pub max_line_bytes: usize,
+    /// File written instead of stdout, which is taken by the console at setup
+    pub output: Option<File>,
+    /// Matches beyond this are dropped, and `cancel` is cancelled to stop the other stages when reached
+    pub max_results: Option<usize>,
+    pub cancel: CancelToken,
+    /// Print nothing, and pass only the numbers of matches ( e.g. for `--quiet` )
+    pub quiet: bool,
+    /// Sort the matches of each file by the key, where the lines are not grouped
+    pub sort_matches: Option<SortMatches>,
+    /// Count the matches by the key instead of printing them, and print the frequency table at the end
+    pub count_by: Option<CountBy>,
+    /// Regexes of the keywords indexed by `Match::keyword` to extract the capture groups of each match, which are
+    /// counted by `count_by` and printed by `OutputFormat::Json` and `OutputFormat::Sarif`
+    pub capture_regexes: Vec<Regex>,
+    /// Log reaching `max_results` at the level 1, and the matches printed of each file at the level 2
+    pub trace: Tracer,
     pub infos: Vec<String>,
     pub errors: Vec<String>,
     console: Console,
+    sarif_results: Vec<Value>,
+    counts: HashMap<Vec<u8>, usize>,
+    printed_group: bool,
+    printed_matches: usize,
     time_beg: Instant,
     time_bsy: Duration,
+    items: usize,
+    bytes: u64,
 }
 
 impl PipelinePrinter {
     pub fn new() -> Self {
         PipelinePrinter {
             is_color: true,
+            display_encoding: None,
+            encoding_map: None,
             print_file: true,
             print_column: false,
             print_row: false,
+            column_unit: ColumnUnit::Bytes,
+            tab_width: 0,
+            format: OutputFormat::Default,
+            strip_prefix: None,
+            path_prefix_map: Vec::new(),
+            root_labels: Vec::new(),
+            null: false,
+            group_line: false,
+            field_separator: String::from(":"),
+            group_separator: None,
+            heading: false,
+            max_lines_per_file: None,
+            template: None,
+            rules: Vec::new(),
+            blame: false,
+            hyperlink_format: None,
+            passthru: false,
+            hex: false,
+            binary_hex: false,
+            line_buffered: false,
+            max_columns: None,
+            max_columns_preview: false,
+            max_line_bytes: 0,
+            output: None,
+            max_results: None,
+            cancel: CancelToken::new(),
+            quiet: false,
+            count_by: None,
+            capture_regexes: Vec::new(),
+            sort_matches: None,
+            trace: Tracer::default(),
             infos: Vec::new(),
             errors: Vec::new(),
             console: Console::new(),
+            sarif_results: Vec::new(),
+            counts: HashMap::new(),
+            printed_group: false,
+            printed_matches: 0,
             time_beg: Instant::now(),
             time_bsy: Duration::new(0, 0),
+            items: 0,
+            bytes: 0,
         }
     }
 
-    fn print_match(&mut self, pm: PathMatch) {
+    fn print_match(&mut self, mut pm: PathMatch) {
         if pm.matches.is_empty() {
             return;
         }
         self.console.is_color = self.is_color;
+        self.console.display_encoding = match self.encoding_map.as_ref().and_then(|x| x.lookup(&pm.path)) {
+            Some(x) => Some(String::from(x)),
+            None => self.display_encoding.clone(),
+        };
+        self.console.hyperlink_format = self.hyperlink_format.clone();
+        self.console.path_prefix_map = self.path_prefix_map.clone();
+        self.console.line_buffered |= self.line_buffered;
+        self.console.max_columns = self.max_columns;
+        self.console.max_columns_preview = self.max_columns_preview;
+        self.console.max_line_bytes = self.max_line_bytes;
+
+        let is_lines = matches!(
+            self.format,
+            OutputFormat::Default | OutputFormat::Grep | OutputFormat::Emacs
+        );
+        let counting = self.count_by.is_some();
+        if pm.binary && is_lines && self.template.is_none() && !self.binary_hex && !counting {
+            self.print_binary(&pm);
+            return;
+        }
 
         let result = catch::<_, (), Error>(|| {
-            let file = File::open(&pm.path)?;
-            let mmap = unsafe { Mmap::map(&file) }?;
-            let src = mmap.deref();
+            let mmap;
+            let src = match pm.content {
+                Some(ref x) => &x[..],
+                None => {
+                    let file = File::open(long_path(&pm.path))?;
+                    mmap = unsafe { Mmap::map(&file) }?;
+                    mmap.deref()
+                }
+            };
+
+            if counting {
+                self.count_matches(&pm, src);
+                return Ok(());
+            }
 
+            if self.hex && is_lines && self.template.is_none() {
+                self.print_hex(&pm, src);
+                return Ok(());
+            }
+
+            if pm.binary && self.binary_hex && is_lines && self.template.is_none() {
+                self.print_hex_dump(&pm, src);
+                return Ok(());
+            }
+
+            // Hex dumps of nearby matches are merged by the position
+            if let Some(key) = self.sort_matches {
+                sort_matches(&mut pm.matches, src, key);
+            }
+
+            if self.format == OutputFormat::Grep || self.format == OutputFormat::Emacs {
+                self.print_grep(&pm, src)?;
+                return Ok(());
+            }
+
+            if self.format != OutputFormat::Default || self.template.is_some() {
+                self.print_records(&pm, src);
+                return Ok(());
+            }
+
+            if let Some(ref sep) = self.group_separator {
+                if self.printed_group {
+                    self.console.write(ConsoleTextKind::Other, &format!("{}\n", sep));
+                }
+                self.printed_group = true;
+            }
+
+            if self.passthru {
+                self.print_passthru(&pm, src);
+                return Ok(());
+            }
+
+            if self.heading {
+                let num = pm.matches.len();
+                self.write_root_label(&pm.path);
+                self.console.write_filename(&pm.path, 1, 1);
+                let unit = if num == 1 { "match" } else { "matches" };
+                self.console
+                    .write(ConsoleTextKind::Other, &format!(" ({} {})\n", num, unit));
+            }
+
+            let blames = self.blames(&pm, src);
+            let sep = self.field_separator.clone();
             let mut pos = 0;
             let mut column = 0;
-            let mut last_lf = 0;
-            for m in &pm.matches {
-                if self.print_file {
-                    self.console.write(ConsoleTextKind::Filename, pm.path.to_str().unwrap());
-                    self.console.write(ConsoleTextKind::Filename, ":");
+            let mut last_lf = bom_len(src);
+            let mut lines = 0;
+            let mut i = 0;
+            while i < pm.matches.len() {
+                if self.max_lines_per_file.is_some_and(|x| lines >= x) {
+                    let rest = pm.matches.len() - i;
+                    self.console
+                        .write(ConsoleTextKind::Other, &format!("… and {} more\n", rest));
+                    break;
+                }
+                lines += 1;
+                let m = &pm.matches[i];
+                let mut num = 1;
+                if self.group_line && self.sort_matches.is_none() {
+                    let end = src[m.beg..]
+                        .iter()
+                        .position(|&x| x == 0x0a)
+                        .map_or(src.len(), |x| m.beg + x);
+                    while i + num < pm.matches.len() && pm.matches[i + num].beg < end {
+                        num += 1;
+                    }
                 }
-                if self.print_column | self.print_row {
+
+                if self.print_column | self.print_row | self.hyperlink_format.is_some() | self.blame {
+                    // Matches sorted by `sort_matches` may go back
+                    if m.beg < pos {
+                        pos = 0;
+                        column = 0;
+                        last_lf = bom_len(src);
+                    }
                     while pos < m.beg {
                         if src[pos] == 0x0a {
                             column += 1;
@@ -67,54 +501,729 @@ impl PipelinePrinter {
                         }
                         pos += 1;
                     }
-                    if self.print_column {
-                        self.console.write(ConsoleTextKind::Other, &format!("{}:", column + 1));
-                    }
-                    if self.print_row {
-                        self.console
-                            .write(ConsoleTextKind::Other, &format!("{}:", m.beg - last_lf));
-                    }
+                }
+                let row = if self.print_row | self.hyperlink_format.is_some() {
+                    let line_beg = if column == 0 { bom_len(src) } else { last_lf + 1 };
+                    self.column_unit.column(&src[line_beg..m.beg], self.tab_width)
+                } else {
+                    1
+                };
+                if !self.heading {
+                    self.write_root_label(&pm.path);
+                }
+                if self.print_file && !self.heading {
+                    self.console.write_filename(&pm.path, column + 1, row);
+                    self.console.write(ConsoleTextKind::Filename, &sep);
+                }
+                if self.print_column {
+                    self.console
+                        .write(ConsoleTextKind::Other, &format!("{}{}", column + 1, sep));
+                }
+                if self.print_row {
+                    self.console.write(ConsoleTextKind::Other, &format!("{}{}", row, sep));
+                }
+                if let Some(rule) = self.rules.get(m.keyword) {
+                    self.console.write(ConsoleTextKind::Other, &format!("[{}] ", rule.name));
+                }
+                if let Some(blame) = blames.get(&(column + 1)) {
+                    self.console
+                        .write(ConsoleTextKind::Other, &format!("{} ", blame.label()));
                 }
 
-                self.console.write_match_line(src, m);
+                if self.group_line {
+                    self.console.write_matches_line(src, &pm.matches[i..i + num]);
+                } else {
+                    self.console.write_match_line(src, m);
+                }
+                i += num;
             }
 
             Ok(())
         });
-        match result {
-            Ok(_) => (),
-            Err(e) => self.console.write(
-                ConsoleTextKind::Error,
-                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), pm.path),
-            ),
+        if let Err(e) = result {
+            self.errors
+                .push(format!("Error: {} @ {:?}\n", decode_error(e.kind()), pm.path));
+        }
+        let long_lines = self.console.take_long_lines();
+        if long_lines != 0 {
+            self.infos.push(format!(
+                "Long lines: {:?} ( {} lines longer than {} bytes are printed around the matches )\n",
+                pm.path, long_lines, self.max_line_bytes
+            ));
         }
     }
 }
 
-impl Pipeline<PathMatch, ()> for PipelinePrinter {
-    fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<PathMatch>>, tx: Sender<PipelineInfo<()>>) {
+impl PipelinePrinter {
+    fn print_header(&mut self) {
+        match self.format {
+            OutputFormat::Csv | OutputFormat::Tsv if !self.rules.is_empty() => {
+                self.write_record(&["path", "line", "column", "rule", "match"])
+            }
+            OutputFormat::Csv | OutputFormat::Tsv => self.write_record(&["path", "line", "column", "match"]),
+            OutputFormat::Xml => {
+                self.write_raw("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+                self.write_raw(&format!("<amber version=\"{}\">\n", env!("CARGO_PKG_VERSION")));
+            }
+            OutputFormat::Default | OutputFormat::Grep | OutputFormat::Emacs | OutputFormat::Json => (),
+            OutputFormat::Sarif => (),
+        }
+    }
+
+    fn count_matches(&mut self, pm: &PathMatch, src: &[u8]) {
+        for m in &pm.matches {
+            let key = match self.count_by {
+                Some(CountBy::Capture(ref x)) => {
+                    let capture = self.captures(src, m).into_iter().find(|c| match x.parse::<usize>() {
+                        Ok(i) => c.index == i,
+                        Err(_) => c.name.as_deref() == Some(x.as_str()),
+                    });
+                    match capture.and_then(|c| c.range) {
+                        Some((beg, end)) => &src[beg..end],
+                        None => continue,
+                    }
+                }
+                _ => &src[m.beg..m.end],
+            };
+            *self.counts.entry(key.to_vec()).or_insert(0) += 1;
+        }
+    }
+
+    // The regex is matched again from the beginning of the match to see the text around it ( e.g. `^`, `\b` )
+    fn captures(&self, src: &[u8], m: &Match) -> Vec<Capture> {
+        let regex = match self.capture_regexes.get(m.keyword) {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let mut locs = regex.capture_locations();
+        match regex.captures_read_at(&mut locs, src, m.beg) {
+            Some(x) if x.start() == m.beg => (),
+            _ => return Vec::new(),
+        }
+        regex
+            .capture_names()
+            .enumerate()
+            .skip(1)
+            .map(|(index, name)| Capture {
+                index,
+                name: name.map(String::from),
+                range: locs.get(index),
+            })
+            .collect()
+    }
+
+    // Capture groups not participating in the match are null
+    fn captures_json(&self, src: &[u8], m: &Match) -> Option<Value> {
+        if self.capture_regexes.is_empty() {
+            return None;
+        }
+        let captures = self
+            .captures(src, m)
+            .into_iter()
+            .map(|c| match c.range {
+                Some((beg, end)) => json!({
+                    "index": c.index,
+                    "name": c.name,
+                    "text": String::from_utf8_lossy(&src[beg..end]),
+                    "offset": beg,
+                    "offset_end": end,
+                }),
+                None => json!({ "index": c.index, "name": c.name, "text": null }),
+            })
+            .collect::<Vec<_>>();
+        Some(json!(captures))
+    }
+
+    // Sorted by the count in descending order, and by the text for the same count
+    fn print_counts(&mut self) {
+        let mut counts: Vec<(Vec<u8>, usize)> = self.counts.drain().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let width = counts.first().map_or(0, |x| x.1.to_string().len());
+        for (text, count) in counts {
+            self.console
+                .write(ConsoleTextKind::Other, &format!("{:>width$}  ", count, width = width));
+            self.console.write(
+                ConsoleTextKind::MatchText,
+                &String::from_utf8_lossy(&text).replace('\n', "\\n"),
+            );
+            self.console.write(ConsoleTextKind::Text, "\n");
+        }
+    }
+
+    fn print_footer(&mut self) {
+        if self.count_by.is_some() {
+            self.print_counts();
+            return;
+        }
+        match self.format {
+            OutputFormat::Xml => self.write_raw("</amber>\n"),
+            OutputFormat::Sarif => {
+                let log = self.sarif_log();
+                self.write_raw(&format!("{}\n", log));
+            }
+            _ => (),
+        }
+    }
+
+    // The driver has the rules referred by `ruleIndex` of the results
+    fn sarif_log(&mut self) -> Value {
+        let rules: Vec<Value> = self
+            .rules
+            .iter()
+            .map(|x| {
+                json!({
+                    "id": x.name,
+                    "shortDescription": { "text": rule_message(x) },
+                    "defaultConfiguration": { "level": x.severity.name() },
+                })
+            })
+            .collect();
+        json!({
+            "version": "2.1.0",
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "amber",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "informationUri": env!("CARGO_PKG_REPOSITORY"),
+                        "rules": rules,
+                    }
+                },
+                "results": self.sarif_results.drain(..).collect::<Vec<_>>(),
+            }]
+        })
+    }
+
+    fn sarif_result(&self, path: &str, line: usize, column: usize, text: &str, rule: Option<&PresetRule>) -> Value {
+        let mut ret = json!({
+            "level": rule.map_or("warning", |x| x.severity.name()),
+            "message": { "text": rule.map_or_else(|| format!("{} matches", text), rule_message) },
+            "locations": [{
+                "physicalLocation": {
+                    "artifactLocation": { "uri": path.trim_start_matches("./").replace('\\', "/") },
+                    "region": { "startLine": line, "startColumn": column, "snippet": { "text": text } },
+                }
+            }],
+        });
+        if let Some(rule) = rule {
+            ret["ruleId"] = json!(rule.name);
+            ret["ruleIndex"] = json!(self.rules.iter().position(|x| x.name == rule.name));
+        }
+        ret
+    }
+
+    fn print_records(&mut self, pm: &PathMatch, src: &[u8]) {
+        let path = PathPrefix::map(&self.path_prefix_map, &pm.path)
+            .to_string_lossy()
+            .into_owned();
+        if self.format == OutputFormat::Xml {
+            self.write_raw(&format!("  <file name=\"{}\">\n", escape_xml(&path)));
+        }
+        let blames = if self.format == OutputFormat::Json {
+            self.blames(pm, src)
+        } else {
+            HashMap::new()
+        };
+
+        let mut pos = 0;
+        let mut line = 1;
+        let mut line_beg = bom_len(src);
+        for m in &pm.matches {
+            if m.beg < pos {
+                pos = 0;
+                line = 1;
+                line_beg = bom_len(src);
+            }
+            while pos < m.beg {
+                if src[pos] == 0x0a {
+                    line += 1;
+                    line_beg = pos + 1;
+                }
+                pos += 1;
+            }
+            let column = self.column_unit.column(&src[line_beg..m.beg], self.tab_width);
+            if let Some(ref template) = self.template {
+                let record = template.render(&path, line, column, src, m.beg, m.end);
+                self.write_raw(&format!("{}\n", record));
+                continue;
+            }
+            let text = if self.hex {
+                Cow::Owned(hex_bytes(&src[m.beg..m.end]))
+            } else {
+                String::from_utf8_lossy(&src[m.beg..m.end])
+            };
+            let rule = self.rules.get(m.keyword).cloned();
+            match (self.format, rule) {
+                (OutputFormat::Json, rule) => {
+                    let mut record = json!({ "path": path, "line": line, "column": column, "match": text });
+                    if let Some(captures) = self.captures_json(src, m) {
+                        record["captures"] = captures;
+                    }
+                    if let Some(label) = root_label(&self.root_labels, &pm.path) {
+                        record["root"] = json!(label);
+                    }
+                    if let Some(blame) = blames.get(&line) {
+                        record["blame"] = json!(blame);
+                    }
+                    if let Some(rule) = rule {
+                        record["rule"] = json!(rule.name);
+                        record["severity"] = json!(rule.severity.name());
+                        record["description"] = json!(rule.description);
+                    }
+                    self.write_raw(&format!("{}\n", record));
+                }
+                (OutputFormat::Sarif, rule) => {
+                    let mut result = self.sarif_result(&path, line, column, &text, rule.as_ref());
+                    if let Some(captures) = self.captures_json(src, m) {
+                        result["properties"] = json!({ "captures": captures });
+                    }
+                    self.sarif_results.push(result);
+                }
+                (OutputFormat::Xml, Some(rule)) => self.write_raw(&format!(
+                    "    <match line=\"{}\" column=\"{}\" rule=\"{}\">{}</match>\n",
+                    line,
+                    column,
+                    escape_xml(&rule.name),
+                    escape_xml(&text)
+                )),
+                (OutputFormat::Xml, None) => self.write_raw(&format!(
+                    "    <match line=\"{}\" column=\"{}\">{}</match>\n",
+                    line,
+                    column,
+                    escape_xml(&text)
+                )),
+                (_, Some(rule)) => {
+                    self.write_record(&[&path, &format!("{}", line), &format!("{}", column), &rule.name, &text])
+                }
+                (_, None) => self.write_record(&[&path, &format!("{}", line), &format!("{}", column), &text]),
+            }
+        }
+
+        if self.format == OutputFormat::Xml {
+            self.write_raw("  </file>\n");
+        }
+    }
+
+    fn write_record(&mut self, fields: &[&str]) {
+        let record = match self.format {
+            OutputFormat::Tsv => fields.iter().map(|x| escape_tsv(x)).collect::<Vec<_>>().join("\t"),
+            _ => fields.iter().map(|x| escape_csv(x)).collect::<Vec<_>>().join(","),
+        };
+        self.write_raw(&format!("{}\n", record));
+    }
+
+    // Files not blamed ( e.g. out of repositories, not committed ) are reported without blames
+    fn blames(&mut self, pm: &PathMatch, src: &[u8]) -> HashMap<usize, Blame> {
+        if !self.blame || pm.matches.is_empty() {
+            return HashMap::new();
+        }
+        match blame_lines(&pm.path, &match_lines(src, &pm.matches)) {
+            Ok(x) => x,
+            Err(e) => {
+                self.infos.push(format!("Not blamed: {:?} ( {} )\n", pm.path, e));
+                HashMap::new()
+            }
+        }
+    }
+
+    fn write_root_label(&mut self, path: &Path) {
+        if let Some(label) = root_label(&self.root_labels, path) {
+            let label = format!("[{}] ", label);
+            self.console.write(ConsoleTextKind::Other, &label);
+        }
+    }
+
+    // Path of grep compatible output rewritten by `path_prefix_map` and `strip_prefix`
+    fn printed_path(&self, path: &Path) -> String {
+        let path = PathPrefix::map(&self.path_prefix_map, path);
+        let path = match self.strip_prefix {
+            Some(ref prefix) => path.strip_prefix(prefix).unwrap_or(&path),
+            None => &path,
+        };
+        path.to_string_lossy().into_owned()
+    }
+
+    // Lines of binary files are not printed like grep not to break the terminal
+    fn print_binary(&mut self, pm: &PathMatch) {
+        self.console.write(ConsoleTextKind::Other, "Binary file ");
+        if self.format == OutputFormat::Grep || self.format == OutputFormat::Emacs {
+            let path = self.printed_path(&pm.path);
+            self.console.write(ConsoleTextKind::Other, &path);
+        } else {
+            self.console.write_filename(&pm.path, 1, 1);
+        }
+        self.console.write(ConsoleTextKind::Other, " matches\n");
+    }
+
+    fn print_hex(&mut self, pm: &PathMatch, src: &[u8]) {
+        let sep = self.field_separator.clone();
+        for m in &pm.matches {
+            if self.print_file {
+                self.console.write_filename(&pm.path, 1, 1);
+                self.console.write(ConsoleTextKind::Filename, &sep);
+            }
+            self.console
+                .write(ConsoleTextKind::Other, &format!("{:08x}{}", m.beg, sep));
+            self.console.write_hex_match(src, m);
+        }
+    }
+
+    // Matches in nearby rows are printed in a hex dump not to repeat the same rows
+    fn print_hex_dump(&mut self, pm: &PathMatch, src: &[u8]) {
+        let context = 1;
+        let last_row = |m: &Match| (m.end.max(m.beg + 1) - 1) / HEX_ROW;
+        let sep = self.field_separator.clone();
+        let mut i = 0;
+        while i < pm.matches.len() {
+            let mut last = last_row(&pm.matches[i]);
+            let mut j = i + 1;
+            while j < pm.matches.len() && pm.matches[j].beg / HEX_ROW <= last + context * 2 + 1 {
+                last = cmp::max(last, last_row(&pm.matches[j]));
+                j += 1;
+            }
+
+            if self.print_file {
+                self.console.write_filename(&pm.path, 1, 1);
+                self.console.write(ConsoleTextKind::Filename, &sep);
+            }
+            self.console
+                .write(ConsoleTextKind::Other, &format!("{:08x}\n", pm.matches[i].beg));
+            self.console.write_hex_dump(src, &pm.matches[i..j], context);
+            i = j;
+        }
+    }
+
+    // Lines without matches are printed as is, and lines of matches across lines are printed at once
+    fn print_passthru(&mut self, pm: &PathMatch, src: &[u8]) {
+        let sep = self.field_separator.clone();
+        let mut beg = bom_len(src);
+        let mut line = 1;
+        let mut i = 0;
+        while beg < src.len() {
+            let end = src[beg..]
+                .iter()
+                .position(|&x| x == 0x0a)
+                .map_or(src.len(), |x| beg + x);
+            while i < pm.matches.len() && pm.matches[i].beg < beg {
+                i += 1;
+            }
+            let mut j = i;
+            while j < pm.matches.len() && pm.matches[j].beg < end {
+                j += 1;
+            }
+
+            if self.print_file {
+                self.console.write_filename(&pm.path, line, 1);
+                self.console.write(ConsoleTextKind::Filename, &sep);
+            }
+            if self.print_column {
+                self.console.write(ConsoleTextKind::Other, &format!("{}{}", line, sep));
+            }
+
+            let next = if i < j {
+                self.console.write_matches_line(src, &pm.matches[i..j]);
+                let last = pm.matches[j - 1].end;
+                src[last..]
+                    .iter()
+                    .position(|&x| x == 0x0a)
+                    .map_or(src.len(), |x| last + x)
+            } else {
+                self.console.write_plain_line(&src[beg..end]);
+                end
+            };
+            line += src[beg..next].iter().filter(|&&x| x == 0x0a).count() + 1;
+            beg = next + 1;
+            i = j;
+        }
+    }
+
+    fn print_grep(&mut self, pm: &PathMatch, src: &[u8]) -> Result<(), Error> {
+        let buf = self.format_grep(pm, src);
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(&buf)
+    }
+
+    // Emacs output is `path:line:text` of grep-mode, which always has the path and `column:` after the line by `print_row`
+    fn format_grep(&self, pm: &PathMatch, src: &[u8]) -> Vec<u8> {
+        let emacs = self.format == OutputFormat::Emacs;
+        // `is_color` is decided by --color like grep, where auto is colored only if stdout is a terminal
+        let colors = if self.is_color {
+            Some(GrepColors::from_env())
+        } else {
+            None
+        };
+        let colored = |buf: &mut Vec<u8>, color: &Option<String>, text: &[u8]| match color {
+            Some(c) if !c.is_empty() => {
+                buf.extend_from_slice(format!("\x1b[{}m\x1b[K", c).as_bytes());
+                buf.extend_from_slice(text);
+                buf.extend_from_slice(b"\x1b[m\x1b[K");
+            }
+            _ => buf.extend_from_slice(text),
+        };
+        let path = self.printed_path(&pm.path);
+        let (mt, fn_, ln, se) = match colors {
+            Some(ref x) => (x.mt.clone(), x.fn_.clone(), x.ln.clone(), x.se.clone()),
+            None => (None, None, None, None),
+        };
+
+        let mut buf = Vec::new();
+        let mut pos = 0;
+        let mut line = 1;
+        let mut i = 0;
+        while i < pm.matches.len() {
+            let m = &pm.matches[i];
+            if m.beg < pos {
+                pos = 0;
+                line = 1;
+            }
+            while pos < m.beg {
+                if src[pos] == 0x0a {
+                    line += 1;
+                }
+                pos += 1;
+            }
+            let beg = src[..m.beg]
+                .iter()
+                .rposition(|&x| x == 0x0a)
+                .map(|x| x + 1)
+                .unwrap_or(0);
+            let beg = cmp::max(beg, bom_len(src));
+
+            if self.print_file || emacs {
+                colored(&mut buf, &fn_, path.as_bytes());
+                if self.null {
+                    buf.push(0);
+                } else {
+                    colored(&mut buf, &se, b":");
+                }
+            }
+            colored(&mut buf, &ln, format!("{}", line).as_bytes());
+            colored(&mut buf, &se, b":");
+            if emacs && self.print_row {
+                let column = self.column_unit.column(&src[beg..m.beg], self.tab_width);
+                buf.extend_from_slice(format!("{}", column).as_bytes());
+                colored(&mut buf, &se, b":");
+            }
+
+            // All matches in the line are printed at once like grep
+            let mut last = beg;
+            let mut end;
+            loop {
+                let m = &pm.matches[i];
+                buf.extend_from_slice(&src[last..m.beg]);
+                colored(&mut buf, &mt, &src[m.beg..m.end]);
+                last = m.end;
+                end = src[m.end..]
+                    .iter()
+                    .position(|&x| x == 0x0a)
+                    .map(|x| x + m.end)
+                    .unwrap_or_else(|| src.len());
+                i += 1;
+                if i >= pm.matches.len() || pm.matches[i].beg >= end || pm.matches[i].beg < last {
+                    break;
+                }
+            }
+            buf.extend_from_slice(&src[last..end]);
+            buf.push(b'\n');
+        }
+        buf
+    }
+
+    fn write_raw(&mut self, val: &str) {
+        self.console.is_color = false;
+        self.console.write(ConsoleTextKind::Text, val);
+    }
+}
+
+/// Returns the label of the innermost root containing `path` in `roots` of `PipelinePrinter::root_labels`
+pub fn root_label<'a>(roots: &'a [(PathBuf, String)], path: &Path) -> Option<&'a str> {
+    roots
+        .iter()
+        .filter(|(root, _)| path.starts_with(root))
+        .max_by_key(|(root, _)| root.components().count())
+        .map(|(_, label)| label.as_str())
+}
+
+// Line numbers of the matches, which may go back by `sort_matches`
+fn match_lines(src: &[u8], matches: &[Match]) -> Vec<usize> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    for m in matches {
+        if m.beg < pos {
+            pos = 0;
+            line = 1;
+        }
+        line += memchr::memchr_iter(0x0a, &src[pos..m.beg]).count();
+        pos = m.beg;
+        ret.push(line);
+    }
+    ret
+}
+
+// Lines of the matches are not needed to sort, so the column is counted from the previous line feed
+fn sort_matches(matches: &mut [Match], src: &[u8], key: SortMatches) {
+    match key {
+        SortMatches::Column => matches.sort_by_cached_key(|m| {
+            let line_beg = src[..m.beg].iter().rposition(|&x| x == 0x0a).map_or(0, |x| x + 1);
+            (m.beg - cmp::max(line_beg, bom_len(src)), m.beg)
+        }),
+        SortMatches::Text => matches.sort_by(|a, b| src[a.beg..a.end].cmp(&src[b.beg..b.end]).then(a.beg.cmp(&b.beg))),
+    }
+}
+
+// RFC 4180: fields containing separators, quotes or line breaks are quoted, and quotes are doubled
+// Description of the rule, or the name if not described
+fn rule_message(rule: &PresetRule) -> String {
+    if rule.description.is_empty() {
+        rule.name.clone()
+    } else {
+        rule.description.clone()
+    }
+}
+
+fn escape_csv(src: &str) -> String {
+    if src.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", src.replace('"', "\"\""))
+    } else {
+        String::from(src)
+    }
+}
+
+// Tabs, line breaks and backslashes are escaped by backslash ( the convention of PostgreSQL / MySQL )
+fn escape_tsv(src: &str) -> String {
+    let mut ret = String::with_capacity(src.len());
+    for c in src.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            '\t' => ret.push_str("\\t"),
+            '\r' => ret.push_str("\\r"),
+            '\n' => ret.push_str("\\n"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// GrepColors
+// ---------------------------------------------------------------------------------------------------------------------
+
+struct GrepColors {
+    mt: Option<String>,
+    fn_: Option<String>,
+    ln: Option<String>,
+    se: Option<String>,
+}
+
+impl GrepColors {
+    fn from_env() -> Self {
+        let mut ret = GrepColors {
+            mt: Some(String::from("01;31")),
+            fn_: Some(String::from("35")),
+            ln: Some(String::from("32")),
+            se: Some(String::from("36")),
+        };
+        // GREP_COLOR is deprecated, and overridden by GREP_COLORS
+        if let Ok(x) = env::var("GREP_COLOR") {
+            ret.mt = Some(x);
+        }
+        if let Ok(x) = env::var("GREP_COLORS") {
+            ret.parse(&x);
+        }
+        ret
+    }
+
+    fn parse(&mut self, src: &str) {
+        for item in src.split(':') {
+            let (key, val) = match item.split_once('=') {
+                Some((k, v)) => (k, Some(String::from(v))),
+                None => (item, None),
+            };
+            match key {
+                "mt" | "ms" => self.mt = val,
+                "fn" => self.fn_ = val,
+                "ln" => self.ln = val,
+                "se" => self.se = val,
+                _ => (),
+            }
+        }
+    }
+}
+
+// Characters not allowed in XML 1.0 are replaced by U+FFFD
+fn escape_xml(src: &str) -> String {
+    let mut ret = String::with_capacity(src.len());
+    for c in src.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&apos;"),
+            '\t' | '\n' | '\r' => ret.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => ret.push('\u{fffd}'),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+impl Pipeline<PathMatch, (PathBuf, usize)> for PipelinePrinter {
+    fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<PathMatch>>, tx: Sender<PipelineInfo<(PathBuf, usize)>>) {
         self.infos = Vec::new();
         self.errors = Vec::new();
+        if let Some(x) = self.output.take() {
+            self.console.set_output(x);
+        }
+        let mut rx = BatchReceiver::new(rx);
         let mut seq_beg_arrived = false;
 
         loop {
             match rx.recv() {
-                Ok(PipelineInfo::SeqDat(x, pm)) => {
+                Ok(PipelineInfo::SeqDat(x, mut pm)) => {
+                    self.items += 1;
                     watch_time!(self.time_bsy, {
-                        self.print_match(pm);
-                        let _ = tx.send(PipelineInfo::SeqDat(x, ()));
+                        if let Some(max) = self.max_results {
+                            pm.matches.truncate(max.saturating_sub(self.printed_matches));
+                            self.printed_matches += pm.matches.len();
+                            if self.printed_matches >= max && !self.cancel.is_cancelled() {
+                                self.trace
+                                    .log(1, || format!("Reached: {} results ( --max-results )", max));
+                                self.cancel.cancel();
+                            }
+                        }
+                        let path = pm.path.clone();
+                        let num = pm.matches.len();
+                        if num != 0 {
+                            self.trace.log(2, || format!("Printed: {:?} ( {} matches )", path, num));
+                        }
+                        if !self.quiet {
+                            self.print_match(pm);
+                        }
+                        let _ = tx.send(PipelineInfo::SeqDat(x, (path, num)));
                     });
                 }
 
                 Ok(PipelineInfo::SeqBeg(x)) => {
                     if !seq_beg_arrived {
                         self.time_beg = Instant::now();
+                        if !self.quiet {
+                            self.print_header();
+                        }
                         let _ = tx.send(PipelineInfo::SeqBeg(x));
                         seq_beg_arrived = true;
                     }
                 }
 
                 Ok(PipelineInfo::SeqEnd(x)) => {
+                    if !self.quiet {
+                        self.print_footer();
+                    }
+                    self.console.flush();
+
                     for i in &self.infos {
                         let _ = tx.send(PipelineInfo::MsgInfo(id, i.clone()));
                     }
@@ -122,6 +1231,7 @@ impl Pipeline<PathMatch, ()> for PipelinePrinter {
                         let _ = tx.send(PipelineInfo::MsgErr(id, e.clone()));
                     }
 
+                    let _ = tx.send(PipelineInfo::MsgCount(id, self.items, self.bytes));
                     let _ = tx.send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
                     let _ = tx.send(PipelineInfo::SeqEnd(x));
                     break;
@@ -136,8 +1246,400 @@ impl Pipeline<PathMatch, ()> for PipelinePrinter {
                 Ok(PipelineInfo::MsgTime(i, t0, t1)) => {
                     let _ = tx.send(PipelineInfo::MsgTime(i, t0, t1));
                 }
+                Ok(PipelineInfo::MsgCount(i, n, b)) => {
+                    let _ = tx.send(PipelineInfo::MsgCount(i, n, b));
+                }
+                Ok(PipelineInfo::SeqBat(_)) => unreachable!(),
                 Err(_) => break,
             }
         }
     }
 }
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::{Matcher, RegexMatcher};
+    use crate::preset::Severity;
+    use crossbeam::channel::unbounded;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn pipeline_printer_max_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let mut printer = PipelinePrinter::new();
+        printer.is_color = false;
+        printer.output = Some(File::create(&output).unwrap());
+        printer.max_results = Some(3);
+        let cancel = printer.cancel.clone();
+
+        let (in_tx, in_rx) = unbounded();
+        let (out_tx, out_rx) = unbounded();
+        thread::spawn(move || {
+            printer.setup(0, in_rx, out_tx);
+        });
+
+        let _ = in_tx.send(PipelineInfo::SeqBeg(0));
+        for i in 0..3 {
+            let path = dir.path().join(format!("{}.txt", i));
+            fs::write(&path, "keyword keyword\n").unwrap();
+            let matches = [0, 8]
+                .iter()
+                .map(|x| Match {
+                    beg: *x,
+                    end: x + 7,
+                    sub_match: Vec::new(),
+                    keyword: 0,
+                })
+                .collect();
+            let pm = PathMatch {
+                path,
+                matches,
+                content: None,
+                binary: false,
+                stamp: None,
+            };
+            let _ = in_tx.send(PipelineInfo::SeqDat(i, pm));
+        }
+        let _ = in_tx.send(PipelineInfo::SeqEnd(3));
+
+        let mut ret = Vec::new();
+        loop {
+            match out_rx.recv().unwrap() {
+                PipelineInfo::SeqDat(_, (_, num)) => ret.push(num),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+        assert_eq!(ret, vec![2, 1, 0]);
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn pipeline_printer_heading() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "foo 1\nfoo 2\nfoo foo 3\nfoo 4\n").unwrap();
+
+        let mut printer = PipelinePrinter::new();
+        printer.is_color = false;
+        printer.output = Some(File::create(&output).unwrap());
+        printer.heading = true;
+        printer.group_line = true;
+        printer.max_lines_per_file = Some(2);
+        printer.console.set_output(printer.output.take().unwrap());
+        let matches = [0, 6, 12, 16, 22]
+            .iter()
+            .map(|x| Match {
+                beg: *x,
+                end: x + 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            })
+            .collect();
+        printer.print_match(PathMatch {
+            path: path.clone(),
+            matches,
+            content: None,
+            binary: false,
+            stamp: None,
+        });
+        printer.console.flush();
+
+        let expected = format!("{} (5 matches)\nfoo 1\nfoo 2\n… and 3 more\n", path.to_string_lossy());
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn pipeline_printer_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "foo(1) bar(2) foo(3)\nfoo(4) baz\n").unwrap();
+        let keyword = br"(?P<name>\w+)\(\d";
+        let pm = || PathMatch {
+            path: path.clone(),
+            matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), keyword),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+        let count = |count_by: &str| {
+            let mut printer = PipelinePrinter::new();
+            printer.is_color = false;
+            printer.count_by = Some(count_by.parse().unwrap());
+            printer.capture_regexes = vec![Regex::new(str::from_utf8(keyword).unwrap()).unwrap()];
+            printer.console.set_output(File::create(&output).unwrap());
+            printer.print_match(pm());
+            printer.print_footer();
+            printer.console.flush();
+            fs::read_to_string(&output).unwrap()
+        };
+
+        assert_eq!(count("capture:1"), "3  foo\n1  bar\n");
+        assert_eq!(count("capture:name"), "3  foo\n1  bar\n");
+        assert_eq!(count("match"), "1  bar(2\n1  foo(1\n1  foo(3\n1  foo(4\n");
+        assert!("capture:".parse::<CountBy>().is_err());
+        assert!("text".parse::<CountBy>().is_err());
+    }
+
+    #[test]
+    fn pipeline_printer_sort_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "x zeta alpha\n  beta\nalpha gamma\n").unwrap();
+        let print = |key: &str| {
+            let mut printer = PipelinePrinter::new();
+            printer.sort_matches = Some(key.parse().unwrap());
+            printer.template = Some("{line}:{column}:{match}".parse().unwrap());
+            printer.console.set_output(File::create(&output).unwrap());
+            printer.print_match(PathMatch {
+                path: path.clone(),
+                matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), b"[a-z]{4,}"),
+                content: None,
+                binary: false,
+                stamp: None,
+            });
+            printer.console.flush();
+            fs::read_to_string(&output).unwrap()
+        };
+
+        assert_eq!(print("text"), "1:8:alpha\n3:1:alpha\n2:3:beta\n3:7:gamma\n1:3:zeta\n");
+        assert_eq!(print("column"), "3:1:alpha\n1:3:zeta\n2:3:beta\n3:7:gamma\n1:8:alpha\n");
+        assert!("line".parse::<SortMatches>().is_err());
+    }
+
+    #[test]
+    fn pipeline_printer_captures() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "v1.2 v10.20-rc\n").unwrap();
+        let keyword = r"\bv(?P<major>\d+)\.(\d+)(-\w+)?";
+        let print = |format| {
+            let mut printer = PipelinePrinter::new();
+            printer.format = format;
+            printer.capture_regexes = vec![Regex::new(keyword).unwrap()];
+            printer.console.set_output(File::create(&output).unwrap());
+            printer.print_match(PathMatch {
+                path: path.clone(),
+                matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), keyword.as_bytes()),
+                content: None,
+                binary: false,
+                stamp: None,
+            });
+            printer.print_footer();
+            printer.console.flush();
+            fs::read_to_string(&output).unwrap()
+        };
+
+        let records: Vec<Value> = print(OutputFormat::Json)
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(
+            records[0]["captures"],
+            json!([
+                { "index": 1, "name": "major", "text": "1", "offset": 1, "offset_end": 2 },
+                { "index": 2, "name": null, "text": "2", "offset": 3, "offset_end": 4 },
+                { "index": 3, "name": null, "text": null },
+            ])
+        );
+        assert_eq!(records[1]["captures"][0]["text"], json!("10"));
+        assert_eq!(records[1]["captures"][2]["text"], json!("-rc"));
+        assert_eq!(records[1]["captures"][2]["offset"], json!(11));
+
+        let log: Value = serde_json::from_str(&print(OutputFormat::Sarif)).unwrap();
+        let result = &log["runs"][0]["results"][1];
+        assert_eq!(result["properties"]["captures"][1]["text"], json!("20"));
+    }
+
+    #[test]
+    fn pipeline_printer_max_line_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("a.txt");
+        fs::write(&path, format!("{}foo{}\nfoo\n", "x".repeat(600), "é".repeat(300))).unwrap();
+
+        let mut printer = PipelinePrinter::new();
+        printer.is_color = false;
+        printer.max_line_bytes = 1000;
+        printer.console.set_output(File::create(&output).unwrap());
+        printer.print_match(PathMatch {
+            path: path.clone(),
+            matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), b"foo"),
+            content: None,
+            binary: false,
+            stamp: None,
+        });
+        printer.console.flush();
+
+        let expected = format!(
+            "{0}:[...344 bytes]{1}foo{2}[...344 bytes]\n{0}:foo\n",
+            path.to_string_lossy(),
+            "x".repeat(256),
+            "é".repeat(128)
+        );
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+        assert_eq!(printer.infos.len(), 1);
+        assert!(printer.infos[0].starts_with("Long lines: "));
+    }
+
+    #[test]
+    fn pipeline_printer_root_labels() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("new/a.txt");
+        fs::create_dir(dir.path().join("new")).unwrap();
+        fs::write(&path, "foo\n").unwrap();
+
+        let mut printer = PipelinePrinter::new();
+        printer.is_color = false;
+        printer.root_labels = vec![
+            (dir.path().to_path_buf(), String::from("all")),
+            (dir.path().join("new"), String::from("new")),
+        ];
+        printer.console.set_output(File::create(&output).unwrap());
+        printer.print_match(PathMatch {
+            path: path.clone(),
+            matches: vec![Match::new(0, 3)],
+            content: None,
+            binary: false,
+            stamp: None,
+        });
+        printer.console.flush();
+
+        let expected = format!("[new] {}:foo\n", path.to_string_lossy());
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+        assert_eq!(root_label(&printer.root_labels, &output), Some("all"));
+        assert_eq!(root_label(&printer.root_labels, Path::new("/")), None);
+    }
+
+    #[test]
+    fn pipeline_printer_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "x TODO\ndbg!(y)\n").unwrap();
+        let rule = |name: &str, severity| PresetRule {
+            name: String::from(name),
+            pattern: String::new(),
+            severity,
+            description: String::new(),
+        };
+        let pm = || PathMatch {
+            path: path.clone(),
+            matches: vec![(2, 6, 0), (7, 11, 1)]
+                .into_iter()
+                .map(|(beg, end, keyword)| Match {
+                    beg,
+                    end,
+                    sub_match: Vec::new(),
+                    keyword,
+                })
+                .collect(),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let mut printer = PipelinePrinter::new();
+        printer.rules = vec![rule("todo", Severity::Note), rule("debug", Severity::Error)];
+        printer.format = OutputFormat::Json;
+        printer.console.set_output(File::create(&output).unwrap());
+        printer.print_match(pm());
+        printer.console.flush();
+        let lines: Vec<Value> = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["rule"], "debug");
+        assert_eq!(lines[1]["severity"], "error");
+        assert_eq!(lines[1]["line"], 2);
+
+        printer.format = OutputFormat::Sarif;
+        printer.console.set_output(File::create(&output).unwrap());
+        printer.print_match(pm());
+        printer.print_footer();
+        printer.console.flush();
+        let log: Value = serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"][0]["id"], "todo");
+        assert_eq!(run["results"][0]["ruleId"], "todo");
+        assert_eq!(run["results"][0]["level"], "note");
+        assert_eq!(run["results"][1]["ruleIndex"], 1);
+        assert_eq!(
+            run["results"][1]["locations"][0]["physicalLocation"]["region"]["startColumn"],
+            1
+        );
+    }
+
+    #[test]
+    fn pipeline_printer_format() {
+        assert_eq!(escape_csv("abc"), "abc");
+        assert_eq!(escape_csv("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv("a\"b"), "\"a\"\"b\"");
+        assert_eq!(escape_csv("a\nb"), "\"a\nb\"");
+        let mut colors = GrepColors::from_env();
+        colors.parse("ms=01;32:fn=:ln");
+        assert_eq!(colors.mt, Some(String::from("01;32")));
+        assert_eq!(colors.fn_, Some(String::new()));
+        assert_eq!(colors.ln, None);
+
+        assert_eq!(escape_xml("<a & 'b'>\u{1}"), "&lt;a &amp; &apos;b&apos;&gt;\u{fffd}");
+        assert_eq!(escape_tsv("a\tb\\c\r\n"), "a\\tb\\\\c\\r\\n");
+
+        let template: FormatTemplate = "{path}\\t{line}:{column} {{{match}}} {offset}-{offset_end} [{text}]"
+            .parse()
+            .unwrap();
+        let src = b"abc\r\nxfoox\r\n";
+        assert_eq!(template.render("a.rs", 2, 2, src, 6, 9), "a.rs\t2:2 {foo} 6-9 [xfoox]");
+        assert!("{unknown}".parse::<FormatTemplate>().is_err());
+        assert!("{path".parse::<FormatTemplate>().is_err());
+    }
+
+    #[test]
+    fn pipeline_printer_emacs() {
+        let mut printer = PipelinePrinter::new();
+        printer.is_color = false;
+        printer.format = OutputFormat::Emacs;
+        printer.print_file = false;
+        let src = b"x\n\tfoo bar foo\n";
+        let matches = [3, 11]
+            .iter()
+            .map(|x| Match {
+                beg: *x,
+                end: x + 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            })
+            .collect();
+        let pm = PathMatch {
+            path: PathBuf::from("./a.txt"),
+            matches,
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+        assert_eq!(printer.format_grep(&pm, src), b"./a.txt:2:\tfoo bar foo\n");
+
+        printer.strip_prefix = Some(PathBuf::from("./"));
+        printer.print_row = true;
+        printer.tab_width = 8;
+        assert_eq!(printer.format_grep(&pm, src), b"a.txt:2:9:\tfoo bar foo\n");
+
+        printer.print_row = false;
+        printer.null = true;
+        assert_eq!(printer.format_grep(&pm, src), b"a.txt\x002:\tfoo bar foo\n");
+    }
+}
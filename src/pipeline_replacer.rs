@@ -1,248 +1,1660 @@
+use crate::checkpoint::{Checkpoint, CheckpointEntry};
 use crate::console::{Console, ConsoleTextKind};
-use crate::pipeline::{Pipeline, PipelineInfo};
-use crate::pipeline_matcher::PathMatch;
-use crate::util::{catch, decode_error, exit};
+use crate::editorconfig::EditorConfig;
+use crate::encoding::EncodingMap;
+use crate::keymap::{KeyAction, Keymap};
+use crate::matcher::{CaseFoldMatcher, Match, Matcher, MemmemMatcher, RegexMatcher};
+use crate::pipeline::{BatchReceiver, Pipeline, PipelineInfo};
+use crate::pipeline_matcher::{filter_lines, has_overlaps, Overlap, PathMatch, PipelineMatcher};
+use crate::report::{HtmlReport, ReportEntry};
+use crate::tmpfile::{self, Registered};
+use crate::trace::Tracer;
+use crate::util::{
+    bom_len, catch, convert_case, decode_error, exit, is_alternate_stream, long_path, shell, transcode_from_utf8,
+    try_lock_exclusive, ColumnUnit, IndexRange, IoStrategy,
+};
 use crossbeam::channel::{Receiver, Sender};
-use ctrlc;
 use filetime::FileTime;
 use getch::Getch;
-use memmap::Mmap;
-use regex::Regex;
+use regex::bytes::{Captures, Regex, RegexBuilder};
+use serde_json::json;
+use std::borrow::Cow;
+use std::cmp;
+use std::env;
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::{Error, Write};
+use std::io::{self, Error, ErrorKind, Seek, SeekFrom, Write};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::str;
-use std::time::{Duration, Instant};
-use tempfile::NamedTempFile;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::{NamedTempFile, TempPath};
 use unicode_width::UnicodeWidthStr;
 
+// ---------------------------------------------------------------------------------------------------------------------
+// SymlinkPolicy
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    Follow,
+    Skip,
+    ReplaceLink,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "replace-link" => Ok(SymlinkPolicy::ReplaceLink),
+            _ => Err(format!("invalid symlink policy: {}", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Occurrence
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// 1-based indices of matches to be replaced like `2` or `3..7` ( both ends inclusive, `3..` and `..7` are open )
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Occurrence {
+    pub beg: usize,
+    pub end: Option<usize>,
+}
+
+impl Occurrence {
+    pub fn contains(&self, index: usize) -> bool {
+        self.beg <= index && self.end.map_or(true, |x| index <= x)
+    }
+}
+
+impl FromStr for Occurrence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid occurrence: {}", s);
+        let parse = |x: &str| x.trim().parse::<usize>().map_err(|_| err());
+        let (beg, end) = match s.find("..") {
+            Some(x) => {
+                let beg = if s[..x].trim().is_empty() { 1 } else { parse(&s[..x])? };
+                let end = if s[x + 2..].trim().is_empty() {
+                    None
+                } else {
+                    Some(parse(&s[x + 2..])?)
+                };
+                (beg, end)
+            }
+            None => {
+                let x = parse(s)?;
+                (x, Some(x))
+            }
+        };
+        if beg == 0 || end.map_or(false, |x| x < beg) {
+            return Err(err());
+        }
+        Ok(Occurrence { beg, end })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PendingReplace
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Replaced contents in a temporary file waiting to be persisted
+struct PendingReplace {
+    /// Closed while pending not to run out of file descriptors by --atomic
+    tmpfile: TempPath,
+    /// Dropped after persisting or discarding to unregister the temporary file
+    _registered: Registered,
+    /// Locked original file, which is rewritten directly in place
+    /// ( closed while pending by --atomic, and opened and locked again when persisted )
+    file: Option<File>,
+    path: PathBuf,
+    target_path: PathBuf,
+    real_path: PathBuf,
+    in_place: bool,
+    /// Length and modified time of the original file when read
+    snapshot: (u64, Option<SystemTime>),
+    time: Option<(FileTime, FileTime)>,
+    log: Vec<String>,
+    /// Numbers of the matches replaced and kept
+    counts: (usize, usize),
+    verification: Option<Verification>,
+    /// Dropped after persisting to make the original file read-only again
+    _writable: Option<WritableGuard>,
+}
+
+impl PendingReplace {
+    fn is_changed(&self) -> bool {
+        match fs::metadata(&self.target_path) {
+            Ok(m) => (m.len(), m.modified().ok()) != self.snapshot,
+            Err(_) => true,
+        }
+    }
+}
+
+/// Contents expected in the file persisted, which are checked by reading it again with `PipelineReplacer::verify`
+struct Verification {
+    len: usize,
+    /// Offsets in the replaced contents and the bytes written of the replacements
+    replaced: Vec<(usize, Vec<u8>)>,
+    /// Number of the keywords left, which is not checked if the replacements contain the keywords
+    remaining: Option<usize>,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// WritableGuard
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Read-only file made writable temporarily, whose permissions are restored when dropped
+struct WritableGuard {
+    path: PathBuf,
+    permissions: fs::Permissions,
+}
+
+impl WritableGuard {
+    fn new(path: &Path, permissions: fs::Permissions) -> Result<Self, Error> {
+        let mut writable = permissions.clone();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            writable.set_mode(writable.mode() | 0o200);
+        }
+        #[cfg(not(unix))]
+        writable.set_readonly(false);
+        fs::set_permissions(path, writable)?;
+        Ok(WritableGuard {
+            path: path.to_path_buf(),
+            permissions,
+        })
+    }
+}
+
+impl Drop for WritableGuard {
+    fn drop(&mut self) {
+        let _ = fs::set_permissions(&self.path, self.permissions.clone());
+    }
+}
+
 // ---------------------------------------------------------------------------------------------------------------------
 // PipelineReplacer
 // ---------------------------------------------------------------------------------------------------------------------
 
+// Changes shown for each file by `confirm_files`
+const CONFIRM_PREVIEWS: usize = 3;
+
 pub struct PipelineReplacer {
     pub is_color: bool,
+    pub display_encoding: Option<String>,
+    /// Encodings of files decided by the path, where replacements are encoded to when written
+    pub encoding_map: Option<EncodingMap>,
     pub is_interactive: bool,
     pub preserve_time: bool,
+    pub preserve_all: bool,
+    pub in_place: bool,
+    pub print_skipped: bool,
+    pub symlink_policy: SymlinkPolicy,
+    pub respect_line_endings: bool,
+    pub strip_bom: bool,
     pub print_file: bool,
     pub print_column: bool,
     pub print_row: bool,
+    /// Files are mapped by `io`, where `mmap_bytes` is the threshold of `IoStrategy::Auto`
+    pub io: IoStrategy,
+    pub mmap_bytes: u64,
+    /// Unit of the column numbers of `print_row`
+    pub column_unit: ColumnUnit,
+    /// Tabs are expanded to the multiples of this width in the column numbers and the preview ( 0: printed as is )
+    pub tab_width: usize,
+    /// Lines of the preview longer than this number of characters are truncated around the match ( None: disabled )
+    pub max_columns: Option<usize>,
+    pub context: usize,
+    pub report_path: Option<PathBuf>,
+    /// Journal where each decision is appended as a JSON line
+    pub log_path: Option<PathBuf>,
+    /// Persist all files at the end only if every file is replaced without errors and not changed since read
+    pub atomic: bool,
+    /// Replace files even if changed since matched or read
+    pub force: bool,
+    /// Clear the read-only bit of files temporarily to replace them without asking
+    pub force_writable: bool,
+    /// Convert line endings and indentation of replacements by `.editorconfig` of each file
+    pub editorconfig: bool,
+    /// Replace only the matches within the lines ( 1-based ) of each file
+    pub lines: Option<IndexRange>,
+    /// Replace only the matches within the byte offsets ( 0-based ) of each file
+    pub byte_range: Option<IndexRange>,
+    /// Replace only the matches of the indices in each file
+    pub occurrence: Option<Occurrence>,
+    /// Count the indices of `occurrence` through all files in the order of replacement instead of each file
+    pub global_occurrence: bool,
+    /// Run the replacement as a shell command reading the matched text from stdin, and use its stdout instead
+    pub exec_replace: bool,
+    /// Shell command reading the replaced contents of each file from stdin, where the file isn't persisted if it fails
+    pub validate: Option<String>,
+    /// Command run for each file after replaced, where `{}` in the arguments is the path or the path is appended
+    pub post_cmd: Option<String>,
+    /// Pairs of keywords and replacements replaced as well as the keyword in the same pass
+    pub map: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Policy of overlapping matches of `map` in the search after editing, where overlapping files are refused
+    pub overlap: Overlap,
+    /// Match case-insensitively, which also applies to the captures of --regex and the search after editing
+    pub ignore_case: bool,
+    /// Log the files skipped at the level 1, and the files persisted at the level 2
+    pub trace: Tracer,
+    /// Keys of the interactive replacement
+    pub keymap: Keymap,
+    /// Review the matches of all files by file after matching instead of asking each match while matching
+    pub review: bool,
+    /// Ask once per file showing the number of the matches and the first changes instead of asking each match
+    pub confirm_files: bool,
+    /// State file where each file is recorded after replaced to resume
+    pub checkpoint: Option<Checkpoint>,
+    /// Read each file again after persisted, and check the replacements and the keywords left by searching again
+    pub verify: bool,
+    /// Matcher configured as the pipeline to search the file again after edited by `$EDITOR`
+    pub matcher: Option<PipelineMatcher<Box<dyn Matcher + Send>>>,
+    /// Command line of the editor opened at the match by `o` ( `$EDITOR` or `vi` if None )
+    pub editor: Option<String>,
     pub infos: Vec<String>,
     pub errors: Vec<String>,
     console: Console,
+    report: HtmlReport,
+    pending: Vec<PendingReplace>,
+    reviewing: Vec<PathMatch>,
+    failed: bool,
     all_replace: bool,
+    all_writable: bool,
+    occurrence_count: usize,
+    // `occurrence_count` before the current file to select the matches again after edited by the editor
+    occurrence_file_beg: usize,
+    // Numbers of the files verified and the files with discrepancies
+    verified: (usize, usize),
     keyword: Vec<u8>,
     replacement: Vec<u8>,
     regex: bool,
     time_beg: Instant,
     time_bsy: Duration,
+    items: usize,
+    bytes: u64,
 }
 
 impl PipelineReplacer {
     pub fn new(keyword: &[u8], replacement: &[u8], regex: bool) -> Self {
         PipelineReplacer {
             is_color: true,
+            display_encoding: None,
+            encoding_map: None,
             is_interactive: true,
             preserve_time: false,
+            preserve_all: false,
+            in_place: false,
+            print_skipped: false,
+            symlink_policy: SymlinkPolicy::Follow,
+            respect_line_endings: false,
+            strip_bom: false,
             print_file: true,
             print_column: false,
             print_row: false,
+            io: IoStrategy::Auto,
+            mmap_bytes: 1024 * 1024,
+            column_unit: ColumnUnit::Bytes,
+            tab_width: 0,
+            max_columns: None,
+            context: 0,
+            report_path: None,
+            log_path: None,
+            atomic: false,
+            force: false,
+            force_writable: false,
+            editorconfig: false,
+            lines: None,
+            byte_range: None,
+            occurrence: None,
+            global_occurrence: false,
+            exec_replace: false,
+            validate: None,
+            post_cmd: None,
+            map: Vec::new(),
+            overlap: Overlap::First,
+            ignore_case: false,
+            trace: Tracer::default(),
+            keymap: Keymap::default(),
+            review: false,
+            confirm_files: false,
+            checkpoint: None,
+            verify: false,
+            matcher: None,
+            editor: None,
             infos: Vec::new(),
             errors: Vec::new(),
             console: Console::new(),
+            report: HtmlReport::new(),
+            pending: Vec::new(),
+            reviewing: Vec::new(),
+            failed: false,
             all_replace: false,
+            all_writable: false,
+            occurrence_count: 0,
+            occurrence_file_beg: 0,
+            verified: (0, 0),
             keyword: Vec::from(keyword),
             replacement: Vec::from(replacement),
             regex,
             time_beg: Instant::now(),
             time_bsy: Duration::new(0, 0),
+            items: 0,
+            bytes: 0,
+        }
+    }
+
+    // Matches are replaced only if both the first and the last byte are within the region
+    fn select_region(&self, mut pm: PathMatch) -> PathMatch {
+        if let Some(bytes) = self.byte_range {
+            pm.matches
+                .retain(|m| bytes.contains(m.beg) && bytes.contains(cmp::max(m.end, m.beg + 1) - 1));
+        }
+
+        let lines = match self.lines {
+            Some(x) if !pm.matches.is_empty() => x,
+            _ => return pm,
+        };
+        let result = catch::<_, Vec<Match>, Error>(|| {
+            let buf;
+            let src = match pm.content {
+                Some(ref x) => &x[..],
+                None => {
+                    let file = File::open(long_path(&pm.path))?;
+                    buf = self.io.load(&file, self.mmap_bytes)?;
+                    buf.deref()
+                }
+            };
+            Ok(filter_lines(src, 1, lines, pm.matches.clone()))
+        });
+        // Unreadable files are reported at replacement
+        if let Ok(x) = result {
+            pm.matches = x;
+        }
+        pm
+    }
+
+    fn select_occurrence(&mut self, mut pm: PathMatch) -> PathMatch {
+        let occurrence = match self.occurrence {
+            Some(x) => x,
+            None => return pm,
+        };
+        if !self.global_occurrence {
+            self.occurrence_count = 0;
         }
+        let beg = self.occurrence_count;
+        self.occurrence_file_beg = beg;
+        self.occurrence_count += pm.matches.len();
+        pm.matches = pm
+            .matches
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| occurrence.contains(beg + i + 1))
+            .map(|(_, m)| m)
+            .collect();
+        pm
     }
 
     fn replace_match(&mut self, pm: PathMatch) {
-        if pm.matches.is_empty() {
+        self.console.is_color = self.is_color;
+        self.console.display_encoding = self.display_encoding.clone();
+        self.console.tab_width = self.tab_width;
+        self.console.max_columns = self.max_columns;
+
+        // Overlapping matches ( e.g. by `--overlap report-all` ) can't be replaced without breaking each other
+        if has_overlaps(&pm.matches) {
+            self.errors.push(format!(
+                "Error: overlapping matches can't be replaced ( use --overlap first or longest ) @ {:?}\n",
+                pm.path
+            ));
             return;
         }
-        self.console.is_color = self.is_color;
 
-        let result = catch::<_, (), Error>(|| {
-            let mut tmpfile = NamedTempFile::new_in(pm.path.parent().unwrap_or(&pm.path))?;
-
-            let tmpfile_path = tmpfile.path().to_path_buf();
-            #[cfg(not(windows))]
-            let c_lflag = crate::util::get_c_lflag();
-            let _ = ctrlc::set_handler(move || {
-                let path = tmpfile_path.clone();
-                let mut console = Console::new();
-                console.write(
-                    ConsoleTextKind::Info,
-                    &format!("\nCleanup temporary file: {:?}\n", path),
-                );
-                let _ = fs::remove_file(path);
-                #[cfg(not(windows))]
-                crate::util::set_c_lflag(c_lflag);
-                exit(0, &mut console);
-            });
+        let pm = self.select_region(pm);
+        let pm = self.select_occurrence(pm);
+        if self.review {
+            if !pm.matches.is_empty() {
+                self.reviewing.push(pm);
+            }
+        } else if self.confirm_files && self.is_interactive && !self.all_replace && !pm.matches.is_empty() {
+            match self.confirm_file(&pm) {
+                Ok(true) => {
+                    self.is_interactive = false;
+                    self.replace_selected(pm);
+                    self.is_interactive = true;
+                }
+                Ok(false) => self
+                    .infos
+                    .push(format!("Skipped: {:?} ( rejected by confirmation )\n", pm.path)),
+                Err(e) => {
+                    self.failed = true;
+                    self.errors
+                        .push(format!("Error: {} @ {:?}\n", error_message(&e), pm.path));
+                }
+            }
+        } else {
+            self.replace_selected(pm);
+        }
+    }
 
-            {
-                let file = File::open(&pm.path)?;
-                let mmap = unsafe { Mmap::map(&file) }?;
-                let src = mmap.deref();
+    fn replace_selected(&mut self, pm: PathMatch) {
+        let is_symlink = fs::symlink_metadata(&pm.path)
+            .map(|x| x.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_symlink && self.symlink_policy == SymlinkPolicy::Skip {
+            self.trace
+                .log(1, || format!("Skipped: {:?} ( symbolic link )", pm.path));
+            if self.print_skipped {
+                self.infos.push(format!("Skipped: {:?} ( symbolic link )\n", pm.path));
+            }
+            return;
+        }
+        let replace_link = is_symlink && self.symlink_policy == SymlinkPolicy::ReplaceLink;
+        let encoding = self.file_encoding(&pm.path);
 
-                let mut i = 0;
-                let mut pos = 0;
-                let mut column = 0;
-                let mut last_lf = 0;
-                for m in &pm.matches {
-                    tmpfile.write_all(&src[i..m.beg])?;
+        if self.report_path.is_some() {
+            if let Err(e) = self.add_report(&pm) {
+                self.errors
+                    .push(format!("Error: {} @ {:?}\n", error_message(&e), pm.path));
+            }
+            return;
+        }
 
-                    let replacement = if self.regex {
-                        self.get_regex_replacement(&src[m.beg..m.end])
-                    } else {
-                        self.replacement.clone()
-                    };
+        let mut pm = pm;
+        while !pm.matches.is_empty() {
+            let result = catch::<_, Option<(usize, usize)>, Error>(|| {
+                let path = long_path(&pm.path);
 
-                    let mut do_replace = true;
-                    if self.is_interactive & !self.all_replace {
-                        let mut header_witdh = 0;
-                        if self.print_file {
-                            let path = pm.path.to_str().unwrap();
-                            header_witdh += UnicodeWidthStr::width(path) + 2;
-                            self.console.write(ConsoleTextKind::Filename, path);
-                            self.console.write(ConsoleTextKind::Other, ": ");
+                // Alternate data streams can't be canonicalized or replaced by renaming the temporary file
+                let is_stream = is_alternate_stream(&pm.path);
+                let in_place = (self.in_place | is_stream) & !replace_link;
+
+                let attr = fs::metadata(&path)?;
+                let writable = if attr.permissions().readonly() {
+                    if !self.allow_writable(&pm.path)? {
+                        return Ok(None);
+                    }
+                    Some(WritableGuard::new(&path, attr.permissions())?)
+                } else {
+                    None
+                };
+
+                // The original file is locked until replaced not to interleave writes with other processes
+                let file = fs::OpenOptions::new().read(true).write(in_place).open(&path)?;
+                if !try_lock_exclusive(&file)? {
+                    self.infos
+                        .push(format!("Skipped: {:?} ( locked by another process )\n", pm.path));
+                    self.failed = true;
+                    return Ok(None);
+                }
+
+                // Matches are found at match time, so the positions are stale if the file is changed since then
+                let attr = file.metadata()?;
+                let stamp = (attr.len(), attr.modified().ok());
+                if !self.force && pm.stamp.is_some_and(|x| x != stamp) {
+                    self.errors
+                        .push(format!("Error: file is changed since matched @ {:?}\n", pm.path));
+                    self.failed = true;
+                    return Ok(None);
+                }
+
+                // Removed by the handler installed at setup if interrupted, and found at the next run if killed
+                let (mut tmpfile, registered) = tmpfile::create_in(path.parent().unwrap_or(&path))?;
+
+                #[cfg(not(windows))]
+                let c_lflag = crate::util::get_c_lflag();
+
+                let (reopen, log, snapshot, counts, verification) = {
+                    let snapshot = stamp;
+                    let buf = self.io.load(&file, self.mmap_bytes)?;
+                    let src = buf.deref();
+
+                    let bom = bom_len(src);
+                    let mut pos = 0;
+                    let mut column = 0;
+                    let mut last_lf = bom;
+                    let mut file_replace = false;
+                    let mut file_skip = false;
+                    let is_crlf = self.respect_line_endings && PipelineReplacer::is_crlf(src);
+                    let editorconfig = self.load_editorconfig(&pm.path);
+                    let mut reopen = None;
+                    // Replacements are decided at first and written at last to be undone
+                    let mut decisions: Vec<(Vec<u8>, bool)> = Vec::new();
+                    while decisions.len() < pm.matches.len() {
+                        let m = &pm.matches[decisions.len()];
+
+                        let mut replacement = self.get_replacement(&src[m.beg..m.end], m.keyword)?;
+                        if is_crlf {
+                            replacement = PipelineReplacer::to_crlf(&replacement);
                         }
-                        if self.print_column | self.print_row {
-                            while pos < m.beg {
-                                if src[pos] == 0x0a {
-                                    column += 1;
-                                    last_lf = pos;
-                                }
-                                pos += 1;
+                        if let Some(ref x) = editorconfig {
+                            replacement = x.apply(&replacement, m.end == src.len());
+                        }
+
+                        let mut do_replace = !file_skip;
+                        let mut undo = false;
+                        if self.is_interactive & !self.all_replace & !file_replace & !file_skip {
+                            if self.context > 0 {
+                                self.console.write_context_before(src, m, self.context);
                             }
-                            if self.print_column {
-                                let column_str = format!("{}:", column + 1);
-                                header_witdh += column_str.width();
-                                self.console.write(ConsoleTextKind::Other, &column_str);
+
+                            let mut header_witdh = 0;
+                            if self.print_file {
+                                let path = pm.path.to_str().unwrap();
+                                header_witdh += UnicodeWidthStr::width(path) + 2;
+                                self.console.write(ConsoleTextKind::Filename, path);
+                                self.console.write(ConsoleTextKind::Other, ": ");
                             }
-                            if self.print_row {
-                                let row_str = format!("{}:", m.beg - last_lf);
-                                header_witdh += row_str.width();
-                                self.console.write(ConsoleTextKind::Other, &row_str);
+                            if self.print_column | self.print_row {
+                                while pos < m.beg {
+                                    if src[pos] == 0x0a {
+                                        column += 1;
+                                        last_lf = pos;
+                                    }
+                                    pos += 1;
+                                }
+                                if self.print_column {
+                                    let column_str = format!("{}:", column + 1);
+                                    header_witdh += column_str.width();
+                                    self.console.write(ConsoleTextKind::Other, &column_str);
+                                }
+                                if self.print_row {
+                                    let line_beg = if column == 0 { bom } else { last_lf + 1 };
+                                    let row_str =
+                                        format!("{}:", self.column_unit.column(&src[line_beg..m.beg], self.tab_width));
+                                    header_witdh += row_str.width();
+                                    self.console.write(ConsoleTextKind::Other, &row_str);
+                                }
                             }
-                        }
 
-                        if header_witdh < 4 {
-                            self.console
-                                .write(ConsoleTextKind::Other, &format!("{}", " ".repeat(4 - header_witdh)));
-                            header_witdh = 4;
-                        }
-
-                        self.console.write_match_line(src, m);
-                        self.console
-                            .write(ConsoleTextKind::Other, &format!("{} -> ", " ".repeat(header_witdh - 4)));
-                        self.console.write_replace_line(src, m, &replacement);
+                            if header_witdh < 4 {
+                                self.console
+                                    .write(ConsoleTextKind::Other, &format!("{}", " ".repeat(4 - header_witdh)));
+                                header_witdh = 4;
+                            }
 
-                        let getch = Getch::new();
-                        loop {
+                            self.console.write_diff_line(src, m, &replacement, false, header_witdh);
                             self.console
-                                .write(ConsoleTextKind::Other, "Replace keyword? [Y]es/[n]o/[a]ll/[q]uit: ");
-                            self.console.flush();
-                            let key = char::from(getch.getch()?);
-                            if key != '\n' {
-                                self.console.write(ConsoleTextKind::Other, &format!("{}\n", key));
-                            } else {
-                                self.console.write(ConsoleTextKind::Other, "\n");
+                                .write(ConsoleTextKind::Other, &format!("{} -> ", " ".repeat(header_witdh - 4)));
+                            self.console.write_diff_line(src, m, &replacement, true, header_witdh);
+
+                            if self.context > 0 {
+                                self.console.write_context_after(src, m, self.context);
                             }
-                            match key {
-                                'Y' | 'y' | ' ' | '\r' | '\n' => do_replace = true,
-                                'N' | 'n' => do_replace = false,
-                                'A' | 'a' => self.all_replace = true,
-                                'Q' | 'q' => {
-                                    let _ = tmpfile.close();
-                                    #[cfg(not(windows))]
-                                    crate::util::set_c_lflag(c_lflag);
-                                    exit(0, &mut self.console);
+
+                            let getch = Getch::new();
+                            // The file can't be opened by the editor before persisting with --atomic
+                            let atomic = self.atomic;
+                            let prompt = self.keymap.prompt(|x| x != KeyAction::Open || !atomic);
+                            loop {
+                                self.console
+                                    .write(ConsoleTextKind::Other, &format!("Replace keyword? {}: ", prompt));
+                                self.console.flush();
+                                let key = char::from(getch.getch()?);
+                                if key != '\n' {
+                                    self.console.write(ConsoleTextKind::Other, &format!("{}\n", key));
+                                } else {
+                                    self.console.write(ConsoleTextKind::Other, "\n");
                                 }
-                                _ => continue,
+                                match self.keymap.action(key) {
+                                    Some(KeyAction::Yes) => do_replace = true,
+                                    Some(KeyAction::No) => do_replace = false,
+                                    Some(KeyAction::All) => self.all_replace = true,
+                                    Some(KeyAction::AllInFile) => file_replace = true,
+                                    Some(KeyAction::Skip) => {
+                                        file_skip = true;
+                                        do_replace = false;
+                                    }
+                                    Some(KeyAction::Edit) => {
+                                        replacement = self.edit_replacement(&getch, &replacement)?;
+                                        do_replace = true;
+                                    }
+                                    Some(KeyAction::Open) if !self.atomic => {
+                                        let line = src[..m.beg].iter().filter(|&&x| x == 0x0a).count() + 1;
+                                        let lf_shift: isize = pm
+                                            .matches
+                                            .iter()
+                                            .zip(&decisions)
+                                            .map(|(m, (x, accepted))| {
+                                                if *accepted {
+                                                    x.iter().filter(|&&x| x == 0x0a).count() as isize
+                                                        - src[m.beg..m.end].iter().filter(|&&x| x == 0x0a).count()
+                                                            as isize
+                                                } else {
+                                                    0
+                                                }
+                                            })
+                                            .sum();
+                                        reopen = Some(((line as isize + lf_shift) as usize, 0));
+                                        do_replace = false;
+                                    }
+                                    Some(KeyAction::Undo) if !decisions.is_empty() => undo = true,
+                                    Some(KeyAction::Quit) => {
+                                        let _ = tmpfile.close();
+                                        self.pending.clear();
+                                        #[cfg(not(windows))]
+                                        crate::util::set_c_lflag(c_lflag);
+                                        exit(0, &mut self.console);
+                                    }
+                                    _ => continue,
+                                }
+                                break;
                             }
+                        }
+
+                        // The previous match is asked again, and column / row are counted from the beginning
+                        if undo {
+                            decisions.pop();
+                            pos = 0;
+                            column = 0;
+                            last_lf = bom;
+                            continue;
+                        }
+
+                        decisions.push((replacement, do_replace));
+
+                        if reopen.is_some() {
                             break;
                         }
                     }
 
-                    if do_replace {
-                        tmpfile.write_all(&replacement)?;
+                    let mut i = if self.strip_bom { bom } else { 0 };
+                    let mut len = 0;
+                    let mut replaced = Vec::new();
+                    for (j, (m, (x, accepted))) in pm.matches.iter().zip(&decisions).enumerate() {
+                        tmpfile.write_all(&src[i..m.beg])?;
+                        len += m.beg - i;
+                        // The offset of the match opened by the editor in the file written
+                        if j + 1 == decisions.len() {
+                            reopen = reopen.map(|(line, _)| (line, len));
+                        }
+                        if *accepted {
+                            // Replacements not in UTF-8 like captures of a legacy encoding are written as is
+                            let y = match encoding.as_ref().and_then(|e| transcode_from_utf8(x, e)) {
+                                Some(y) => Cow::Owned(y),
+                                None => Cow::Borrowed(&x[..]),
+                            };
+                            tmpfile.write_all(&y)?;
+                            if self.verify {
+                                replaced.push((len, y.to_vec()));
+                            }
+                            len += y.len();
+                        } else {
+                            tmpfile.write_all(&src[m.beg..m.end])?;
+                            len += m.end - m.beg;
+                        }
+                        i = m.end;
+                    }
+                    if i < src.len() {
+                        tmpfile.write_all(&src[i..src.len()])?;
+                        len += src.len() - i;
+                    }
+                    tmpfile.flush()?;
+
+                    let log = if self.log_path.is_some() {
+                        PipelineReplacer::log_records(&pm, src, &decisions)
+                    } else {
+                        Vec::new()
+                    };
+                    let accepted = decisions.iter().filter(|x| x.1).count();
+                    let verification = if self.verify {
+                        // Keywords not matched ( e.g. filtered by --not ) are left as well as the matches kept
+                        let remaining = if replaced.iter().any(|(_, x)| !self.search_keywords(x).is_empty()) {
+                            None
+                        } else {
+                            Some(self.search_keywords(src).len().saturating_sub(accepted))
+                        };
+                        Some(Verification {
+                            len,
+                            replaced,
+                            remaining,
+                        })
                     } else {
-                        tmpfile.write_all(&src[m.beg..m.end])?;
+                        None
+                    };
+                    (
+                        reopen,
+                        log,
+                        snapshot,
+                        (accepted, decisions.len() - accepted),
+                        verification,
+                    )
+                };
+
+                // Files without replacements are persisted as is
+                if let Some(cmd) = self.validate.as_ref().filter(|_| counts.0 > 0) {
+                    if let Err(e) = PipelineReplacer::validate_file(cmd, &pm.path, &tmpfile) {
+                        self.errors.push(format!(
+                            "Error: {} ( not replaced ) @ {:?}\n",
+                            error_message(&e),
+                            pm.path
+                        ));
+                        self.failed = true;
+                        return Ok(None);
+                    }
+                }
+
+                let target_path = if is_stream {
+                    path.to_path_buf()
+                } else {
+                    fs::canonicalize(&path)?
+                };
+                let real_path = if replace_link {
+                    path.to_path_buf()
+                } else {
+                    target_path.clone()
+                };
+
+                let metadata = fs::metadata(&target_path)?;
+
+                let time = if self.preserve_time | self.preserve_all {
+                    let mtime = FileTime::from_last_modification_time(&metadata);
+                    let atime = FileTime::from_last_access_time(&metadata);
+                    Some((mtime, atime))
+                } else {
+                    None
+                };
+
+                if !in_place {
+                    if self.preserve_all {
+                        #[cfg(unix)]
+                        {
+                            use std::os::unix::fs::MetadataExt;
+                            std::os::unix::fs::chown(tmpfile.path(), Some(metadata.uid()), Some(metadata.gid()))?;
+                        }
+                    } else {
+                        // The temporary file inherits the group and the ACL from the directory, not from the target
+                        crate::util::inherit_access(tmpfile.path(), &target_path, &metadata)?;
+                    }
+
+                    fs::set_permissions(tmpfile.path(), metadata.permissions())?;
+
+                    // POSIX ACLs are stored as extended attributes ( system.posix_acl_* ) on Linux.
+                    // SELinux contexts and Finder metadata are always restored not to break system files and bundles.
+                    #[cfg(any(target_os = "linux", target_os = "macos"))]
+                    {
+                        let xattrs = if self.preserve_all {
+                            crate::util::get_xattrs(&target_path)?
+                        } else {
+                            crate::util::get_metadata_xattrs(&target_path)?
+                        };
+                        crate::util::set_xattrs(tmpfile.path(), &xattrs)?;
+                    }
+                    #[cfg(target_os = "macos")]
+                    {
+                        use std::os::macos::fs::MetadataExt;
+                        crate::util::set_finder_flags(tmpfile.path(), metadata.st_flags())?;
+                    }
+                }
+
+                let pending = PendingReplace {
+                    tmpfile: tmpfile.into_temp_path(),
+                    _registered: registered,
+                    file: if self.atomic { None } else { Some(file) },
+                    path: pm.path.clone(),
+                    target_path,
+                    real_path,
+                    in_place,
+                    snapshot,
+                    time,
+                    log,
+                    counts,
+                    verification,
+                    _writable: writable,
+                };
+                if self.atomic {
+                    self.pending.push(pending);
+                } else if !self.force && pending.is_changed() {
+                    self.errors
+                        .push(format!("Error: file is changed during replacement @ {:?}\n", pm.path));
+                    self.failed = true;
+                } else {
+                    self.persist(pending)?;
+                }
+
+                Ok(reopen)
+            });
+            match result {
+                Ok(Some((line, offset))) => match self.open_editor(&pm, line, offset) {
+                    Ok(x) => pm = x,
+                    Err(e) => {
+                        self.errors
+                            .push(format!("Error: {} @ {:?}\n", decode_error(e.kind()), pm.path));
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    self.failed = true;
+                    self.errors
+                        .push(format!("Error: {} @ {:?}\n", error_message(&e), pm.path));
+                    break;
+                }
+            }
+        }
+    }
+
+    // The rejected files and matches are dropped, and the rest is replaced without asking again
+    fn review_all(&mut self) {
+        let reviewing = std::mem::take(&mut self.reviewing);
+        let mut accepted = Vec::new();
+        for pm in reviewing {
+            let path = pm.path.clone();
+            match self.review_file(pm) {
+                Ok(Some(x)) => accepted.push(x),
+                Ok(None) => (),
+                Err(e) => {
+                    self.failed = true;
+                    self.errors.push(format!("Error: {} @ {:?}\n", error_message(&e), path));
+                }
+            }
+        }
+
+        let is_interactive = self.is_interactive;
+        self.is_interactive = false;
+        for pm in accepted {
+            self.replace_selected(pm);
+        }
+        self.is_interactive = is_interactive;
+    }
+
+    // Returns the matches accepted, or None if the whole file is rejected
+    fn review_file(&mut self, mut pm: PathMatch) -> Result<Option<PathMatch>, Error> {
+        self.file_encoding(&pm.path);
+        let file = File::open(long_path(&pm.path))?;
+        let buf = self.io.load(&file, self.mmap_bytes)?;
+        let src = buf.deref();
+
+        let mut hunks = Vec::new();
+        for m in &pm.matches {
+            let line = src[..m.beg].iter().filter(|&&x| x == 0x0a).count() + 1;
+            hunks.push((line, self.get_replacement(&src[m.beg..m.end], m.keyword)?));
+        }
+        let mut accepted = vec![true; hunks.len()];
+
+        #[cfg(not(windows))]
+        let c_lflag = crate::util::get_c_lflag();
+
+        let getch = Getch::new();
+        loop {
+            let num = accepted.iter().filter(|x| **x).count();
+            self.console
+                .write(ConsoleTextKind::Filename, &pm.path.to_string_lossy());
+            self.console.write(
+                ConsoleTextKind::Other,
+                &format!(" ( {} of {} changes )\n", num, hunks.len()),
+            );
+            for (i, (m, (line, replacement))) in pm.matches.iter().zip(&hunks).enumerate() {
+                self.write_hunk(src, m, *line, replacement, accepted[i]);
+            }
+
+            let key = self.ask(&getch, "Apply changes? [Y]es/[n]o/[h]unks/[q]uit: ")?;
+            match key {
+                'Y' | 'y' | ' ' | '\r' | '\n' => break,
+                'N' | 'n' => {
+                    accepted.iter_mut().for_each(|x| *x = false);
+                    break;
+                }
+                'H' | 'h' => {
+                    for (i, (m, (line, replacement))) in pm.matches.iter().zip(&hunks).enumerate() {
+                        self.write_hunk(src, m, *line, replacement, accepted[i]);
+                        match self.ask(&getch, "Apply this change? [y]es/[n]o: ")? {
+                            'Y' | 'y' => accepted[i] = true,
+                            'N' | 'n' => accepted[i] = false,
+                            _ => (),
+                        }
                     }
-                    i = m.end;
                 }
+                'Q' | 'q' => {
+                    #[cfg(not(windows))]
+                    crate::util::set_c_lflag(c_lflag);
+                    exit(0, &mut self.console);
+                }
+                _ => (),
+            }
+        }
+
+        let mut i = 0;
+        pm.matches.retain(|_| {
+            i += 1;
+            accepted[i - 1]
+        });
+        if pm.matches.is_empty() {
+            self.infos
+                .push(format!("Skipped: {:?} ( rejected by review )\n", pm.path));
+            return Ok(None);
+        }
+        Ok(Some(pm))
+    }
+
+    // Returns whether all the matches of the file are replaced, where [a]ll replaces the following files without asking
+    fn confirm_file(&mut self, pm: &PathMatch) -> Result<bool, Error> {
+        self.file_encoding(&pm.path);
+        let file = File::open(long_path(&pm.path))?;
+        let buf = self.io.load(&file, self.mmap_bytes)?;
+        let src = buf.deref();
 
-                if i < src.len() {
-                    tmpfile.write_all(&src[i..src.len()])?;
+        let num = pm.matches.len();
+        self.console
+            .write(ConsoleTextKind::Filename, &pm.path.to_string_lossy());
+        self.console.write(
+            ConsoleTextKind::Other,
+            &format!(" ( {} {} )\n", num, if num == 1 { "match" } else { "matches" }),
+        );
+        for m in pm.matches.iter().take(CONFIRM_PREVIEWS) {
+            let line = src[..m.beg].iter().filter(|&&x| x == 0x0a).count() + 1;
+            let replacement = self.get_replacement(&src[m.beg..m.end], m.keyword)?;
+            self.write_hunk(src, m, line, &replacement, true);
+        }
+        if num > CONFIRM_PREVIEWS {
+            self.console.write(
+                ConsoleTextKind::Other,
+                &format!("  ... and {} more\n", num - CONFIRM_PREVIEWS),
+            );
+        }
+
+        #[cfg(not(windows))]
+        let c_lflag = crate::util::get_c_lflag();
+
+        let getch = Getch::new();
+        loop {
+            match self.ask(&getch, "Replace all in this file? [Y]es/[n]o/[a]ll files/[q]uit: ")? {
+                'Y' | 'y' | ' ' | '\r' | '\n' => return Ok(true),
+                'N' | 'n' => return Ok(false),
+                'A' | 'a' => {
+                    self.all_replace = true;
+                    return Ok(true);
+                }
+                'Q' | 'q' => {
+                    self.pending.clear();
+                    #[cfg(not(windows))]
+                    crate::util::set_c_lflag(c_lflag);
+                    exit(0, &mut self.console);
                 }
-                tmpfile.flush()?;
+                _ => (),
             }
+        }
+    }
+
+    // `+` is the change accepted, and `-` is rejected
+    fn write_hunk(&mut self, src: &[u8], m: &Match, line: usize, replacement: &[u8], accepted: bool) {
+        let mark = if accepted { "+" } else { "-" };
+        let header = format!("  {} {}: ", mark, line);
+        self.console.write(ConsoleTextKind::Other, &header);
+        self.console.write_diff_line(src, m, replacement, false, header.len());
+        self.console.write(
+            ConsoleTextKind::Other,
+            &format!("{}-> ", " ".repeat(header.len().saturating_sub(3))),
+        );
+        self.console.write_diff_line(src, m, replacement, true, header.len());
+    }
 
-            let real_path = fs::canonicalize(&pm.path)?;
+    fn ask(&mut self, getch: &Getch, prompt: &str) -> Result<char, Error> {
+        self.console.write(ConsoleTextKind::Other, prompt);
+        self.console.flush();
+        let key = char::from(getch.getch()?);
+        if key != '\n' {
+            self.console.write(ConsoleTextKind::Other, &format!("{}\n", key));
+        } else {
+            self.console.write(ConsoleTextKind::Other, "\n");
+        }
+        Ok(key)
+    }
 
-            let metadata = fs::metadata(&real_path)?;
+    // Encoding of the file by `encoding_map`, which is also used to display the file instead of `display_encoding`
+    fn file_encoding(&mut self, path: &Path) -> Option<String> {
+        let encoding = self
+            .encoding_map
+            .as_ref()
+            .and_then(|x| x.lookup(path))
+            .map(String::from);
+        self.console.display_encoding = encoding.clone().or_else(|| self.display_encoding.clone());
+        encoding
+    }
 
-            let time = if self.preserve_time {
-                let mtime = FileTime::from_last_modification_time(&metadata);
-                let atime = FileTime::from_last_access_time(&metadata);
-                Some((mtime, atime))
+    fn allow_writable(&mut self, path: &Path) -> Result<bool, Error> {
+        if self.force_writable || self.all_writable {
+            return Ok(true);
+        }
+        if !self.is_interactive {
+            self.errors.push(format!(
+                "Error: file is read-only ( use --force-writable to override ) @ {:?}\n",
+                path
+            ));
+            self.failed = true;
+            return Ok(false);
+        }
+
+        let getch = Getch::new();
+        self.console.write(
+            ConsoleTextKind::Other,
+            &format!("{:?} is read-only. Override? [y]es/[n]o/[a]ll: ", path),
+        );
+        self.console.flush();
+        let key = char::from(getch.getch()?);
+        self.console.write(ConsoleTextKind::Other, &format!("{}\n", key));
+        match key {
+            'Y' | 'y' => Ok(true),
+            'A' | 'a' => {
+                self.all_writable = true;
+                Ok(true)
+            }
+            _ => {
+                self.trace.log(1, || format!("Skipped: {:?} ( read-only file )", path));
+                self.infos.push(format!("Skipped: {:?} ( read-only file )\n", path));
+                Ok(false)
+            }
+        }
+    }
+
+    fn open_editor(&mut self, pm: &PathMatch, line: usize, offset: usize) -> Result<PathMatch, Error> {
+        let editor = self
+            .editor
+            .clone()
+            .or_else(|| env::var("EDITOR").ok())
+            .unwrap_or_else(|| String::from("vi"));
+        let mut args = editor.split_whitespace();
+        let cmd = args.next().unwrap_or("vi");
+        let path = long_path(&pm.path);
+        let org = fs::read(&path)?;
+        Command::new(cmd)
+            .args(args)
+            .arg(format!("+{}", line))
+            .arg(&pm.path)
+            .status()?;
+
+        // The file may be modified by the editor, so the whole file is searched and selected again as the first time.
+        // The matches decided before the opened match are not asked again unless the editor modified the file before it.
+        let src = fs::read(&path)?;
+        let matches = match self.matcher {
+            Some(ref mut x) => x.search_contents(&pm.path, &src),
+            None => self.search_keywords(&src),
+        };
+        let found = PathMatch {
+            path: pm.path.clone(),
+            matches,
+            content: None,
+            binary: pm.binary,
+            stamp: None,
+        };
+        let found = self.select_region(found);
+        self.occurrence_count = self.occurrence_file_beg;
+        let mut found = self.select_occurrence(found);
+
+        let unchanged = org.iter().zip(&src).take_while(|(x, y)| x == y).count();
+        let beg = cmp::min(offset, unchanged);
+        found.matches.retain(|m| m.beg >= beg);
+        Ok(found)
+    }
+
+    // The keyword and `map` are searched after the BOM without the filters of the matcher
+    fn search_keywords(&self, src: &[u8]) -> Vec<Match> {
+        let bom = bom_len(src);
+        let mut matches = Vec::new();
+        for i in 0..self.map.len() + 1 {
+            let keyword = self.pair(i).0;
+            let found = if self.regex {
+                let mut matcher = RegexMatcher::new();
+                matcher.case_insensitive = self.ignore_case;
+                matcher.search(&src[bom..], keyword)
+            } else if self.ignore_case {
+                CaseFoldMatcher::new(MemmemMatcher::new()).search(&src[bom..], keyword)
             } else {
-                None
+                MemmemMatcher::new().search(&src[bom..], keyword)
             };
+            matches.extend(found.into_iter().map(|m| Match { keyword: i, ..m }));
+        }
+        self.overlap
+            .resolve(matches)
+            .into_iter()
+            .map(|m| Match {
+                beg: m.beg + bom,
+                end: m.end + bom,
+                sub_match: m.sub_match,
+                keyword: m.keyword,
+            })
+            .collect()
+    }
 
-            fs::set_permissions(tmpfile.path(), metadata.permissions())?;
-            tmpfile.persist(&real_path)?;
+    // Discrepancies are reported as errors, but the file already persisted is not rolled back
+    fn verify(&mut self, path: &Path, real_path: &Path, expected: &Verification) {
+        self.verified.0 += 1;
+        let src = match fs::read(long_path(real_path)) {
+            Ok(x) => x,
+            Err(e) => {
+                self.verified.1 += 1;
+                self.errors.push(format!(
+                    "Error: {} ( not verified ) @ {:?}\n",
+                    decode_error(e.kind()),
+                    path
+                ));
+                return;
+            }
+        };
 
-            if let Some((mtime, atime)) = time {
-                filetime::set_file_times(&real_path, atime, mtime)?;
+        let mut discrepancies = Vec::new();
+        if src.len() != expected.len {
+            discrepancies.push(format!("{} bytes instead of {}", src.len(), expected.len));
+        }
+        let missing = expected
+            .replaced
+            .iter()
+            .filter(|(pos, x)| src.get(*pos..pos + x.len()) != Some(&x[..]))
+            .count();
+        if missing != 0 {
+            discrepancies.push(format!(
+                "{} of {} replacements not found",
+                missing,
+                expected.replaced.len()
+            ));
+        }
+        if let Some(remaining) = expected.remaining {
+            let found = self.search_keywords(&src).len();
+            if found > remaining {
+                discrepancies.push(format!("{} keywords left", found - remaining));
             }
+        }
+        if !discrepancies.is_empty() {
+            self.verified.1 += 1;
+            self.errors.push(format!(
+                "Error: verification failed ( {} ) @ {:?}\n",
+                discrepancies.join(", "),
+                path
+            ));
+        }
+    }
 
-            Ok(())
-        });
-        match result {
-            Ok(_) => (),
-            Err(e) => self.console.write(
-                ConsoleTextKind::Error,
-                &format!("Error: {} @ {:?}\n", decode_error(e.kind()), pm.path),
-            ),
+    fn persist(&mut self, mut x: PendingReplace) -> Result<(), Error> {
+        let mut file = match x.file.take() {
+            Some(x) => x,
+            None => {
+                let file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(x.in_place)
+                    .open(long_path(&x.path))?;
+                if !try_lock_exclusive(&file)? {
+                    return Err(Error::other("locked by another process"));
+                }
+                file
+            }
+        };
+        if x.in_place {
+            // Rewrite the original file to keep its inode ( hard links, bind mounts, inotify watchers )
+            let mut src = File::open(&x.tmpfile)?;
+            let file = &mut file;
+            tmpfile::critical(|| -> Result<(), Error> {
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(&mut src, file)?;
+                file.sync_all()
+            })?;
+        } else {
+            x.tmpfile.persist(&x.real_path)?;
+        }
+        let (path, how) = (&x.real_path, if x.in_place { "in place" } else { "renamed" });
+        self.trace.log(2, || format!("Persisted: {:?} ( {} )", path, how));
+
+        if let Some((mtime, atime)) = x.time {
+            filetime::set_file_times(&x.real_path, atime, mtime)?;
+        }
+
+        // Verified before --post-cmd, which may rewrite the file ( e.g. formatters )
+        if let Some(ref verification) = x.verification {
+            self.verify(&x.path, &x.real_path, verification);
+        }
+
+        if let Some(ref log_path) = self.log_path {
+            let mut f = fs::OpenOptions::new().create(true).append(true).open(log_path)?;
+            for record in &x.log {
+                writeln!(f, "{}", record)?;
+            }
+        }
+
+        if let Some(ref mut checkpoint) = self.checkpoint {
+            checkpoint.record(&CheckpointEntry {
+                path: x.path.clone(),
+                accepted: x.counts.0,
+                rejected: x.counts.1,
+            })?;
+        }
+
+        // The file is already replaced, so the failure is reported but not rolled back
+        let replaced = x.counts.0 > 0;
+        if let Some(cmd) = self.post_cmd.as_ref().filter(|_| replaced) {
+            if let Err(e) = PipelineReplacer::run_post_cmd(cmd, &x.path) {
+                self.errors
+                    .push(format!("Error: {} @ {:?}\n", error_message(&e), x.path));
+            }
+        }
+        Ok(())
+    }
+
+    fn run_post_cmd(cmd: &str, path: &Path) -> Result<(), Error> {
+        if cmd.trim().is_empty() {
+            return Ok(());
+        }
+        let mut args: Vec<OsString> = Vec::new();
+        let mut placed = false;
+        for arg in cmd.split_whitespace() {
+            if arg.contains("{}") {
+                let path = path.to_string_lossy();
+                args.push(OsString::from(arg.replace("{}", &path)));
+                placed = true;
+            } else {
+                args.push(OsString::from(arg));
+            }
+        }
+        if !placed {
+            args.push(OsString::from(path));
+        }
+        let status = Command::new(&args[0]).args(&args[1..]).stdin(Stdio::null()).status()?;
+        if !status.success() {
+            return Err(Error::other(format!("post command {:?} failed with {}", cmd, status)));
+        }
+        Ok(())
+    }
+
+    // Nothing is persisted if any file failed or has been changed since read by another process.
+    // The files are persisted one by one, so the files before a failure are already replaced and reported.
+    fn persist_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        if self.failed {
+            self.errors.push(String::from(
+                "Aborted: no file is replaced because of the errors above\n",
+            ));
+            return;
+        }
+        for x in &pending {
+            if !self.force && x.is_changed() {
+                self.errors.push(format!(
+                    "Aborted: no file is replaced because {:?} is changed during replacement\n",
+                    x.path
+                ));
+                return;
+            }
+        }
+        let num = pending.len();
+        let mut replaced = Vec::new();
+        for x in pending {
+            let path = x.path.clone();
+            let changed = !self.force && x.is_changed();
+            let ret = if changed {
+                Err(Error::other("file is changed during replacement"))
+            } else {
+                self.persist(x)
+            };
+            if let Err(e) = ret {
+                self.failed = true;
+                self.errors.push(format!("Error: {} @ {:?}\n", error_message(&e), path));
+                self.errors.push(format!(
+                    "Aborted: {} files are not replaced, but {} files are already replaced below\n",
+                    num - replaced.len(),
+                    replaced.len()
+                ));
+                for x in &replaced {
+                    self.errors.push(format!("Replaced: {:?}\n", x));
+                }
+                return;
+            }
+            replaced.push(path);
+        }
+    }
+
+    // Decisions are logged after the file is replaced, so undone or unapplied decisions don't appear
+    fn log_records(pm: &PathMatch, src: &[u8], decisions: &[(Vec<u8>, bool)]) -> Vec<String> {
+        let time = time::OffsetDateTime::now_utc().format(time::Format::Rfc3339);
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        let mut line = 1;
+        let mut line_beg = 0;
+        for (m, (replacement, accepted)) in pm.matches.iter().zip(decisions) {
+            while pos < m.beg {
+                if src[pos] == 0x0a {
+                    line += 1;
+                    line_beg = pos + 1;
+                }
+                pos += 1;
+            }
+            let record = json!({
+                "time": time,
+                "path": pm.path.to_string_lossy(),
+                "line": line,
+                "column": m.beg - line_beg + 1,
+                "old": String::from_utf8_lossy(&src[m.beg..m.end]),
+                "new": String::from_utf8_lossy(replacement),
+                "accepted": accepted,
+            });
+            ret.push(record.to_string());
+        }
+        ret
+    }
+
+    fn add_report(&mut self, pm: &PathMatch) -> Result<(), Error> {
+        let src = fs::read(&pm.path)?;
+        let is_crlf = self.respect_line_endings && PipelineReplacer::is_crlf(&src);
+        let editorconfig = self.load_editorconfig(&pm.path);
+
+        let mut entries = Vec::new();
+        for m in &pm.matches {
+            let mut replacement = self.get_replacement(&src[m.beg..m.end], m.keyword)?;
+            if is_crlf {
+                replacement = PipelineReplacer::to_crlf(&replacement);
+            }
+            if let Some(ref x) = editorconfig {
+                replacement = x.apply(&replacement, m.end == src.len());
+            }
+
+            let beg = src[..m.beg]
+                .iter()
+                .rposition(|&x| x == 0x0a)
+                .map(|x| x + 1)
+                .unwrap_or(0);
+            let beg = cmp::max(beg, bom_len(&src));
+            let end = src[m.end..]
+                .iter()
+                .position(|&x| x == 0x0a || x == 0x0d)
+                .map(|x| x + m.end)
+                .unwrap_or_else(|| src.len());
+
+            entries.push(ReportEntry {
+                line: src[..m.beg].iter().filter(|&&x| x == 0x0a).count() + 1,
+                prefix: String::from_utf8_lossy(&src[beg..m.beg]).into_owned(),
+                before: String::from_utf8_lossy(&src[m.beg..m.end]).into_owned(),
+                after: String::from_utf8_lossy(&replacement).into_owned(),
+                suffix: String::from_utf8_lossy(&src[m.end..end]).into_owned(),
+            });
+        }
+        self.report.push(&pm.path, entries);
+        Ok(())
+    }
+
+    fn edit_replacement(&mut self, getch: &Getch, init: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::from(init);
+        loop {
+            self.console.write_with_clear(ConsoleTextKind::Other, "Replacement: ");
+            self.console
+                .write(ConsoleTextKind::MatchText, &String::from_utf8_lossy(&buf));
+            self.console.flush();
+
+            match getch.getch()? {
+                // EOF or Enter
+                0 | b'\r' | b'\n' => break,
+                // Backspace / Delete
+                0x08 | 0x7f => {
+                    // pop a whole UTF-8 character
+                    while let Some(x) = buf.pop() {
+                        if x & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                }
+                // Ctrl-U
+                0x15 => buf.clear(),
+                x if x < 0x20 => (),
+                x => buf.push(x),
+            }
         }
+        self.console.write(ConsoleTextKind::Other, "\n");
+        Ok(buf)
     }
 
-    fn get_regex_replacement(&self, org: &[u8]) -> Vec<u8> {
+    fn load_editorconfig(&self, path: &Path) -> Option<EditorConfig> {
+        if self.editorconfig {
+            Some(EditorConfig::load(path))
+        } else {
+            None
+        }
+    }
+
+    fn is_crlf(src: &[u8]) -> bool {
+        let mut crlf = 0;
+        let mut lf = 0;
+        for i in 0..src.len() {
+            if src[i] == 0x0a {
+                if i > 0 && src[i - 1] == 0x0d {
+                    crlf += 1;
+                } else {
+                    lf += 1;
+                }
+            }
+        }
+        crlf > lf
+    }
+
+    fn to_crlf(src: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(src.len());
+        for i in 0..src.len() {
+            if src[i] == 0x0a && (i == 0 || src[i - 1] != 0x0d) {
+                ret.push(0x0d);
+            }
+            ret.push(src[i]);
+        }
+        ret
+    }
+
+    // Matches of `map` are indexed from 1 by pipeline_matcher
+    fn pair(&self, index: usize) -> (&[u8], &[u8]) {
+        match index {
+            0 => (&self.keyword[..], &self.replacement[..]),
+            _ => (&self.map[index - 1].0[..], &self.map[index - 1].1[..]),
+        }
+    }
+
+    fn get_replacement(&self, org: &[u8], index: usize) -> Result<Vec<u8>, Error> {
+        if self.exec_replace {
+            self.exec_replacement(org, index)
+        } else if self.regex {
+            self.get_regex_replacement(org, index)
+        } else {
+            Ok(self.pair(index).1.to_vec())
+        }
+    }
+
+    fn get_regex(&self, index: usize) -> Regex {
         // All unwrap() is safe bacause keyword is already matched in pipeline_matcher
-        let org = str::from_utf8(org).unwrap();
-        let keyword = str::from_utf8(&self.keyword).unwrap();
+        let keyword = str::from_utf8(self.pair(index).0).unwrap();
         // `\b` may not be matched with `org` because `\b` is affected by the charactor before and
         // after `org`.
         let keyword = keyword.trim_start_matches("\\b").trim_end_matches("\\b");
-        let replacement = str::from_utf8(&self.replacement).unwrap();
-        let regex = Regex::new(&keyword).unwrap();
-        let captures = regex.captures(&org).unwrap();
+        RegexBuilder::new(&keyword)
+            .case_insensitive(self.ignore_case)
+            .build()
+            .unwrap()
+    }
 
-        let mut dst = String::new();
-        captures.expand(&replacement, &mut dst);
+    // The replaced contents are passed as stdin and AMBR_FILE, and the original path as AMBR_PATH
+    fn validate_file(cmd: &str, path: &Path, tmpfile: &NamedTempFile) -> Result<(), Error> {
+        let output = shell(cmd)
+            .env("AMBR_PATH", path)
+            .env("AMBR_FILE", tmpfile.path())
+            .stdin(tmpfile.reopen()?)
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let msg = match stderr.lines().find(|x| !x.trim().is_empty()) {
+            Some(x) => format!("validation {:?} failed with {}: {}", cmd, output.status, x.trim()),
+            None => format!("validation {:?} failed with {}", cmd, output.status),
+        };
+        Err(Error::other(msg))
+    }
+
+    // The matched text is passed as stdin and AMBR_MATCH, and the captures of --regex as AMBR_1 or AMBR_<name>
+    fn exec_replacement(&self, org: &[u8], index: usize) -> Result<Vec<u8>, Error> {
+        let cmd = String::from_utf8_lossy(self.pair(index).1).into_owned();
+        let mut command = shell(&cmd);
+        command.env("AMBR_MATCH", String::from_utf8_lossy(org).as_ref());
+        if self.regex {
+            let regex = self.get_regex(index);
+            let captures = regex.captures(org).unwrap();
+            for (i, name) in regex.capture_names().enumerate().skip(1) {
+                let val = captures
+                    .get(i)
+                    .map_or(String::new(), |x| String::from_utf8_lossy(x.as_bytes()).into_owned());
+                command.env(format!("AMBR_{}", i), &val);
+                if let Some(name) = name {
+                    command.env(format!("AMBR_{}", name), &val);
+                }
+            }
+        }
+
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        // stdin is written by another thread not to be blocked by the command writing stdout
+        let mut stdin = child.stdin.take().unwrap();
+        let input = org.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+        if !output.status.success() {
+            return Err(Error::other(format!("command {:?} failed with {}", cmd, output.status)));
+        }
 
-        dst.into_bytes()
+        let mut ret = output.stdout;
+        if ret.ends_with(b"\n") {
+            ret.pop();
+            if ret.ends_with(b"\r") {
+                ret.pop();
+            }
+        }
+        Ok(ret)
+    }
+
+    fn get_regex_replacement(&self, org: &[u8], index: usize) -> Result<Vec<u8>, Error> {
+        let regex = self.get_regex(index);
+        let captures = regex.captures(org).unwrap();
+
+        // Replacement may contain arbitrary bytes given by --escapes
+        let mut dst = Vec::new();
+        expand_replacement(&captures, self.pair(index).1, &mut dst)?;
+
+        Ok(dst)
+    }
+}
+
+// Failures of --exec-replace, --validate and --post-cmd carry their own messages
+fn error_message(e: &Error) -> String {
+    match e.get_ref() {
+        Some(x) if e.kind() == ErrorKind::Other => x.to_string(),
+        _ => String::from(decode_error(e.kind())),
+    }
+}
+
+// `${1:snake}`, `${name:camel}` and `${1+1}` are expanded by `eval_capture`, and the rest is expanded by regex
+fn expand_replacement(captures: &Captures, replacement: &[u8], dst: &mut Vec<u8>) -> Result<(), Error> {
+    let mut lit = 0;
+    let mut i = 0;
+    while i < replacement.len() {
+        if replacement[i..].starts_with(b"$$") {
+            i += 2;
+            continue;
+        }
+        if !replacement[i..].starts_with(b"${") {
+            i += 1;
+            continue;
+        }
+        let end = match replacement[i..].iter().position(|x| *x == b'}') {
+            Some(x) => i + x,
+            None => break,
+        };
+        let converted = match str::from_utf8(&replacement[i + 2..end]) {
+            Ok(x) => eval_capture(captures, x)?,
+            Err(_) => None,
+        };
+        if let Some(x) = converted {
+            captures.expand(&replacement[lit..i], dst);
+            dst.extend_from_slice(x.as_bytes());
+            lit = end + 1;
+        }
+        i = end + 1;
     }
+    captures.expand(&replacement[lit..], dst);
+    Ok(())
 }
 
-impl Pipeline<PathMatch, ()> for PipelineReplacer {
-    fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<PathMatch>>, tx: Sender<PipelineInfo<()>>) {
+// `name:style` converts the case, and `name+1*2` calculates from left to right keeping the zero padding of the capture
+// ( a failed calculation is an error instead of being left to regex, which would expand it to an empty string )
+fn eval_capture(captures: &Captures, expr: &str) -> Result<Option<String>, Error> {
+    let capture = |name: &str| {
+        let cap = match name.parse::<usize>() {
+            Ok(x) => captures.get(x),
+            Err(_) => captures.name(name),
+        };
+        cap.map_or(String::new(), |x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+    };
+    let is_op = |c: char| "+-*/%".contains(c);
+
+    let failed = |reason: String| Error::other(format!("{} in `${{{}}}`", reason, expr));
+
+    if let Some(pos) = expr.find(':') {
+        return Ok(convert_case(&capture(&expr[..pos]), &expr[pos + 1..]));
+    }
+
+    let pos = match expr.find(is_op) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let cap = capture(expr[..pos].trim());
+    let mut val: i64 = cap
+        .parse()
+        .map_err(|_| failed(format!("capture {:?} is not a number", cap)))?;
+    let mut rest = &expr[pos..];
+    while let Some(op) = rest.chars().next() {
+        let len = rest[1..].find(is_op).map_or(rest.len(), |x| x + 1);
+        let operand = rest[1..len].trim();
+        let num: i64 = operand
+            .parse()
+            .map_err(|_| failed(format!("operand {:?} is not a number", operand)))?;
+        let ret = match op {
+            '+' => val.checked_add(num),
+            '-' => val.checked_sub(num),
+            '*' => val.checked_mul(num),
+            '/' => val.checked_div(num),
+            _ => val.checked_rem(num),
+        };
+        val = match ret {
+            Some(x) => x,
+            None if num == 0 && (op == '/' || op == '%') => return Err(failed(String::from("division by zero"))),
+            None => return Err(failed(String::from("arithmetic overflow"))),
+        };
+        rest = &rest[len..];
+    }
+    if cap.len() > 1 && cap.starts_with('0') {
+        Ok(Some(format!("{:01$}", val, cap.len())))
+    } else {
+        Ok(Some(val.to_string()))
+    }
+}
+
+impl Pipeline<PathMatch, usize> for PipelineReplacer {
+    fn setup(&mut self, id: usize, rx: Receiver<PipelineInfo<PathMatch>>, tx: Sender<PipelineInfo<usize>>) {
         self.infos = Vec::new();
         self.errors = Vec::new();
+        tmpfile::install_handler();
+        let mut rx = BatchReceiver::new(rx);
         let mut seq_beg_arrived = false;
 
         loop {
             match rx.recv() {
                 Ok(PipelineInfo::SeqDat(x, pm)) => {
+                    self.items += 1;
                     watch_time!(self.time_bsy, {
+                        let num = pm.matches.len();
                         self.replace_match(pm);
-                        let _ = tx.send(PipelineInfo::SeqDat(x, ()));
+                        let _ = tx.send(PipelineInfo::SeqDat(x, num));
                     });
                 }
 
@@ -255,6 +1667,30 @@ impl Pipeline<PathMatch, ()> for PipelineReplacer {
                 }
 
                 Ok(PipelineInfo::SeqEnd(x)) => {
+                    if self.review {
+                        self.review_all();
+                    }
+                    if self.atomic {
+                        self.persist_pending();
+                    }
+                    self.console.flush();
+
+                    if self.verify {
+                        self.infos.push(format!(
+                            "Verified: {} files ( {} with discrepancies )\n",
+                            self.verified.0, self.verified.1
+                        ));
+                    }
+
+                    if let Some(ref path) = self.report_path {
+                        match self.report.write(path) {
+                            Ok(_) => self.infos.push(format!("Report: {:?}\n", path)),
+                            Err(e) => self
+                                .errors
+                                .push(format!("Error: {} @ {:?}\n", decode_error(e.kind()), path)),
+                        }
+                    }
+
                     for i in &self.infos {
                         let _ = tx.send(PipelineInfo::MsgInfo(id, i.clone()));
                     }
@@ -262,6 +1698,7 @@ impl Pipeline<PathMatch, ()> for PipelineReplacer {
                         let _ = tx.send(PipelineInfo::MsgErr(id, e.clone()));
                     }
 
+                    let _ = tx.send(PipelineInfo::MsgCount(id, self.items, self.bytes));
                     let _ = tx.send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
                     let _ = tx.send(PipelineInfo::SeqEnd(x));
                     break;
@@ -276,6 +1713,10 @@ impl Pipeline<PathMatch, ()> for PipelineReplacer {
                 Ok(PipelineInfo::MsgTime(i, t0, t1)) => {
                     let _ = tx.send(PipelineInfo::MsgTime(i, t0, t1));
                 }
+                Ok(PipelineInfo::MsgCount(i, n, b)) => {
+                    let _ = tx.send(PipelineInfo::MsgCount(i, n, b));
+                }
+                Ok(PipelineInfo::SeqBat(_)) => unreachable!(),
                 Err(_) => break,
             }
         }
@@ -285,3 +1726,692 @@ impl Pipeline<PathMatch, ()> for PipelineReplacer {
 // ---------------------------------------------------------------------------------------------------------------------
 // Test
 // ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_replacer_line_endings() {
+        assert!(PipelineReplacer::is_crlf(b"aaa\r\nbbb\r\nccc\n"));
+        assert!(!PipelineReplacer::is_crlf(b"aaa\r\nbbb\nccc\n"));
+        assert!(!PipelineReplacer::is_crlf(b"aaa"));
+        assert_eq!(PipelineReplacer::to_crlf(b"a\nb\r\nc\n"), b"a\r\nb\r\nc\r\n");
+        assert_eq!(PipelineReplacer::to_crlf(b"\n"), b"\r\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_log() {
+        let src = b"aaa\nx aaa\n";
+        let pm = PathMatch {
+            path: PathBuf::from("file"),
+            matches: vec![
+                Match {
+                    beg: 0,
+                    end: 3,
+                    sub_match: Vec::new(),
+                    keyword: 0,
+                },
+                Match {
+                    beg: 6,
+                    end: 9,
+                    sub_match: Vec::new(),
+                    keyword: 0,
+                },
+            ],
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+        let decisions = vec![(b"bbb".to_vec(), true), (b"ccc".to_vec(), false)];
+        let log = PipelineReplacer::log_records(&pm, src, &decisions);
+        assert_eq!(log.len(), 2);
+
+        let record: serde_json::Value = serde_json::from_str(&log[1]).unwrap();
+        assert_eq!(record["path"], "file");
+        assert_eq!(record["line"], 2);
+        assert_eq!(record["column"], 3);
+        assert_eq!(record["old"], "aaa");
+        assert_eq!(record["new"], "ccc");
+        assert_eq!(record["accepted"], false);
+    }
+
+    #[test]
+    fn pipeline_replacer_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = vec![dir.path().join("a"), dir.path().join("b")];
+        let path_match = |path: &PathBuf| PathMatch {
+            path: path.clone(),
+            matches: vec![Match {
+                beg: 0,
+                end: 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            }],
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        for p in &paths {
+            fs::write(p, "aaa\n").unwrap();
+        }
+        let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+        replacer.is_interactive = false;
+        replacer.atomic = true;
+        for p in &paths {
+            replacer.replace_match(path_match(p));
+        }
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"aaa\n");
+        fs::write(&paths[1], "aaa\nccc\n").unwrap();
+        replacer.persist_pending();
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"aaa\n");
+        assert_eq!(replacer.errors.len(), 1);
+
+        for p in &paths {
+            fs::write(p, "aaa\n").unwrap();
+        }
+        let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+        replacer.is_interactive = false;
+        replacer.atomic = true;
+        for p in &paths {
+            replacer.replace_match(path_match(p));
+        }
+        replacer.persist_pending();
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"bbb\n");
+        assert_eq!(fs::read(&paths[1]).unwrap(), b"bbb\n");
+        assert!(replacer.errors.is_empty());
+
+        // The files after a failure are not persisted, and the files before it are reported
+        let paths = vec![dir.path().join("a"), dir.path().join("b"), dir.path().join("c")];
+        for p in &paths {
+            fs::write(p, "aaa\n").unwrap();
+        }
+        let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+        replacer.is_interactive = false;
+        replacer.atomic = true;
+        for p in &paths {
+            replacer.replace_match(path_match(p));
+        }
+        let lock = File::open(&paths[1]).unwrap();
+        assert!(try_lock_exclusive(&lock).unwrap());
+        replacer.persist_pending();
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"bbb\n");
+        assert_eq!(fs::read(&paths[1]).unwrap(), b"aaa\n");
+        assert_eq!(fs::read(&paths[2]).unwrap(), b"aaa\n");
+        assert_eq!(
+            replacer.errors,
+            vec![
+                format!("Error: locked by another process @ {:?}\n", paths[1]),
+                String::from("Aborted: 2 files are not replaced, but 1 files are already replaced below\n"),
+                format!("Replaced: {:?}\n", paths[0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pipeline_replacer_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, "aaa\n").unwrap();
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: vec![Match {
+                beg: 0,
+                end: 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            }],
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let lock = File::open(&path).unwrap();
+        assert!(try_lock_exclusive(&lock).unwrap());
+
+        let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+        replacer.is_interactive = false;
+        replacer.replace_match(pm.clone());
+        assert_eq!(fs::read(&path).unwrap(), b"aaa\n");
+        assert_eq!(replacer.infos.len(), 1);
+
+        drop(lock);
+        replacer.replace_match(pm);
+        assert_eq!(fs::read(&path).unwrap(), b"bbb\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, "aaa\n").unwrap();
+        let attr = fs::metadata(&path).unwrap();
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: vec![Match {
+                beg: 0,
+                end: 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            }],
+            content: None,
+            binary: false,
+            stamp: Some((attr.len(), attr.modified().ok())),
+        };
+
+        fs::write(&path, "aaaa\n").unwrap();
+
+        let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+        replacer.is_interactive = false;
+        replacer.replace_match(pm.clone());
+        assert_eq!(fs::read(&path).unwrap(), b"aaaa\n");
+        assert_eq!(replacer.errors.len(), 1);
+
+        replacer.force = true;
+        replacer.replace_match(pm);
+        assert_eq!(fs::read(&path).unwrap(), b"bbba\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_readonly() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, "aaa\n").unwrap();
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: vec![Match {
+                beg: 0,
+                end: 3,
+                sub_match: Vec::new(),
+                keyword: 0,
+            }],
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+        replacer.is_interactive = false;
+        replacer.replace_match(pm.clone());
+        assert_eq!(fs::read(&path).unwrap(), b"aaa\n");
+        assert_eq!(replacer.errors.len(), 1);
+
+        replacer.force_writable = true;
+        replacer.in_place = true;
+        replacer.replace_match(pm);
+        assert_eq!(fs::read(&path).unwrap(), b"bbb\n");
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pipeline_replacer_access() {
+        use std::ffi::CString;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let root = unsafe { libc::geteuid() } == 0;
+        let dir = tempfile::tempdir().unwrap();
+        let replace = |path: &Path| {
+            let mut replacer = PipelineReplacer::new(b"aaa", b"bbb", false);
+            replacer.is_interactive = false;
+            replacer.replace_match(PathMatch {
+                path: path.to_path_buf(),
+                matches: MemmemMatcher::new().search(&fs::read(path).unwrap(), b"aaa"),
+                content: None,
+                binary: false,
+                stamp: None,
+            });
+            assert!(replacer.errors.is_empty(), "{:?}", replacer.errors);
+            assert_eq!(fs::read(path).unwrap(), b"bbb\n");
+        };
+
+        // Files in a setgid directory get the group of the directory
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        if root {
+            std::os::unix::fs::chown(&shared, None, Some(12345)).unwrap();
+        }
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o2775)).unwrap();
+        let path = shared.join("a");
+        fs::write(&path, "aaa\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o664)).unwrap();
+        replace(&path);
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.gid(), fs::metadata(&shared).unwrap().gid());
+        assert_eq!(metadata.mode() & 0o7777, 0o664);
+
+        // The group changed after creation is kept
+        if root {
+            let path = dir.path().join("b");
+            fs::write(&path, "aaa\n").unwrap();
+            std::os::unix::fs::chown(&path, None, Some(12345)).unwrap();
+            replace(&path);
+            assert_eq!(fs::metadata(&path).unwrap().gid(), 12345);
+        }
+
+        // The ACL of the file is used instead of the default ACL of the directory
+        let acl = |user: u32| {
+            let mut ret = 2u32.to_le_bytes().to_vec();
+            for (tag, perm, id) in [
+                (1u16, 6u16, u32::MAX),
+                (2, 6, user),
+                (4, 4, u32::MAX),
+                (0x10, 6, u32::MAX),
+                (0x20, 4, u32::MAX),
+            ] {
+                ret.extend(tag.to_le_bytes());
+                ret.extend(perm.to_le_bytes());
+                ret.extend(id.to_le_bytes());
+            }
+            ret
+        };
+        let name = CString::new("system.posix_acl_access").unwrap();
+        let default = CString::new("system.posix_acl_default").unwrap();
+        let path = dir.path().join("c");
+        fs::write(&path, "aaa\n").unwrap();
+        if crate::util::set_xattrs(&path, &[(name.clone(), acl(65534))]).is_err() {
+            // POSIX ACLs are not supported by the filesystem
+            return;
+        }
+        crate::util::set_xattrs(dir.path(), &[(default, acl(65533))]).unwrap();
+        replace(&path);
+        let xattrs = crate::util::get_xattrs(&path).unwrap();
+        assert!(xattrs.contains(&(name, acl(65534))));
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o664);
+    }
+
+    #[test]
+    fn pipeline_replacer_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, b"\xffa\x00\n").unwrap();
+        let keyword = br"(?-u:\xff)(a)";
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), keyword),
+            content: None,
+            binary: true,
+            stamp: None,
+        };
+        assert_eq!(pm.matches.len(), 1);
+
+        let mut replacer = PipelineReplacer::new(keyword, b"${1}\xfe", true);
+        replacer.is_interactive = false;
+        replacer.replace_match(pm);
+        assert_eq!(fs::read(&path).unwrap(), b"a\xfe\x00\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_case() {
+        let regex = Regex::new(r"(?P<name>\w+)Service").unwrap();
+        let captures = regex.captures(b"HTTPClientService").unwrap();
+        let expand = |x: &[u8]| {
+            let mut dst = Vec::new();
+            expand_replacement(&captures, x, &mut dst).unwrap();
+            String::from_utf8(dst).unwrap()
+        };
+        assert_eq!(expand(b"${1:snake}_service"), "http_client_service");
+        assert_eq!(
+            expand(b"${name:kebab}/${1:camel}/$1"),
+            "http-client/httpClient/HTTPClient"
+        );
+        assert_eq!(expand(b"$${1:pascal}"), "${1:pascal}");
+    }
+
+    #[test]
+    fn pipeline_replacer_arithmetic() {
+        let regex = Regex::new(r"test_(\d+)_(?P<port>\d+)").unwrap();
+        let captures = regex.captures(b"test_007_8080").unwrap();
+        let expand = |x: &[u8]| {
+            let mut dst = Vec::new();
+            expand_replacement(&captures, x, &mut dst).unwrap();
+            String::from_utf8(dst).unwrap()
+        };
+        assert_eq!(expand(b"test_${1+1}_${port * 2 - 1}"), "test_008_16159");
+        assert_eq!(expand(b"${port/3%7}"), "5");
+
+        let error = |x: &[u8]| {
+            let mut dst = Vec::new();
+            expand_replacement(&captures, x, &mut dst).unwrap_err().to_string()
+        };
+        assert_eq!(error(b"${1/0}"), "division by zero in `${1/0}`");
+        assert_eq!(
+            error(b"${port*9223372036854775807}"),
+            "arithmetic overflow in `${port*9223372036854775807}`"
+        );
+        assert_eq!(error(b"${2+x}"), "operand \"x\" is not a number in `${2+x}`");
+
+        let captures = regex.captures(b"test_1_2").unwrap();
+        let mut dst = Vec::new();
+        assert!(expand_replacement(&captures, b"${3+1}", &mut dst).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, b"test_1 test_2\n").unwrap();
+        let keyword = br"test_(\d+)";
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), keyword),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+        let mut replacer = PipelineReplacer::new(keyword, b"test_${1/0}", true);
+        replacer.is_interactive = false;
+        replacer.replace_match(pm);
+        assert_eq!(fs::read(&path).unwrap(), b"test_1 test_2\n");
+        assert_eq!(replacer.errors.len(), 1);
+        assert!(replacer.errors[0].starts_with("Error: division by zero in `${1/0}` @ "));
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn pipeline_replacer_exec() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, b"id: abc-12\n").unwrap();
+        let keyword = br"(\w+)-(?P<num>\d+)";
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: RegexMatcher::new().search(&fs::read(&path).unwrap(), keyword),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let mut replacer = PipelineReplacer::new(keyword, b"tr a-z A-Z; echo \"_$AMBR_num\"", true);
+        replacer.is_interactive = false;
+        replacer.exec_replace = true;
+        replacer.replace_match(pm.clone());
+        assert_eq!(fs::read(&path).unwrap(), b"id: ABC-12_12\n");
+        assert!(replacer.errors.is_empty());
+
+        fs::write(&path, b"id: abc-12\n").unwrap();
+        let mut replacer = PipelineReplacer::new(keyword, b"exit 1", true);
+        replacer.is_interactive = false;
+        replacer.exec_replace = true;
+        replacer.replace_match(pm);
+        assert_eq!(replacer.errors.len(), 1);
+        assert_eq!(fs::read(&path).unwrap(), b"id: abc-12\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.json");
+        fs::write(&path, b"{\"key\": 1}\n").unwrap();
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: MemmemMatcher::new().search(&fs::read(&path).unwrap(), b"1"),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let mut replacer = PipelineReplacer::new(b"1", b"1,", false);
+        replacer.is_interactive = false;
+        replacer.validate = Some(String::from("grep -q '1}' && test -f \"$AMBR_FILE\""));
+        replacer.replace_match(pm.clone());
+        assert_eq!(replacer.errors.len(), 1);
+        assert!(replacer.errors[0].contains("not replaced"));
+        assert_eq!(fs::read(&path).unwrap(), b"{\"key\": 1}\n");
+
+        let mut replacer = PipelineReplacer::new(b"1", b"2", false);
+        replacer.is_interactive = false;
+        replacer.validate = Some(String::from("grep -q '2}' && test -f \"$AMBR_FILE\""));
+        replacer.replace_match(pm);
+        assert!(replacer.errors.is_empty());
+        assert_eq!(fs::read(&path).unwrap(), b"{\"key\": 2}\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let pm = |src: &[u8], keyword: &[u8]| {
+            fs::write(&path, src).unwrap();
+            PathMatch {
+                path: path.clone(),
+                matches: MemmemMatcher::new().search(src, keyword),
+                content: None,
+                binary: false,
+                stamp: None,
+            }
+        };
+
+        let mut replacer = PipelineReplacer::new(b"foo", b"bar", false);
+        replacer.is_interactive = false;
+        replacer.verify = true;
+        replacer.replace_match(pm(b"foo foo\n", b"foo"));
+        assert!(replacer.errors.is_empty());
+        assert_eq!(replacer.verified, (1, 0));
+
+        // Removing `ab` from `aabb` leaves a new `ab`
+        let mut replacer = PipelineReplacer::new(b"ab", b"", false);
+        replacer.is_interactive = false;
+        replacer.verify = true;
+        replacer.replace_match(pm(b"aabb", b"ab"));
+        assert_eq!(fs::read(&path).unwrap(), b"ab");
+        assert_eq!(replacer.verified, (1, 1));
+        assert!(replacer.errors[0].contains("( 1 keywords left )"));
+
+        // Keywords in the replacements are not expected to be removed
+        let mut replacer = PipelineReplacer::new(b"foo", b"foo()", false);
+        replacer.is_interactive = false;
+        replacer.verify = true;
+        replacer.replace_match(pm(b"foo\n", b"foo"));
+        assert!(replacer.errors.is_empty());
+
+        fs::write(&path, b"baz bar\n").unwrap();
+        let expected = Verification {
+            len: 9,
+            replaced: vec![(0, b"bar".to_vec()), (4, b"bar".to_vec())],
+            remaining: Some(0),
+        };
+        let mut replacer = PipelineReplacer::new(b"baz", b"bar", false);
+        replacer.verify(&path, &path, &expected);
+        assert!(replacer.errors[0].contains("( 8 bytes instead of 9, 1 of 2 replacements not found, 1 keywords left )"));
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn pipeline_replacer_open_editor() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: Vec::new(),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        // Only the matches of the configured matcher after the opened match are asked again
+        fs::write(&path, b"abc abcd abc\nabc\n").unwrap();
+        let mut replacer = PipelineReplacer::new(b"abc", b"x", false);
+        replacer.editor = Some(String::from("true"));
+        let mut matcher = PipelineMatcher::new(Box::new(MemmemMatcher::new()) as Box<dyn Matcher + Send>, b"abc");
+        matcher.identifier = true;
+        replacer.matcher = Some(matcher);
+        let ret = replacer.open_editor(&pm, 1, 9).unwrap();
+        assert_eq!(ret.matches.iter().map(|m| m.beg).collect::<Vec<_>>(), vec![9, 13]);
+
+        // The matches are asked again from the first change by the editor
+        let editor = dir.path().join("editor");
+        let mut replacer = PipelineReplacer::new(b"abc", b"x", false);
+        replacer.editor = Some(editor.to_string_lossy().into_owned());
+        fs::write(&editor, "#!/bin/sh\nprintf 'abc abc\\nabc abc\\n' > \"$2\"\n").unwrap();
+        fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(&path, b"abc abc\nabc\n").unwrap();
+        let ret = replacer.open_editor(&pm, 1, 4).unwrap();
+        assert_eq!(ret.matches.iter().map(|m| m.beg).collect::<Vec<_>>(), vec![4, 8, 12]);
+        fs::write(&editor, "#!/bin/sh\nprintf 'xyz abc\\nabc\\n' > \"$2\"\n").unwrap();
+        fs::write(&path, b"abc abc\nabc\n").unwrap();
+        let ret = replacer.open_editor(&pm, 1, 4).unwrap();
+        assert_eq!(ret.matches.iter().map(|m| m.beg).collect::<Vec<_>>(), vec![4, 8]);
+
+        // The matches are selected by --occurrence again
+        let mut replacer = PipelineReplacer::new(b"abc", b"x", false);
+        replacer.editor = Some(String::from("true"));
+        replacer.occurrence = Some("2".parse().unwrap());
+        fs::write(&path, b"abc abc abc\n").unwrap();
+        let src = fs::read(&path).unwrap();
+        let pm = replacer.select_occurrence(PathMatch {
+            matches: MemmemMatcher::new().search(&src, b"abc"),
+            ..pm
+        });
+        assert_eq!(pm.matches.iter().map(|m| m.beg).collect::<Vec<_>>(), vec![4]);
+        let ret = replacer.open_editor(&pm, 1, 0).unwrap();
+        assert_eq!(ret.matches.iter().map(|m| m.beg).collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[test]
+    fn pipeline_replacer_post_cmd() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let copied = dir.path().join("b");
+        fs::write(&path, b"foo\n").unwrap();
+        let pm = |path: &Path| PathMatch {
+            path: path.to_path_buf(),
+            matches: MemmemMatcher::new().search(&fs::read(path).unwrap(), b"foo"),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let mut replacer = PipelineReplacer::new(b"foo", b"bar", false);
+        replacer.is_interactive = false;
+        replacer.post_cmd = Some(format!("cp {{}} {}", copied.display()));
+        replacer.replace_match(pm(&path));
+        assert!(replacer.errors.is_empty());
+        assert_eq!(fs::read(&copied).unwrap(), b"bar\n");
+
+        replacer.post_cmd = Some(String::from("false"));
+        fs::write(&path, b"foo\n").unwrap();
+        replacer.replace_match(pm(&path));
+        assert_eq!(replacer.errors.len(), 1);
+        assert_eq!(fs::read(&path).unwrap(), b"bar\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, b"foo bar foobar\n").unwrap();
+        let src = fs::read(&path).unwrap();
+        let mut matches = Vec::new();
+        for (i, keyword) in [&b"foo"[..], b"bar", b"foobar"].iter().enumerate() {
+            let found = MemmemMatcher::new().search(&src, keyword);
+            matches.extend(found.into_iter().map(|m| Match { keyword: i, ..m }));
+        }
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: Overlap::First.resolve(matches.clone()),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        let mut replacer = PipelineReplacer::new(b"foo", b"bar", false);
+        replacer.is_interactive = false;
+        replacer.map = vec![(b"bar".to_vec(), b"foo".to_vec()), (b"foobar".to_vec(), b"x".to_vec())];
+        replacer.replace_match(pm);
+        assert_eq!(fs::read(&path).unwrap(), b"bar foo x\n");
+
+        fs::write(&path, &src).unwrap();
+        let pm = PathMatch {
+            path: path.clone(),
+            matches: Overlap::ReportAll.resolve(matches),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+        replacer.replace_match(pm);
+        assert_eq!(replacer.errors.len(), 1);
+        assert_eq!(fs::read(&path).unwrap(), src);
+    }
+
+    #[test]
+    fn pipeline_replacer_region() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let path_match = |keyword: &[u8]| PathMatch {
+            path: path.clone(),
+            matches: MemmemMatcher::new().search(&fs::read(&path).unwrap(), keyword),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        fs::write(&path, "aa\naa\naa\naa\n").unwrap();
+        let mut replacer = PipelineReplacer::new(b"aa", b"bb", false);
+        replacer.is_interactive = false;
+        replacer.lines = Some(IndexRange { beg: 2, end: Some(3) });
+        replacer.replace_match(path_match(b"aa"));
+        assert_eq!(fs::read(&path).unwrap(), b"aa\nbb\nbb\naa\n");
+
+        // A match across the end of the region is not replaced
+        fs::write(&path, "aa\naa\naa\n").unwrap();
+        replacer.replace_match(path_match(b"aa\naa"));
+        assert_eq!(fs::read(&path).unwrap(), b"aa\naa\naa\n");
+
+        let mut replacer = PipelineReplacer::new(b"aa", b"bb", false);
+        replacer.is_interactive = false;
+        replacer.byte_range = Some(IndexRange { beg: 3, end: Some(6) });
+        replacer.replace_match(path_match(b"aa"));
+        assert_eq!(fs::read(&path).unwrap(), b"aa\nbb\naa\n");
+    }
+
+    #[test]
+    fn pipeline_replacer_occurrence() {
+        assert_eq!("2".parse::<Occurrence>(), Ok(Occurrence { beg: 2, end: Some(2) }));
+        assert_eq!("3..7".parse::<Occurrence>(), Ok(Occurrence { beg: 3, end: Some(7) }));
+        assert_eq!("3..".parse::<Occurrence>(), Ok(Occurrence { beg: 3, end: None }));
+        assert_eq!("..7".parse::<Occurrence>(), Ok(Occurrence { beg: 1, end: Some(7) }));
+        assert!("0".parse::<Occurrence>().is_err());
+        assert!("7..3".parse::<Occurrence>().is_err());
+        assert!("a".parse::<Occurrence>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths = vec![dir.path().join("a"), dir.path().join("b")];
+        let path_match = |path: &PathBuf| PathMatch {
+            path: path.clone(),
+            matches: MemmemMatcher::new().search(&fs::read(path).unwrap(), b"aa"),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+
+        for p in &paths {
+            fs::write(p, "aa aa aa\n").unwrap();
+        }
+        let mut replacer = PipelineReplacer::new(b"aa", b"bb", false);
+        replacer.is_interactive = false;
+        replacer.occurrence = Some(Occurrence { beg: 2, end: None });
+        for p in &paths {
+            replacer.replace_match(path_match(p));
+        }
+        for p in &paths {
+            assert_eq!(fs::read(p).unwrap(), b"aa bb bb\n");
+        }
+
+        for p in &paths {
+            fs::write(p, "aa aa aa\n").unwrap();
+        }
+        let mut replacer = PipelineReplacer::new(b"aa", b"bb", false);
+        replacer.is_interactive = false;
+        replacer.occurrence = Some(Occurrence { beg: 3, end: Some(4) });
+        replacer.global_occurrence = true;
+        for p in &paths {
+            replacer.replace_match(path_match(p));
+        }
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"aa aa bb\n");
+        assert_eq!(fs::read(&paths[1]).unwrap(), b"bb aa aa\n");
+    }
+}
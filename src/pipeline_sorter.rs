@@ -1,6 +1,7 @@
-use crate::pipeline::{PipelineInfo, PipelineJoin};
+use crate::pipeline::{BatchSender, PipelineInfo, PipelineJoin};
 use crate::pipeline_matcher::PathMatch;
-use crossbeam::channel::{Receiver, Sender};
+use crate::trace::Tracer;
+use crossbeam::channel::{Receiver, Select, Sender, TryRecvError};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
@@ -8,15 +9,26 @@ use std::time::{Duration, Instant};
 // PipelineSorter
 // ---------------------------------------------------------------------------------------------------------------------
 
+/// Stage joining the matchers, which passes each result as soon as all the results numbered before it are passed.
+///
+/// Results are received from any matcher ready, so a slow file delays only the results after it.
+/// `seq_no` is the low watermark, the number of the next result to be passed, and results beyond it are held
+/// in `map` until it arrives. With `through`, results are passed as received without holding.
 pub struct PipelineSorter {
     pub infos: Vec<String>,
     pub errors: Vec<String>,
     pub through: bool,
+    /// Number of sorted files sent at once to the next stage ( 1: one by one )
+    pub batch_size: usize,
+    /// Log the results held until the results before them arrive at the level 2
+    pub trace: Tracer,
     map: HashMap<usize, PathMatch>,
     seq_no: usize,
     join_num: usize,
     time_beg: Instant,
     time_bsy: Duration,
+    items: usize,
+    bytes: u64,
 }
 
 impl PipelineSorter {
@@ -25,11 +37,38 @@ impl PipelineSorter {
             infos: Vec::new(),
             errors: Vec::new(),
             through: false,
+            batch_size: 1,
+            trace: Tracer::default(),
             map: HashMap::new(),
             seq_no: 0,
             join_num: num,
             time_beg: Instant::now(),
             time_bsy: Duration::new(0, 0),
+            items: 0,
+            bytes: 0,
+        }
+    }
+
+    fn push(&mut self, x: usize, p: PathMatch, tx: &mut BatchSender<PathMatch>) {
+        self.items += 1;
+        if self.through {
+            let _ = tx.send(PipelineInfo::SeqDat(x, p));
+            return;
+        }
+        if x != self.seq_no {
+            self.trace.log(2, || {
+                format!("Held: {:?} ( #{} waits for #{} )", p.path, x, self.seq_no)
+            });
+            self.map.insert(x, p);
+            return;
+        }
+
+        // The contiguous results from the low watermark are passed at once
+        let _ = tx.send(PipelineInfo::SeqDat(x, p));
+        self.seq_no += 1;
+        while let Some(p) = self.map.remove(&self.seq_no) {
+            let _ = tx.send(PipelineInfo::SeqDat(self.seq_no, p));
+            self.seq_no += 1;
         }
     }
 }
@@ -38,71 +77,91 @@ impl PipelineJoin<PathMatch, PathMatch> for PipelineSorter {
     fn setup(&mut self, id: usize, rx: Vec<Receiver<PipelineInfo<PathMatch>>>, tx: Sender<PipelineInfo<PathMatch>>) {
         self.infos = Vec::new();
         self.errors = Vec::new();
+        let mut tx = BatchSender::new(tx, self.batch_size);
         let mut seq_beg_arrived = false;
         let mut end_num = 0;
 
+        let mut select = Select::new();
+        for rx in &rx {
+            select.recv(rx);
+        }
+
         loop {
-            for rx in &rx {
-                match rx.recv() {
-                    Ok(PipelineInfo::SeqDat(x, p)) => {
-                        watch_time!(self.time_bsy, {
-                            if self.through {
-                                let _ = tx.send(PipelineInfo::SeqDat(x, p));
-                            } else {
-                                self.map.insert(x, p);
-                                loop {
-                                    if !self.map.contains_key(&self.seq_no) {
-                                        break;
-                                    }
-                                    {
-                                        let ret = self.map.get(&self.seq_no).unwrap();
-                                        let _ = tx.send(PipelineInfo::SeqDat(self.seq_no, ret.clone()));
-                                    }
-                                    let _ = self.map.remove(&self.seq_no);
-                                    self.seq_no += 1;
-                                }
-                            }
-                        });
-                    }
+            // Results already sorted are sent before waiting for the slow matchers
+            let i = match select.try_ready() {
+                Ok(i) => i,
+                Err(_) => {
+                    let _ = tx.flush();
+                    select.ready()
+                }
+            };
 
-                    Ok(PipelineInfo::SeqBeg(x)) => {
-                        if !seq_beg_arrived {
-                            self.seq_no = x;
-                            self.time_beg = Instant::now();
-                            let _ = tx.send(PipelineInfo::SeqBeg(x));
-                            seq_beg_arrived = true;
-                        }
-                    }
+            match rx[i].try_recv() {
+                Ok(PipelineInfo::SeqDat(x, p)) => {
+                    watch_time!(self.time_bsy, {
+                        self.push(x, p, &mut tx);
+                    });
+                }
 
-                    Ok(PipelineInfo::SeqEnd(x)) => {
-                        end_num += 1;
-                        if end_num != self.join_num {
-                            continue;
+                Ok(PipelineInfo::SeqBat(items)) => {
+                    watch_time!(self.time_bsy, {
+                        for (x, p) in items {
+                            self.push(x, p, &mut tx);
                         }
+                    });
+                }
 
-                        for i in &self.infos {
-                            let _ = tx.send(PipelineInfo::MsgInfo(id, i.clone()));
-                        }
-                        for e in &self.errors {
-                            let _ = tx.send(PipelineInfo::MsgErr(id, e.clone()));
-                        }
+                Ok(PipelineInfo::SeqBeg(x)) => {
+                    if !seq_beg_arrived {
+                        self.seq_no = x;
+                        self.time_beg = Instant::now();
+                        let _ = tx.send(PipelineInfo::SeqBeg(x));
+                        seq_beg_arrived = true;
+                    }
+                }
 
-                        let _ = tx.send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
-                        let _ = tx.send(PipelineInfo::SeqEnd(x));
-                        break;
+                Ok(PipelineInfo::SeqEnd(x)) => {
+                    select.remove(i);
+                    end_num += 1;
+                    if end_num != self.join_num {
+                        continue;
                     }
 
-                    Ok(PipelineInfo::MsgInfo(i, e)) => {
-                        let _ = tx.send(PipelineInfo::MsgInfo(i, e));
+                    for i in &self.infos {
+                        let _ = tx.send(PipelineInfo::MsgInfo(id, i.clone()));
                     }
-                    Ok(PipelineInfo::MsgErr(i, e)) => {
-                        let _ = tx.send(PipelineInfo::MsgErr(i, e));
+                    for e in &self.errors {
+                        let _ = tx.send(PipelineInfo::MsgErr(id, e.clone()));
                     }
-                    Ok(PipelineInfo::MsgTime(i, t0, t1)) => {
-                        let _ = tx.send(PipelineInfo::MsgTime(i, t0, t1));
+
+                    let _ = tx.send(PipelineInfo::MsgCount(id, self.items, self.bytes));
+                    let _ = tx.send(PipelineInfo::MsgTime(id, self.time_bsy, self.time_beg.elapsed()));
+                    let _ = tx.send(PipelineInfo::SeqEnd(x));
+                    break;
+                }
+
+                Ok(PipelineInfo::MsgInfo(i, e)) => {
+                    let _ = tx.send(PipelineInfo::MsgInfo(i, e));
+                }
+                Ok(PipelineInfo::MsgErr(i, e)) => {
+                    let _ = tx.send(PipelineInfo::MsgErr(i, e));
+                }
+                Ok(PipelineInfo::MsgTime(i, t0, t1)) => {
+                    let _ = tx.send(PipelineInfo::MsgTime(i, t0, t1));
+                }
+                Ok(PipelineInfo::MsgCount(i, n, b)) => {
+                    let _ = tx.send(PipelineInfo::MsgCount(i, n, b));
+                }
+                // The matcher ended without `SeqEnd`
+                Err(TryRecvError::Disconnected) => {
+                    select.remove(i);
+                    end_num += 1;
+                    if end_num == self.join_num {
+                        break;
                     }
-                    Err(_) => break,
                 }
+                // Taken by the other operation of the same channel
+                Err(TryRecvError::Empty) => (),
             }
         }
     }
@@ -137,6 +196,9 @@ mod tests {
             PathMatch {
                 path: PathBuf::from("./"),
                 matches: Vec::new(),
+                content: None,
+                binary: false,
+                stamp: None,
             },
         ));
         let _ = in_tx.send(PipelineInfo::SeqDat(
@@ -144,6 +206,9 @@ mod tests {
             PathMatch {
                 path: PathBuf::from("./"),
                 matches: Vec::new(),
+                content: None,
+                binary: false,
+                stamp: None,
             },
         ));
         let _ = in_tx.send(PipelineInfo::SeqDat(
@@ -151,6 +216,9 @@ mod tests {
             PathMatch {
                 path: PathBuf::from("./"),
                 matches: Vec::new(),
+                content: None,
+                binary: false,
+                stamp: None,
             },
         ));
         let _ = in_tx.send(PipelineInfo::SeqEnd(3));
@@ -168,4 +236,47 @@ mod tests {
         assert_eq!(ret[1], 1);
         assert_eq!(ret[2], 2);
     }
+
+    #[test]
+    fn pipeline_sorter_slow_matcher() {
+        let mut sorter = PipelineSorter::new(2);
+
+        let (in_tx0, in_rx0) = unbounded();
+        let (in_tx1, in_rx1) = unbounded();
+        let (out_tx, out_rx) = unbounded();
+        thread::spawn(move || {
+            sorter.setup(0, vec![in_rx0, in_rx1], out_tx);
+        });
+
+        let pm = PathMatch {
+            path: PathBuf::from("./"),
+            matches: Vec::new(),
+            content: None,
+            binary: false,
+            stamp: None,
+        };
+        let _ = in_tx0.send(PipelineInfo::SeqBeg(0));
+        let _ = in_tx1.send(PipelineInfo::SeqBeg(0));
+        let _ = in_tx1.send(PipelineInfo::SeqDat(0, pm.clone()));
+        let _ = in_tx1.send(PipelineInfo::SeqDat(2, pm.clone()));
+
+        // The result of the fast matcher is passed while the slow matcher is searching the 2nd file
+        let timeout = Duration::from_secs(10);
+        assert!(matches!(out_rx.recv_timeout(timeout), Ok(PipelineInfo::SeqBeg(0))));
+        assert!(matches!(out_rx.recv_timeout(timeout), Ok(PipelineInfo::SeqDat(0, _))));
+
+        let _ = in_tx0.send(PipelineInfo::SeqDat(1, pm));
+        let _ = in_tx0.send(PipelineInfo::SeqEnd(3));
+        let _ = in_tx1.send(PipelineInfo::SeqEnd(3));
+
+        let mut ret = Vec::new();
+        loop {
+            match out_rx.recv_timeout(timeout).unwrap() {
+                PipelineInfo::SeqDat(x, _) => ret.push(x),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+        assert_eq!(ret, vec![1, 2]);
+    }
 }
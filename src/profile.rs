@@ -0,0 +1,126 @@
+use crate::console::Diagnostic;
+use crate::util::as_secsf64;
+use serde_json::json;
+use std::time::Duration;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Profile
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct StageProfile {
+    pub id: usize,
+    pub stage: &'static str,
+    pub thread: usize,
+    pub busy: Duration,
+    pub wall: Duration,
+    pub items: usize,
+    pub bytes: u64,
+}
+
+/// Breakdown of the pipeline built from `MsgTime` and `MsgCount` of each stage
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub stages: Vec<StageProfile>,
+    /// Files skipped or failed with the reasons, which are included only if set
+    pub skipped: Option<Vec<Diagnostic>>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            stages: Vec::new(),
+            skipped: None,
+        }
+    }
+
+    pub fn add_stage(&mut self, id: usize, stage: &'static str, thread: usize) {
+        self.stages.push(StageProfile {
+            id,
+            stage,
+            thread,
+            busy: Duration::new(0, 0),
+            wall: Duration::new(0, 0),
+            items: 0,
+            bytes: 0,
+        });
+    }
+
+    pub fn set_time(&mut self, id: usize, busy: Duration, wall: Duration) {
+        if let Some(x) = self.stages.iter_mut().find(|x| x.id == id) {
+            x.busy = busy;
+            x.wall = wall;
+        }
+    }
+
+    pub fn set_count(&mut self, id: usize, items: usize, bytes: u64) {
+        if let Some(x) = self.stages.iter_mut().find(|x| x.id == id) {
+            x.items = items;
+            x.bytes = bytes;
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let stages: Vec<_> = self
+            .stages
+            .iter()
+            .map(|x| {
+                json!({
+                    "stage": x.stage,
+                    "thread": x.thread,
+                    "busy_sec": as_secsf64(x.busy),
+                    "wall_sec": as_secsf64(x.wall),
+                    "items": x.items,
+                    "bytes": x.bytes,
+                })
+            })
+            .collect();
+        let mut ret = json!({"version": env!("CARGO_PKG_VERSION"), "stages": stages});
+        if let Some(ref skipped) = self.skipped {
+            let skipped: Vec<_> = skipped
+                .iter()
+                .map(|x| json!({"path": x.path, "kind": x.kind}))
+                .collect();
+            ret["skipped"] = json!(skipped);
+        }
+        ret.to_string()
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn profile() {
+        let mut profile = Profile::new();
+        profile.add_stage(0, "find", 0);
+        profile.add_stage(3, "match", 0);
+        profile.add_stage(4, "match", 1);
+        profile.set_time(4, Duration::from_millis(500), Duration::from_secs(2));
+        profile.set_count(4, 10, 1024);
+        profile.set_count(9, 1, 1);
+
+        let json: Value = serde_json::from_str(&profile.to_json()).unwrap();
+        let stages = json["stages"].as_array().unwrap();
+        assert_eq!(stages.len(), 3);
+        assert_eq!(stages[2]["stage"], "match");
+        assert_eq!(stages[2]["thread"], 1);
+        assert_eq!(stages[2]["busy_sec"], 0.5);
+        assert_eq!(stages[2]["wall_sec"], 2.0);
+        assert_eq!(stages[2]["items"], 10);
+        assert_eq!(stages[2]["bytes"], 1024);
+        assert_eq!(stages[0]["items"], 0);
+        assert!(json.get("skipped").is_none());
+
+        profile.skipped = Some(vec![Diagnostic::parse("Skipped: \"a.bin\" ( binary file )")]);
+        let json: Value = serde_json::from_str(&profile.to_json()).unwrap();
+        assert_eq!(json["skipped"][0]["path"], "a.bin");
+        assert_eq!(json["skipped"][0]["kind"], "binary_file");
+    }
+}
@@ -0,0 +1,212 @@
+// ---------------------------------------------------------------------------------------------------------------------
+// Query
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Boolean combination of keywords like `(foo OR bar) AND NOT baz`
+///
+/// Keywords are separated by whitespace or parentheses, or quoted by `"` to contain them.
+/// Operators are `AND`, `OR` and `NOT` in upper case, and `NOT` binds tighter than `AND`, which binds tighter than `OR`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(usize),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Query {
+    /// Returns the query and the keywords indexed by `Query::Term`
+    pub fn parse(src: &str) -> Result<(Query, Vec<String>), String> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            keywords: Vec::new(),
+        };
+        let query = parser.parse_or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok((query, parser.keywords)),
+            Some(Token::Close) => Err(String::from("unmatched ')'")),
+            Some(_) => Err(String::from("operator is required between keywords")),
+        }
+    }
+
+    /// Evaluates the query by whether each keyword is found
+    pub fn eval(&self, found: &[bool]) -> bool {
+        match self {
+            Query::Term(i) => found[*i],
+            Query::And(x, y) => x.eval(found) && y.eval(found),
+            Query::Or(x, y) => x.eval(found) || y.eval(found),
+            Query::Not(x) => !x.eval(found),
+        }
+    }
+
+    /// Returns whether each keyword appears without negation, whose matches are worth printing
+    pub fn positives(&self, num: usize) -> Vec<bool> {
+        let mut ret = vec![false; num];
+        self.collect_positives(false, &mut ret);
+        ret
+    }
+
+    fn collect_positives(&self, negated: bool, ret: &mut Vec<bool>) {
+        match self {
+            Query::Term(i) => ret[*i] |= !negated,
+            Query::And(x, y) | Query::Or(x, y) => {
+                x.collect_positives(negated, ret);
+                y.collect_positives(negated, ret);
+            }
+            Query::Not(x) => x.collect_positives(!negated, ret),
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut ret = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '(' => {
+                chars.next();
+                ret.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                ret.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(x) => word.push(x),
+                            None => return Err(String::from("unterminated '\"'")),
+                        },
+                        Some(x) => word.push(x),
+                        None => return Err(String::from("unterminated '\"'")),
+                    }
+                }
+                ret.push(Token::Word(word));
+            }
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&x) = chars.peek() {
+                    if x.is_whitespace() || x == '(' || x == ')' || x == '"' {
+                        break;
+                    }
+                    word.push(x);
+                    chars.next();
+                }
+                ret.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(ret)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    keywords: Vec<String>,
+}
+
+impl Parser {
+    fn parse_or(&mut self) -> Result<Query, String> {
+        let mut ret = self.parse_and()?;
+        while self.tokens.get(self.pos) == Some(&Token::Or) {
+            self.pos += 1;
+            ret = Query::Or(Box::new(ret), Box::new(self.parse_and()?));
+        }
+        Ok(ret)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, String> {
+        let mut ret = self.parse_not()?;
+        while self.tokens.get(self.pos) == Some(&Token::And) {
+            self.pos += 1;
+            ret = Query::And(Box::new(ret), Box::new(self.parse_not()?));
+        }
+        Ok(ret)
+    }
+
+    fn parse_not(&mut self) -> Result<Query, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.parse_not()?))),
+            Some(Token::Open) => {
+                let ret = self.parse_or()?;
+                if self.tokens.get(self.pos) != Some(&Token::Close) {
+                    return Err(String::from("unmatched '('"));
+                }
+                self.pos += 1;
+                Ok(ret)
+            }
+            Some(Token::Word(x)) => {
+                if x.is_empty() {
+                    return Err(String::from("keyword is empty"));
+                }
+                let i = match self.keywords.iter().position(|k| *k == x) {
+                    Some(i) => i,
+                    None => {
+                        self.keywords.push(x);
+                        self.keywords.len() - 1
+                    }
+                };
+                Ok(Query::Term(i))
+            }
+            Some(_) => Err(String::from("keyword is required before operator")),
+            None => Err(String::from("keyword is required at the end")),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query() {
+        let (query, keywords) = Query::parse("(foo OR \"b(a)r\") AND NOT baz").unwrap();
+        assert_eq!(keywords, vec!["foo", "b(a)r", "baz"]);
+        assert!(query.eval(&[true, false, false]));
+        assert!(query.eval(&[false, true, false]));
+        assert!(!query.eval(&[true, true, true]));
+        assert!(!query.eval(&[false, false, false]));
+        assert_eq!(query.positives(3), vec![true, true, false]);
+
+        let (query, keywords) = Query::parse("a OR b AND NOT a").unwrap();
+        assert_eq!(keywords, vec!["a", "b"]);
+        assert!(query.eval(&[true, true]));
+        assert!(query.eval(&[false, true]));
+        assert_eq!(query.positives(2), vec![true, true]);
+
+        assert!(Query::parse("(foo OR bar").is_err());
+        assert!(Query::parse("foo bar").is_err());
+        assert!(Query::parse("foo AND").is_err());
+        assert!(Query::parse("NOT \"foo").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::console::{Console, ConsoleTextKind};
+use crate::util::{decode_error, exit, EXIT_ERROR};
+use getch::Getch;
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Renamer
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct Renamer {
+    pub is_color: bool,
+    pub is_interactive: bool,
+    pub dry_run: bool,
+    console: Console,
+    all_rename: bool,
+    keyword: String,
+    replacement: String,
+    regex: Option<Regex>,
+}
+
+impl Renamer {
+    pub fn new(keyword: &str, replacement: &str, regex: bool) -> Result<Self, regex::Error> {
+        let regex = if regex { Some(Regex::new(keyword)?) } else { None };
+        Ok(Renamer {
+            is_color: true,
+            is_interactive: true,
+            dry_run: false,
+            console: Console::new(),
+            all_rename: false,
+            keyword: String::from(keyword),
+            replacement: String::from(replacement),
+            regex,
+        })
+    }
+
+    fn new_name(&self, name: &str) -> Option<String> {
+        let ret = match self.regex {
+            Some(ref regex) if regex.is_match(name) => regex.replace_all(name, self.replacement.as_str()).into_owned(),
+            None if !self.keyword.is_empty() && name.contains(&self.keyword) => {
+                name.replace(&self.keyword, &self.replacement)
+            }
+            _ => return None,
+        };
+        if ret != name && !ret.is_empty() {
+            Some(ret)
+        } else {
+            None
+        }
+    }
+
+    /// Returns renames of `files` and the directories between `bases` and them. Deeper paths come first.
+    pub fn plan(&self, bases: &[PathBuf], files: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+        let mut paths = Vec::new();
+        let mut visited = HashSet::new();
+        for file in files {
+            let base = bases
+                .iter()
+                .filter(|x| file.starts_with(x))
+                .max_by_key(|x| x.components().count());
+            let mut path = file.as_path();
+            loop {
+                if visited.insert(path.to_path_buf()) {
+                    paths.push(path.to_path_buf());
+                }
+                match (path.parent(), base) {
+                    (Some(parent), Some(base)) if parent.starts_with(base) && parent != base.as_path() => path = parent,
+                    _ => break,
+                }
+            }
+        }
+        paths.sort_by_key(|x| std::cmp::Reverse(x.components().count()));
+
+        let mut ret = Vec::new();
+        for path in paths {
+            let name = match path.file_name() {
+                Some(x) => x.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            if let Some(new_name) = self.new_name(&name) {
+                let new_path = path.with_file_name(new_name);
+                ret.push((path, new_path));
+            }
+        }
+        ret
+    }
+
+    pub fn rename(&mut self, plan: &[(PathBuf, PathBuf)]) -> usize {
+        self.console.is_color = self.is_color;
+
+        let mut targets = HashSet::new();
+        let mut renamed = 0;
+        for (src, dst) in plan {
+            if dst.exists() || !targets.insert(dst.clone()) {
+                self.console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: rename target already exists @ {:?} -> {:?}\n", src, dst),
+                );
+                continue;
+            }
+
+            self.print_rename(src, dst);
+
+            if self.dry_run {
+                continue;
+            }
+
+            if self.is_interactive & !self.all_rename {
+                match self.ask() {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        targets.remove(dst);
+                        continue;
+                    }
+                    Err(e) => {
+                        self.console.write(ConsoleTextKind::Error, &format!("Error: {}\n", e));
+                        exit(EXIT_ERROR, &mut self.console);
+                    }
+                }
+            }
+
+            match fs::rename(src, dst) {
+                Ok(_) => renamed += 1,
+                Err(e) => self.console.write(
+                    ConsoleTextKind::Error,
+                    &format!("Error: {} @ {:?}\n", decode_error(e.kind()), src),
+                ),
+            }
+        }
+        renamed
+    }
+
+    /// Returns whether any file failed to be renamed
+    pub fn has_error(&self) -> bool {
+        self.console.has_error()
+    }
+
+    fn print_rename(&mut self, src: &Path, dst: &Path) {
+        self.console.write(ConsoleTextKind::Filename, &src.to_string_lossy());
+        self.console.write(ConsoleTextKind::Other, " -> ");
+        if let Some(parent) = dst.parent() {
+            let parent = parent.to_string_lossy();
+            if !parent.is_empty() {
+                self.console
+                    .write(ConsoleTextKind::Filename, &format!("{}/", parent.trim_end_matches('/')));
+            }
+        }
+        let name = dst
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.console.write(ConsoleTextKind::MatchText, &format!("{}\n", name));
+    }
+
+    fn ask(&mut self) -> Result<bool, std::io::Error> {
+        let getch = Getch::new();
+        loop {
+            self.console
+                .write(ConsoleTextKind::Other, "Rename file? [Y]es/[n]o/[a]ll/[q]uit: ");
+            self.console.flush();
+            let key = char::from(getch.getch()?);
+            if key != '\n' {
+                self.console.write(ConsoleTextKind::Other, &format!("{}\n", key));
+            } else {
+                self.console.write(ConsoleTextKind::Other, "\n");
+            }
+            match key {
+                'Y' | 'y' | ' ' | '\r' | '\n' => return Ok(true),
+                'N' | 'n' => return Ok(false),
+                'A' | 'a' => {
+                    self.all_rename = true;
+                    return Ok(true);
+                }
+                'Q' | 'q' => exit(0, &mut self.console),
+                _ => continue,
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renamer() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().to_path_buf();
+        fs::create_dir_all(base.join("foo_dir/sub")).unwrap();
+        fs::write(base.join("foo_dir/sub/foo.txt"), "a").unwrap();
+        fs::write(base.join("foo_dir/bar.txt"), "a").unwrap();
+        fs::write(base.join("foo_dir/baz.txt"), "a").unwrap();
+        fs::write(base.join("foo_dir/qux.txt"), "a").unwrap();
+
+        let files = vec![
+            base.join("foo_dir/sub/foo.txt"),
+            base.join("foo_dir/bar.txt"),
+            base.join("foo_dir/baz.txt"),
+            base.join("foo_dir/qux.txt"),
+        ];
+
+        let mut renamer = Renamer::new("(ba[rz])", "qux", true).unwrap();
+        renamer.is_interactive = false;
+        let plan = renamer.plan(std::slice::from_ref(&base), &files);
+        assert_eq!(plan.len(), 2);
+        // both collide with qux.txt
+        assert_eq!(renamer.rename(&plan), 0);
+
+        let mut renamer = Renamer::new("foo", "hoge", false).unwrap();
+        renamer.is_interactive = false;
+        let plan = renamer.plan(std::slice::from_ref(&base), &files);
+        assert_eq!(
+            plan,
+            vec![
+                (base.join("foo_dir/sub/foo.txt"), base.join("foo_dir/sub/hoge.txt")),
+                (base.join("foo_dir"), base.join("hoge_dir")),
+            ]
+        );
+
+        renamer.dry_run = true;
+        assert_eq!(renamer.rename(&plan), 0);
+        assert!(base.join("foo_dir/sub/foo.txt").exists());
+
+        renamer.dry_run = false;
+        assert_eq!(renamer.rename(&plan), 2);
+        assert!(base.join("hoge_dir/sub/hoge.txt").exists());
+        assert!(!base.join("foo_dir").exists());
+    }
+}
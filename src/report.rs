@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// HtmlReport
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    pub line: usize,
+    pub prefix: String,
+    pub before: String,
+    pub after: String,
+    pub suffix: String,
+}
+
+#[derive(Debug, Default)]
+pub struct HtmlReport {
+    pub files: Vec<(PathBuf, Vec<ReportEntry>)>,
+}
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+h2 { font-size: 1em; font-family: monospace; background: #eee; padding: 0.3em; }
+table { border-collapse: collapse; width: 100%; table-layout: fixed; }
+td { font-family: monospace; white-space: pre-wrap; word-break: break-all; vertical-align: top; padding: 0 0.5em; }
+td.line { width: 4em; text-align: right; color: #888; }
+td.before { background: #fff0f0; }
+td.after { background: #f0fff0; }
+del { background: #fcc; text-decoration: none; }
+ins { background: #cfc; text-decoration: none; }";
+
+impl HtmlReport {
+    pub fn new() -> Self {
+        HtmlReport { files: Vec::new() }
+    }
+
+    pub fn push(&mut self, path: &Path, entries: Vec<ReportEntry>) {
+        if !entries.is_empty() {
+            self.files.push((path.to_path_buf(), entries));
+        }
+    }
+
+    pub fn render<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let num: usize = self.files.iter().map(|(_, x)| x.len()).sum();
+
+        writeln!(w, "<!DOCTYPE html>")?;
+        writeln!(w, "<html>")?;
+        writeln!(w, "<head>")?;
+        writeln!(w, "<meta charset=\"utf-8\">")?;
+        writeln!(w, "<title>amber replace report</title>")?;
+        writeln!(w, "<style>\n{}\n</style>", STYLE)?;
+        writeln!(w, "</head>")?;
+        writeln!(w, "<body>")?;
+        writeln!(w, "<h1>{} changes in {} files</h1>", num, self.files.len())?;
+        for (path, entries) in &self.files {
+            writeln!(w, "<h2>{}</h2>", escape(&path.to_string_lossy()))?;
+            writeln!(w, "<table>")?;
+            for e in entries {
+                writeln!(
+                    w,
+                    "<tr><td class=\"line\">{}</td><td class=\"before\">{}<del>{}</del>{}</td><td class=\"after\">{}<ins>{}</ins>{}</td></tr>",
+                    e.line,
+                    escape(&e.prefix),
+                    escape(&e.before),
+                    escape(&e.suffix),
+                    escape(&e.prefix),
+                    escape(&e.after),
+                    escape(&e.suffix)
+                )?;
+            }
+            writeln!(w, "</table>")?;
+        }
+        writeln!(w, "</body>")?;
+        writeln!(w, "</html>")?;
+        Ok(())
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.render(&mut w)?;
+        w.flush()
+    }
+}
+
+fn escape(src: &str) -> String {
+    let mut ret = String::with_capacity(src.len());
+    for c in src.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_report() {
+        let mut report = HtmlReport::new();
+        report.push(
+            &PathBuf::from("a<b>.rs"),
+            vec![ReportEntry {
+                line: 3,
+                prefix: String::from("let "),
+                before: String::from("x"),
+                after: String::from("y&z"),
+                suffix: String::from(" = 1;"),
+            }],
+        );
+        report.push(&PathBuf::from("empty.rs"), Vec::new());
+
+        let mut buf = Vec::new();
+        report.render(&mut buf).unwrap();
+        let html = String::from_utf8(buf).unwrap();
+
+        assert!(html.contains("<h1>1 changes in 1 files</h1>"));
+        assert!(html.contains("<h2>a&lt;b&gt;.rs</h2>"));
+        assert!(html.contains("let <del>x</del> = 1;"));
+        assert!(html.contains("let <ins>y&amp;z</ins> = 1;"));
+        assert!(!html.contains("empty.rs"));
+    }
+}
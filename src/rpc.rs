@@ -0,0 +1,486 @@
+use crate::matcher::{Match, MemmemMatcher, RegexMatcher};
+use crate::pipeline::{CancelToken, Pipeline, PipelineFork, PipelineInfo, PipelineJoin};
+use crate::pipeline_finder::PipelineFinder;
+use crate::pipeline_matcher::{PathMatch, PipelineMatcher};
+use crate::pipeline_sorter::PipelineSorter;
+use crossbeam::channel::{unbounded, Receiver};
+use regex::Regex;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Request
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    keyword: String,
+    #[serde(default)]
+    paths: Vec<String>,
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    replacement: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelParams {
+    id: Value,
+}
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// RpcServer
+// ---------------------------------------------------------------------------------------------------------------------
+
+// Requests are newline-delimited JSON-RPC 2.0 objects. Methods:
+//   search          { keyword, paths, regex }              -> "search/match" notifications, then the result
+//   replace-preview { keyword, replacement, paths, regex } -> "replace-preview/match" notifications, then the result
+//   cancel          { id }                                 -> stops the search of the request `id`
+// The id of a search must not be the same as the searches still running, so that `cancel` selects one of them.
+
+pub struct RpcServer<W: Write + Send + 'static> {
+    pub max_threads: usize,
+    pub size_per_thread: usize,
+    pub output: Arc<Mutex<W>>,
+    searches: Arc<Mutex<HashMap<String, CancelToken>>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<W: Write + Send + 'static> RpcServer<W> {
+    pub fn new(output: W) -> Self {
+        RpcServer {
+            max_threads: num_cpus::get(),
+            size_per_thread: 1024 * 1024,
+            output: Arc::new(Mutex::new(output)),
+            searches: Arc::new(Mutex::new(HashMap::new())),
+            handles: Vec::new(),
+        }
+    }
+
+    pub fn serve<R: BufRead>(&mut self, input: R) {
+        for line in input.lines() {
+            let line = match line {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let req: Request = match serde_json::from_str(&line) {
+                Ok(x) => x,
+                Err(e) => {
+                    send_error(&self.output, &Value::Null, PARSE_ERROR, &format!("{}", e));
+                    continue;
+                }
+            };
+
+            match req.method.as_str() {
+                "search" | "replace-preview" => match serde_json::from_value::<SearchParams>(req.params) {
+                    Ok(x) => {
+                        let preview = req.method == "replace-preview";
+                        if preview && x.replacement.is_none() {
+                            send_error(&self.output, &req.id, INVALID_PARAMS, "missing field `replacement`");
+                        } else {
+                            self.search(req.id, x, preview);
+                        }
+                    }
+                    Err(e) => send_error(&self.output, &req.id, INVALID_PARAMS, &format!("{}", e)),
+                },
+                "cancel" => match serde_json::from_value::<CancelParams>(req.params) {
+                    Ok(x) => {
+                        let found = match self.searches.lock().unwrap().get(&x.id.to_string()) {
+                            Some(cancel) => {
+                                cancel.cancel();
+                                true
+                            }
+                            None => false,
+                        };
+                        send(
+                            &self.output,
+                            json!({"jsonrpc": "2.0", "id": req.id, "result": {"cancelled": found}}),
+                        );
+                    }
+                    Err(e) => send_error(&self.output, &req.id, INVALID_PARAMS, &format!("{}", e)),
+                },
+                _ => send_error(
+                    &self.output,
+                    &req.id,
+                    METHOD_NOT_FOUND,
+                    &format!("unknown method: {}", req.method),
+                ),
+            }
+        }
+
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    fn search(&mut self, id: Value, params: SearchParams, preview: bool) {
+        // An invalid regex is an error of the request instead of a search without matches
+        let regex = if params.regex {
+            match Regex::new(&params.keyword) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    send_error(&self.output, &id, INVALID_PARAMS, &format!("invalid regex: {}", e));
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        let cancel = CancelToken::new();
+        {
+            let mut searches = self.searches.lock().unwrap();
+            if searches.contains_key(&id.to_string()) {
+                send_error(&self.output, &id, INVALID_PARAMS, &format!("duplicate id: {}", id));
+                return;
+            }
+            searches.insert(id.to_string(), cancel.clone());
+        }
+
+        let output = self.output.clone();
+        let searches = self.searches.clone();
+        let rx = self.spawn_pipeline(&params, &cancel);
+        let method = if preview {
+            "replace-preview/match"
+        } else {
+            "search/match"
+        };
+
+        let handle = thread::spawn(move || {
+            let mut files = 0;
+            let mut matches = 0;
+            loop {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                match rx.recv() {
+                    Ok(PipelineInfo::SeqDat(_, pm)) => {
+                        if pm.matches.is_empty() {
+                            continue;
+                        }
+                        let src = match fs::read(&pm.path) {
+                            Ok(x) => x,
+                            Err(e) => {
+                                send_log(&output, &id, &format!("Error: {} @ {:?}", e, pm.path));
+                                continue;
+                            }
+                        };
+                        files += 1;
+                        for (line, column, m) in locate(&src, &pm) {
+                            if cancel.is_cancelled() {
+                                break;
+                            }
+                            let (beg, end) = line_range(&src, m.beg, m.end);
+                            let text = String::from_utf8_lossy(&src[beg..end]).into_owned();
+                            let mut notification = json!({
+                                "id": id,
+                                "path": pm.path.to_string_lossy(),
+                                "line": line,
+                                "column": column,
+                                "text": text,
+                            });
+                            if let Some(ref replacement) = params.replacement {
+                                let mut replaced = Vec::from(&src[beg..m.beg]);
+                                replaced.extend_from_slice(replace(&regex, replacement, &src, m).as_bytes());
+                                replaced.extend_from_slice(&src[m.end..end]);
+                                notification["replaced"] = Value::from(String::from_utf8_lossy(&replaced).into_owned());
+                            }
+                            send(
+                                &output,
+                                json!({"jsonrpc": "2.0", "method": method, "params": notification}),
+                            );
+                            matches += 1;
+                        }
+                    }
+                    Ok(PipelineInfo::MsgInfo(_, s)) => send_log(&output, &id, &s),
+                    Ok(PipelineInfo::MsgErr(_, s)) => send_log(&output, &id, &s),
+                    Ok(PipelineInfo::SeqEnd(_)) => break,
+                    Ok(_) => (),
+                    Err(_) => break,
+                }
+            }
+
+            searches.lock().unwrap().remove(&id.to_string());
+            send(
+                &output,
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": {
+                        "files": files,
+                        "matches": matches,
+                        "cancelled": cancel.is_cancelled(),
+                    },
+                }),
+            );
+        });
+        self.handles.push(handle);
+    }
+
+    fn spawn_pipeline(&self, params: &SearchParams, cancel: &CancelToken) -> Receiver<PipelineInfo<PathMatch>> {
+        let matcher_num = self.max_threads.clamp(1, 8);
+
+        let (tx_finder, rx_finder) = unbounded();
+        let (tx_out, rx_out) = unbounded();
+        let mut tx_matcher = Vec::new();
+        let mut rx_sorter = Vec::new();
+
+        for i in 0..matcher_num {
+            let (tx_in, rx_in) = unbounded();
+            let (tx_sorter, rx) = unbounded();
+            tx_matcher.push(tx_in);
+            rx_sorter.push(rx);
+
+            let keyword = params.keyword.clone().into_bytes();
+            let use_regex = params.regex;
+            let max_threads = self.max_threads;
+            let size_per_thread = self.size_per_thread;
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                if use_regex {
+                    let mut matcher = PipelineMatcher::new(RegexMatcher::new(), &keyword);
+                    matcher.cancel = cancel;
+                    matcher.setup(1 + i, rx_in, tx_sorter);
+                } else {
+                    let mut m = MemmemMatcher::new();
+                    m.max_threads = max_threads;
+                    m.size_per_thread = size_per_thread;
+                    let mut matcher = PipelineMatcher::new(m, &keyword);
+                    matcher.cancel = cancel;
+                    matcher.setup(1 + i, rx_in, tx_sorter);
+                }
+            });
+        }
+
+        let mut finder = PipelineFinder::new();
+        finder.cancel = cancel.clone();
+        let mut sorter = PipelineSorter::new(matcher_num);
+        thread::spawn(move || finder.setup(0, rx_finder, tx_matcher));
+        thread::spawn(move || sorter.setup(matcher_num + 1, rx_sorter, tx_out));
+
+        let mut paths = params.paths.clone();
+        if paths.is_empty() {
+            paths.push(String::from("./"));
+        }
+        let mut seq_no = 0;
+        let _ = tx_finder.send(PipelineInfo::SeqBeg(seq_no));
+        for p in paths {
+            let _ = tx_finder.send(PipelineInfo::SeqDat(seq_no, PathBuf::from(p)));
+            seq_no += 1;
+        }
+        let _ = tx_finder.send(PipelineInfo::SeqEnd(seq_no));
+
+        rx_out
+    }
+}
+
+fn replace(regex: &Option<Regex>, replacement: &str, src: &[u8], m: &Match) -> String {
+    match regex {
+        Some(regex) => {
+            let text = String::from_utf8_lossy(&src[m.beg..m.end]);
+            regex.replace(&text, replacement).into_owned()
+        }
+        None => String::from(replacement),
+    }
+}
+
+fn locate<'a>(src: &[u8], pm: &'a PathMatch) -> Vec<(usize, usize, &'a Match)> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    let mut line_beg = 0;
+    for m in &pm.matches {
+        while pos < m.beg {
+            if src[pos] == 0x0a {
+                line += 1;
+                line_beg = pos + 1;
+            }
+            pos += 1;
+        }
+        ret.push((line, m.beg - line_beg + 1, m));
+    }
+    ret
+}
+
+fn line_range(src: &[u8], beg: usize, end: usize) -> (usize, usize) {
+    let beg = match memchr::memrchr(0x0a, &src[..beg]) {
+        Some(x) => x + 1,
+        None => 0,
+    };
+    let mut end = match memchr::memchr(0x0a, &src[end..]) {
+        Some(x) => end + x,
+        None => src.len(),
+    };
+    if end > beg && src[end - 1] == 0x0d {
+        end -= 1;
+    }
+    (beg, end)
+}
+
+fn send<W: Write>(output: &Mutex<W>, value: Value) {
+    let mut output = output.lock().unwrap();
+    let _ = writeln!(output, "{}", value);
+    let _ = output.flush();
+}
+
+fn send_error<W: Write>(output: &Mutex<W>, id: &Value, code: i64, message: &str) {
+    send(
+        output,
+        json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}}),
+    );
+}
+
+fn send_log<W: Write>(output: &Mutex<W>, id: &Value, message: &str) {
+    send(
+        output,
+        json!({"jsonrpc": "2.0", "method": "log", "params": {"id": id, "message": message.trim_end()}}),
+    );
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(input: &str) -> Vec<Value> {
+        let mut server = RpcServer::new(Vec::new());
+        server.serve(input.as_bytes());
+        let output = server.output.lock().unwrap();
+        String::from_utf8_lossy(&output)
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect()
+    }
+
+    fn write_source() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "aaa\r\n  bbb ccc\nddd bbb\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn rpc_search() {
+        let dir = write_source();
+        let ret = test(&format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"search","params":{{"keyword":"bbb","paths":[{:?}]}}}}"#,
+            dir.path()
+        ));
+        assert_eq!(ret.len(), 3);
+        assert_eq!(ret[0]["method"], "search/match");
+        assert_eq!(ret[0]["params"]["text"], "  bbb ccc");
+        assert_eq!(ret[0]["params"]["line"], 2);
+        assert_eq!(ret[0]["params"]["column"], 3);
+        assert_eq!(ret[1]["params"]["text"], "ddd bbb");
+        assert_eq!(ret[1]["params"]["line"], 3);
+        assert_eq!(ret[1]["params"]["column"], 5);
+        assert_eq!(ret[2]["id"], 1);
+        assert_eq!(ret[2]["result"]["files"], 1);
+        assert_eq!(ret[2]["result"]["matches"], 2);
+    }
+
+    #[test]
+    fn rpc_replace_preview() {
+        let dir = write_source();
+        let ret = test(&format!(
+            r#"{{"jsonrpc":"2.0","id":"a","method":"replace-preview","params":{{"keyword":"(b+) (c+)","replacement":"$2 $1","regex":true,"paths":[{:?}]}}}}"#,
+            dir.path()
+        ));
+        assert_eq!(ret[0]["method"], "replace-preview/match");
+        assert_eq!(ret[0]["params"]["replaced"], "  ccc bbb");
+        assert_eq!(ret[1]["result"]["matches"], 1);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "aaa\r\n  bbb ccc\nddd bbb\n"
+        );
+    }
+
+    #[test]
+    fn rpc_error() {
+        let ret = test("{\n{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"foo\"}");
+        assert_eq!(ret[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(ret[1]["error"]["code"], METHOD_NOT_FOUND);
+
+        let ret = test(r#"{"jsonrpc":"2.0","id":3,"method":"search","params":{"keyword":"(a","regex":true}}"#);
+        assert_eq!(ret.len(), 1);
+        assert_eq!(ret[0]["id"], 3);
+        assert_eq!(ret[0]["error"]["code"], INVALID_PARAMS);
+        assert!(ret[0]["error"]["message"].as_str().unwrap().contains("unclosed group"));
+    }
+
+    #[test]
+    fn rpc_cancel() {
+        let dir = write_source();
+        let mut server = RpcServer::new(Vec::new());
+        let cancel = CancelToken::new();
+        server
+            .searches
+            .lock()
+            .unwrap()
+            .insert(String::from("1"), cancel.clone());
+        server.serve(
+            format!(
+                "{}\n{}\n",
+                r#"{"jsonrpc":"2.0","id":2,"method":"cancel","params":{"id":1}}"#,
+                format_args!(
+                    r#"{{"jsonrpc":"2.0","id":1,"method":"search","params":{{"keyword":"bbb","paths":[{:?}]}}}}"#,
+                    dir.path()
+                ),
+            )
+            .as_bytes(),
+        );
+        assert!(cancel.is_cancelled());
+        let output = server.output.lock().unwrap();
+        let ret: Vec<Value> = String::from_utf8_lossy(&output)
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret[0]["result"]["cancelled"], true);
+        assert_eq!(ret[1]["id"], 1);
+        assert_eq!(ret[1]["error"]["code"], INVALID_PARAMS);
+
+        // The finder and the matchers stop by the token of the search
+        let params = SearchParams {
+            keyword: String::from("bbb"),
+            paths: vec![dir.path().to_string_lossy().into_owned()],
+            regex: false,
+            replacement: None,
+        };
+        let rx = server.spawn_pipeline(&params, &cancel);
+        let mut matches = 0;
+        while let Ok(info) = rx.recv() {
+            match info {
+                PipelineInfo::SeqDat(_, pm) => matches += pm.matches.len(),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+        assert_eq!(matches, 0);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::matcher::{Matcher, MemmemMatcher, RegexMatcher};
+use crate::pipeline::PipelineInfo;
+use crate::pipeline_builder::PipelineBuilder;
+use crate::pipeline_finder::PipelineFinder;
+use crate::pipeline_matcher::{BinaryFiles, PathMatch, PipelineMatcher};
+use crate::util::decode_error;
+use crossbeam::channel::Receiver;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SearchMatch
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Match produced by `Search`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    pub path: PathBuf,
+    /// Line number from 1
+    pub line: usize,
+    /// Column in bytes from 1
+    pub column: usize,
+    /// Byte offset of the beginning of the match in the file
+    pub beg: usize,
+    /// Byte offset of the end of the match in the file
+    pub end: usize,
+    /// Line containing the match without the line ending
+    pub text: Vec<u8>,
+}
+
+impl SearchMatch {
+    /// Converts the matches of `pm` to `SearchMatch` with the line and the column in `src`
+    pub fn from_path_match(pm: &PathMatch, src: &[u8]) -> Vec<Self> {
+        let mut ret = Vec::new();
+        let mut line = 1;
+        let mut line_beg = 0;
+        let mut pos = 0;
+        for m in &pm.matches {
+            while pos < m.beg {
+                if src[pos] == b'\n' {
+                    line += 1;
+                    line_beg = pos + 1;
+                }
+                pos += 1;
+            }
+
+            let mut line_end = memchr::memchr(b'\n', &src[m.end..]).map_or(src.len(), |x| m.end + x);
+            if line_end > line_beg && src[line_end - 1] == b'\r' {
+                line_end -= 1;
+            }
+            ret.push(SearchMatch {
+                path: pm.path.clone(),
+                line,
+                column: m.beg - line_beg + 1,
+                beg: m.beg,
+                end: m.end,
+                text: src[line_beg..line_end].to_vec(),
+            });
+        }
+        ret
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Search
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Search for library users, which runs the pipeline on background threads and produces `SearchMatch`
+/// without printing to the console nor exiting the process.
+///
+/// ```no_run
+/// use amber::search::Search;
+///
+/// let mut search = Search::new(b"keyword");
+/// search.paths.push("src".into());
+/// for m in search.run().results() {
+///     println!("{:?}:{}:{}", m.path, m.line, m.column);
+/// }
+/// ```
+pub struct Search {
+    pub keyword: Vec<u8>,
+    /// Paths to search ( default: `./` )
+    pub paths: Vec<PathBuf>,
+    pub regex: bool,
+    pub binary_files: BinaryFiles,
+    /// Produce matches in the order found instead of as soon as matched
+    pub fixed_order: bool,
+    pub matcher_threads: usize,
+    pub max_threads: usize,
+    pub size_per_thread: usize,
+    /// Finder to configure directory traversal ( e.g. `is_recursive`, `skip_gitignore` )
+    pub finder: PipelineFinder,
+}
+
+impl Search {
+    pub fn new(keyword: &[u8]) -> Self {
+        Search {
+            keyword: keyword.to_vec(),
+            paths: Vec::new(),
+            regex: false,
+            binary_files: BinaryFiles::WithoutMatch,
+            fixed_order: true,
+            matcher_threads: num_cpus::get().clamp(1, 8),
+            max_threads: num_cpus::get(),
+            size_per_thread: 1024 * 1024,
+            finder: PipelineFinder::new(),
+        }
+    }
+
+    /// Starts the search on background threads
+    pub fn run(self) -> SearchRun {
+        SearchRun { rx: self.spawn() }
+    }
+
+    // Matches of each file are received from the returned channel until `SeqEnd`
+    pub(crate) fn spawn(self) -> Receiver<PipelineInfo<PathMatch>> {
+        let mut builder = PipelineBuilder::new(self.finder).through(!self.fixed_order);
+        for _ in 0..self.matcher_threads.max(1) {
+            let m: Box<dyn Matcher + Send> = if self.regex {
+                Box::new(RegexMatcher::new())
+            } else {
+                let mut m = MemmemMatcher::new();
+                m.max_threads = self.max_threads;
+                m.size_per_thread = self.size_per_thread;
+                Box::new(m)
+            };
+            let mut matcher = PipelineMatcher::new(m, &self.keyword);
+            matcher.binary_files = self.binary_files;
+            builder = builder.matcher(matcher);
+        }
+        let (tx, rx) = builder.build();
+
+        let mut paths = self.paths;
+        if paths.is_empty() {
+            paths.push(PathBuf::from("./"));
+        }
+        let mut seq_no = 0;
+        let _ = tx.send(PipelineInfo::SeqBeg(seq_no));
+        for p in paths {
+            let _ = tx.send(PipelineInfo::SeqDat(seq_no, p));
+            seq_no += 1;
+        }
+        let _ = tx.send(PipelineInfo::SeqEnd(seq_no));
+        rx
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SearchRun
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Running search
+pub struct SearchRun {
+    rx: Receiver<PipelineInfo<PathMatch>>,
+}
+
+impl SearchRun {
+    /// Returns the iterator of matches produced as the search goes
+    pub fn results(self) -> SearchResults {
+        SearchResults {
+            rx: self.rx,
+            pending: VecDeque::new(),
+            infos: Vec::new(),
+            errors: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Calls `func` for each match, and returns the errors
+    pub fn for_each<F: FnMut(SearchMatch)>(self, func: F) -> Vec<String> {
+        let mut results = self.results();
+        results.by_ref().for_each(func);
+        results.errors
+    }
+}
+
+/// Iterator of `SearchMatch`, which ends when the search is finished
+pub struct SearchResults {
+    rx: Receiver<PipelineInfo<PathMatch>>,
+    pending: VecDeque<SearchMatch>,
+    infos: Vec<String>,
+    errors: Vec<String>,
+    finished: bool,
+}
+
+impl SearchResults {
+    /// Information such as skipped files received so far
+    pub fn infos(&self) -> &[String] {
+        &self.infos
+    }
+
+    /// Errors received so far
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+}
+
+impl Iterator for SearchResults {
+    type Item = SearchMatch;
+
+    fn next(&mut self) -> Option<SearchMatch> {
+        while self.pending.is_empty() && !self.finished {
+            match self.rx.recv() {
+                Ok(PipelineInfo::SeqDat(_, pm)) => {
+                    if pm.matches.is_empty() {
+                        continue;
+                    }
+                    // Contents extracted or decompressed by the matcher are passed as is
+                    let src = match pm.content {
+                        Some(ref x) => Ok(x.to_vec()),
+                        None => fs::read(&pm.path),
+                    };
+                    match src {
+                        Ok(src) => self.pending.extend(SearchMatch::from_path_match(&pm, &src)),
+                        Err(e) => self
+                            .errors
+                            .push(format!("Error: {} @ {:?}", decode_error(e.kind()), pm.path)),
+                    }
+                }
+                Ok(PipelineInfo::MsgInfo(_, s)) => self.infos.push(s),
+                Ok(PipelineInfo::MsgErr(_, s)) => self.errors.push(s),
+                Ok(PipelineInfo::SeqEnd(_)) | Err(_) => self.finished = true,
+                Ok(_) => (),
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "abc\r\nxx keyword keyword\r\nkeyword").unwrap();
+        fs::write(dir.path().join("b.txt"), "none\n").unwrap();
+
+        let mut search = Search::new(b"keyword");
+        search.paths.push(dir.path().to_path_buf());
+        let ret: Vec<_> = search
+            .run()
+            .results()
+            .map(|x| (x.line, x.column, x.beg, String::from_utf8(x.text).unwrap()))
+            .collect();
+        assert_eq!(
+            ret,
+            vec![
+                (2, 4, 8, String::from("xx keyword keyword")),
+                (2, 12, 16, String::from("xx keyword keyword")),
+                (3, 1, 25, String::from("keyword")),
+            ]
+        );
+
+        let mut search = Search::new(br"key\w+");
+        search.paths.push(dir.path().to_path_buf());
+        search.regex = true;
+        let mut num = 0;
+        let errors = search.run().for_each(|_| num += 1);
+        assert_eq!(num, 3);
+        assert!(errors.is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::matcher::Match;
+use std::str::FromStr;
+#[cfg(feature = "syntax")]
+use tree_sitter::Parser;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SyntaxLang
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntaxLang {
+    C,
+    Go,
+    JavaScript,
+    Python,
+    Rust,
+}
+
+impl FromStr for SyntaxLang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(SyntaxLang::C),
+            "go" => Ok(SyntaxLang::Go),
+            "javascript" | "js" => Ok(SyntaxLang::JavaScript),
+            "python" | "py" => Ok(SyntaxLang::Python),
+            "rust" | "rs" => Ok(SyntaxLang::Rust),
+            _ => Err(format!("invalid syntax language: {}", s)),
+        }
+    }
+}
+
+#[cfg(feature = "syntax")]
+impl SyntaxLang {
+    fn language(self) -> tree_sitter::Language {
+        match self {
+            SyntaxLang::C => tree_sitter_c::language(),
+            SyntaxLang::Go => tree_sitter_go::language(),
+            SyntaxLang::JavaScript => tree_sitter_javascript::language(),
+            SyntaxLang::Python => tree_sitter_python::language(),
+            SyntaxLang::Rust => tree_sitter_rust::language(),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SyntaxFilter
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Matches are kept only inside ( or outside with `exclude` ) the syntax nodes of `kinds` like `string_literal`
+#[derive(Clone, Debug)]
+pub struct SyntaxFilter {
+    pub lang: SyntaxLang,
+    pub kinds: Vec<String>,
+    pub exclude: bool,
+}
+
+impl SyntaxFilter {
+    /// Returns whether tree-sitter is available with the `syntax` feature
+    pub fn is_enabled() -> bool {
+        cfg!(feature = "syntax")
+    }
+
+    /// Files failed to parse are not filtered
+    pub fn filter(&self, src: &[u8], matches: Vec<Match>) -> Vec<Match> {
+        match node_spans(self.lang, src, &self.kinds) {
+            Some(spans) => filter_spans(&spans, self.exclude, matches),
+            None => matches,
+        }
+    }
+}
+
+// Spans of the outermost nodes of `kinds` in document order
+#[cfg(feature = "syntax")]
+fn node_spans(lang: SyntaxLang, src: &[u8], kinds: &[String]) -> Option<Vec<(usize, usize)>> {
+    let mut parser = Parser::new();
+    parser.set_language(lang.language()).ok()?;
+    let tree = parser.parse(src, None)?;
+    let mut cursor = tree.walk();
+    let mut ret = Vec::new();
+    loop {
+        let node = cursor.node();
+        let found = kinds.iter().any(|x| x == node.kind());
+        if found {
+            ret.push((node.start_byte(), node.end_byte()));
+        } else if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return Some(ret);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "syntax"))]
+fn node_spans(_lang: SyntaxLang, _src: &[u8], _kinds: &[String]) -> Option<Vec<(usize, usize)>> {
+    None
+}
+
+// Matches must be wholly inside a span to be treated as inside
+fn filter_spans(spans: &[(usize, usize)], exclude: bool, mut matches: Vec<Match>) -> Vec<Match> {
+    matches.retain(|m| spans.iter().any(|x| x.0 <= m.beg && m.end <= x.1) != exclude);
+    matches
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syntax_filter_spans() {
+        let matches = || {
+            [(0, 3), (5, 8), (10, 14)]
+                .iter()
+                .map(|x| Match {
+                    beg: x.0,
+                    end: x.1,
+                    sub_match: Vec::new(),
+                    keyword: 0,
+                })
+                .collect::<Vec<_>>()
+        };
+        let spans = [(4, 9), (12, 20)];
+
+        let ret: Vec<_> = filter_spans(&spans, false, matches()).iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![5]);
+        let ret: Vec<_> = filter_spans(&spans, true, matches()).iter().map(|m| m.beg).collect();
+        assert_eq!(ret, vec![0, 10]);
+
+        assert_eq!("rs".parse::<SyntaxLang>(), Ok(SyntaxLang::Rust));
+        assert!("cobol".parse::<SyntaxLang>().is_err());
+    }
+}
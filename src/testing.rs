@@ -0,0 +1,295 @@
+//! Support of end-to-end tests, which builds file trees in temporary directories and runs the pipeline headlessly.
+//!
+//! ```
+//! use amber::testing::Fixture;
+//!
+//! let fixture = Fixture::new()
+//!     .file("src/a.txt", "foo bar\n")
+//!     .binary("b.bin", b"\0foo")
+//!     .symlink("src", "c");
+//! let outcome = fixture.run(fixture.search(b"foo"));
+//! assert_eq!(outcome.lines(), vec!["src/a.txt:1:1:foo bar"]);
+//! ```
+
+use crate::pipeline::{Pipeline, PipelineInfo};
+use crate::pipeline_matcher::PathMatch;
+use crate::pipeline_replacer::PipelineReplacer;
+use crate::search::{Search, SearchMatch};
+use crate::util::transcode_from_utf8;
+use crossbeam::channel::unbounded;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tempfile::TempDir;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Fixture
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// File tree in a temporary directory removed on drop, where paths are relative to the root with `/` separators.
+///
+/// The builder methods panic on failure to be used in tests directly.
+pub struct Fixture {
+    dir: TempDir,
+}
+
+impl Default for Fixture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Fixture {
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    /// Root directory of the tree
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.dir.path().join(path)
+    }
+
+    /// Creates a directory with the parents
+    pub fn dir(self, path: &str) -> Self {
+        fs::create_dir_all(self.join(path)).unwrap();
+        self
+    }
+
+    /// Creates a text file with the parent directories
+    pub fn file(self, path: &str, contents: &str) -> Self {
+        self.binary(path, contents.as_bytes())
+    }
+
+    /// Creates a file of arbitrary bytes ( e.g. binary files or broken UTF-8 )
+    pub fn binary(self, path: &str, contents: &[u8]) -> Self {
+        let path = self.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, contents).unwrap();
+        self
+    }
+
+    /// Creates a text file encoded to `encoding` of iconv ( e.g. `SHIFT_JIS` or `UTF-16LE` )
+    pub fn encoded(self, path: &str, contents: &str, encoding: &str) -> Self {
+        let encoded = transcode_from_utf8(contents.as_bytes(), encoding)
+            .unwrap_or_else(|| panic!("failed to encode to {}: {:?}", encoding, contents));
+        self.binary(path, &encoded)
+    }
+
+    /// Creates a symbolic link at `link` to `target`, where relative targets are resolved from the link like `ln -s`
+    pub fn symlink(self, target: &str, link: &str) -> Self {
+        let link = self.join(link);
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(target, &link).unwrap();
+
+        // Links to directories are distinguished on Windows
+        #[cfg(windows)]
+        {
+            let resolved = link.parent().map_or_else(|| PathBuf::from(target), |x| x.join(target));
+            if resolved.is_dir() {
+                std::os::windows::fs::symlink_dir(target, &link).unwrap();
+            } else {
+                std::os::windows::fs::symlink_file(target, &link).unwrap();
+            }
+        }
+        self
+    }
+
+    /// Creates a hard link at `link` to the file `original`
+    pub fn hardlink(self, original: &str, link: &str) -> Self {
+        fs::hard_link(self.join(original), self.join(link)).unwrap();
+        self
+    }
+
+    /// Returns the contents of the file
+    pub fn read(&self, path: &str) -> Vec<u8> {
+        fs::read(self.join(path)).unwrap()
+    }
+
+    /// Returns a search of `keyword` over the whole tree in the order found
+    pub fn search(&self, keyword: &[u8]) -> Search {
+        let mut search = Search::new(keyword);
+        search.paths.push(self.path().to_path_buf());
+        search
+    }
+
+    /// Returns a replacer writing files without asking nor printing
+    pub fn replacer(&self, keyword: &[u8], replacement: &[u8], regex: bool) -> PipelineReplacer {
+        let mut replacer = PipelineReplacer::new(keyword, replacement, regex);
+        replacer.is_color = false;
+        replacer.is_interactive = false;
+        replacer.print_file = false;
+        replacer
+    }
+
+    /// Runs `search` to the end, and collects the matches and the messages
+    pub fn run(&self, search: Search) -> Outcome {
+        let mut outcome = Outcome::default();
+        for x in search.spawn() {
+            match x {
+                PipelineInfo::SeqDat(_, pm) => outcome.push_matches(self.path(), &pm),
+                PipelineInfo::MsgInfo(_, s) => outcome.infos.push(s),
+                PipelineInfo::MsgErr(_, s) => outcome.errors.push(s),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+        outcome
+            .matches
+            .sort_by(|a, b| a.path.cmp(&b.path).then(a.beg.cmp(&b.beg)));
+        outcome
+    }
+
+    /// Runs `replacer` for the matches of `search`, where the matches are collected before replaced
+    pub fn replace(&self, search: Search, mut replacer: PipelineReplacer) -> Outcome {
+        let rx = search.spawn();
+        let (tx_replacer, rx_replacer) = unbounded();
+        let (tx_main, rx_main) = unbounded();
+        let root = self.path().to_path_buf();
+        let collector = thread::spawn(move || {
+            let mut outcome = Outcome::default();
+            let _ = tx_replacer.send(PipelineInfo::SeqBeg(0));
+            let mut seq_no = 0;
+            for x in rx {
+                match x {
+                    PipelineInfo::SeqDat(_, pm) => {
+                        outcome.push_matches(&root, &pm);
+                        let _ = tx_replacer.send(PipelineInfo::SeqDat(seq_no, pm));
+                        seq_no += 1;
+                    }
+                    PipelineInfo::MsgInfo(_, s) => outcome.infos.push(s),
+                    PipelineInfo::MsgErr(_, s) => outcome.errors.push(s),
+                    PipelineInfo::SeqEnd(_) => break,
+                    _ => (),
+                }
+            }
+            let _ = tx_replacer.send(PipelineInfo::SeqEnd(seq_no));
+            outcome
+        });
+        let replacer = thread::spawn(move || {
+            replacer.setup(0, rx_replacer, tx_main);
+        });
+
+        let mut messages = Outcome::default();
+        for x in rx_main {
+            match x {
+                PipelineInfo::MsgInfo(_, s) => messages.infos.push(s),
+                PipelineInfo::MsgErr(_, s) => messages.errors.push(s),
+                PipelineInfo::SeqEnd(_) => break,
+                _ => (),
+            }
+        }
+        let _ = replacer.join();
+
+        let mut outcome = collector.join().unwrap();
+        outcome.infos.append(&mut messages.infos);
+        outcome.errors.append(&mut messages.errors);
+        outcome
+            .matches
+            .sort_by(|a, b| a.path.cmp(&b.path).then(a.beg.cmp(&b.beg)));
+        outcome
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Outcome
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Results of a headless run, where the paths of the matches are relative to the root of the fixture
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// Matches sorted by the path and the position
+    pub matches: Vec<SearchMatch>,
+    pub infos: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl Outcome {
+    /// Matches as `path:line:column:text` lines like `ambs --format grep` to compare at once
+    pub fn lines(&self) -> Vec<String> {
+        self.matches
+            .iter()
+            .map(|x| {
+                format!(
+                    "{}:{}:{}:{}",
+                    x.path.to_string_lossy().replace('\\', "/"),
+                    x.line,
+                    x.column,
+                    String::from_utf8_lossy(&x.text)
+                )
+            })
+            .collect()
+    }
+
+    /// Relative paths of the files with matches
+    pub fn paths(&self) -> Vec<String> {
+        let mut ret: Vec<String> = self
+            .matches
+            .iter()
+            .map(|x| x.path.to_string_lossy().replace('\\', "/"))
+            .collect();
+        ret.dedup();
+        ret
+    }
+
+    fn push_matches(&mut self, root: &Path, pm: &PathMatch) {
+        if pm.matches.is_empty() {
+            return;
+        }
+        let src = match pm.content {
+            Some(ref x) => x.to_vec(),
+            None => fs::read(&pm.path).unwrap_or_default(),
+        };
+        for mut x in SearchMatch::from_path_match(pm, &src) {
+            if let Ok(path) = x.path.strip_prefix(root) {
+                x.path = path.to_path_buf();
+            }
+            self.matches.push(x);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline_matcher::BinaryFiles;
+
+    #[test]
+    fn testing() {
+        let fixture = Fixture::new()
+            .file("a.txt", "foo\nbar foo\n")
+            .file("sub/b.txt", "bar\n")
+            .binary("c.bin", b"\0\xfffoo")
+            .hardlink("a.txt", "d.txt")
+            .symlink("sub", "e")
+            .encoded("f.txt", "foo\n", "UTF-16LE");
+
+        // Hard links are searched once
+        let outcome = fixture.run(fixture.search(b"foo"));
+        assert_eq!(outcome.matches.len(), 2);
+        assert!(outcome.lines()[1].ends_with(".txt:2:5:bar foo"));
+        assert!(outcome.errors.is_empty());
+
+        let mut search = fixture.search(b"foo");
+        search.binary_files = BinaryFiles::Text;
+        assert!(fixture.run(search).paths().contains(&String::from("c.bin")));
+
+        let mut search = fixture.search(b"bar");
+        search.paths = vec![fixture.join("sub")];
+        let outcome = fixture.replace(search, fixture.replacer(b"bar", b"baz", false));
+        assert_eq!(outcome.lines(), vec!["sub/b.txt:1:1:bar"]);
+        assert_eq!(fixture.read("sub/b.txt"), b"baz\n");
+    }
+}
@@ -0,0 +1,148 @@
+use crate::console::{Console, ConsoleTextKind};
+use crate::util::exit;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+use tempfile::{Builder, NamedTempFile};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Registry
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Prefix of temporary files created beside the replaced files, which identifies the files left by a killed process
+pub const TMPFILE_PREFIX: &str = ".amber-tmp";
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    // Held while the original files are rewritten not to be interrupted halfway by Ctrl-C
+    static ref CRITICAL: Mutex<()> = Mutex::new(());
+}
+
+static HANDLER: Once = Once::new();
+
+/// Registration of a temporary file, which is removed from the registry when dropped
+pub struct Registered {
+    path: PathBuf,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        if let Ok(mut x) = REGISTRY.lock() {
+            x.remove(&self.path);
+        }
+    }
+}
+
+/// Creates a temporary file in `dir`, which is removed by Ctrl-C until the registration is dropped
+pub fn create_in(dir: &Path) -> Result<(NamedTempFile, Registered), Error> {
+    let file = Builder::new().prefix(TMPFILE_PREFIX).tempfile_in(dir)?;
+    let path = file.path().to_path_buf();
+    if let Ok(mut x) = REGISTRY.lock() {
+        x.insert(path.clone());
+    }
+    Ok((file, Registered { path }))
+}
+
+/// Removes all the registered files, and returns the files removed
+pub fn cleanup() -> Vec<PathBuf> {
+    let paths: Vec<PathBuf> = match REGISTRY.lock() {
+        Ok(mut x) => x.drain().collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.into_iter().filter(|x| fs::remove_file(x).is_ok()).collect()
+}
+
+/// Runs `f` without being interrupted by the handler ( e.g. rewriting a file in place )
+pub fn critical<T, F: FnOnce() -> T>(f: F) -> T {
+    let _lock = CRITICAL.lock();
+    f()
+}
+
+/// Installs the Ctrl-C handler removing the registered files once for the process.
+///
+/// The terminal mode at the installation is restored too, because Ctrl-C may arrive while reading a key.
+pub fn install_handler() {
+    HANDLER.call_once(|| {
+        #[cfg(not(windows))]
+        let c_lflag = crate::util::get_c_lflag();
+        let _ = ctrlc::set_handler(move || {
+            let _lock = CRITICAL.lock();
+            let mut console = Console::new();
+            for path in cleanup() {
+                console.write(
+                    ConsoleTextKind::Info,
+                    &format!("\nCleanup temporary file: {:?}\n", path),
+                );
+            }
+            #[cfg(not(windows))]
+            crate::util::set_c_lflag(c_lflag);
+            exit(0, &mut console);
+        });
+    });
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Leftover
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub fn is_leftover(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|x| x.to_string_lossy().starts_with(TMPFILE_PREFIX))
+}
+
+/// Selects temporary files left by a process killed during replacement ( e.g. SIGKILL ) from `paths` found by the
+/// finder, which may include the files created and already removed by this process.
+///
+/// The files not existing and the files registered by this process are excluded.
+pub fn filter_leftovers(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    paths.retain(|p| p.is_file());
+    if let Ok(x) = REGISTRY.lock() {
+        paths.retain(|p| !x.contains(p));
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn tmpfile() {
+        let dir = TempDir::new().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let leftover = sub.join(format!("{}abc123", TMPFILE_PREFIX));
+        fs::write(&leftover, "x").unwrap();
+        fs::write(sub.join("file.txt"), "x").unwrap();
+
+        let (file, registered) = create_in(&sub).unwrap();
+        assert!(is_leftover(file.path()));
+        let found = vec![
+            file.path().to_path_buf(),
+            leftover.clone(),
+            sub.join(format!("{}removed", TMPFILE_PREFIX)),
+        ];
+        assert_eq!(filter_leftovers(found), vec![leftover.clone()]);
+
+        let path = file.path().to_path_buf();
+        let _ = file.into_temp_path().keep();
+        assert_eq!(cleanup(), vec![path.clone()]);
+        assert!(!path.exists());
+        drop(registered);
+
+        let (file, registered) = create_in(&sub).unwrap();
+        drop(registered);
+        assert!(cleanup().is_empty());
+        assert!(file.path().exists());
+    }
+}
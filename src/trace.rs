@@ -0,0 +1,161 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// TraceStage
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceStage {
+    Finder,
+    Matcher,
+    Sorter,
+    Printer,
+    Replacer,
+}
+
+impl TraceStage {
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceStage::Finder => "finder",
+            TraceStage::Matcher => "matcher",
+            TraceStage::Sorter => "sorter",
+            TraceStage::Printer => "printer",
+            TraceStage::Replacer => "replacer",
+        }
+    }
+}
+
+impl FromStr for TraceStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "finder" => Ok(TraceStage::Finder),
+            "matcher" => Ok(TraceStage::Matcher),
+            "sorter" => Ok(TraceStage::Sorter),
+            "printer" => Ok(TraceStage::Printer),
+            "replacer" => Ok(TraceStage::Replacer),
+            _ => Err(format!("invalid trace stage: {}", s)),
+        }
+    }
+}
+
+/// Stages given by a comma-separated list like `finder,matcher`, or `all`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStages(pub Vec<TraceStage>);
+
+impl TraceStages {
+    pub fn contains(&self, stage: TraceStage) -> bool {
+        self.0.contains(&stage)
+    }
+}
+
+impl FromStr for TraceStages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(TraceStages(vec![
+                TraceStage::Finder,
+                TraceStage::Matcher,
+                TraceStage::Sorter,
+                TraceStage::Printer,
+                TraceStage::Replacer,
+            ]));
+        }
+        let mut ret = Vec::new();
+        for x in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            ret.push(x.parse()?);
+        }
+        Ok(TraceStages(ret))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Tracer
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Logger of a stage writing `[stage] message` to stderr, where the level 1 is decisions and 2 is each file
+#[derive(Clone, Debug, Default)]
+pub struct Tracer {
+    level: usize,
+    stage: &'static str,
+}
+
+impl Tracer {
+    /// `verbose` is the number of `-v`, and `--trace` without `-v` enables the level 1 of the stages given
+    pub fn new(stage: TraceStage, verbose: usize, stages: Option<&TraceStages>) -> Self {
+        let level = match stages {
+            Some(x) if !x.contains(stage) => 0,
+            Some(_) => std::cmp::max(1, verbose),
+            None => verbose,
+        };
+        Tracer {
+            level,
+            stage: stage.name(),
+        }
+    }
+
+    pub fn enabled(&self, level: usize) -> bool {
+        level != 0 && level <= self.level
+    }
+
+    /// The message is built only if the level is enabled not to format paths of every file
+    /// ( failures to write are ignored not to panic on a closed stderr like `2>&-` )
+    pub fn log<F: FnOnce() -> String>(&self, level: usize, f: F) {
+        if self.enabled(level) {
+            let _ = writeln!(io::stderr(), "[{}] {}", self.stage, f());
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_stages() {
+        let stages: TraceStages = "finder, matcher".parse().unwrap();
+        assert_eq!(stages, TraceStages(vec![TraceStage::Finder, TraceStage::Matcher]));
+        assert!(!stages.contains(TraceStage::Printer));
+
+        let stages: TraceStages = "all".parse().unwrap();
+        assert_eq!(stages.0.len(), 5);
+
+        assert_eq!(
+            "finder,walker".parse::<TraceStages>(),
+            Err(String::from("invalid trace stage: walker"))
+        );
+    }
+
+    #[test]
+    fn tracer() {
+        let tracer = Tracer::default();
+        assert!(!tracer.enabled(1));
+
+        let tracer = Tracer::new(TraceStage::Finder, 2, None);
+        assert!(tracer.enabled(1));
+        assert!(tracer.enabled(2));
+        assert!(!tracer.enabled(3));
+
+        let stages: TraceStages = "matcher".parse().unwrap();
+        let tracer = Tracer::new(TraceStage::Finder, 2, Some(&stages));
+        assert!(!tracer.enabled(1));
+
+        let tracer = Tracer::new(TraceStage::Matcher, 0, Some(&stages));
+        assert!(tracer.enabled(1));
+        assert!(!tracer.enabled(2));
+
+        let mut called = false;
+        tracer.log(2, || {
+            called = true;
+            String::new()
+        });
+        assert!(!called);
+    }
+}
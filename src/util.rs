@@ -1,8 +1,20 @@
 use crate::console::Console;
-use std::fs::File;
+use memmap::Mmap;
+use std::borrow::Cow;
+use std::cmp;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{DirEntry, File, FileType, Metadata};
 use std::io::{BufReader, Error, ErrorKind, Read};
-use std::process;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Utility
@@ -59,6 +71,222 @@ pub fn read_from_file(path: &str) -> Result<Vec<u8>, Error> {
     Ok(ret.into_bytes())
 }
 
+/// Returns the command-line arguments with default options in the environment variable ( e.g. `AMBS_OPTIONS` )
+/// and `extra` ( e.g. options of a profile in the config file ) inserted before them
+pub fn args_with_env(name: &str, extra: Vec<String>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let mut opts = match env::var(name) {
+        Ok(x) => split_args(&x),
+        Err(_) => Vec::new(),
+    };
+    opts.extend(extra);
+    let pos = cmp::min(1, args.len());
+    args.splice(pos..pos, opts.into_iter().map(OsString::from));
+    args
+}
+
+/// Returns the value of the option given as `--name VALUE` or `--name=VALUE` in the command-line arguments
+pub fn find_arg(name: &str) -> Option<String> {
+    let mut args = env::args_os().skip(1).map(|x| x.to_string_lossy().into_owned());
+    while let Some(x) = args.next() {
+        if x == "--" {
+            break;
+        } else if x == name {
+            return args.next();
+        } else if let Some(x) = x.strip_prefix(name).and_then(|x| x.strip_prefix('=')) {
+            return Some(x.to_string());
+        }
+    }
+    None
+}
+
+/// Splits arguments by whitespaces except quoted by `'` or `"` like shells
+pub fn split_args(src: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut arg = None;
+    let mut quote = None;
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                if let Some(x) = chars.next() {
+                    arg.get_or_insert_with(String::new).push(x);
+                }
+            }
+            (Some(_), _) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, _) if c.is_whitespace() => ret.extend(arg.take()),
+            (None, _) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    ret.extend(arg);
+    ret
+}
+
+fn hex_escape(src: &[u8]) -> Option<u8> {
+    match src {
+        [b'\\', b'x', h, l, ..] => {
+            let h = (*h as char).to_digit(16)?;
+            let l = (*l as char).to_digit(16)?;
+            Some((h * 16 + l) as u8)
+        }
+        _ => None,
+    }
+}
+
+/// Decodes `\xNN` escapes into the bytes and `\\` into a backslash, and leaves the others as is
+pub fn unescape(src: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < src.len() {
+        if let Some(x) = hex_escape(&src[i..]) {
+            ret.push(x);
+            i += 4;
+        } else if src[i..].starts_with(b"\\\\") {
+            ret.push(b'\\');
+            i += 2;
+        } else {
+            ret.push(src[i]);
+            i += 1;
+        }
+    }
+    ret
+}
+
+/// Splits a labelled root like `frontend:web/` into the label and the path, where existing paths are not split and
+/// the label must be 2 or more alphanumerics, `_`, `-` or `.` not to split drive letters like `C:\`
+pub fn split_root_label(arg: &str) -> Option<(&str, &str)> {
+    if Path::new(arg).exists() {
+        return None;
+    }
+    let (label, path) = arg.split_once(':')?;
+    let is_label = label.len() >= 2
+        && label
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-' || x == '.');
+    if is_label && !path.is_empty() {
+        Some((label, path))
+    } else {
+        None
+    }
+}
+
+/// Parses hex bytes like `DE AD BE EF` or `deadbeef`
+pub fn parse_hex(src: &str) -> Option<Vec<u8>> {
+    let mut ret = Vec::new();
+    for token in src.split_whitespace() {
+        let digits = token.chars().map(|x| x.to_digit(16)).collect::<Option<Vec<_>>>()?;
+        if digits.len() % 2 != 0 {
+            return None;
+        }
+        ret.extend(digits.chunks(2).map(|x| (x[0] * 16 + x[1]) as u8));
+    }
+    if ret.is_empty() {
+        None
+    } else {
+        Some(ret)
+    }
+}
+
+/// Rewrites `\xNN` escapes of a regex to match the bytes instead of the code points ( e.g. `\xff` is not `ÿ` )
+pub fn unescape_regex(src: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < src.len() {
+        if hex_escape(&src[i..]).is_some() {
+            ret.extend_from_slice(b"(?-u:");
+            ret.extend_from_slice(&src[i..i + 4]);
+            ret.push(b')');
+            i += 4;
+        } else if src[i] == b'\\' && i + 1 < src.len() {
+            ret.extend_from_slice(&src[i..i + 2]);
+            i += 2;
+        } else {
+            ret.push(src[i]);
+            i += 1;
+        }
+    }
+    ret
+}
+
+/// Converts `src` to the naming convention of `style` ( snake, camel, pascal, kebab ), or None if `style` is unknown
+pub fn convert_case(src: &str, style: &str) -> Option<String> {
+    let words = split_words(src);
+    let capitalize = |x: &str| {
+        let mut chars = x.chars();
+        chars
+            .next()
+            .map_or(String::new(), |c| c.to_uppercase().chain(chars).collect())
+    };
+    let ret = match style {
+        "snake" => words.join("_"),
+        "kebab" => words.join("-"),
+        "pascal" => words.iter().map(|x| capitalize(x)).collect(),
+        "camel" => words
+            .iter()
+            .enumerate()
+            .map(|(i, x)| if i == 0 { x.clone() } else { capitalize(x) })
+            .collect(),
+        _ => return None,
+    };
+    Some(ret)
+}
+
+// Words are split at non-alphanumerics and case changes like `fooBar` and `HTTPServer`, and lowercased
+fn split_words(src: &str) -> Vec<String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut ret = Vec::new();
+    let mut word = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                ret.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).map_or(false, |x| x.is_lowercase());
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_lower) {
+                ret.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        ret.push(word);
+    }
+    ret
+}
+
+/// Parses lines of `keyword<TAB>replacement` skipping empty lines, or returns the line number without them as Err
+pub fn parse_map(src: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, usize> {
+    let mut ret = Vec::new();
+    for (i, line) in src.split(|x| *x == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let pos = memchr::memchr(b'\t', line).filter(|x| *x != 0).ok_or(i + 1)?;
+        ret.push((line[..pos].to_vec(), line[pos + 1..].to_vec()));
+    }
+    Ok(ret)
+}
+
+pub fn bom_len(src: &[u8]) -> usize {
+    if src.starts_with(&[0xef, 0xbb, 0xbf]) {
+        3 // UTF-8
+    } else if src.starts_with(&[0xff, 0xfe]) || src.starts_with(&[0xfe, 0xff]) {
+        2 // UTF-16 LE/BE
+    } else {
+        0
+    }
+}
+
 pub fn decode_error(e: ErrorKind) -> &'static str {
     match e {
         ErrorKind::NotFound => "file not found",
@@ -91,9 +319,358 @@ pub enum PipelineInfo<T> {
     End(usize),
 }
 
+/// Range of line numbers or byte offsets like `100..250` containing both ends, which may be open like `100..`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexRange {
+    pub beg: usize,
+    pub end: Option<usize>,
+}
+
+impl IndexRange {
+    pub fn contains(&self, index: usize) -> bool {
+        self.beg <= index && self.end.map_or(true, |x| index <= x)
+    }
+}
+
+impl FromStr for IndexRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid range: {}", s);
+        let parse = |x: &str| x.trim().parse::<usize>().map_err(|_| err());
+        let (beg, end) = match s.find("..") {
+            Some(x) if s[..x].trim().is_empty() => (0, Some(parse(&s[x + 2..])?)),
+            Some(x) if s[x + 2..].trim().is_empty() => (parse(&s[..x])?, None),
+            Some(x) => (parse(&s[..x])?, Some(parse(&s[x + 2..])?)),
+            None => {
+                let x = parse(s)?;
+                (x, Some(x))
+            }
+        };
+        if end.map_or(false, |x| x < beg) {
+            return Err(err());
+        }
+        Ok(IndexRange { beg, end })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PathPrefix
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Rewriting of reported paths like `/container/src=/home/me/src`, where `from` is matched by whole components
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathPrefix {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl PathPrefix {
+    /// Rewrites `path` by the longest `from` matched in `prefixes`, or returns it as is
+    pub fn map<'a>(prefixes: &[PathPrefix], path: &'a Path) -> Cow<'a, Path> {
+        let matched = prefixes
+            .iter()
+            .filter_map(|x| path.strip_prefix(&x.from).ok().map(|rest| (x, rest)))
+            .max_by_key(|(x, _)| x.from.components().count());
+        match matched {
+            Some((x, rest)) if rest.as_os_str().is_empty() => Cow::Owned(x.to.clone()),
+            Some((x, rest)) => Cow::Owned(x.to.join(rest)),
+            None => Cow::Borrowed(path),
+        }
+    }
+}
+
+impl FromStr for PathPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(PathPrefix {
+                from: PathBuf::from(from),
+                to: PathBuf::from(to),
+            }),
+            _ => Err(format!("invalid path prefix map: {} ( FROM=TO )", s)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// IoStrategy
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// How to load files ( auto: mmap only larger files than the threshold because mmap is slow for small files )
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IoStrategy {
+    Auto,
+    Mmap,
+    Read,
+}
+
+impl IoStrategy {
+    /// Returns whether a file of `len` bytes is mapped, where empty files can't be mapped
+    pub fn use_mmap(self, len: u64, threshold: u64) -> bool {
+        match self {
+            IoStrategy::Auto => len > threshold,
+            IoStrategy::Mmap => len > 0,
+            IoStrategy::Read => false,
+        }
+    }
+
+    pub fn load(self, file: &File, threshold: u64) -> Result<FileBuffer, Error> {
+        if self.use_mmap(file.metadata()?.len(), threshold) {
+            Ok(FileBuffer::Mmap(unsafe { Mmap::map(file) }?))
+        } else {
+            let mut buf = Vec::new();
+            let mut file = file;
+            file.read_to_end(&mut buf)?;
+            Ok(FileBuffer::Read(buf))
+        }
+    }
+}
+
+impl FromStr for IoStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(IoStrategy::Auto),
+            "mmap" => Ok(IoStrategy::Mmap),
+            "read" => Ok(IoStrategy::Read),
+            _ => Err(format!("invalid io strategy: {}", s)),
+        }
+    }
+}
+
+/// Contents of a file loaded by `IoStrategy`
+pub enum FileBuffer {
+    Mmap(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for FileBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileBuffer::Mmap(x) => &x[..],
+            FileBuffer::Read(x) => &x[..],
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// ColumnUnit
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Unit of the printed column numbers ( chars: grapheme clusters, display: cells on terminals like CJK and emoji as 2 )
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnUnit {
+    Bytes,
+    Chars,
+    Display,
+}
+
+impl ColumnUnit {
+    /// Returns the 1-based column next to `prefix`, which is the text from the beginning of the line
+    ///
+    /// Tabs are expanded to the next multiple of `tab_width` if it is not 0.
+    pub fn column(self, prefix: &[u8], tab_width: usize) -> usize {
+        if tab_width == 0 {
+            return self.len(prefix) + 1;
+        }
+        let mut len = 0;
+        let mut segments = prefix.split(|x| *x == b'\t').peekable();
+        while let Some(x) = segments.next() {
+            len += self.len(x);
+            if segments.peek().is_some() {
+                len = (len / tab_width + 1) * tab_width;
+            }
+        }
+        len + 1
+    }
+
+    fn len(self, src: &[u8]) -> usize {
+        match self {
+            ColumnUnit::Bytes => src.len(),
+            ColumnUnit::Chars => String::from_utf8_lossy(src).graphemes(true).count(),
+            ColumnUnit::Display => UnicodeWidthStr::width(String::from_utf8_lossy(src).as_ref()),
+        }
+    }
+}
+
+impl FromStr for ColumnUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bytes" => Ok(ColumnUnit::Bytes),
+            "chars" => Ok(ColumnUnit::Chars),
+            "display" => Ok(ColumnUnit::Display),
+            _ => Err(format!("invalid column unit: {}", s)),
+        }
+    }
+}
+
+pub struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    cond: Condvar,
+}
+
+pub struct MemoryBudgetGuard<'a> {
+    budget: &'a MemoryBudget,
+    size: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `size` bytes are available. A size over the limit is clamped so that it can be acquired alone.
+    pub fn acquire(&self, size: u64) -> MemoryBudgetGuard<'_> {
+        let size = if size < self.limit { size } else { self.limit };
+        let mut used = self.used.lock().unwrap();
+        while *used + size > self.limit {
+            used = self.cond.wait(used).unwrap();
+        }
+        *used += size;
+        MemoryBudgetGuard { budget: self, size }
+    }
+}
+
+impl<'a> Drop for MemoryBudgetGuard<'a> {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().unwrap();
+        *used -= self.size;
+        self.budget.cond.notify_all();
+    }
+}
+
+/// Bandwidth like `50MB/s` in bytes per second, where K, M and G are powers of 1000 and Ki, Mi and Gi are of 1024
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoRate(pub u64);
+
+impl FromStr for IoRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid rate: {}", s);
+        let x = s.trim();
+        let x = x.strip_suffix("/s").unwrap_or(x);
+        let x = x.strip_suffix(['B', 'b']).unwrap_or(x);
+        let pos = x.find(|c: char| !c.is_ascii_digit()).unwrap_or(x.len());
+        let (num, unit) = x.split_at(pos);
+        let unit = match unit.trim().to_ascii_lowercase().as_str() {
+            "" => 1,
+            "k" => 1000,
+            "m" => 1000 * 1000,
+            "g" => 1000 * 1000 * 1000,
+            "ki" => 1 << 10,
+            "mi" => 1 << 20,
+            "gi" => 1 << 30,
+            _ => return Err(err()),
+        };
+        match num.parse::<u64>() {
+            Ok(x) if x != 0 => Ok(IoRate(x.saturating_mul(unit))),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Token bucket shared by the threads reading files, which throttles the total bandwidth to the rate.
+///
+/// The bucket holds the bytes of a second at most, and a read larger than the tokens left goes into debt, which is
+/// paid by sleeping. So the following reads of all threads wait until the debt is refilled.
+pub struct IoLimiter {
+    rate: u64,
+    // Tokens in bytes, which are negative in debt, and the time refilled last
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl IoLimiter {
+    pub fn new(rate: IoRate) -> Self {
+        IoLimiter {
+            rate: rate.0,
+            bucket: Mutex::new((rate.0 as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `size` bytes from the bucket, and blocks until the bytes are refilled if the bucket runs short
+    pub fn acquire(&self, size: u64) {
+        let rate = self.rate as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let tokens = bucket.0 + now.duration_since(bucket.1).as_secs_f64() * rate;
+            *bucket = (tokens.min(rate) - size as f64, now);
+            if bucket.0 < 0.0 {
+                Duration::from_secs_f64(-bucket.0 / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Lowers the CPU and I/O priority of the current thread, which is inherited by the threads spawned after
+#[cfg(unix)]
+pub fn lower_priority() {
+    unsafe {
+        libc::nice(10);
+    }
+
+    // The lowest level of the best-effort class, because the idle class may starve on busy disks
+    #[cfg(target_os = "linux")]
+    unsafe {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_BE: libc::c_long = 2;
+        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, (IOPRIO_CLASS_BE << 13) | 7);
+    }
+}
+
+/// Lowers the CPU and I/O priority of the process by the background mode
+#[cfg(windows)]
+pub fn lower_priority() {
+    use winapi::um::processthreadsapi::{GetCurrentProcess, SetPriorityClass};
+    use winapi::um::winbase::PROCESS_MODE_BACKGROUND_BEGIN;
+    unsafe {
+        SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower_priority() {}
+
+/// Exit status when any match is found ( like grep )
+pub const EXIT_MATCH: i32 = 0;
+/// Exit status when no match is found
+pub const EXIT_NO_MATCH: i32 = 1;
+/// Exit status on errors such as invalid arguments or unreadable files, which takes precedence over matches
+pub const EXIT_ERROR: i32 = 2;
+
+/// Returns the exit status of grep-style by whether any match is found and any error occurred
+pub fn exit_status(found: bool, errored: bool) -> i32 {
+    if errored {
+        EXIT_ERROR
+    } else if found {
+        EXIT_MATCH
+    } else {
+        EXIT_NO_MATCH
+    }
+}
+
 pub fn exit(code: i32, console: &mut Console) -> ! {
     console.reset();
     console.flush();
+    Console::restore_codepage();
     process::exit(code);
 }
 
@@ -115,3 +692,824 @@ pub fn get_c_lflag() -> Option<termios::tcflag_t> {
         None
     }
 }
+
+pub fn transcode_to_utf8(src: &[u8], encoding: &str) -> Option<String> {
+    iconv(src, encoding, "UTF-8").and_then(|x| String::from_utf8(x).ok())
+}
+
+/// Encodes `src` to `encoding` like the keyword searched in files of legacy encodings
+pub fn transcode_from_utf8(src: &[u8], encoding: &str) -> Option<Vec<u8>> {
+    iconv(src, "UTF-8", encoding)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn iconv(src: &[u8], from: &str, to: &str) -> Option<Vec<u8>> {
+    let to = std::ffi::CString::new(to).ok()?;
+    let from = std::ffi::CString::new(from).ok()?;
+
+    let cd = unsafe { libc::iconv_open(to.as_ptr(), from.as_ptr()) };
+    if cd as isize == -1 {
+        return None;
+    }
+
+    let mut src = Vec::from(src);
+    let mut dst = vec![0u8; src.len() * 4];
+    let mut src_ptr = src.as_mut_ptr() as *mut libc::c_char;
+    let mut dst_ptr = dst.as_mut_ptr() as *mut libc::c_char;
+    let mut src_left = src.len();
+    let mut dst_left = dst.len();
+    let ret = unsafe { libc::iconv(cd, &mut src_ptr, &mut src_left, &mut dst_ptr, &mut dst_left) };
+    unsafe { libc::iconv_close(cd) };
+
+    if ret == usize::MAX {
+        return None;
+    }
+    let len = dst.len() - dst_left;
+    dst.truncate(len);
+    Some(dst)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn iconv(_src: &[u8], _from: &str, _to: &str) -> Option<Vec<u8>> {
+    None
+}
+
+/// Returns the ranges of data in a sparse file by SEEK_DATA / SEEK_HOLE, or None if the file has no holes
+/// ( e.g. a file whose blocks are fewer than the length by the compression of btrfs / ZFS )
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn data_ranges(file: &File) -> Option<Vec<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let attr = file.metadata().ok()?;
+    if attr.blocks() * 512 >= attr.len() {
+        return None;
+    }
+
+    let fd = file.as_raw_fd();
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    while pos < attr.len() {
+        let beg = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if beg < 0 {
+            // ENXIO means that there is no data after pos
+            match Error::last_os_error().raw_os_error() {
+                Some(libc::ENXIO) => break,
+                _ => return None,
+            }
+        }
+        let end = unsafe { libc::lseek(fd, beg, libc::SEEK_HOLE) };
+        if end < 0 {
+            return None;
+        }
+        ret.push((beg as u64, end as u64));
+        pos = end as u64;
+    }
+    if ret[..] == [(0, attr.len())] {
+        return None;
+    }
+    Some(ret)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn data_ranges(_file: &File) -> Option<Vec<(u64, u64)>> {
+    None
+}
+
+/// Returns whether the error may be resolved by retrying, such as timeouts and stale handles of network file systems
+pub fn is_transient_error(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => true,
+        _ => is_stale_error(e),
+    }
+}
+
+#[cfg(unix)]
+fn is_stale_error(e: &Error) -> bool {
+    e.raw_os_error() == Some(libc::ESTALE)
+}
+
+// ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED and ERROR_SEM_TIMEOUT are returned by SMB shares
+#[cfg(not(unix))]
+fn is_stale_error(e: &Error) -> bool {
+    matches!(e.raw_os_error(), Some(59) | Some(64) | Some(121))
+}
+
+/// Returns the delay before the retry of `count` ( from 0 ), which is doubled from 100ms
+pub fn retry_delay(count: usize) -> Duration {
+    Duration::from_millis(100 << cmp::min(count, 6))
+}
+
+/// Returns the ID of the device containing the file to detect mount points
+#[cfg(unix)]
+pub fn device_id(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device_id(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Returns the device and the inode, which are the same between hard links of a file
+#[cfg(unix)]
+pub fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Returns the user ID owning the file
+#[cfg(unix)]
+pub fn file_owner(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.uid())
+}
+
+#[cfg(not(unix))]
+pub fn file_owner(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+/// Returns the permission bits of the file including setuid, setgid and sticky bits
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+/// Returns the kind of the file which is neither a regular file, a directory nor a symbolic link ( e.g. fifo )
+#[cfg(unix)]
+pub fn special_file_kind(file_type: &FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_fifo() {
+        Some("fifo")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn special_file_kind(_file_type: &FileType) -> Option<&'static str> {
+    None
+}
+
+/// Returns the user ID of the user name by the password database
+#[cfg(unix)]
+pub fn user_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut ret = std::ptr::null_mut();
+    let err = unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut ret) };
+    if err != 0 || ret.is_null() {
+        None
+    } else {
+        Some(pwd.pw_uid)
+    }
+}
+
+#[cfg(not(unix))]
+pub fn user_id(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Returns the inode of the entry without reading the metadata
+#[cfg(unix)]
+pub fn entry_inode(entry: &DirEntry) -> Option<u64> {
+    use std::os::unix::fs::DirEntryExt;
+    Some(entry.ino())
+}
+
+#[cfg(not(unix))]
+pub fn entry_inode(_entry: &DirEntry) -> Option<u64> {
+    None
+}
+
+/// Converts the path to the extended-length form like `\\?\C:\dir\file` to access paths longer than MAX_PATH
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    let abs = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match std::env::current_dir() {
+            Ok(x) => x.join(path),
+            Err(_) => return Cow::Borrowed(path),
+        }
+    };
+    // 248 is the limit of directories, which is shorter than MAX_PATH ( 260 ) for files
+    if abs.as_os_str().len() < 248 {
+        return Cow::Borrowed(path);
+    }
+
+    // The extended-length form is not normalized by Windows, so `.` and `..` are resolved here
+    let mut ret = OsString::new();
+    let mut parts = Vec::new();
+    for c in abs.components() {
+        match c {
+            Component::Prefix(x) => match x.kind() {
+                Prefix::Disk(d) => ret.push(format!(r"\\?\{}:", d as char)),
+                Prefix::UNC(server, share) => {
+                    ret.push(r"\\?\UNC\");
+                    ret.push(server);
+                    ret.push(r"\");
+                    ret.push(share);
+                }
+                _ => return Cow::Borrowed(path),
+            },
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Normal(x) => parts.push(x),
+        }
+    }
+    for x in parts {
+        ret.push(r"\");
+        ret.push(x);
+    }
+    Cow::Owned(PathBuf::from(ret))
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Command running `cmd` by the shell of the platform
+pub fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut x = Command::new("cmd");
+        x.arg("/C").arg(cmd);
+        x
+    } else {
+        let mut x = Command::new("sh");
+        x.arg("-c").arg(cmd);
+        x
+    }
+}
+
+/// Takes an exclusive advisory lock of the file, and returns false without blocking if another process locks it
+#[cfg(not(windows))]
+pub fn try_lock_exclusive(file: &File) -> Result<bool, Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = Error::last_os_error();
+    if e.kind() == ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct Overlapped {
+    internal: usize,
+    internal_high: usize,
+    offset: u32,
+    offset_high: u32,
+    event: *mut std::ffi::c_void,
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn LockFileEx(
+        file: *mut std::ffi::c_void,
+        flags: u32,
+        reserved: u32,
+        bytes_low: u32,
+        bytes_high: u32,
+        overlapped: *mut Overlapped,
+    ) -> i32;
+}
+
+#[cfg(windows)]
+pub fn try_lock_exclusive(file: &File) -> Result<bool, Error> {
+    use std::os::windows::io::AsRawHandle;
+
+    // LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY
+    let flags = 0x2 | 0x1;
+    let mut overlapped = Overlapped {
+        internal: 0,
+        internal_high: 0,
+        offset: 0,
+        offset_high: 0,
+        event: std::ptr::null_mut(),
+    };
+    let ret = unsafe { LockFileEx(file.as_raw_handle() as _, flags, 0, !0, !0, &mut overlapped) };
+    if ret != 0 {
+        return Ok(true);
+    }
+    let e = Error::last_os_error();
+    // ERROR_LOCK_VIOLATION
+    if e.raw_os_error() == Some(33) {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(windows)]
+#[repr(C)]
+struct Win32FindStreamData {
+    stream_size: i64,
+    stream_name: [u16; 260 + 36],
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn FindFirstStreamW(
+        file_name: *const u16,
+        info_level: u32,
+        find_stream_data: *mut Win32FindStreamData,
+        flags: u32,
+    ) -> *mut std::ffi::c_void;
+    fn FindNextStreamW(find_stream: *mut std::ffi::c_void, find_stream_data: *mut Win32FindStreamData) -> i32;
+    fn FindClose(find_file: *mut std::ffi::c_void) -> i32;
+}
+
+/// Returns the paths like `file.txt:stream` of NTFS alternate data streams of the file
+#[cfg(windows)]
+pub fn alternate_streams(path: &Path) -> Vec<PathBuf> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut data = Win32FindStreamData {
+        stream_size: 0,
+        stream_name: [0; 260 + 36],
+    };
+    let mut ret = Vec::new();
+
+    // 0 is FindStreamInfoStandard
+    let handle = unsafe { FindFirstStreamW(name.as_ptr(), 0, &mut data, 0) };
+    if handle as isize == -1 {
+        return ret;
+    }
+    loop {
+        let len = data
+            .stream_name
+            .iter()
+            .position(|x| *x == 0)
+            .unwrap_or(data.stream_name.len());
+        let stream = String::from_utf16_lossy(&data.stream_name[..len]);
+        // Stream names are like ":stream:$DATA", and the default stream is "::$DATA"
+        if let Some(x) = stream.strip_suffix(":$DATA") {
+            if x.len() > 1 {
+                let mut path = path.as_os_str().to_os_string();
+                path.push(x);
+                ret.push(PathBuf::from(path));
+            }
+        }
+        if unsafe { FindNextStreamW(handle, &mut data) } == 0 {
+            break;
+        }
+    }
+    unsafe { FindClose(handle) };
+    ret
+}
+
+#[cfg(not(windows))]
+pub fn alternate_streams(_path: &Path) -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Returns whether the path is an alternate data stream like `file.txt:stream`
+pub fn is_alternate_stream(path: &Path) -> bool {
+    cfg!(windows) && path.file_name().is_some_and(|x| x.to_string_lossy().contains(':'))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn path_to_cstring(path: &Path) -> Result<std::ffi::CString, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::from(ErrorKind::InvalidInput))
+}
+
+/// Extended attributes holding the security context or Finder metadata, which are restored even without preserve
+#[cfg(target_os = "linux")]
+pub const METADATA_XATTRS: &[&str] = &["security.selinux"];
+
+/// Extended attributes holding the security context or Finder metadata, which are restored even without preserve
+#[cfg(target_os = "macos")]
+pub const METADATA_XATTRS: &[&str] = &["com.apple.FinderInfo", "com.apple.ResourceFork"];
+
+// An empty buffer is passed as NULL to get the required size
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn buf_ptr(buf: &mut [u8]) -> *mut libc::c_void {
+    if buf.is_empty() {
+        std::ptr::null_mut()
+    } else {
+        buf.as_mut_ptr() as *mut libc::c_void
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sys_listxattr(path: &std::ffi::CStr, buf: &mut [u8]) -> isize {
+    unsafe { libc::llistxattr(path.as_ptr(), buf_ptr(buf) as *mut libc::c_char, buf.len()) }
+}
+
+#[cfg(target_os = "macos")]
+fn sys_listxattr(path: &std::ffi::CStr, buf: &mut [u8]) -> isize {
+    unsafe {
+        libc::listxattr(
+            path.as_ptr(),
+            buf_ptr(buf) as *mut libc::c_char,
+            buf.len(),
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sys_getxattr(path: &std::ffi::CStr, name: &std::ffi::CStr, buf: &mut [u8]) -> isize {
+    unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf_ptr(buf), buf.len()) }
+}
+
+#[cfg(target_os = "macos")]
+fn sys_getxattr(path: &std::ffi::CStr, name: &std::ffi::CStr, buf: &mut [u8]) -> isize {
+    unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf_ptr(buf),
+            buf.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sys_setxattr(path: &std::ffi::CStr, name: &std::ffi::CStr, value: &[u8]) -> libc::c_int {
+    unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn sys_setxattr(path: &std::ffi::CStr, name: &std::ffi::CStr, value: &[u8]) -> libc::c_int {
+    unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn get_xattr_cstr(path: &std::ffi::CStr, name: &std::ffi::CStr) -> Result<Option<Vec<u8>>, Error> {
+    #[cfg(target_os = "linux")]
+    let no_attr = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    let no_attr = libc::ENOATTR;
+
+    let size = sys_getxattr(path, name, &mut []);
+    if size < 0 {
+        let e = Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(x) if x == no_attr || x == libc::ENOTSUP => Ok(None),
+            _ => Err(e),
+        };
+    }
+    let mut value = vec![0u8; size as usize];
+    let size = sys_getxattr(path, name, &mut value);
+    if size < 0 {
+        return Err(Error::last_os_error());
+    }
+    value.truncate(size as usize);
+    Ok(Some(value))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn get_xattrs(path: &Path) -> Result<Vec<(std::ffi::CString, Vec<u8>)>, Error> {
+    let path = path_to_cstring(path)?;
+    let mut ret = Vec::new();
+
+    let size = sys_listxattr(&path, &mut []);
+    if size < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = sys_listxattr(&path, &mut names);
+    if size < 0 {
+        return Err(Error::last_os_error());
+    }
+    names.truncate(size as usize);
+
+    for name in names.split(|x| *x == 0).filter(|x| !x.is_empty()) {
+        let name = std::ffi::CString::new(name).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+        if let Some(value) = get_xattr_cstr(&path, &name)? {
+            ret.push((name, value));
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Returns the extended attributes of `METADATA_XATTRS` which the path has
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn get_metadata_xattrs(path: &Path) -> Result<Vec<(std::ffi::CString, Vec<u8>)>, Error> {
+    let path = path_to_cstring(path)?;
+    let mut ret = Vec::new();
+    for name in METADATA_XATTRS {
+        let name = std::ffi::CString::new(*name).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+        if let Some(value) = get_xattr_cstr(&path, &name)? {
+            ret.push((name, value));
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn set_xattrs(path: &Path, xattrs: &[(std::ffi::CString, Vec<u8>)]) -> Result<(), Error> {
+    let path = path_to_cstring(path)?;
+    for (name, value) in xattrs {
+        if sys_setxattr(&path, name, value) < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Extended attribute of the POSIX ACL of a file, which is inherited from the default ACL of the directory at creation
+#[cfg(target_os = "linux")]
+const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+
+/// Gives the group and the POSIX ACL of `target` to the temporary file at `path`.
+///
+/// The temporary file gets the group of the user or the setgid directory and the ACL from the default ACL of the
+/// directory, which differ from the target if they are changed after creation.
+/// The group is left as inherited if the user isn't a member of the group of the target.
+#[cfg(unix)]
+pub fn inherit_access(path: &Path, target_path: &Path, target: &Metadata) -> Result<(), Error> {
+    use std::os::unix::fs::MetadataExt;
+    if std::fs::symlink_metadata(path)?.gid() != target.gid() {
+        if let Err(e) = std::os::unix::fs::chown(path, None, Some(target.gid())) {
+            if e.raw_os_error() != Some(libc::EPERM) {
+                return Err(e);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let name = std::ffi::CString::new(ACL_ACCESS_XATTR).map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+        let (path, target_path) = (path_to_cstring(path)?, path_to_cstring(target_path)?);
+        if let Some(acl) = get_xattr_cstr(&target_path, &name)? {
+            if get_xattr_cstr(&path, &name)?.as_ref() != Some(&acl) && sys_setxattr(&path, &name, &acl) < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn inherit_access(_path: &Path, _target_path: &Path, _target: &Metadata) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Sets the file flags shown by Finder ( e.g. hidden ), because immutable or append-only flags prevent the rename
+#[cfg(target_os = "macos")]
+pub fn set_finder_flags(path: &Path, flags: u32) -> Result<(), Error> {
+    let path = path_to_cstring(path)?;
+    let flags = flags & libc::UF_HIDDEN;
+    if flags == 0 {
+        return Ok(());
+    }
+    if unsafe { libc::chflags(path.as_ptr(), flags as _) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn util_split_args() {
+        assert_eq!(
+            split_args("  --regex  --max-threads 4 "),
+            vec!["--regex", "--max-threads", "4"]
+        );
+        assert_eq!(
+            split_args("--not 'a b' \"c \\\" d\" e\\ f"),
+            vec!["--not", "a b", "c \" d", "e f"]
+        );
+        assert_eq!(split_args("--field-separator ''"), vec!["--field-separator", ""]);
+        assert!(split_args("").is_empty());
+    }
+
+    #[test]
+    fn util_index_range() {
+        let range = |beg, end| IndexRange { beg, end };
+        assert_eq!("100..250".parse::<IndexRange>(), Ok(range(100, Some(250))));
+        assert_eq!("100..".parse::<IndexRange>(), Ok(range(100, None)));
+        assert_eq!("..250".parse::<IndexRange>(), Ok(range(0, Some(250))));
+        assert_eq!("7".parse::<IndexRange>(), Ok(range(7, Some(7))));
+        assert!("250..100".parse::<IndexRange>().is_err());
+        assert!("a..b".parse::<IndexRange>().is_err());
+        assert!(range(1, Some(3)).contains(3));
+        assert!(!range(1, Some(3)).contains(4));
+        assert!(range(1, None).contains(usize::MAX));
+    }
+
+    #[test]
+    fn util_split_root_label() {
+        assert_eq!(split_root_label("frontend:web/"), Some(("frontend", "web/")));
+        assert_eq!(split_root_label("v1.2:../old"), Some(("v1.2", "../old")));
+        assert_eq!(split_root_label("C:\\src"), None);
+        assert_eq!(split_root_label("src"), None);
+        assert_eq!(split_root_label("api:"), None);
+        assert_eq!(split_root_label("a b:src"), None);
+        assert_eq!(split_root_label("./src"), None);
+    }
+
+    #[test]
+    fn util_path_prefix() {
+        let prefixes: Vec<PathPrefix> = vec![
+            "/work=/home/me/work".parse().unwrap(),
+            "/work/src=/mnt/src".parse().unwrap(),
+        ];
+        let map = |x: &str| PathPrefix::map(&prefixes, Path::new(x)).into_owned();
+        assert_eq!(map("/work/a.rs"), PathBuf::from("/home/me/work/a.rs"));
+        assert_eq!(map("/work/src/b.rs"), PathBuf::from("/mnt/src/b.rs"));
+        assert_eq!(map("/work/src"), PathBuf::from("/mnt/src"));
+        assert_eq!(map("/workspace/a.rs"), PathBuf::from("/workspace/a.rs"));
+        assert_eq!(map("./work/a.rs"), PathBuf::from("./work/a.rs"));
+        assert!("/work".parse::<PathPrefix>().is_err());
+        assert!("=/work".parse::<PathPrefix>().is_err());
+    }
+
+    #[test]
+    fn util_io_limiter() {
+        assert_eq!("50MB/s".parse(), Ok(IoRate(50_000_000)));
+        assert_eq!("2KiB/s".parse(), Ok(IoRate(2048)));
+        assert_eq!("1 Gi".parse(), Ok(IoRate(1 << 30)));
+        assert_eq!("100".parse(), Ok(IoRate(100)));
+        assert!("0MB/s".parse::<IoRate>().is_err());
+        assert!("50XB/s".parse::<IoRate>().is_err());
+        assert!("MB/s".parse::<IoRate>().is_err());
+
+        // The bucket is full at first, and the debt is paid by sleeping
+        let limiter = IoLimiter::new(IoRate(10_000));
+        let beg = Instant::now();
+        limiter.acquire(10_000);
+        assert!(beg.elapsed() < Duration::from_millis(100));
+        limiter.acquire(2_000);
+        assert!(beg.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn util_exit_status() {
+        assert_eq!(exit_status(true, false), EXIT_MATCH);
+        assert_eq!(exit_status(false, false), EXIT_NO_MATCH);
+        assert_eq!(exit_status(true, true), EXIT_ERROR);
+        assert_eq!(exit_status(false, true), EXIT_ERROR);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn util_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a");
+        let dst = dir.path().join("b");
+        std::fs::write(&src, "a").unwrap();
+        std::fs::write(&dst, "b").unwrap();
+
+        let name = std::ffi::CString::new("user.test").unwrap();
+        if set_xattrs(&src, &[(name.clone(), b"x".to_vec())]).is_err() {
+            // user extended attributes are not supported by the filesystem
+            return;
+        }
+        let xattrs = get_xattrs(&src).unwrap();
+        assert!(xattrs.contains(&(name.clone(), b"x".to_vec())));
+        assert!(!get_metadata_xattrs(&src).unwrap().iter().any(|(x, _)| *x == name));
+
+        set_xattrs(&dst, &xattrs).unwrap();
+        assert!(get_xattrs(&dst).unwrap().contains(&(name, b"x".to_vec())));
+    }
+
+    #[test]
+    fn util_unescape() {
+        assert_eq!(unescape(br"a\x00\xFFb"), b"a\x00\xffb");
+        assert_eq!(unescape(br"\\x41\x4"), br"\x41\x4");
+        assert_eq!(unescape(br"\n\xzz"), br"\n\xzz");
+        assert_eq!(unescape_regex(br"a\xff."), br"a(?-u:\xff).");
+        assert_eq!(unescape_regex(br"\\xff\x{41}"), br"\\xff\x{41}");
+    }
+
+    #[test]
+    fn util_convert_case() {
+        assert_eq!(convert_case("HTTPServer", "snake").unwrap(), "http_server");
+        assert_eq!(convert_case("fooBar_baz", "pascal").unwrap(), "FooBarBaz");
+        assert_eq!(convert_case("user-id", "camel").unwrap(), "userId");
+        assert_eq!(convert_case("Base64Encode", "kebab").unwrap(), "base64-encode");
+        assert!(convert_case("foo", "title").is_none());
+    }
+
+    #[test]
+    fn util_parse_map() {
+        let ret = parse_map(b"foo\tbar\r\n\nbaz\t\n").unwrap();
+        assert_eq!(
+            ret,
+            vec![(b"foo".to_vec(), b"bar".to_vec()), (b"baz".to_vec(), Vec::new())]
+        );
+        assert_eq!(parse_map(b"foo\tbar\nbaz\n"), Err(2));
+        assert_eq!(parse_map(b"\tbar\n"), Err(1));
+    }
+
+    #[test]
+    fn util_parse_hex() {
+        assert_eq!(parse_hex("DE AD be ef"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(parse_hex("deadbeef"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(parse_hex("DE A"), None);
+        assert_eq!(parse_hex("zz"), None);
+        assert_eq!(parse_hex(" "), None);
+    }
+
+    #[test]
+    fn util_transient_error() {
+        assert!(is_transient_error(&Error::from(ErrorKind::TimedOut)));
+        assert!(is_transient_error(&Error::from(ErrorKind::WouldBlock)));
+        assert!(!is_transient_error(&Error::from(ErrorKind::NotFound)));
+        #[cfg(unix)]
+        assert!(is_transient_error(&Error::from_raw_os_error(libc::ESTALE)));
+        assert_eq!(retry_delay(0), Duration::from_millis(100));
+        assert_eq!(retry_delay(2), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn util_column_unit() {
+        let prefix = "a\u{3042}e\u{301}\u{1f44d}".as_bytes();
+        assert_eq!(ColumnUnit::Bytes.column(prefix, 0), 12);
+        assert_eq!(ColumnUnit::Chars.column(prefix, 0), 5);
+        assert_eq!(ColumnUnit::Display.column(prefix, 0), 7);
+        assert_eq!(ColumnUnit::Chars.column(b"", 0), 1);
+
+        assert_eq!(ColumnUnit::Bytes.column(b"\tab\t", 0), 5);
+        assert_eq!(ColumnUnit::Bytes.column(b"\tab\t", 4), 9);
+        assert_eq!(ColumnUnit::Bytes.column(b"abcd\t", 4), 9);
+        assert_eq!(ColumnUnit::Display.column("\u{3042}\t".as_bytes(), 8), 9);
+        assert_eq!("display".parse::<ColumnUnit>(), Ok(ColumnUnit::Display));
+        assert!("cells".parse::<ColumnUnit>().is_err());
+    }
+
+    #[test]
+    fn util_io_strategy() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        assert!(IoStrategy::Auto.use_mmap(2048, 1024));
+        assert!(!IoStrategy::Auto.use_mmap(1024, 1024));
+        assert!(IoStrategy::Mmap.use_mmap(1, 1024));
+        assert!(!IoStrategy::Mmap.use_mmap(0, 1024));
+        assert!(!IoStrategy::Read.use_mmap(2048, 1024));
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"quartz").unwrap();
+        for io in &[IoStrategy::Mmap, IoStrategy::Read] {
+            file.seek(SeekFrom::Start(0)).unwrap();
+            assert_eq!(io.load(&file, 0).unwrap().deref(), b"quartz");
+        }
+    }
+}